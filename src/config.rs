@@ -1,17 +1,70 @@
+use crate::ingest::graphite::GraphiteTemplates;
 use dotenv::dotenv;
 use std::env;
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("missing environment variable {0}")]
+    Missing(&'static str),
+    #[error("invalid value {value:?} for {key}")]
+    Invalid { key: &'static str, value: String },
+}
+
+/// Where metrics are persisted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// MongoDB for storage, Redis for caching.
+    Mongo,
+    /// Process memory only, no external services. Intended for development and tests.
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mongo" | "mongodb" => Ok(StorageBackend::Mongo),
+            "memory" | "in-memory" => Ok(StorageBackend::Memory),
+            _ => Err(()),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub app_env: String,
+    pub storage_backend: StorageBackend,
     pub mongo_uri: String,
     pub redis_uri: String,
     pub port: String,
     pub database_name: String,
+    pub max_batch_size: usize,
+    pub max_timestamp_age_secs: u64,
+    pub max_timestamp_future_secs: u64,
+    /// `GET /metrics` page size when the request has no `limit`, and the cap on
+    /// `limit`. Both are at least 1, and the default is at most the cap.
+    pub default_page_size: usize,
+    pub max_page_size: usize,
+    /// UDP port for the StatsD listener; the listener is disabled when unset.
+    pub statsd_port: Option<u16>,
+    pub statsd_flush_interval_secs: u64,
+    /// TCP ports for the Graphite plaintext and pickle listeners; each is
+    /// disabled when unset.
+    pub graphite_port: Option<u16>,
+    pub graphite_pickle_port: Option<u16>,
+    pub graphite_templates: GraphiteTemplates,
+    /// Disables both cache tiers and the Redis connection when false.
+    pub cache_enabled: bool,
+    /// Limits for the in-process query cache; zero entries disables it.
+    pub local_cache_max_entries: usize,
+    pub local_cache_max_bytes: usize,
+    pub local_cache_ttl_secs: u64,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, env::VarError> {
+    pub fn from_env() -> Result<Self, ConfigError> {
         let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "local".to_string());
 
         let env_file = format!(".env.{environment}");
@@ -22,17 +75,93 @@ impl Config {
         }
 
         let app_env = env::var("APP_ENV").unwrap_or_else(|_| "dev".to_string());
-        let mongo_uri = env::var("MONGO_URI")?;
-        let redis_uri = env::var("REDIS_URI")?;
+        let storage_backend = parse_var("STORAGE_BACKEND", StorageBackend::Mongo)?;
+
+        // The in-memory backend needs neither MongoDB nor Redis.
+        let required = |key: &'static str| match env::var(key) {
+            Ok(value) => Ok(value),
+            Err(_) if storage_backend == StorageBackend::Memory => Ok(String::new()),
+            Err(_) => Err(ConfigError::Missing(key)),
+        };
+        let mongo_uri = required("MONGO_URI")?;
+        // Redis is optional; without it the server runs with the in-process cache only
+        let redis_uri = env::var("REDIS_URI").unwrap_or_default();
+
         let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
         let database_name = format!("telemetry_server_{app_env}");
+        let max_batch_size = parse_var("MAX_BATCH_SIZE", 1000)?;
+        let max_timestamp_age_secs = parse_var("MAX_TIMESTAMP_AGE_SECS", 30 * 24 * 60 * 60)?;
+        let max_timestamp_future_secs = parse_var("MAX_TIMESTAMP_FUTURE_SECS", 10 * 60)?;
+        let default_page_size = parse_var_at_least("DEFAULT_PAGE_SIZE", 100, 1)?;
+        let max_page_size = parse_var_at_least("MAX_PAGE_SIZE", 1000, 1)?;
+        if default_page_size > max_page_size {
+            return Err(ConfigError::Invalid {
+                key: "DEFAULT_PAGE_SIZE",
+                value: default_page_size.to_string(),
+            });
+        }
+        let statsd_port = parse_optional_var("STATSD_PORT")?;
+        let statsd_flush_interval_secs = parse_var_at_least("STATSD_FLUSH_INTERVAL_SECS", 10, 1)?;
+        let graphite_port = parse_optional_var("GRAPHITE_PORT")?;
+        let graphite_pickle_port = parse_optional_var("GRAPHITE_PICKLE_PORT")?;
+        let graphite_templates = parse_var("GRAPHITE_TEMPLATES", GraphiteTemplates::default())?;
+        let cache_enabled = parse_var("CACHE_ENABLED", true)?;
+        let local_cache_max_entries = parse_var("LOCAL_CACHE_MAX_ENTRIES", 10_000)?;
+        let local_cache_max_bytes = parse_var("LOCAL_CACHE_MAX_BYTES", 64 * 1024 * 1024)?;
+        let local_cache_ttl_secs = parse_var("LOCAL_CACHE_TTL_SECS", 30)?;
 
         Ok(Config {
             app_env,
+            storage_backend,
             mongo_uri,
             redis_uri,
             port,
             database_name,
+            max_batch_size,
+            max_timestamp_age_secs,
+            max_timestamp_future_secs,
+            default_page_size,
+            max_page_size,
+            statsd_port,
+            statsd_flush_interval_secs,
+            graphite_port,
+            graphite_pickle_port,
+            graphite_templates,
+            cache_enabled,
+            local_cache_max_entries,
+            local_cache_max_bytes,
+            local_cache_ttl_secs,
         })
     }
 }
+
+/// Reads an optional variable, falling back to `default` when it is unset.
+fn parse_var<T: FromStr>(key: &'static str, default: T) -> Result<T, ConfigError> {
+    Ok(parse_optional_var(key)?.unwrap_or(default))
+}
+
+/// Like `parse_var`, rejecting values below `min`.
+fn parse_var_at_least<T: FromStr + PartialOrd + ToString>(
+    key: &'static str,
+    default: T,
+    min: T,
+) -> Result<T, ConfigError> {
+    let value = parse_var(key, default)?;
+    if value < min {
+        return Err(ConfigError::Invalid {
+            key,
+            value: value.to_string(),
+        });
+    }
+    Ok(value)
+}
+
+fn parse_optional_var<T: FromStr>(key: &'static str) -> Result<Option<T>, ConfigError> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Invalid { key, value }),
+        Err(_) => Ok(None),
+    }
+}
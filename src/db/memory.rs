@@ -0,0 +1,99 @@
+use crate::db::store::{MetricStore, StoreError};
+use crate::models::{Metric, MetricFilter, UpdateMetricRequest};
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Metric store that keeps everything in process memory.
+///
+/// Used for hermetic tests and for running the server as a single binary
+/// without MongoDB. Data is lost on restart.
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    metrics: Arc<RwLock<HashMap<ObjectId, Metric>>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl MetricStore for InMemoryStore {
+    async fn insert(&self, mut metric: Metric) -> Result<Metric, StoreError> {
+        let id = metric.id.unwrap_or_default();
+        metric.id = Some(id);
+
+        self.metrics.write().unwrap().insert(id, metric.clone());
+
+        Ok(metric)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Metric>, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        Ok(self.metrics.read().unwrap().get(&object_id).cloned())
+    }
+
+    async fn find(&self, filter: &MetricFilter) -> Result<Vec<Metric>, StoreError> {
+        let mut metrics: Vec<Metric> = self
+            .metrics
+            .read()
+            .unwrap()
+            .values()
+            .filter(|metric| filter.matches(metric))
+            .filter(|metric| filter.cursor.as_ref().is_none_or(|c| c.is_past(metric)))
+            .cloned()
+            .collect();
+
+        metrics.sort_by(|a, b| {
+            let ordering = filter.sort.key(a).compare(&filter.sort.key(b));
+            filter.order.apply(ordering.then(a.id.cmp(&b.id)))
+        });
+
+        if let Some(limit) = filter.limit {
+            metrics.truncate(limit);
+        }
+
+        Ok(metrics)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: UpdateMetricRequest,
+    ) -> Result<Option<Metric>, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        let mut metrics = self.metrics.write().unwrap();
+        let Some(metric) = metrics.get_mut(&object_id) else {
+            return Ok(None);
+        };
+
+        if let Some(name) = update.name {
+            metric.name = name;
+        }
+
+        if let Some(tags) = update.tags {
+            metric.tags = Some(tags);
+        }
+
+        if let Some(labels) = update.labels {
+            metric.labels = labels;
+        }
+
+        if let Some(value) = update.value {
+            metric.value = value;
+        }
+
+        Ok(Some(metric.clone()))
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        Ok(self.metrics.write().unwrap().remove(&object_id).is_some())
+    }
+}
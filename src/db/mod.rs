@@ -1,5 +1,9 @@
+pub mod memory;
 pub mod mongo;
 pub mod redis;
+pub mod store;
 
+pub use memory::InMemoryStore;
 pub use mongo::MongoDb;
 pub use redis::RedisDb;
+pub use store::{MetricStore, MetricStream, StoreError};
@@ -1,7 +1,20 @@
 use crate::config::Config;
-use crate::models::Metric;
-use bson::doc;
-use mongodb::{options::ClientOptions, Client, Collection, Database, IndexModel};
+use crate::db::store::{rate_range, MetricStore, MetricStream, StoreError};
+use crate::models::{
+    BucketValue, Grouping, LabelMatcher, Labels, Metric, MetricFilter, MetricKind, PageCursor,
+    RangeFunction, RangeQuery, SeriesSummary, SortKey, SortOrder, UpdateMetricRequest,
+};
+use async_trait::async_trait;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use futures::stream::{StreamExt, TryStreamExt};
+use mongodb::{
+    error::ErrorKind,
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOptions, InsertManyOptions, ReturnDocument,
+    },
+    Client, Collection, Database, IndexModel,
+};
+use serde::Serialize;
 
 #[derive(Clone)]
 pub struct MongoDb {
@@ -33,6 +46,12 @@ impl MongoDb {
             }
         }
 
+        match mongo_db.backfill_labels().await {
+            Ok(Some(count)) => log::info!("Backfilled labels from tags on {count} metrics"),
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to backfill labels from tags: {e}"),
+        }
+
         Ok(mongo_db)
     }
 
@@ -40,6 +59,69 @@ impl MongoDb {
         self.database.collection::<Metric>("metrics")
     }
 
+    /// Opens a cursor over the metrics matching `filter`, sorted and paged as it asks.
+    async fn find_cursor(
+        &self,
+        filter: &MetricFilter,
+    ) -> Result<mongodb::Cursor<Metric>, mongodb::error::Error> {
+        let find_options = FindOptions::builder()
+            .sort(sort_document(filter))
+            .limit(filter.limit.map(|limit| limit as i64))
+            .build();
+
+        self.metrics_collection()
+            .find(find_query(filter), find_options)
+            .await
+    }
+
+    /// Metrics stored before labels existed only carry `key:value` tags. Copies
+    /// those into `labels`, as `labels_from_tags` does for new metrics, so label
+    /// selectors and groupings see them too.
+    ///
+    /// The scan runs once per database: it is recorded in the `migrations`
+    /// collection, and later starts return `None` without touching the metrics.
+    /// Otherwise returns how many metrics were updated.
+    async fn backfill_labels(&self) -> Result<Option<u64>, mongodb::error::Error> {
+        let migrations = self.database.collection::<Document>("migrations");
+        let marker = doc! { "_id": "labels_from_tags" };
+        if migrations.find_one(marker.clone(), None).await?.is_some() {
+            return Ok(None);
+        }
+
+        let label_tag = "^[a-zA-Z_][a-zA-Z0-9_]*:.";
+        let separator = doc! { "$indexOfCP": ["$$tag", ":"] };
+
+        let result = self
+            .metrics_collection()
+            .update_many(
+                doc! {
+                    "labels": { "$exists": false },
+                    "tags": { "$regex": label_tag },
+                },
+                vec![doc! { "$set": { "labels": { "$arrayToObject": { "$map": {
+                    "input": { "$filter": {
+                        "input": "$tags",
+                        "as": "tag",
+                        "cond": { "$regexMatch": { "input": "$$tag", "regex": label_tag } },
+                    } },
+                    "as": "tag",
+                    "in": {
+                        "k": { "$substrCP": ["$$tag", 0, separator.clone()] },
+                        "v": { "$substrCP": [
+                            "$$tag",
+                            { "$add": [separator, 1] },
+                            { "$strLenCP": "$$tag" },
+                        ] },
+                    },
+                } } } } }],
+                None,
+            )
+            .await?;
+
+        migrations.insert_one(marker, None).await?;
+        Ok(Some(result.modified_count))
+    }
+
     async fn create_indexes(&self) -> Result<(), mongodb::error::Error> {
         let collection = self.metrics_collection();
 
@@ -49,6 +131,9 @@ impl MongoDb {
         // Index on tags
         let tags_index = IndexModel::builder().keys(doc! { "tags": 1 }).build();
 
+        // Wildcard index so matchers on any label key can use an index
+        let labels_index = IndexModel::builder().keys(doc! { "labels.$**": 1 }).build();
+
         // Index on timestamp
         let timestamp_index = IndexModel::builder().keys(doc! { "timestamp": -1 }).build();
 
@@ -71,6 +156,7 @@ impl MongoDb {
                 vec![
                     name_index,
                     tags_index,
+                    labels_index,
                     timestamp_index,
                     compound_index,
                     ttl_index,
@@ -84,3 +170,471 @@ impl MongoDb {
         Ok(())
     }
 }
+
+/// Translates a `MetricFilter` into a MongoDB query document.
+pub fn filter_query(filter: &MetricFilter) -> Document {
+    let mut query = doc! {};
+
+    if let Some(name) = &filter.name {
+        query.insert("name", name);
+    }
+
+    if let Some(tags) = &filter.tags {
+        query.insert("tags", doc! { "$in": tags });
+    }
+
+    let mut conditions: Vec<Document> = filter
+        .labels
+        .iter()
+        .flat_map(|selector| &selector.0)
+        .map(label_condition)
+        .collect();
+
+    // Separate conditions, so e.g. two `gt` predicates do not overwrite each other
+    conditions.extend(filter.values.iter().map(|predicate| {
+        doc! { "value": { predicate.op.mongo_operator(): predicate.value } }
+    }));
+
+    if !conditions.is_empty() {
+        query.insert("$and", conditions);
+    }
+
+    if let Some(kind) = filter.kind {
+        // Documents without a kind predate kinds and are gauges
+        if kind == MetricKind::Gauge {
+            query.insert("kind", doc! { "$in": [kind.to_string(), Bson::Null] });
+        } else {
+            query.insert("kind", kind.to_string());
+        }
+    }
+
+    let (start, end) = filter.time_bounds();
+    let mut date_filter = doc! {};
+
+    if let Some(start) = start {
+        date_filter.insert("$gte", start);
+    }
+
+    if let Some(end) = end {
+        date_filter.insert("$lte", end);
+    }
+
+    if !date_filter.is_empty() {
+        query.insert("timestamp", date_filter);
+    }
+
+    query
+}
+
+/// The query of a `find` for `filter`: its selection plus the page cursor.
+pub fn find_query(filter: &MetricFilter) -> Document {
+    let mut query = filter_query(filter);
+    if let Some(cursor) = &filter.cursor {
+        query.insert("$or", cursor_condition(cursor));
+    }
+    query
+}
+
+/// The sort of a `find` for `filter`, with `_id` breaking ties for paging.
+pub fn sort_document(filter: &MetricFilter) -> Document {
+    let direction = filter.order.direction();
+    doc! { filter.sort.as_str(): direction, "_id": direction }
+}
+
+/// The pipeline `MongoDb::summarize` runs.
+pub fn summarize_pipeline(filter: &MetricFilter, grouping: &Grouping) -> Vec<Document> {
+    vec![
+        doc! { "$match": filter_query(filter) },
+        doc! { "$group": {
+            "_id": { "name": "$name", "labels": group_labels(grouping) },
+            "sum": { "$sum": "$value" },
+            "count": { "$sum": 1 },
+            "min": { "$min": "$value" },
+            "max": { "$max": "$value" },
+            "mean": { "$avg": "$value" },
+            "std_dev": { "$stdDevPop": "$value" },
+        } },
+    ]
+}
+
+/// The pipeline `MongoDb::aggregate_range` runs. `None` for rate functions,
+/// which are computed from the metrics themselves, see `rate_range`.
+pub fn range_pipeline(query: &RangeQuery) -> Option<Vec<Document>> {
+    let value = match query.function {
+        RangeFunction::Rate(_) => return None,
+        RangeFunction::Avg => doc! { "$avg": "$value" },
+        RangeFunction::Sum => doc! { "$sum": "$value" },
+        RangeFunction::Min => doc! { "$min": "$value" },
+        RangeFunction::Max => doc! { "$max": "$value" },
+        RangeFunction::Count => doc! { "$sum": 1 },
+    };
+
+    Some(vec![
+        doc! { "$match": filter_query(&query.filter) },
+        doc! { "$group": {
+            "_id": {
+                "name": "$name",
+                "labels": group_labels(&query.grouping),
+                "start": { "$dateTrunc": {
+                    "date": "$timestamp",
+                    "unit": "second",
+                    "binSize": query.step.secs() as i64,
+                } },
+            },
+            "value": value,
+        } },
+    ])
+}
+
+/// A command as `MongoDb` would send it for the `metrics` collection, in
+/// relaxed extended JSON. Reported by `GET /query/explain`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum MongoCommand {
+    Find {
+        filter: serde_json::Value,
+        sort: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    Aggregate {
+        pipeline: Vec<serde_json::Value>,
+    },
+}
+
+impl MongoCommand {
+    pub fn find(filter: &MetricFilter) -> Self {
+        MongoCommand::Find {
+            filter: extended_json(find_query(filter)),
+            sort: extended_json(sort_document(filter)),
+            limit: filter.limit,
+        }
+    }
+
+    pub fn aggregate(pipeline: Vec<Document>) -> Self {
+        MongoCommand::Aggregate {
+            pipeline: pipeline.into_iter().map(extended_json).collect(),
+        }
+    }
+}
+
+fn extended_json(document: Document) -> serde_json::Value {
+    Bson::Document(document).into_relaxed_extjson()
+}
+
+/// Translates one label matcher into a query on the `labels` subdocument. Missing
+/// labels fall through `$ne`, `$not` and `$nin`, matching `LabelMatcher::matches`.
+fn label_condition(matcher: &LabelMatcher) -> Document {
+    let field = format!("labels.{}", matcher.name());
+
+    match matcher {
+        LabelMatcher::Equal(_, value) => doc! { field: value },
+        LabelMatcher::NotEqual(_, value) => doc! { field: { "$ne": value } },
+        LabelMatcher::Regex(_, pattern, _) => {
+            doc! { field: { "$regex": format!("^(?:{pattern})$") } }
+        }
+        LabelMatcher::NotRegex(_, pattern, _) => doc! { field: { "$not": bson::Regex {
+            pattern: format!("^(?:{pattern})$"),
+            options: String::new(),
+        } } },
+        LabelMatcher::In(_, values) => doc! { field: { "$in": values } },
+        LabelMatcher::NotIn(_, values) => doc! { field: { "$nin": values } },
+    }
+}
+
+/// Matches documents that sort after `cursor`: past its sort key, or equal to it
+/// and past its id.
+fn cursor_condition(cursor: &PageCursor) -> Vec<Document> {
+    let op = match cursor.order {
+        SortOrder::Asc => "$gt",
+        SortOrder::Desc => "$lt",
+    };
+    let field = cursor.after.field().as_str();
+    let key = match &cursor.after {
+        SortKey::Timestamp(ms) => Bson::DateTime(bson::DateTime::from_millis(*ms)),
+        SortKey::Value(value) => Bson::Double(*value),
+        SortKey::Name(name) => Bson::String(name.clone()),
+    };
+
+    vec![
+        doc! { field: { op: key.clone() } },
+        doc! { field: key, "_id": { op: cursor.id } },
+    ]
+}
+
+/// The `$group` key expression for the labels `grouping` keeps. Label names are
+/// validated, so they are safe to use in field paths.
+fn group_labels(grouping: &Grouping) -> Bson {
+    match grouping {
+        Grouping::Name => Bson::Document(doc! {}),
+        Grouping::By(keys) => Bson::Document(
+            keys.iter()
+                .map(|key| (key.clone(), Bson::String(format!("$labels.{key}"))))
+                .collect(),
+        ),
+        Grouping::Without(keys) => Bson::Document(doc! { "$arrayToObject": { "$filter": {
+            "input": { "$objectToArray": { "$ifNull": ["$labels", {}] } },
+            "as": "label",
+            "cond": { "$not": { "$in": ["$$label.k", keys] } },
+        } } }),
+    }
+}
+
+/// Reads the metric name and grouping labels from a `$group` output's `_id`.
+fn series_key(group: &Document) -> (String, Labels) {
+    let id = group.get_document("_id").ok();
+    let name = id
+        .and_then(|id| id.get_str("name").ok())
+        .unwrap_or_default()
+        .to_string();
+    let labels = id
+        .and_then(|id| id.get_document("labels").ok())
+        .and_then(|labels| bson::from_document(labels.clone()).ok())
+        .unwrap_or_default();
+
+    (name, labels)
+}
+
+/// Reads a numeric aggregation output, which MongoDB may return as int32, int64 or double.
+fn bson_number(value: Option<&Bson>) -> Option<f64> {
+    match value? {
+        Bson::Double(v) => Some(*v),
+        Bson::Int32(v) => Some(*v as f64),
+        Bson::Int64(v) => Some(*v as f64),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl MetricStore for MongoDb {
+    async fn insert(&self, mut metric: Metric) -> Result<Metric, StoreError> {
+        let insert_result = self.metrics_collection().insert_one(&metric, None).await?;
+        metric.id = insert_result.inserted_id.as_object_id();
+
+        Ok(metric)
+    }
+
+    async fn insert_many(
+        &self,
+        mut metrics: Vec<Metric>,
+    ) -> Result<Vec<Result<Metric, StoreError>>, StoreError> {
+        if metrics.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Assign ids up front so successful documents can be reported even when
+        // part of the batch fails.
+        for metric in &mut metrics {
+            metric.id.get_or_insert_with(ObjectId::new);
+        }
+
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let mut failed = std::collections::HashMap::new();
+
+        if let Err(e) = self
+            .metrics_collection()
+            .insert_many(&metrics, options)
+            .await
+        {
+            match *e.kind {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    for write_error in failure.write_errors.unwrap_or_default() {
+                        failed.insert(write_error.index, write_error.message);
+                    }
+                }
+                _ => return Err(e.into()),
+            }
+        }
+
+        Ok(metrics
+            .into_iter()
+            .enumerate()
+            .map(|(index, metric)| match failed.remove(&index) {
+                Some(message) => Err(StoreError::Rejected(message)),
+                None => Ok(metric),
+            })
+            .collect())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Metric>, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        let metric = self
+            .metrics_collection()
+            .find_one(doc! { "_id": object_id }, None)
+            .await?;
+
+        Ok(metric)
+    }
+
+    async fn find(&self, filter: &MetricFilter) -> Result<Vec<Metric>, StoreError> {
+        // MongoDB reads a limit of 0 as no limit
+        if filter.limit == Some(0) {
+            return Ok(Vec::new());
+        }
+        Ok(self.find_cursor(filter).await?.try_collect().await?)
+    }
+
+    async fn stream(&self, filter: &MetricFilter) -> Result<MetricStream, StoreError> {
+        if filter.limit == Some(0) {
+            return Ok(futures::stream::empty().boxed());
+        }
+        // The driver fetches further batches only as the stream is polled
+        Ok(self
+            .find_cursor(filter)
+            .await?
+            .map_err(StoreError::from)
+            .boxed())
+    }
+
+    async fn series(&self, filter: &MetricFilter) -> Result<Vec<(String, Labels)>, StoreError> {
+        let pipeline = vec![
+            doc! { "$match": filter_query(filter) },
+            doc! { "$group": { "_id": { "name": "$name", "labels": "$labels" } } },
+        ];
+
+        let cursor = self.metrics_collection().aggregate(pipeline, None).await?;
+        let groups: Vec<Document> = cursor.try_collect().await?;
+
+        // Label documents with different field orders group separately
+        let mut series: Vec<(String, Labels)> = groups.iter().map(series_key).collect();
+        series.sort();
+        series.dedup();
+        Ok(series)
+    }
+
+    async fn names(&self) -> Result<Vec<String>, StoreError> {
+        let values = self
+            .metrics_collection()
+            .distinct("name", None, None)
+            .await?;
+
+        let mut names: Vec<String> = values
+            .into_iter()
+            .filter_map(|value| match value {
+                Bson::String(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn summarize(
+        &self,
+        filter: &MetricFilter,
+        grouping: &Grouping,
+    ) -> Result<Vec<SeriesSummary>, StoreError> {
+        let pipeline = summarize_pipeline(filter, grouping);
+
+        let cursor = self.metrics_collection().aggregate(pipeline, None).await?;
+        let groups: Vec<Document> = cursor.try_collect().await?;
+
+        let mut series: Vec<SeriesSummary> = groups
+            .into_iter()
+            .map(|group| {
+                let (name, labels) = series_key(&group);
+                let sum = bson_number(group.get("sum")).unwrap_or(0.0);
+                let count = bson_number(group.get("count")).unwrap_or(0.0) as u64;
+                let std_dev = bson_number(group.get("std_dev")).unwrap_or(0.0);
+                SeriesSummary {
+                    name,
+                    labels,
+                    sum,
+                    count,
+                    min: bson_number(group.get("min")).unwrap_or(0.0),
+                    max: bson_number(group.get("max")).unwrap_or(0.0),
+                    mean: bson_number(group.get("mean")).unwrap_or(0.0),
+                    // The squared deviations merging needs, from the population
+                    // standard deviation
+                    m2: std_dev * std_dev * count as f64,
+                }
+            })
+            .collect();
+
+        // Sorting on the label subdocument in the pipeline would depend on field order
+        series.sort_by(|a, b| (&a.name, &a.labels).cmp(&(&b.name, &b.labels)));
+        Ok(series)
+    }
+
+    async fn aggregate_range(&self, query: &RangeQuery) -> Result<Vec<BucketValue>, StoreError> {
+        if let RangeFunction::Rate(function) = query.function {
+            return rate_range(self, query, function).await;
+        }
+        let Some(pipeline) = range_pipeline(query) else {
+            return Ok(Vec::new());
+        };
+
+        let cursor = self.metrics_collection().aggregate(pipeline, None).await?;
+        let groups: Vec<Document> = cursor.try_collect().await?;
+
+        let mut buckets: Vec<BucketValue> = groups
+            .into_iter()
+            .filter_map(|group| {
+                let (name, labels) = series_key(&group);
+                let id = group.get_document("_id").ok()?;
+                Some(BucketValue {
+                    name,
+                    labels,
+                    start: id.get_datetime("start").ok()?.timestamp_millis(),
+                    value: bson_number(group.get("value"))?,
+                })
+            })
+            .collect();
+
+        buckets.sort_by(|a, b| (&a.name, &a.labels, a.start).cmp(&(&b.name, &b.labels, b.start)));
+        Ok(buckets)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: UpdateMetricRequest,
+    ) -> Result<Option<Metric>, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        let mut update_doc = doc! {};
+
+        if let Some(name) = update.name {
+            update_doc.insert("name", name);
+        }
+
+        if let Some(tags) = update.tags {
+            update_doc.insert("tags", tags);
+        }
+
+        if let Some(labels) = update.labels {
+            update_doc.insert("labels", bson::to_bson(&labels).unwrap_or_default());
+        }
+
+        if let Some(value) = update.value {
+            update_doc.insert("value", value);
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let result = self
+            .metrics_collection()
+            .find_one_and_update(
+                doc! { "_id": object_id },
+                doc! { "$set": update_doc },
+                options,
+            )
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, StoreError> {
+        let object_id = ObjectId::parse_str(id)?;
+
+        let result = self
+            .metrics_collection()
+            .delete_one(doc! { "_id": object_id }, None)
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+}
@@ -1,18 +1,54 @@
 use crate::config::Config;
-use redis::{aio::ConnectionManager, Client};
+use redis::{aio::ConnectionManager, Client, RedisResult};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
+/// Connection attempts retry once, after up to 200ms. Longer outages are left
+/// to the cache's circuit breaker, which keeps trying while Redis is down.
+const CONNECT_RETRIES: usize = 1;
+const CONNECT_BACKOFF_BASE: u64 = 2;
+const CONNECT_BACKOFF_FACTOR_MS: u64 = 100;
+
+/// A Redis client whose connection is established on first use. Once
+/// connected, `ConnectionManager` reconnects by itself; until then every
+/// `connection` call tries again, so a Redis that was down at startup is
+/// picked up when it comes back.
 #[derive(Clone)]
 pub struct RedisDb {
-    pub conn: ConnectionManager,
+    pub client: Client,
+    conn: Arc<Mutex<Option<ConnectionManager>>>,
 }
 
 impl RedisDb {
-    pub async fn connect(config: &Config) -> Result<Self, redis::RedisError> {
-        let client = Client::open(config.redis_uri.as_str())?;
-        let conn = ConnectionManager::new(client).await?;
+    /// Opens a client for `REDIS_URI` without connecting. Fails only when the
+    /// URI is invalid.
+    pub fn open(config: &Config) -> RedisResult<Self> {
+        Ok(Self::new(Client::open(config.redis_uri.as_str())?))
+    }
 
-        log::info!("Connected to Redis successfully");
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            conn: Arc::default(),
+        }
+    }
+
+    /// Returns the shared connection, connecting first if there is none yet.
+    pub async fn connection(&self) -> RedisResult<ConnectionManager> {
+        let mut conn = self.conn.lock().await;
+        if let Some(conn) = conn.as_ref() {
+            return Ok(conn.clone());
+        }
 
-        Ok(RedisDb { conn })
+        let connected = ConnectionManager::new_with_backoff(
+            self.client.clone(),
+            CONNECT_BACKOFF_BASE,
+            CONNECT_BACKOFF_FACTOR_MS,
+            CONNECT_RETRIES,
+        )
+        .await?;
+        log::info!("Connected to Redis successfully");
+        *conn = Some(connected.clone());
+        Ok(connected)
     }
 }
@@ -0,0 +1,303 @@
+use crate::models::{
+    BucketAccumulator, BucketValue, Distribution, Grouping, Labels, Metric, MetricFilter,
+    MetricKind, RangeFunction, RangeQuery, RateAccumulator, RateBuckets, RateFunction,
+    SeriesDistribution, SeriesSummary, SeriesValue, UpdateMetricRequest,
+};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("invalid metric id: {0}")]
+    InvalidId(#[from] bson::oid::Error),
+    #[error("MongoDB error: {0}")]
+    Mongo(#[from] mongodb::error::Error),
+    #[error("write rejected: {0}")]
+    Rejected(String),
+    #[error("cannot aggregate: {0}")]
+    Aggregation(String),
+}
+
+/// Metrics read one at a time, see `MetricStore::stream`.
+pub type MetricStream = BoxStream<'static, Result<Metric, StoreError>>;
+
+/// Storage backend for metrics.
+///
+/// `TelemetryService` only talks to this trait, so the MongoDB backend can be
+/// swapped for `InMemoryStore` in tests or when running without external services.
+#[async_trait]
+pub trait MetricStore: Send + Sync {
+    /// Inserts a metric and returns it with its assigned id.
+    async fn insert(&self, metric: Metric) -> Result<Metric, StoreError>;
+
+    /// Inserts several metrics at once. The outer error means the whole batch failed;
+    /// otherwise there is one result per input metric, in input order.
+    async fn insert_many(
+        &self,
+        metrics: Vec<Metric>,
+    ) -> Result<Vec<Result<Metric, StoreError>>, StoreError> {
+        let mut results = Vec::with_capacity(metrics.len());
+
+        for metric in metrics {
+            results.push(self.insert(metric).await);
+        }
+
+        Ok(results)
+    }
+
+    /// Returns a single metric by id.
+    async fn get(&self, id: &str) -> Result<Option<Metric>, StoreError>;
+
+    /// Returns metrics matching `filter` in its sort order, ties broken by id.
+    /// Honours the filter's `cursor` and `limit` when set.
+    async fn find(&self, filter: &MetricFilter) -> Result<Vec<Metric>, StoreError>;
+
+    /// Like `find`, but yields metrics as they are read so callers can forward
+    /// them without holding the whole result. The default implementation
+    /// buffers `find`; backends with database cursors should override it.
+    async fn stream(&self, filter: &MetricFilter) -> Result<MetricStream, StoreError> {
+        let metrics = self.find(filter).await?;
+        Ok(stream::iter(metrics.into_iter().map(Ok)).boxed())
+    }
+
+    /// Returns the distinct metric name and label set of every matching metric,
+    /// ordered by name and then labels. Paging fields of `filter` are ignored.
+    ///
+    /// The default implementation folds over `stream`; backends that can group
+    /// in the database should override it.
+    async fn series(&self, filter: &MetricFilter) -> Result<Vec<(String, Labels)>, StoreError> {
+        let mut series = BTreeSet::new();
+        let mut metrics = self.stream(&filter.unpaged()).await?;
+
+        while let Some(metric) = metrics.next().await {
+            let metric = metric?;
+            series.insert((metric.name, metric.labels));
+        }
+
+        Ok(series.into_iter().collect())
+    }
+
+    /// Returns the distinct names of all stored metrics, sorted.
+    ///
+    /// The default implementation takes them from `series`; backends that can
+    /// list distinct values in the database should override it.
+    async fn names(&self) -> Result<Vec<String>, StoreError> {
+        let mut names: Vec<String> = self
+            .series(&MetricFilter::default())
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Returns running totals of matching values per series, ordered by metric
+    /// name and then labels. `grouping` decides which labels split a series.
+    ///
+    /// Paging fields of `filter` are ignored. The default implementation folds
+    /// over `find`; backends that can push the grouping down to the database
+    /// should override it.
+    async fn summarize(
+        &self,
+        filter: &MetricFilter,
+        grouping: &Grouping,
+    ) -> Result<Vec<SeriesSummary>, StoreError> {
+        let mut series: BTreeMap<(String, Labels), SeriesSummary> = BTreeMap::new();
+
+        for metric in self.find(&filter.unpaged()).await? {
+            let labels = grouping.key(&metric.labels);
+            series
+                .entry((metric.name.clone(), labels.clone()))
+                .or_insert_with(|| SeriesSummary::new(metric.name, labels))
+                .add(metric.value);
+        }
+
+        Ok(series.into_values().collect())
+    }
+
+    /// Collects the matching values per series for quantiles, ordered like
+    /// `summarize`. Each series keeps its values exactly until there are too
+    /// many, then summarizes them in a `DDSketch` of bounded size, see
+    /// `Distribution`. Histogram metrics contribute
+    /// their buckets instead, merged per series; they must share bounds.
+    ///
+    /// The default implementation folds over `stream`, so only the sketches are
+    /// held in memory.
+    async fn distributions(
+        &self,
+        filter: &MetricFilter,
+        grouping: &Grouping,
+    ) -> Result<Vec<SeriesDistribution>, StoreError> {
+        let mut series: BTreeMap<(String, Labels), SeriesDistribution> = BTreeMap::new();
+        let mut metrics = self.stream(&filter.unpaged()).await?;
+
+        while let Some(metric) = metrics.next().await {
+            let metric = metric?;
+            let labels = grouping.key(&metric.labels);
+            let entry = series
+                .entry((metric.name.clone(), labels.clone()))
+                .or_insert_with(|| SeriesDistribution {
+                    name: metric.name.clone(),
+                    labels,
+                    distribution: Distribution::new(),
+                    histogram: None,
+                });
+
+            match (metric.kind, metric.histogram) {
+                (MetricKind::Histogram, Some(histogram)) => match &mut entry.histogram {
+                    Some(merged) => merged
+                        .merge(&histogram)
+                        .map_err(|e| StoreError::Aggregation(format!("{}: {e}", metric.name)))?,
+                    None => entry.histogram = Some(histogram),
+                },
+                _ => entry.distribution.add(metric.value),
+            }
+        }
+
+        Ok(series.into_values().collect())
+    }
+
+    /// Applies `function` to each series, by name and full label set, over the
+    /// whole time range and sums the results per grouping, like PromQL's
+    /// `sum by (...) (rate(...))`. Ordered like `summarize`.
+    ///
+    /// The default implementation folds over `stream` in time order, so only
+    /// one accumulator per series is held in memory.
+    async fn rates(
+        &self,
+        filter: &MetricFilter,
+        grouping: &Grouping,
+        function: RateFunction,
+    ) -> Result<Vec<SeriesValue>, StoreError> {
+        let mut series: BTreeMap<(String, Labels), RateAccumulator> = BTreeMap::new();
+        let mut metrics = self.stream(&filter.time_ordered()).await?;
+
+        while let Some(metric) = metrics.next().await {
+            let metric = metric?;
+            let sample = (metric.timestamp.timestamp_millis(), metric.value);
+            series
+                .entry((metric.name, metric.labels))
+                .or_default()
+                .add(sample);
+        }
+
+        let mut groups: BTreeMap<(String, Labels), SeriesValue> = BTreeMap::new();
+        for ((name, labels), accumulator) in series {
+            let labels = grouping.key(&labels);
+            let group = groups
+                .entry((name.clone(), labels.clone()))
+                .or_insert_with(|| SeriesValue {
+                    name,
+                    labels,
+                    value: None,
+                    points: 0,
+                });
+
+            if let Some(value) = accumulator.value(function) {
+                *group.value.get_or_insert(0.0) += value;
+            }
+            group.points += accumulator.count();
+        }
+
+        Ok(groups.into_values().collect())
+    }
+
+    /// Reduces the metrics selected by `query` into time buckets per series,
+    /// ordered by metric name, labels and bucket start. Empty buckets are omitted.
+    ///
+    /// The default implementation folds over `find`; backends that can push the
+    /// bucketing down to the database should override it, deferring rate
+    /// functions to `rate_range`.
+    async fn aggregate_range(&self, query: &RangeQuery) -> Result<Vec<BucketValue>, StoreError> {
+        if let RangeFunction::Rate(function) = query.function {
+            return rate_range(self, query, function).await;
+        }
+
+        let mut buckets: BTreeMap<(String, Labels, i64), BucketAccumulator> = BTreeMap::new();
+
+        for metric in self.find(&query.filter).await? {
+            let start = query.step.bucket_start(metric.timestamp.timestamp_millis());
+            let labels = query.grouping.key(&metric.labels);
+            buckets
+                .entry((metric.name, labels, start))
+                .or_default()
+                .add(metric.value);
+        }
+
+        Ok(buckets
+            .into_iter()
+            .filter_map(|((name, labels, start), bucket)| {
+                Some(BucketValue {
+                    name,
+                    labels,
+                    start,
+                    value: bucket.value(query.function)?,
+                })
+            })
+            .collect())
+    }
+
+    /// Applies the set fields of `update` and returns the updated metric.
+    async fn update(
+        &self,
+        id: &str,
+        update: UpdateMetricRequest,
+    ) -> Result<Option<Metric>, StoreError>;
+
+    /// Deletes a metric, returning whether it existed.
+    async fn delete(&self, id: &str) -> Result<bool, StoreError>;
+}
+
+/// `MetricStore::aggregate_range` for rate functions. Each series, by name and
+/// full label set, is bucketed with `RateBuckets`; the results are then summed
+/// per grouping, like PromQL's `sum by (...) (rate(...))`. Folds over `stream`
+/// in time order, holding one window per series.
+pub async fn rate_range<S: MetricStore + ?Sized>(
+    store: &S,
+    query: &RangeQuery,
+    function: RateFunction,
+) -> Result<Vec<BucketValue>, StoreError> {
+    let mut buckets: BTreeMap<(String, Labels, i64), f64> = BTreeMap::new();
+    let mut series: BTreeMap<(String, Labels), (Labels, RateBuckets)> = BTreeMap::new();
+    let mut metrics = store.stream(&query.filter.time_ordered()).await?;
+
+    let mut add = |name: &str, labels: &Labels, (start, value): (i64, f64)| {
+        *buckets
+            .entry((name.to_string(), labels.clone(), start))
+            .or_default() += value;
+    };
+
+    while let Some(metric) = metrics.next().await {
+        let metric = metric?;
+        let sample = (metric.timestamp.timestamp_millis(), metric.value);
+        let (group, windows) = series
+            .entry((metric.name.clone(), metric.labels))
+            .or_insert_with_key(|(_, labels)| {
+                (
+                    query.grouping.key(labels),
+                    RateBuckets::new(query.step, function),
+                )
+            });
+        if let Some(bucket) = windows.add(sample) {
+            add(&metric.name, group, bucket);
+        }
+    }
+
+    for ((name, _), (group, windows)) in &series {
+        if let Some(bucket) = windows.finish() {
+            add(name, group, bucket);
+        }
+    }
+
+    Ok(buckets
+        .into_iter()
+        .map(|((name, labels, start), value)| BucketValue {
+            name,
+            labels,
+            start,
+            value,
+        })
+        .collect())
+}
@@ -0,0 +1,699 @@
+use crate::models::{
+    is_valid_label_name, sanitize_label_name, CreateMetricRequest, Labels, MetricKind,
+    MetricTimestamp,
+};
+use crate::services::TelemetryService;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long a plaintext connection may buffer points before writing them.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest pickle frame accepted, matching the HTTP bulk ingestion limit.
+const MAX_PICKLE_FRAME: usize = 16 * 1024 * 1024;
+
+/// Datapoints nest three deep; anything much deeper is not from carbon.
+const MAX_PICKLE_DEPTH: usize = 32;
+
+/// Bound on the memory one frame decodes to, counting memo copies. Carbon
+/// sends at most a few hundred datapoints per frame, far below this.
+const MAX_PICKLE_DECODED: usize = 4 * MAX_PICKLE_FRAME;
+
+/// Longest plaintext line accepted. A connection sending longer lines is closed.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// One Graphite datapoint. `path` may carry tags as `name;tag=value;...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphiteSample {
+    pub path: String,
+    pub value: f64,
+    /// Milliseconds since the Unix epoch; `None` when the sender asked for
+    /// "now".
+    pub timestamp: Option<i64>,
+}
+
+/// Converts a timestamp in seconds, which some senders write with a fraction,
+/// to milliseconds. Negative and non-finite timestamps are rejected.
+fn epoch_millis(secs: f64) -> Option<i64> {
+    (secs.is_finite() && secs >= 0.0).then_some((secs * 1000.0) as i64)
+}
+
+/// Parses a plaintext line: `path value [timestamp]`. A timestamp of `-1`, like
+/// a missing one, means the time the point is received.
+pub fn parse_line(line: &str) -> Result<GraphiteSample, String> {
+    let mut parts = line.split_whitespace();
+    let (Some(path), Some(value)) = (parts.next(), parts.next()) else {
+        return Err(format!("expected 'path value timestamp' in {line:?}"));
+    };
+
+    let value = value
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| format!("invalid value {value:?}"))?;
+
+    let timestamp = match parts.next() {
+        None | Some("-1") => None,
+        Some(raw) => Some(
+            raw.parse::<f64>()
+                .ok()
+                .and_then(epoch_millis)
+                .ok_or_else(|| format!("invalid timestamp {raw:?}"))?,
+        ),
+    };
+
+    if parts.next().is_some() {
+        return Err(format!("unexpected trailing data in {line:?}"));
+    }
+
+    Ok(GraphiteSample {
+        path: path.to_string(),
+        value,
+        timestamp,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Skip,
+    Measurement,
+    /// `measurement*`: the remaining path segments all belong to the name.
+    MeasurementRest,
+    Label(String),
+}
+
+/// A rule mapping dotted path segments onto a metric name and labels, in the
+/// style of the InfluxDB and Telegraf Graphite templates:
+///
+/// ```text
+/// [filter] template [label=value,...]
+/// servers.* .host.measurement* env=prod
+/// ```
+///
+/// The filter is a dotted pattern where `*` matches one segment, compared
+/// against the leading segments of the path. Each template segment consumes one
+/// path segment: `measurement` adds it to the metric name, `measurement*` adds
+/// it and every following segment, an empty segment drops it, and any other
+/// word stores it as a label of that name.
+#[derive(Debug, Clone)]
+pub struct GraphiteTemplate {
+    filter: Option<Vec<String>>,
+    parts: Vec<TemplatePart>,
+    labels: Labels,
+}
+
+impl GraphiteTemplate {
+    fn matches(&self, segments: &[&str]) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+
+        filter.len() <= segments.len()
+            && filter
+                .iter()
+                .zip(segments)
+                .all(|(pattern, segment)| pattern == "*" || pattern == segment)
+    }
+
+    /// Returns the metric name and labels for `segments`. Without any
+    /// `measurement` segment the full path is kept as the name.
+    fn apply(&self, segments: &[&str]) -> (String, Labels) {
+        let mut name = Vec::new();
+        let mut labels = self.labels.clone();
+
+        for (i, part) in self.parts.iter().enumerate() {
+            let Some(segment) = segments.get(i) else {
+                break;
+            };
+
+            match part {
+                TemplatePart::Skip => {}
+                TemplatePart::Measurement => name.push(*segment),
+                TemplatePart::MeasurementRest => {
+                    name.extend_from_slice(&segments[i..]);
+                    break;
+                }
+                TemplatePart::Label(label) => {
+                    labels.insert(label.clone(), segment.to_string());
+                }
+            }
+        }
+
+        if name.is_empty() {
+            name = segments.to_vec();
+        }
+
+        (name.join("."), labels)
+    }
+}
+
+impl FromStr for GraphiteTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (filter, template, labels) = match words.as_slice() {
+            [template] => (None, *template, None),
+            [template, labels] if labels.contains('=') => (None, *template, Some(*labels)),
+            [filter, template] => (Some(*filter), *template, None),
+            [filter, template, labels] => (Some(*filter), *template, Some(*labels)),
+            _ => return Err(format!("expected '[filter] template [labels]' in {s:?}")),
+        };
+
+        let parts = template
+            .split('.')
+            .map(|part| match part {
+                "" => Ok(TemplatePart::Skip),
+                "measurement" => Ok(TemplatePart::Measurement),
+                "measurement*" => Ok(TemplatePart::MeasurementRest),
+                label if is_valid_label_name(label) => Ok(TemplatePart::Label(label.to_string())),
+                other => Err(format!("invalid template segment {other:?} in {s:?}")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let labels = labels
+            .map(|labels| {
+                labels
+                    .split(',')
+                    .map(|pair| match pair.split_once('=') {
+                        Some((key, value)) if is_valid_label_name(key) && !value.is_empty() => {
+                            Ok((key.to_string(), value.to_string()))
+                        }
+                        _ => Err(format!("invalid label {pair:?} in {s:?}")),
+                    })
+                    .collect::<Result<Labels, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(GraphiteTemplate {
+            filter: filter.map(|f| f.split('.').map(str::to_string).collect()),
+            parts,
+            labels,
+        })
+    }
+}
+
+/// Template rules tried in order; the first whose filter matches is applied.
+/// Parsed from `GRAPHITE_TEMPLATES`, with rules separated by `;` or newlines.
+#[derive(Debug, Clone, Default)]
+pub struct GraphiteTemplates(pub Vec<GraphiteTemplate>);
+
+impl GraphiteTemplates {
+    /// Maps a sample onto a gauge. Graphite tags from `name;tag=value` are kept
+    /// as labels and take precedence over labels set by a template.
+    pub fn to_metric_request(&self, sample: GraphiteSample) -> CreateMetricRequest {
+        let mut tags = sample.path.split(';');
+        let path = tags.next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('.').collect();
+
+        let (name, mut labels) = match self.0.iter().find(|t| t.matches(&segments)) {
+            Some(template) => template.apply(&segments),
+            None => (path.to_string(), Labels::new()),
+        };
+
+        for tag in tags {
+            if let Some((key, value)) = tag.split_once('=').filter(|(_, v)| !v.is_empty()) {
+                labels.insert(sanitize_label_name(key), value.to_string());
+            }
+        }
+
+        CreateMetricRequest {
+            name,
+            tags: None,
+            labels: Some(labels),
+            kind: MetricKind::Gauge,
+            value: Some(sample.value),
+            histogram: None,
+            summary: None,
+            timestamp: sample.timestamp.map(MetricTimestamp::Epoch),
+        }
+    }
+}
+
+impl FromStr for GraphiteTemplates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split([';', '\n'])
+            .filter(|rule| !rule.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(GraphiteTemplates)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PickleValue {
+    Mark,
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<PickleValue>),
+}
+
+/// A value on the unpickler's stack, with how deeply it nests and roughly how
+/// many bytes it holds, so nesting and memo copies can be bounded.
+#[derive(Debug, Clone)]
+struct Node {
+    value: PickleValue,
+    depth: usize,
+    weight: usize,
+}
+
+impl Node {
+    fn leaf(value: PickleValue) -> Self {
+        let weight = match &value {
+            PickleValue::Str(s) => std::mem::size_of::<PickleValue>() + s.len(),
+            _ => std::mem::size_of::<PickleValue>(),
+        };
+        Node {
+            value,
+            depth: 0,
+            weight,
+        }
+    }
+
+    fn list(items: Vec<Node>) -> Result<Self, String> {
+        let mut list = Node {
+            value: PickleValue::List(Vec::with_capacity(items.len())),
+            depth: 1,
+            weight: std::mem::size_of::<PickleValue>(),
+        };
+        for item in items {
+            list.push(item)?;
+        }
+        Ok(list)
+    }
+
+    fn push(&mut self, item: Node) -> Result<(), String> {
+        let PickleValue::List(list) = &mut self.value else {
+            return Err("APPEND without a list".to_string());
+        };
+        self.depth = self.depth.max(item.depth + 1);
+        if self.depth > MAX_PICKLE_DEPTH {
+            return Err("pickle nests too deeply".to_string());
+        }
+        self.weight += item.weight;
+        list.push(item.value);
+        Ok(())
+    }
+}
+
+/// Decodes a carbon pickle payload: a list of `(path, (timestamp, value))`
+/// tuples. Only the data opcodes of pickle protocols 0-4 are understood;
+/// anything that would construct objects is rejected, so untrusted input is
+/// never executed.
+pub fn unpickle(data: &[u8]) -> Result<Vec<GraphiteSample>, String> {
+    let value = Unpickler { data, pos: 0 }.load()?;
+
+    let PickleValue::List(items) = value else {
+        return Err("expected a list of datapoints".to_string());
+    };
+
+    items
+        .into_iter()
+        .map(|item| match item {
+            PickleValue::List(pair) => match pair.as_slice() {
+                [PickleValue::Str(path), PickleValue::List(point)] => match point.as_slice() {
+                    [timestamp, value] => Ok(GraphiteSample {
+                        path: path.clone(),
+                        value: pickle_number(value)
+                            .filter(|v| v.is_finite())
+                            .ok_or("invalid value")?,
+                        timestamp: Some(
+                            pickle_number(timestamp)
+                                .and_then(epoch_millis)
+                                .ok_or("invalid timestamp")?,
+                        ),
+                    }),
+                    _ => Err("expected (timestamp, value)".to_string()),
+                },
+                _ => Err("expected (path, (timestamp, value))".to_string()),
+            },
+            _ => Err("expected (path, (timestamp, value))".to_string()),
+        })
+        .collect()
+}
+
+fn pickle_number(value: &PickleValue) -> Option<f64> {
+    match value {
+        PickleValue::Int(n) => Some(*n as f64),
+        PickleValue::Float(f) => Some(*f),
+        PickleValue::Str(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+struct Unpickler<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Unpickler<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("truncated pickle")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("slice has length N"))
+    }
+
+    fn take_line(&mut self) -> Result<&'a str, String> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or("truncated pickle")?;
+        let line = self.take(len + 1)?;
+        std::str::from_utf8(&line[..len]).map_err(|_| "invalid text in pickle".to_string())
+    }
+
+    fn take_str(&mut self, len: usize) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    /// Decodes the frame. Memo writes and reads copy their value, so what they
+    /// copy counts towards `MAX_PICKLE_DECODED`; a few bytes cannot double a
+    /// list until memory runs out.
+    fn load(mut self) -> Result<PickleValue, String> {
+        let mut stack: Vec<Node> = Vec::new();
+        let mut memo: HashMap<u64, Node> = HashMap::new();
+        let mut decoded = 0;
+        let mut count = |weight: usize| {
+            decoded += weight;
+            if decoded > MAX_PICKLE_DECODED {
+                return Err("pickle decodes to too much data".to_string());
+            }
+            Ok(())
+        };
+
+        loop {
+            let [opcode] = self.take_array::<1>()?;
+            let node = match opcode {
+                // PROTO, FRAME
+                0x80 => {
+                    self.take(1)?;
+                    continue;
+                }
+                0x95 => {
+                    self.take(8)?;
+                    continue;
+                }
+                b'.' => {
+                    return stack
+                        .pop()
+                        .map(|node| node.value)
+                        .ok_or_else(|| "empty pickle".to_string())
+                }
+                b']' | b')' => Node::list(Vec::new())?,
+                b'a' => {
+                    let item = stack.pop().ok_or("stack underflow")?;
+                    stack
+                        .last_mut()
+                        .ok_or("APPEND without a list")?
+                        .push(item)?;
+                    continue;
+                }
+                b'e' => {
+                    let items = pop_mark(&mut stack)?;
+                    let list = stack.last_mut().ok_or("APPENDS without a list")?;
+                    for item in items {
+                        list.push(item)?;
+                    }
+                    continue;
+                }
+                b'l' | b't' => Node::list(pop_mark(&mut stack)?)?,
+                0x85..=0x87 => {
+                    let n = (opcode - 0x84) as usize;
+                    let start = stack.len().checked_sub(n).ok_or("stack underflow")?;
+                    Node::list(stack.split_off(start))?
+                }
+                b'p' | b'q' | b'r' | 0x94 => {
+                    let key = match opcode {
+                        b'p' => self.take_line()?.parse().map_err(|_| "bad memo key")?,
+                        b'q' => self.take_array::<1>()?[0] as u64,
+                        b'r' => u32::from_le_bytes(self.take_array()?) as u64,
+                        _ => memo.len() as u64,
+                    };
+                    let top = stack.last().ok_or("stack underflow")?;
+                    count(top.weight)?;
+                    memo.insert(key, top.clone());
+                    continue;
+                }
+                b'g' | b'h' | b'j' => {
+                    let key = match opcode {
+                        b'g' => self.take_line()?.parse().map_err(|_| "bad memo key")?,
+                        b'h' => self.take_array::<1>()?[0] as u64,
+                        _ => u32::from_le_bytes(self.take_array()?) as u64,
+                    };
+                    let node = memo.get(&key).ok_or("unknown memo key")?;
+                    count(node.weight)?;
+                    node.clone()
+                }
+                _ => {
+                    let node = Node::leaf(self.scalar(opcode)?);
+                    count(node.weight)?;
+                    node
+                }
+            };
+            stack.push(node);
+        }
+    }
+
+    /// Reads the operand of an opcode that pushes a single, non-list value.
+    fn scalar(&mut self, opcode: u8) -> Result<PickleValue, String> {
+        Ok(match opcode {
+            b'(' => PickleValue::Mark,
+            b'N' => PickleValue::None,
+            0x88 => PickleValue::Bool(true),
+            0x89 => PickleValue::Bool(false),
+            b'J' => PickleValue::Int(i32::from_le_bytes(self.take_array()?) as i64),
+            b'K' => PickleValue::Int(self.take_array::<1>()?[0] as i64),
+            b'M' => PickleValue::Int(u16::from_le_bytes(self.take_array()?) as i64),
+            0x8a => {
+                let [len] = self.take_array::<1>()?;
+                let bytes = self.take(len as usize)?;
+                if bytes.len() > 8 {
+                    return Err("integer too large".to_string());
+                }
+                let negative = bytes.last().is_some_and(|b| b & 0x80 != 0);
+                let mut buf = if negative { [0xff; 8] } else { [0; 8] };
+                buf[..bytes.len()].copy_from_slice(bytes);
+                PickleValue::Int(i64::from_le_bytes(buf))
+            }
+            b'G' => PickleValue::Float(f64::from_be_bytes(self.take_array()?)),
+            b'I' => match self.take_line()? {
+                "01" => PickleValue::Bool(true),
+                "00" => PickleValue::Bool(false),
+                n => PickleValue::Int(n.parse().map_err(|_| format!("bad int {n:?}"))?),
+            },
+            b'L' => {
+                let n = self.take_line()?;
+                let digits = n.strip_suffix('L').unwrap_or(n);
+                PickleValue::Int(digits.parse().map_err(|_| format!("bad long {n:?}"))?)
+            }
+            b'F' => {
+                let f = self.take_line()?;
+                PickleValue::Float(f.parse().map_err(|_| format!("bad float {f:?}"))?)
+            }
+            b'S' => {
+                let s = self.take_line()?;
+                let unquoted = s
+                    .strip_prefix(['\'', '"'])
+                    .and_then(|s| s.strip_suffix(['\'', '"']))
+                    .ok_or_else(|| format!("bad string {s:?}"))?;
+                PickleValue::Str(unquoted.to_string())
+            }
+            b'V' => PickleValue::Str(self.take_line()?.to_string()),
+            b'U' | 0x8c | b'C' => {
+                let [len] = self.take_array::<1>()?;
+                PickleValue::Str(self.take_str(len as usize)?)
+            }
+            b'T' | b'X' | b'B' => {
+                let len = u32::from_le_bytes(self.take_array()?);
+                PickleValue::Str(self.take_str(len as usize)?)
+            }
+            0x8d | 0x8e => {
+                let len = u64::from_le_bytes(self.take_array()?);
+                let len = usize::try_from(len).map_err(|_| "string too large")?;
+                PickleValue::Str(self.take_str(len)?)
+            }
+            other => return Err(format!("unsupported pickle opcode 0x{other:02x}")),
+        })
+    }
+}
+
+fn pop_mark(stack: &mut Vec<Node>) -> Result<Vec<Node>, String> {
+    let mark = stack
+        .iter()
+        .rposition(|node| node.value == PickleValue::Mark)
+        .ok_or("missing MARK")?;
+    let items = stack.split_off(mark + 1);
+    stack.pop();
+    Ok(items)
+}
+
+/// Accepts plaintext connections on `listener`, one `path value timestamp` line
+/// per point. Runs until the task is dropped.
+pub async fn run_plaintext(
+    listener: TcpListener,
+    service: TelemetryService,
+    templates: GraphiteTemplates,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                actix_web::rt::spawn(handle_plaintext(stream, service.clone(), templates.clone()));
+            }
+            Err(e) => log::warn!("Graphite accept failed: {e}"),
+        }
+    }
+}
+
+/// Accepts pickle connections on `listener`: length-prefixed frames as sent by
+/// carbon-relay. Runs until the task is dropped.
+pub async fn run_pickle(
+    listener: TcpListener,
+    service: TelemetryService,
+    templates: GraphiteTemplates,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                actix_web::rt::spawn(handle_pickle(stream, service.clone(), templates.clone()));
+            }
+            Err(e) => log::warn!("Graphite pickle accept failed: {e}"),
+        }
+    }
+}
+
+/// Buffers points from one connection and writes them once a batch is full,
+/// every `FLUSH_INTERVAL`, and when the sender disconnects. Connections sending
+/// lines longer than `MAX_LINE_LENGTH` are closed.
+async fn handle_plaintext(
+    stream: TcpStream,
+    service: TelemetryService,
+    templates: GraphiteTemplates,
+) {
+    let max_batch_size = service.ingest_options().max_batch_size;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut batch = Vec::new();
+
+    loop {
+        // Reads resume where a cancelled one stopped, so only the rest of the
+        // allowance is left
+        let mut limited = (&mut reader).take((MAX_LINE_LENGTH + 1 - line.len()) as u64);
+
+        tokio::select! {
+            read = limited.read_until(b'\n', &mut line) => {
+                let eof = match read {
+                    Ok(n) => n == 0,
+                    Err(e) => {
+                        log::warn!("Graphite connection failed: {e}");
+                        break;
+                    }
+                };
+
+                if !eof && !line.ends_with(b"\n") {
+                    if line.len() > MAX_LINE_LENGTH {
+                        log::warn!("Closing Graphite connection: line longer than {MAX_LINE_LENGTH} bytes");
+                        line.clear();
+                        break;
+                    }
+                    // The sender closed mid-line; the next read sees the end
+                    continue;
+                }
+
+                let text = String::from_utf8_lossy(&line);
+                if !text.trim().is_empty() {
+                    match parse_line(text.trim_end()) {
+                        Ok(sample) => {
+                            batch.push(templates.to_metric_request(sample));
+                            if batch.len() >= max_batch_size {
+                                write(&service, std::mem::take(&mut batch)).await;
+                            }
+                        }
+                        Err(e) => log::debug!("Dropping Graphite line: {e}"),
+                    }
+                }
+                line.clear();
+
+                if eof {
+                    break;
+                }
+            }
+            _ = interval.tick() => write(&service, std::mem::take(&mut batch)).await,
+        }
+    }
+
+    write(&service, batch).await;
+}
+
+async fn handle_pickle(
+    mut stream: TcpStream,
+    service: TelemetryService,
+    templates: GraphiteTemplates,
+) {
+    loop {
+        let len = match stream.read_u32().await {
+            Ok(len) => len as usize,
+            // The sender closed the connection between frames
+            Err(_) => return,
+        };
+        if len > MAX_PICKLE_FRAME {
+            log::warn!("Closing Graphite pickle connection: {len} byte frame is too large");
+            return;
+        }
+
+        let mut frame = vec![0u8; len];
+        if let Err(e) = stream.read_exact(&mut frame).await {
+            log::warn!("Graphite pickle connection failed: {e}");
+            return;
+        }
+
+        match unpickle(&frame) {
+            Ok(samples) => {
+                let requests = samples
+                    .into_iter()
+                    .map(|sample| templates.to_metric_request(sample))
+                    .collect();
+                write(&service, requests).await;
+            }
+            Err(e) => log::debug!("Dropping Graphite pickle frame: {e}"),
+        }
+    }
+}
+
+async fn write(service: &TelemetryService, mut requests: Vec<CreateMetricRequest>) {
+    let max_batch_size = service.ingest_options().max_batch_size.max(1);
+
+    while !requests.is_empty() {
+        let chunk = requests
+            .drain(..requests.len().min(max_batch_size))
+            .map(Ok)
+            .collect();
+
+        match service.create_metrics(chunk).await {
+            Ok(response) if response.failed > 0 => {
+                log::warn!("Graphite write rejected {} points", response.failed);
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Graphite write failed: {e}"),
+        }
+    }
+}
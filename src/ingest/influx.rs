@@ -0,0 +1,228 @@
+use crate::models::{
+    sanitize_label_name, CreateMetricRequest, Labels, MetricKind, MetricTimestamp,
+};
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Unit of line protocol timestamps, from the `precision` query parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    fn to_millis(self, timestamp: i64) -> Option<i64> {
+        match self {
+            Precision::Nanoseconds => Some(timestamp.div_euclid(1_000_000)),
+            Precision::Microseconds => Some(timestamp.div_euclid(1_000)),
+            Precision::Milliseconds => Some(timestamp),
+            Precision::Seconds => timestamp.checked_mul(1_000),
+        }
+    }
+}
+
+/// Accepts both the InfluxDB 1.x (`n`, `u`) and 2.x (`ns`, `us`) spellings.
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "n" | "ns" => Ok(Precision::Nanoseconds),
+            "u" | "us" | "µ" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            other => Err(format!("unsupported precision {other:?}")),
+        }
+    }
+}
+
+/// A line that could not be parsed or stored, numbered from 1.
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+#[error("line {line}: {error}")]
+pub struct LineError {
+    pub line: usize,
+    pub error: String,
+}
+
+/// A metric request together with the line it came from.
+pub struct LinePoint {
+    pub line: usize,
+    pub request: CreateMetricRequest,
+}
+
+/// Parses a line protocol body. Every line is parsed independently, so a bad
+/// line does not prevent the others from being written.
+pub fn parse(body: &str, precision: Precision) -> (Vec<LinePoint>, Vec<LineError>) {
+    let mut points = Vec::new();
+    let mut errors = Vec::new();
+
+    for (i, line) in body.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_line(line, precision) {
+            Ok(requests) => points.extend(requests.into_iter().map(|request| LinePoint {
+                line: line_number,
+                request,
+            })),
+            Err(error) => errors.push(LineError {
+                line: line_number,
+                error,
+            }),
+        }
+    }
+
+    (points, errors)
+}
+
+/// Parses `measurement[,tag=value...] field=value[,field=value...] [timestamp]`
+/// into one gauge per numeric or boolean field, named `measurement.field`. String
+/// fields carry no value to store and are skipped.
+pub fn parse_line(line: &str, precision: Precision) -> Result<Vec<CreateMetricRequest>, String> {
+    let (series, rest) = split_once_unescaped(line, ' ', false)
+        .ok_or_else(|| "expected a field set after the measurement".to_string())?;
+    let (field_set, timestamp) = match split_once_unescaped(rest.trim_start(), ' ', true) {
+        Some((fields, timestamp)) => (fields, Some(timestamp.trim())),
+        None => (rest.trim_start(), None),
+    };
+
+    let mut series_parts = split_unescaped(series, ',', false).into_iter();
+    let measurement = unescape(series_parts.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+
+    let mut labels = Labels::new();
+    for tag in series_parts {
+        let (key, value) = split_once_unescaped(tag, '=', false)
+            .ok_or_else(|| format!("tag {tag:?} is missing '='"))?;
+        if key.is_empty() || value.is_empty() {
+            return Err(format!("tag {tag:?} needs a key and a value"));
+        }
+        labels.insert(sanitize_label_name(&unescape(key)), unescape(value));
+    }
+
+    let timestamp = timestamp
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            t.parse::<i64>()
+                .ok()
+                .and_then(|t| precision.to_millis(t))
+                .map(MetricTimestamp::Epoch)
+                .ok_or_else(|| format!("invalid timestamp {t:?}"))
+        })
+        .transpose()?;
+
+    if field_set.is_empty() {
+        return Err("missing fields".to_string());
+    }
+
+    let mut requests = Vec::new();
+    for field in split_unescaped(field_set, ',', true) {
+        let (key, raw) = split_once_unescaped(field, '=', false)
+            .ok_or_else(|| format!("field {field:?} is missing '='"))?;
+        if key.is_empty() {
+            return Err(format!("field {field:?} has no key"));
+        }
+
+        let Some(value) = parse_field_value(raw)? else {
+            continue;
+        };
+
+        requests.push(CreateMetricRequest {
+            name: format!("{measurement}.{}", unescape(key)),
+            tags: None,
+            labels: Some(labels.clone()),
+            kind: MetricKind::Gauge,
+            value: Some(value),
+            histogram: None,
+            summary: None,
+            timestamp: timestamp.clone(),
+        });
+    }
+
+    Ok(requests)
+}
+
+/// Returns `None` for string fields, which have no numeric value.
+fn parse_field_value(raw: &str) -> Result<Option<f64>, String> {
+    let invalid = || format!("invalid field value {raw:?}");
+
+    if raw.starts_with('"') {
+        return if raw.len() >= 2 && raw.ends_with('"') {
+            Ok(None)
+        } else {
+            Err(format!("unterminated string {raw:?}"))
+        };
+    }
+
+    let value = match raw {
+        "t" | "T" | "true" | "True" | "TRUE" => 1.0,
+        "f" | "F" | "false" | "False" | "FALSE" => 0.0,
+        _ => {
+            if let Some(int) = raw.strip_suffix('i') {
+                int.parse::<i64>().map_err(|_| invalid())? as f64
+            } else if let Some(uint) = raw.strip_suffix('u') {
+                uint.parse::<u64>().map_err(|_| invalid())? as f64
+            } else {
+                raw.parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(invalid)?
+            }
+        }
+    };
+
+    Ok(Some(value))
+}
+
+/// Splits on `sep` where it is not backslash-escaped. With `quoted`, separators
+/// inside double-quoted string field values are ignored too.
+fn split_unescaped(s: &str, sep: char, quoted: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some((part, tail)) = split_once_unescaped(rest, sep, quoted) {
+        parts.push(part);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+fn split_once_unescaped(s: &str, sep: char, quoted: bool) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    let mut in_quotes = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quoted => in_quotes = !in_quotes,
+            _ if c == sep && !in_quotes => return Some((&s[..i], &s[i + c.len_utf8()..])),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(',' | '=' | ' ' | '"' | '\\')) => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
@@ -0,0 +1,5 @@
+pub mod graphite;
+pub mod influx;
+pub mod otlp;
+pub mod prometheus;
+pub mod statsd;
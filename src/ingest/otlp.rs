@@ -0,0 +1,624 @@
+use crate::models::{
+    sanitize_label_name, CreateMetricRequest, Histogram as MetricHistogram, HistogramBucket,
+    Labels, MetricKind, MetricTimestamp, Summary as MetricSummary, SummaryQuantile,
+};
+use prost::Message;
+use serde::{Deserialize, Deserializer, Serialize};
+
+// OTLP metrics messages (opentelemetry/proto/metrics/v1). The same structs decode
+// both the protobuf and the JSON encoding; JSON uses lowerCamelCase names and
+// may carry 64-bit integers as strings. Exemplars are not declared and are skipped.
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+    #[prost(message, repeated, tag = "3")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(oneof = "MetricData", tags = "5, 7, 9, 10, 11")]
+    #[serde(flatten)]
+    pub data: Option<MetricData>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MetricData {
+    #[prost(message, tag = "5")]
+    Gauge(Gauge),
+    #[prost(message, tag = "7")]
+    Sum(Sum),
+    #[prost(message, tag = "9")]
+    Histogram(Histogram),
+    #[prost(message, tag = "10")]
+    ExponentialHistogram(ExponentialHistogram),
+    #[prost(message, tag = "11")]
+    Summary(Summary),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    #[prost(enumeration = "AggregationTemporality", tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Histogram {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<HistogramDataPoint>,
+    #[prost(enumeration = "AggregationTemporality", tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExponentialHistogram {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<ExponentialHistogramDataPoint>,
+    #[prost(enumeration = "AggregationTemporality", tag = "2")]
+    pub aggregation_temporality: i32,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Summary {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<SummaryDataPoint>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum AggregationTemporality {
+    Unspecified = 0,
+    Delta = 1,
+    Cumulative = 2,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "json_u64")]
+    pub time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "4, 6")]
+    #[serde(flatten)]
+    pub value: Option<NumberValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NumberValue {
+    #[prost(double, tag = "4")]
+    AsDouble(f64),
+    #[prost(sfixed64, tag = "6")]
+    AsInt(#[serde(deserialize_with = "json_i64")] i64),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "json_u64")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "json_u64")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    /// Per-bucket (not cumulative) counts; one more than `explicit_bounds`.
+    #[prost(fixed64, repeated, tag = "6")]
+    #[serde(deserialize_with = "json_u64_vec")]
+    pub bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    pub explicit_bounds: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExponentialHistogramDataPoint {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "json_u64")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "json_u64")]
+    pub count: u64,
+    #[prost(double, optional, tag = "5")]
+    pub sum: Option<f64>,
+    #[prost(sint32, tag = "6")]
+    pub scale: i32,
+    #[prost(fixed64, tag = "7")]
+    #[serde(deserialize_with = "json_u64")]
+    pub zero_count: u64,
+    #[prost(message, optional, tag = "8")]
+    pub positive: Option<Buckets>,
+    #[prost(message, optional, tag = "9")]
+    pub negative: Option<Buckets>,
+    #[prost(double, tag = "14")]
+    pub zero_threshold: f64,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Buckets {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint64, repeated, tag = "2")]
+    #[serde(deserialize_with = "json_u64_vec")]
+    pub bucket_counts: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SummaryDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "json_u64")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(deserialize_with = "json_u64")]
+    pub count: u64,
+    #[prost(double, tag = "5")]
+    pub sum: f64,
+    #[prost(message, repeated, tag = "6")]
+    pub quantile_values: Vec<ValueAtQuantile>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ValueAtQuantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AnyValue {
+    #[prost(oneof = "Value", tags = "1, 2, 3, 4, 5, 6")]
+    #[serde(flatten)]
+    pub value: Option<Value>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Value {
+    #[prost(string, tag = "1")]
+    StringValue(String),
+    #[prost(bool, tag = "2")]
+    BoolValue(bool),
+    #[prost(int64, tag = "3")]
+    IntValue(#[serde(deserialize_with = "json_i64")] i64),
+    #[prost(double, tag = "4")]
+    DoubleValue(f64),
+    #[prost(message, tag = "5")]
+    ArrayValue(ArrayValue),
+    #[prost(message, tag = "6")]
+    KvlistValue(KeyValueList),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+/// `ExportMetricsServiceResponse`, sent back in the request's encoding.
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportMetricsServiceResponse {
+    #[prost(message, optional, tag = "1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_success: Option<ExportMetricsPartialSuccess>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportMetricsPartialSuccess {
+    #[prost(int64, tag = "1")]
+    #[serde(serialize_with = "json_i64_string")]
+    pub rejected_data_points: i64,
+    #[prost(string, tag = "2")]
+    pub error_message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInt<T> {
+    Number(T),
+    String(String),
+}
+
+impl<T: std::str::FromStr> JsonInt<T> {
+    fn parse<E: serde::de::Error>(self) -> Result<T, E> {
+        match self {
+            JsonInt::Number(n) => Ok(n),
+            JsonInt::String(s) => s
+                .parse()
+                .map_err(|_| E::custom(format!("invalid integer {s:?}"))),
+        }
+    }
+}
+
+fn json_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    JsonInt::<u64>::deserialize(deserializer)?.parse()
+}
+
+fn json_i64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    JsonInt::<i64>::deserialize(deserializer)?.parse()
+}
+
+fn json_u64_vec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+    Vec::<JsonInt<u64>>::deserialize(deserializer)?
+        .into_iter()
+        .map(JsonInt::parse)
+        .collect()
+}
+
+fn json_i64_string<S: serde::Serializer>(value: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OtlpError {
+    #[error("invalid protobuf payload: {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("invalid JSON payload: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid data point in {metric:?}: {reason}")]
+    InvalidPoint {
+        metric: String,
+        reason: &'static str,
+    },
+}
+
+pub fn decode_protobuf(body: &[u8]) -> Result<ExportMetricsServiceRequest, OtlpError> {
+    Ok(ExportMetricsServiceRequest::decode(body)?)
+}
+
+pub fn decode_json(body: &[u8]) -> Result<ExportMetricsServiceRequest, OtlpError> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Flattens an export request into metric requests, one per data point. Resource,
+/// scope and data point attributes are merged into labels, most specific last.
+/// Fails on a data point no metric can be built from, such as a timestamp past
+/// the year 2262 or bucket counts that overflow.
+pub fn to_metric_requests(
+    request: ExportMetricsServiceRequest,
+) -> Result<Vec<CreateMetricRequest>, OtlpError> {
+    let mut requests = Vec::new();
+
+    for resource_metrics in request.resource_metrics {
+        let mut resource_labels = Labels::new();
+        if let Some(resource) = &resource_metrics.resource {
+            add_attributes(&mut resource_labels, &resource.attributes);
+        }
+
+        for scope_metrics in resource_metrics.scope_metrics {
+            let mut scope_labels = resource_labels.clone();
+            if let Some(scope) = &scope_metrics.scope {
+                if !scope.name.is_empty() {
+                    scope_labels.insert("otel_scope_name".to_string(), scope.name.clone());
+                }
+                if !scope.version.is_empty() {
+                    scope_labels.insert("otel_scope_version".to_string(), scope.version.clone());
+                }
+                add_attributes(&mut scope_labels, &scope.attributes);
+            }
+
+            for metric in scope_metrics.metrics {
+                add_metric(&mut requests, metric, &scope_labels)?;
+            }
+        }
+    }
+
+    Ok(requests)
+}
+
+fn add_metric(
+    requests: &mut Vec<CreateMetricRequest>,
+    metric: Metric,
+    labels: &Labels,
+) -> Result<(), OtlpError> {
+    let invalid = |reason: &'static str| OtlpError::InvalidPoint {
+        metric: metric.name.clone(),
+        reason,
+    };
+    let request = |attributes: &[KeyValue], time_unix_nano: u64, kind: MetricKind| {
+        let mut point_labels = labels.clone();
+        add_attributes(&mut point_labels, attributes);
+
+        let timestamp = match time_unix_nano {
+            0 => None,
+            nanos => Some(MetricTimestamp::Epoch(
+                i64::try_from(nanos).map_err(|_| invalid("timestamp out of range"))?,
+            )),
+        };
+
+        Ok::<_, OtlpError>(CreateMetricRequest {
+            name: metric.name.clone(),
+            tags: None,
+            labels: Some(point_labels),
+            kind,
+            value: None,
+            histogram: None,
+            summary: None,
+            timestamp,
+        })
+    };
+
+    match &metric.data {
+        Some(MetricData::Gauge(gauge)) => {
+            for point in &gauge.data_points {
+                requests.push(CreateMetricRequest {
+                    value: number_value(point),
+                    ..request(&point.attributes, point.time_unix_nano, MetricKind::Gauge)?
+                });
+            }
+        }
+        Some(MetricData::Sum(sum)) => {
+            // Only cumulative monotonic sums behave like counters; delta sums hold
+            // per-interval increments and are stored as gauges.
+            let kind = if sum.is_monotonic
+                && sum.aggregation_temporality == AggregationTemporality::Cumulative as i32
+            {
+                MetricKind::Counter
+            } else {
+                MetricKind::Gauge
+            };
+
+            for point in &sum.data_points {
+                requests.push(CreateMetricRequest {
+                    value: number_value(point),
+                    ..request(&point.attributes, point.time_unix_nano, kind)?
+                });
+            }
+        }
+        Some(MetricData::Histogram(histogram)) => {
+            for point in &histogram.data_points {
+                requests.push(CreateMetricRequest {
+                    histogram: Some(
+                        explicit_histogram(point)
+                            .ok_or_else(|| invalid("bucket counts overflow"))?,
+                    ),
+                    ..request(
+                        &point.attributes,
+                        point.time_unix_nano,
+                        MetricKind::Histogram,
+                    )?
+                });
+            }
+        }
+        Some(MetricData::ExponentialHistogram(histogram)) => {
+            for point in &histogram.data_points {
+                requests.push(CreateMetricRequest {
+                    histogram: Some(
+                        exponential_histogram(point)
+                            .ok_or_else(|| invalid("bucket counts or indexes overflow"))?,
+                    ),
+                    ..request(
+                        &point.attributes,
+                        point.time_unix_nano,
+                        MetricKind::Histogram,
+                    )?
+                });
+            }
+        }
+        Some(MetricData::Summary(summary)) => {
+            for point in &summary.data_points {
+                let mut quantiles: Vec<SummaryQuantile> = point
+                    .quantile_values
+                    .iter()
+                    .map(|q| SummaryQuantile {
+                        quantile: q.quantile,
+                        value: q.value,
+                    })
+                    .collect();
+                quantiles.sort_by(|a, b| a.quantile.total_cmp(&b.quantile));
+
+                requests.push(CreateMetricRequest {
+                    summary: Some(MetricSummary {
+                        quantiles,
+                        count: point.count,
+                        sum: point.sum,
+                    }),
+                    ..request(&point.attributes, point.time_unix_nano, MetricKind::Summary)?
+                });
+            }
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn number_value(point: &NumberDataPoint) -> Option<f64> {
+    match point.value {
+        Some(NumberValue::AsDouble(value)) => Some(value),
+        Some(NumberValue::AsInt(value)) => Some(value as f64),
+        None => None,
+    }
+}
+
+fn add_attributes(labels: &mut Labels, attributes: &[KeyValue]) {
+    for attribute in attributes {
+        if let Some(value) = attribute.value.as_ref().and_then(attribute_string) {
+            labels.insert(sanitize_label_name(&attribute.key), value);
+        }
+    }
+}
+
+fn attribute_string(value: &AnyValue) -> Option<String> {
+    Some(match value.value.as_ref()? {
+        Value::StringValue(s) => s.clone(),
+        Value::BoolValue(b) => b.to_string(),
+        Value::IntValue(i) => i.to_string(),
+        Value::DoubleValue(d) => d.to_string(),
+        Value::ArrayValue(array) => {
+            let values: Vec<String> = array.values.iter().filter_map(attribute_string).collect();
+            format!("[{}]", values.join(","))
+        }
+        Value::KvlistValue(list) => {
+            let values: Vec<String> = list
+                .values
+                .iter()
+                .filter_map(|kv| {
+                    Some(format!(
+                        "{}={}",
+                        kv.key,
+                        attribute_string(kv.value.as_ref()?)?
+                    ))
+                })
+                .collect();
+            format!("{{{}}}", values.join(","))
+        }
+    })
+}
+
+/// Converts OTLP per-bucket counts into cumulative buckets. The last OTLP bucket
+/// is the overflow bucket and is covered by `count`. `None` when the counts
+/// overflow.
+fn explicit_histogram(point: &HistogramDataPoint) -> Option<MetricHistogram> {
+    let mut cumulative: u64 = 0;
+    let buckets = point
+        .explicit_bounds
+        .iter()
+        .zip(&point.bucket_counts)
+        .map(|(&le, &count)| {
+            cumulative = cumulative.checked_add(count)?;
+            Some(HistogramBucket {
+                le,
+                count: cumulative,
+            })
+        })
+        .collect::<Option<_>>()?;
+
+    Some(MetricHistogram {
+        buckets,
+        count: point.count,
+        sum: point.sum.unwrap_or(0.0),
+    })
+}
+
+/// Converts an exponential histogram into explicit cumulative buckets. Bucket
+/// `i` covers `(base^i, base^(i+1)]` with `base = 2^(2^-scale)`; negative
+/// buckets mirror that below zero, and the zero bucket ends at `zero_threshold`.
+/// `None` when the counts or bucket indexes overflow.
+fn exponential_histogram(point: &ExponentialHistogramDataPoint) -> Option<MetricHistogram> {
+    let base = 2f64.powf(2f64.powi(-point.scale));
+    let mut buckets = Vec::new();
+    let mut cumulative: u64 = 0;
+    let index = |offset: i32, i: usize| offset.checked_add(i32::try_from(i).ok()?);
+
+    if let Some(negative) = &point.negative {
+        // Walk from the most negative bucket towards zero
+        for (i, &count) in negative.bucket_counts.iter().enumerate().rev() {
+            cumulative = cumulative.checked_add(count)?;
+            buckets.push(HistogramBucket {
+                le: -base.powi(index(negative.offset, i)?),
+                count: cumulative,
+            });
+        }
+    }
+
+    cumulative = cumulative.checked_add(point.zero_count)?;
+    buckets.push(HistogramBucket {
+        le: point.zero_threshold,
+        count: cumulative,
+    });
+
+    if let Some(positive) = &point.positive {
+        for (i, &count) in positive.bucket_counts.iter().enumerate() {
+            cumulative = cumulative.checked_add(count)?;
+            buckets.push(HistogramBucket {
+                le: base.powi(index(positive.offset, i)?.checked_add(1)?),
+                count: cumulative,
+            });
+        }
+    }
+
+    Some(MetricHistogram {
+        buckets,
+        count: point.count,
+        sum: point.sum.unwrap_or(0.0),
+    })
+}
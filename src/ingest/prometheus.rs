@@ -0,0 +1,163 @@
+use crate::models::{CreateMetricRequest, Labels, MetricKind, MetricTimestamp};
+use prost::Message;
+use std::collections::HashMap;
+
+/// Prometheus remote write payload. Only the fields needed for float samples are
+/// declared; native histograms and exemplars are skipped by the decoder.
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: Vec<MetricMetadata>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricMetadata {
+    #[prost(enumeration = "MetricType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricType {
+    Unknown = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    GaugeHistogram = 4,
+    Summary = 5,
+    Info = 6,
+    Stateset = 7,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteWriteError {
+    #[error("invalid snappy payload: {0}")]
+    Snappy(#[from] snap::Error),
+    #[error("invalid protobuf payload: {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("payload decompresses to {size} bytes, more than the limit of {limit}")]
+    TooLarge { size: usize, limit: usize },
+}
+
+/// Decompresses and decodes a remote write request body. The decompressed size
+/// is read from the snappy header and checked against `limit` before anything
+/// is allocated.
+pub fn decode(body: &[u8], limit: usize) -> Result<WriteRequest, RemoteWriteError> {
+    let size = snap::raw::decompress_len(body)?;
+    if size > limit {
+        return Err(RemoteWriteError::TooLarge { size, limit });
+    }
+
+    let decompressed = snap::raw::Decoder::new().decompress_vec(body)?;
+    Ok(WriteRequest::decode(decompressed.as_slice())?)
+}
+
+/// Maps every sample onto a metric request. `__name__` becomes the metric name and
+/// the remaining labels are kept as labels. NaN samples, which Prometheus uses as
+/// staleness markers, are dropped.
+pub fn to_metric_requests(request: WriteRequest) -> Vec<CreateMetricRequest> {
+    let families: HashMap<&str, MetricType> = request
+        .metadata
+        .iter()
+        .map(|m| {
+            let metric_type = MetricType::try_from(m.r#type).unwrap_or(MetricType::Unknown);
+            (m.metric_family_name.as_str(), metric_type)
+        })
+        .collect();
+
+    let mut requests = Vec::new();
+
+    for series in &request.timeseries {
+        let mut name = None;
+        let mut labels = Labels::new();
+
+        for label in &series.labels {
+            if label.name == "__name__" {
+                name = Some(label.value.clone());
+            } else {
+                labels.insert(label.name.clone(), label.value.clone());
+            }
+        }
+
+        let Some(name) = name else {
+            continue;
+        };
+        let kind = series_kind(&name, &families);
+
+        for sample in series.samples.iter().filter(|s| !s.value.is_nan()) {
+            requests.push(CreateMetricRequest {
+                name: name.clone(),
+                tags: None,
+                labels: Some(labels.clone()),
+                kind,
+                value: Some(sample.value),
+                histogram: None,
+                summary: None,
+                timestamp: Some(MetricTimestamp::Epoch(sample.timestamp)),
+            });
+        }
+    }
+
+    requests
+}
+
+/// Infers the kind of one series. Classic histograms and summaries arrive as
+/// separate `_bucket`, `_sum` and `_count` series, which are cumulative counters;
+/// summary quantile series are gauges. Without metadata, the `_total` suffix
+/// convention identifies counters.
+fn series_kind(name: &str, families: &HashMap<&str, MetricType>) -> MetricKind {
+    let family_type = |family: &str| families.get(family).copied();
+
+    if let Some(metric_type) = family_type(name) {
+        return match metric_type {
+            MetricType::Counter => MetricKind::Counter,
+            _ => MetricKind::Gauge,
+        };
+    }
+
+    for suffix in ["_bucket", "_sum", "_count"] {
+        if let Some(family) = name.strip_suffix(suffix) {
+            if matches!(
+                family_type(family),
+                Some(MetricType::Histogram | MetricType::Summary)
+            ) {
+                return MetricKind::Counter;
+            }
+        }
+    }
+
+    if name.ends_with("_total") {
+        MetricKind::Counter
+    } else {
+        MetricKind::Gauge
+    }
+}
@@ -0,0 +1,346 @@
+use crate::models::{CreateMetricRequest, MetricKind, Summary, SummaryQuantile};
+use crate::services::TelemetryService;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Quantiles reported for timers, histograms and distributions at each flush.
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Counters and gauges not updated for this many flushes are forgotten, so tags
+/// that stop appearing do not hold memory for the life of the listener.
+pub const IDLE_FLUSHES: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdType {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+    Distribution,
+    Set,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatsdValue {
+    Number(f64),
+    /// A gauge value with an explicit sign, applied relative to the current value.
+    Delta(f64),
+    /// A set member, counted by distinct value.
+    Member(String),
+}
+
+/// One StatsD line: `name:value[:value...]|type[|@rate][|#tag,key:value]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdSample {
+    pub name: String,
+    pub metric_type: StatsdType,
+    pub values: Vec<StatsdValue>,
+    pub sample_rate: f64,
+    pub tags: Vec<String>,
+}
+
+/// Parses a single StatsD or DogStatsD line. Unknown `|` sections, such as
+/// DogStatsD container ids, are ignored; events and service checks are rejected.
+pub fn parse_line(line: &str) -> Result<StatsdSample, String> {
+    let line = line.trim();
+    if line.starts_with("_e{") || line.starts_with("_sc|") {
+        return Err("events and service checks are not supported".to_string());
+    }
+
+    let (name, rest) = line
+        .split_once(':')
+        .ok_or_else(|| format!("missing ':' in {line:?}"))?;
+    if name.is_empty() {
+        return Err(format!("missing metric name in {line:?}"));
+    }
+
+    let mut sections = rest.split('|');
+    let raw_values = sections.next().unwrap_or_default();
+    let metric_type = match sections.next() {
+        Some("c") => StatsdType::Counter,
+        Some("g") => StatsdType::Gauge,
+        Some("ms") => StatsdType::Timer,
+        Some("h") => StatsdType::Histogram,
+        Some("d") => StatsdType::Distribution,
+        Some("s") => StatsdType::Set,
+        Some(other) => return Err(format!("unknown metric type {other:?}")),
+        None => return Err(format!("missing metric type in {line:?}")),
+    };
+
+    let mut sample_rate = 1.0;
+    let mut tags = Vec::new();
+
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                .ok_or_else(|| format!("invalid sample rate {rate:?}"))?;
+        } else if let Some(tag_list) = section.strip_prefix('#') {
+            tags.extend(
+                tag_list
+                    .split(',')
+                    .filter(|tag| !tag.is_empty())
+                    .map(str::to_string),
+            );
+        }
+    }
+
+    let values = raw_values
+        .split(':')
+        .map(|raw| parse_value(raw, metric_type))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(StatsdSample {
+        name: name.to_string(),
+        metric_type,
+        values,
+        sample_rate,
+        tags,
+    })
+}
+
+fn parse_value(raw: &str, metric_type: StatsdType) -> Result<StatsdValue, String> {
+    if metric_type == StatsdType::Set {
+        return Ok(StatsdValue::Member(raw.to_string()));
+    }
+
+    let number = raw
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .ok_or_else(|| format!("invalid value {raw:?}"))?;
+
+    if metric_type == StatsdType::Gauge && (raw.starts_with('+') || raw.starts_with('-')) {
+        Ok(StatsdValue::Delta(number))
+    } else {
+        Ok(StatsdValue::Number(number))
+    }
+}
+
+/// Metric name plus sorted tags; each distinct combination is its own series.
+type SeriesKey = (String, Vec<String>);
+
+/// A counter total or gauge value, and the flush interval it last changed in.
+struct Tracked {
+    value: f64,
+    updated: u64,
+}
+
+#[derive(Default)]
+struct Distribution {
+    values: Vec<f64>,
+    count: f64,
+    sum: f64,
+}
+
+/// Accumulates StatsD samples between flushes.
+///
+/// Counters are kept as running totals so they can be stored as cumulative
+/// `counter` metrics; only series that changed since the last flush are written.
+/// Counters and gauges idle for `IDLE_FLUSHES` are dropped, and a counter that
+/// comes back after that starts again from zero, which reads as a reset. Timers,
+/// histograms and distributions flush as summaries of the interval, sets as a
+/// gauge of distinct members.
+#[derive(Default)]
+pub struct StatsdAggregator {
+    counters: HashMap<SeriesKey, Tracked>,
+    gauges: HashMap<SeriesKey, Tracked>,
+    distributions: HashMap<SeriesKey, Distribution>,
+    sets: HashMap<SeriesKey, HashSet<String>>,
+    /// Flushes so far, numbering the current interval.
+    flushes: u64,
+}
+
+impl StatsdAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, sample: StatsdSample) {
+        let mut tags = sample.tags;
+        tags.sort();
+        let key = (sample.name, tags);
+        let scale = 1.0 / sample.sample_rate;
+        let flushes = self.flushes;
+
+        for value in sample.values {
+            match (sample.metric_type, value) {
+                (StatsdType::Counter, StatsdValue::Number(n)) => {
+                    // Counters only grow; a negative increment would look like a reset
+                    let increment = (n.max(0.0) * scale).min(f64::MAX);
+                    let total = track(&mut self.counters, &key, flushes);
+                    *total += increment;
+                    if !total.is_finite() {
+                        // Past the largest float: start over, which reads as a reset
+                        *total = increment;
+                    }
+                }
+                (StatsdType::Gauge, StatsdValue::Number(n)) => {
+                    *track(&mut self.gauges, &key, flushes) = n;
+                }
+                (StatsdType::Gauge, StatsdValue::Delta(n)) => {
+                    let gauge = track(&mut self.gauges, &key, flushes);
+                    *gauge = (*gauge + n).clamp(f64::MIN, f64::MAX);
+                }
+                (
+                    StatsdType::Timer | StatsdType::Histogram | StatsdType::Distribution,
+                    StatsdValue::Number(n),
+                ) => {
+                    let distribution = self.distributions.entry(key.clone()).or_default();
+                    distribution.values.push(n);
+                    distribution.count += scale;
+                    distribution.sum += n * scale;
+                }
+                (StatsdType::Set, StatsdValue::Member(member)) => {
+                    self.sets.entry(key.clone()).or_default().insert(member);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the metrics accumulated since the last flush and resets interval
+    /// state, dropping counters and gauges idle for `IDLE_FLUSHES`.
+    pub fn flush(&mut self) -> Vec<CreateMetricRequest> {
+        let mut requests = Vec::new();
+        let current = self.flushes;
+        self.flushes += 1;
+
+        for (series, kind) in [
+            (&mut self.counters, MetricKind::Counter),
+            (&mut self.gauges, MetricKind::Gauge),
+        ] {
+            series.retain(|key, tracked| {
+                if tracked.updated == current {
+                    requests.push(request(key, kind, Some(tracked.value), None));
+                }
+                current - tracked.updated < IDLE_FLUSHES
+            });
+        }
+
+        for (key, mut distribution) in self.distributions.drain() {
+            distribution.values.sort_by(f64::total_cmp);
+            let quantiles = QUANTILES
+                .iter()
+                .map(|&quantile| SummaryQuantile {
+                    quantile,
+                    value: nearest_rank(&distribution.values, quantile),
+                })
+                .collect();
+            let summary = Summary {
+                quantiles,
+                count: distribution.count.round() as u64,
+                sum: distribution.sum,
+            };
+            requests.push(request(&key, MetricKind::Summary, None, Some(summary)));
+        }
+
+        for (key, members) in self.sets.drain() {
+            let count = members.len() as f64;
+            requests.push(request(&key, MetricKind::Gauge, Some(count), None));
+        }
+
+        requests
+    }
+}
+
+/// The value of `key` in `series`, marked as updated in interval `flush`.
+fn track<'a>(
+    series: &'a mut HashMap<SeriesKey, Tracked>,
+    key: &SeriesKey,
+    flush: u64,
+) -> &'a mut f64 {
+    let tracked = series.entry(key.clone()).or_insert(Tracked {
+        value: 0.0,
+        updated: flush,
+    });
+    tracked.updated = flush;
+    &mut tracked.value
+}
+
+fn request(
+    (name, tags): &SeriesKey,
+    kind: MetricKind,
+    value: Option<f64>,
+    summary: Option<Summary>,
+) -> CreateMetricRequest {
+    CreateMetricRequest {
+        name: name.clone(),
+        // `key:value` tags become labels when the metric is built
+        tags: (!tags.is_empty()).then(|| tags.clone()),
+        labels: None,
+        kind,
+        value,
+        histogram: None,
+        summary,
+        timestamp: None,
+    }
+}
+
+fn nearest_rank(sorted: &[f64], quantile: f64) -> f64 {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+/// Receives StatsD datagrams on `socket` and writes aggregates through `service`
+/// every `flush_interval`. Runs until the task is dropped.
+///
+/// Writes run in their own task so datagrams keep being read while the store is
+/// slow. While one is still running, later ticks are skipped and samples keep
+/// accumulating for the next flush.
+pub async fn run(socket: UdpSocket, service: TelemetryService, flush_interval: Duration) {
+    let mut aggregator = StatsdAggregator::new();
+    let mut interval = tokio::time::interval(flush_interval);
+    let mut buf = vec![0u8; 65_535];
+    let mut writing: Option<tokio::task::JoinHandle<()>> = None;
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let len = match received {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        log::warn!("StatsD receive failed: {e}");
+                        continue;
+                    }
+                };
+
+                for line in String::from_utf8_lossy(&buf[..len]).lines() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match parse_line(line) {
+                        Ok(sample) => aggregator.add(sample),
+                        Err(e) => log::debug!("Dropping StatsD line: {e}"),
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                if writing.as_ref().is_some_and(|write| !write.is_finished()) {
+                    log::warn!("StatsD flush still running; delaying the next one");
+                    continue;
+                }
+
+                let requests: Vec<_> = aggregator.flush().into_iter().map(Ok).collect();
+                if requests.is_empty() {
+                    continue;
+                }
+
+                writing = Some(actix_web::rt::spawn(write(service.clone(), requests)));
+            }
+        }
+    }
+}
+
+async fn write(service: TelemetryService, requests: Vec<Result<CreateMetricRequest, String>>) {
+    match service.create_metrics(requests).await {
+        Ok(response) if response.failed > 0 => {
+            log::warn!("StatsD flush rejected {} metrics", response.failed);
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("StatsD flush failed: {e}"),
+    }
+}
@@ -3,7 +3,9 @@ use serde::Serialize;
 
 pub mod config;
 pub mod db;
+pub mod ingest;
 pub mod models;
+pub mod promql;
 pub mod routes;
 pub mod services;
 
@@ -1,12 +1,19 @@
 use actix_governor::{Governor, GovernorConfigBuilder};
 use actix_web::{middleware, web, App, HttpServer};
+use std::sync::Arc;
+use std::time::Duration;
 use telemetry_server::{
-    config::Config,
-    db::{MongoDb, RedisDb},
-    health_check, routes,
-    services::{QueryService, TelemetryService},
+    config::{Config, StorageBackend},
+    db::{InMemoryStore, MetricStore, MongoDb, RedisDb},
+    health_check,
+    ingest::{graphite, statsd},
+    routes,
+    services::{CacheOptions, IngestOptions, PageOptions, QueryService, TelemetryService},
     version,
 };
+use tokio::net::{TcpListener, UdpSocket};
+
+const REDIS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -14,17 +21,69 @@ async fn main() -> std::io::Result<()> {
 
     let config = Config::from_env().expect("Failed to load configuration");
 
-    let mongo = MongoDb::connect(&config)
-        .await
-        .expect("Failed to connect to MongoDB");
+    let (store, redis): (Arc<dyn MetricStore>, Option<RedisDb>) = match config.storage_backend {
+        StorageBackend::Mongo => {
+            let mongo = MongoDb::connect(&config)
+                .await
+                .expect("Failed to connect to MongoDB");
+
+            (Arc::new(mongo), connect_redis(&config).await)
+        }
+        StorageBackend::Memory => {
+            log::warn!("Using in-memory metric store; data will not survive a restart");
+            (Arc::new(InMemoryStore::new()), None)
+        }
+    };
 
-    let redis = RedisDb::connect(&config)
-        .await
-        .expect("Failed to connect to Redis");
+    let cache_options = if config.cache_enabled {
+        CacheOptions::from(&config)
+    } else {
+        log::info!("Query caching is disabled");
+        CacheOptions::disabled()
+    };
 
-    let telemetry_service = TelemetryService::new(mongo.clone(), redis.clone());
+    let telemetry_service = TelemetryService::new(store, redis)
+        .with_ingest_options(IngestOptions::from(&config))
+        .with_page_options(PageOptions::from(&config))
+        .with_cache_options(cache_options);
     let query_service = QueryService::new(telemetry_service.clone());
 
+    let listener_service = telemetry_service.clone();
+    actix_web::rt::spawn(async move { listener_service.listen_for_cache_invalidations().await });
+
+    if let Some(statsd_port) = config.statsd_port {
+        let socket = UdpSocket::bind(("0.0.0.0", statsd_port)).await?;
+        log::info!("StatsD listener on udp://0.0.0.0:{statsd_port}");
+
+        actix_web::rt::spawn(statsd::run(
+            socket,
+            telemetry_service.clone(),
+            Duration::from_secs(config.statsd_flush_interval_secs),
+        ));
+    }
+
+    if let Some(graphite_port) = config.graphite_port {
+        let listener = TcpListener::bind(("0.0.0.0", graphite_port)).await?;
+        log::info!("Graphite plaintext listener on tcp://0.0.0.0:{graphite_port}");
+
+        actix_web::rt::spawn(graphite::run_plaintext(
+            listener,
+            telemetry_service.clone(),
+            config.graphite_templates.clone(),
+        ));
+    }
+
+    if let Some(graphite_pickle_port) = config.graphite_pickle_port {
+        let listener = TcpListener::bind(("0.0.0.0", graphite_pickle_port)).await?;
+        log::info!("Graphite pickle listener on tcp://0.0.0.0:{graphite_pickle_port}");
+
+        actix_web::rt::spawn(graphite::run_pickle(
+            listener,
+            telemetry_service.clone(),
+            config.graphite_templates.clone(),
+        ));
+    }
+
     let bind_address = format!("0.0.0.0:{}", config.port);
 
     log::info!("Starting server at: {bind_address}");
@@ -34,6 +93,7 @@ async fn main() -> std::io::Result<()> {
         build_date = env!("BUILD_DATE")
     );
     log::info!("Environment: {}", config.app_env);
+    log::info!("Storage backend: {:?}", config.storage_backend);
     log::info!("Database: {}", config.database_name);
 
     // Configure rate limiting
@@ -57,3 +117,32 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+/// Connects to Redis for the shared query cache. Redis is optional: when it is
+/// disabled or unconfigured the server runs without it, and when it is
+/// unreachable the cache keeps trying to connect behind its circuit breaker.
+async fn connect_redis(config: &Config) -> Option<RedisDb> {
+    if !config.cache_enabled {
+        return None;
+    }
+
+    if config.redis_uri.is_empty() {
+        log::info!("REDIS_URI is not set; caching query results in process only");
+        return None;
+    }
+
+    let redis = match RedisDb::open(config) {
+        Ok(redis) => redis,
+        Err(e) => {
+            log::warn!("Invalid REDIS_URI, continuing without Redis: {e}");
+            return None;
+        }
+    };
+
+    match tokio::time::timeout(REDIS_CONNECT_TIMEOUT, redis.connection()).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::warn!("Failed to connect to Redis, retrying on use: {e}"),
+        Err(_) => log::warn!("Timed out connecting to Redis, retrying on use"),
+    }
+    Some(redis)
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a metric's values should be interpreted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    /// Monotonically increasing total that may reset to zero, e.g. requests served.
+    Counter,
+    /// Point-in-time value that can go up and down, e.g. memory in use.
+    #[default]
+    Gauge,
+    /// Distribution of observations in cumulative buckets.
+    Histogram,
+    /// Distribution of observations as precomputed quantiles.
+    Summary,
+}
+
+impl fmt::Display for MetricKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+            MetricKind::Summary => "summary",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A histogram bucket counting observations less than or equal to `le`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+/// Cumulative histogram, as in Prometheus. `buckets` are sorted by `le` and hold
+/// running counts; the implicit `+Inf` bucket is `count`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub buckets: Vec<HistogramBucket>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.sum.is_finite() {
+            return Err("sum must be finite".to_string());
+        }
+
+        for pair in self.buckets.windows(2) {
+            if pair[0].le >= pair[1].le {
+                return Err("bucket bounds must be strictly increasing".to_string());
+            }
+            if pair[0].count > pair[1].count {
+                return Err("bucket counts must be cumulative".to_string());
+            }
+        }
+
+        if self.buckets.iter().any(|bucket| !bucket.le.is_finite()) {
+            return Err("bucket bounds must be finite; +Inf is implied by count".to_string());
+        }
+
+        if self
+            .buckets
+            .last()
+            .is_some_and(|last| last.count > self.count)
+        {
+            return Err("count must include every bucket".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Adds `other` into this histogram. Both must share the same bucket bounds,
+    /// which is what makes histograms from different series mergeable. On error
+    /// the histogram is left unchanged.
+    pub fn merge(&mut self, other: &Histogram) -> Result<(), String> {
+        let same_bounds = self.buckets.len() == other.buckets.len()
+            && self
+                .buckets
+                .iter()
+                .zip(&other.buckets)
+                .all(|(a, b)| a.le == b.le);
+
+        if !same_bounds {
+            return Err("cannot merge histograms with different bucket bounds".to_string());
+        }
+
+        let overflow = || "merged bucket counts overflow".to_string();
+        let counts = self
+            .buckets
+            .iter()
+            .zip(&other.buckets)
+            .map(|(a, b)| a.count.checked_add(b.count).ok_or_else(overflow))
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = self.count.checked_add(other.count).ok_or_else(overflow)?;
+
+        for (bucket, count) in self.buckets.iter_mut().zip(counts) {
+            bucket.count = count;
+        }
+        self.count = count;
+        self.sum += other.sum;
+
+        Ok(())
+    }
+
+    /// Estimates the `q` quantile with `bucket_quantile`, like PromQL's
+    /// `histogram_quantile`. `None` for an empty histogram or `q` outside 0..=1.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+
+        let mut buckets: Vec<(f64, f64)> = self
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.le, bucket.count as f64))
+            .chain([(f64::INFINITY, self.count as f64)])
+            .collect();
+        Some(bucket_quantile(q, &mut buckets)).filter(|value| !value.is_nan())
+    }
+}
+
+/// Estimates the `q` quantile of cumulative `buckets`, `(le, count)` pairs that
+/// must include `+Inf`, by linear interpolation within the bucket that holds
+/// it, as Prometheus does. The first bucket starts at zero unless its bound is
+/// not above it; observations above the last finite bound are reported as that
+/// bound. NaN without observations or a `+Inf` bucket.
+pub fn bucket_quantile(q: f64, buckets: &mut [(f64, f64)]) -> f64 {
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+
+    buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let Some(&(last_bound, observations)) = buckets.last() else {
+        return f64::NAN;
+    };
+    if last_bound != f64::INFINITY || buckets.len() < 2 || observations <= 0.0 {
+        return f64::NAN;
+    }
+
+    // Rates of cumulative counts can dip slightly out of order
+    for i in 1..buckets.len() {
+        buckets[i].1 = buckets[i].1.max(buckets[i - 1].1);
+    }
+    let observations = buckets[buckets.len() - 1].1;
+
+    let rank = q * observations;
+    let index = buckets
+        .iter()
+        .position(|&(_, count)| count >= rank)
+        .unwrap_or(buckets.len() - 1);
+
+    if index == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].0;
+    }
+    if index == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+
+    let (lower, below) = match index {
+        0 => (0.0, 0.0),
+        _ => buckets[index - 1],
+    };
+    let (upper, count) = buckets[index];
+    lower + (upper - lower) * ((rank - below) / (count - below))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SummaryQuantile {
+    pub quantile: f64,
+    pub value: f64,
+}
+
+/// Client-computed quantiles over a sliding window. Unlike histograms, summaries
+/// from different series cannot be merged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub quantiles: Vec<SummaryQuantile>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Summary {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.sum.is_finite() {
+            return Err("sum must be finite".to_string());
+        }
+
+        if self
+            .quantiles
+            .iter()
+            .any(|q| !(0.0..=1.0).contains(&q.quantile) || !q.value.is_finite())
+        {
+            return Err("quantiles must be within [0, 1] with finite values".to_string());
+        }
+
+        if self
+            .quantiles
+            .windows(2)
+            .any(|pair| pair[0].quantile >= pair[1].quantile)
+        {
+            return Err("quantiles must be strictly increasing".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Total increase of a counter over samples in time order. A sample lower than
+/// its predecessor is treated as a reset to zero, so the post-reset value counts
+/// in full rather than producing a negative delta.
+pub fn counter_increase(values: impl IntoIterator<Item = f64>) -> f64 {
+    let mut values = values.into_iter();
+    let Some(mut previous) = values.next() else {
+        return 0.0;
+    };
+
+    let mut increase = 0.0;
+    for value in values {
+        increase += if value < previous {
+            value
+        } else {
+            value - previous
+        };
+        previous = value;
+    }
+
+    increase
+}
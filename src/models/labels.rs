@@ -0,0 +1,391 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Key/value dimensions attached to a metric, e.g. `env=production`.
+pub type Labels = BTreeMap<String, String>;
+
+/// Label names follow the Prometheus rules (`[a-zA-Z_][a-zA-Z0-9_]*`), which also
+/// keeps them safe to use as MongoDB field paths.
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Rewrites a foreign attribute key into a valid label name the way Prometheus
+/// does, e.g. `service.name` becomes `service_name`.
+pub fn sanitize_label_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        sanitized.insert(0, '_');
+    }
+
+    sanitized
+}
+
+/// Extracts labels from legacy `key:value` tags. Tags without a separator are not labels.
+pub fn labels_from_tags(tags: &[String]) -> Labels {
+    tags.iter()
+        .filter_map(|tag| tag.split_once(':'))
+        .filter(|(key, value)| is_valid_label_name(key) && !value.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid label selector: {0}")]
+pub struct SelectorError(String);
+
+/// A single condition on one label.
+///
+/// A metric without the label never satisfies a positive matcher (`=`, `=~`, `in`)
+/// and always satisfies a negative one (`!=`, `!~`, `notin`). Regexes are anchored
+/// at both ends, as in PromQL.
+#[derive(Debug, Clone)]
+pub enum LabelMatcher {
+    Equal(String, String),
+    NotEqual(String, String),
+    Regex(String, String, Regex),
+    NotRegex(String, String, Regex),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+}
+
+impl LabelMatcher {
+    pub fn regex(name: &str, pattern: &str, negate: bool) -> Result<Self, SelectorError> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .map_err(|e| SelectorError(format!("bad regex for {name}: {e}")))?;
+
+        Ok(if negate {
+            LabelMatcher::NotRegex(name.to_string(), pattern.to_string(), regex)
+        } else {
+            LabelMatcher::Regex(name.to_string(), pattern.to_string(), regex)
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            LabelMatcher::Equal(name, _)
+            | LabelMatcher::NotEqual(name, _)
+            | LabelMatcher::Regex(name, _, _)
+            | LabelMatcher::NotRegex(name, _, _)
+            | LabelMatcher::In(name, _)
+            | LabelMatcher::NotIn(name, _) => name,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        matches!(
+            self,
+            LabelMatcher::NotEqual(..) | LabelMatcher::NotRegex(..) | LabelMatcher::NotIn(..)
+        )
+    }
+
+    /// Operator, label name and values in a normalized form: list values are
+    /// sorted and deduplicated, so equivalent matchers compare equal.
+    pub(crate) fn canonical(&self) -> (&'static str, &str, Vec<&str>) {
+        let (op, name, mut values) = match self {
+            LabelMatcher::Equal(name, value) => ("=", name, vec![value.as_str()]),
+            LabelMatcher::NotEqual(name, value) => ("!=", name, vec![value.as_str()]),
+            LabelMatcher::Regex(name, pattern, _) => ("=~", name, vec![pattern.as_str()]),
+            LabelMatcher::NotRegex(name, pattern, _) => ("!~", name, vec![pattern.as_str()]),
+            LabelMatcher::In(name, values) => {
+                ("in", name, values.iter().map(String::as_str).collect())
+            }
+            LabelMatcher::NotIn(name, values) => {
+                ("notin", name, values.iter().map(String::as_str).collect())
+            }
+        };
+
+        values.sort_unstable();
+        values.dedup();
+        (op, name.as_str(), values)
+    }
+
+    pub fn matches(&self, labels: &Labels) -> bool {
+        let Some(value) = labels.get(self.name()) else {
+            return self.is_negative();
+        };
+
+        match self {
+            LabelMatcher::Equal(_, expected) => value == expected,
+            LabelMatcher::NotEqual(_, expected) => value != expected,
+            LabelMatcher::Regex(_, _, regex) => regex.is_match(value),
+            LabelMatcher::NotRegex(_, _, regex) => !regex.is_match(value),
+            LabelMatcher::In(_, values) => values.contains(value),
+            LabelMatcher::NotIn(_, values) => !values.contains(value),
+        }
+    }
+}
+
+impl fmt::Display for LabelMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelMatcher::Equal(name, value) => write!(f, "{name}={value:?}"),
+            LabelMatcher::NotEqual(name, value) => write!(f, "{name}!={value:?}"),
+            LabelMatcher::Regex(name, pattern, _) => write!(f, "{name}=~{pattern:?}"),
+            LabelMatcher::NotRegex(name, pattern, _) => write!(f, "{name}!~{pattern:?}"),
+            LabelMatcher::In(name, values) => write!(f, "{name} in ({})", values.join(",")),
+            LabelMatcher::NotIn(name, values) => write!(f, "{name} notin ({})", values.join(",")),
+        }
+    }
+}
+
+/// A conjunction of label matchers, parsed from the `labels` query parameter:
+///
+/// ```text
+/// env=production,host!=web-1,region=~"eu-.*",tier in (api,worker)
+/// ```
+///
+/// Values may be double-quoted to include commas or spaces; surrounding braces
+/// are optional so PromQL-style `{env="production"}` is accepted too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LabelSelector(pub Vec<LabelMatcher>);
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.0.iter().all(|matcher| matcher.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matchers: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", matchers.join(","))
+    }
+}
+
+impl From<LabelSelector> for String {
+    fn from(selector: LabelSelector) -> Self {
+        selector.to_string()
+    }
+}
+
+impl TryFrom<String> for LabelSelector {
+    type Error = SelectorError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix('{')
+            .and_then(|s| s.strip_suffix('}'))
+            .unwrap_or(s);
+
+        split_top_level(s)?
+            .iter()
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| parse_matcher(item.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(LabelSelector)
+    }
+}
+
+/// Splits on commas that are not inside quotes or parentheses.
+fn split_top_level(s: &str) -> Result<Vec<&str>, SelectorError> {
+    let mut items = Vec::new();
+    let mut depth = 0usize;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| SelectorError("unbalanced ')'".to_string()))?;
+            }
+            ',' if !in_quotes && depth == 0 => {
+                items.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    if in_quotes || depth != 0 {
+        return Err(SelectorError(format!(
+            "unterminated quote or parenthesis in {s:?}"
+        )));
+    }
+
+    items.push(&s[start..]);
+    Ok(items)
+}
+
+fn parse_matcher(item: &str) -> Result<LabelMatcher, SelectorError> {
+    let name_end = item
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(item.len());
+    let name = &item[..name_end];
+
+    if !is_valid_label_name(name) {
+        return Err(SelectorError(format!("invalid label name in {item:?}")));
+    }
+
+    let rest = item[name_end..].trim_start();
+
+    for (keyword, negate) in [("notin", true), ("in", false)] {
+        if let Some(list) = rest.strip_prefix(keyword) {
+            let list = list.trim();
+            let inner = list
+                .strip_prefix('(')
+                .and_then(|l| l.strip_suffix(')'))
+                .ok_or_else(|| SelectorError(format!("expected ({keyword} list) in {item:?}")))?;
+            let values = split_top_level(inner)?
+                .into_iter()
+                .map(unquote)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            return Ok(if negate {
+                LabelMatcher::NotIn(name.to_string(), values)
+            } else {
+                LabelMatcher::In(name.to_string(), values)
+            });
+        }
+    }
+
+    for op in ["=~", "!~", "!=", "==", "="] {
+        if let Some(value) = rest.strip_prefix(op) {
+            let value = unquote(value)?;
+
+            return match op {
+                "=~" => LabelMatcher::regex(name, &value, false),
+                "!~" => LabelMatcher::regex(name, &value, true),
+                "!=" => Ok(LabelMatcher::NotEqual(name.to_string(), value)),
+                _ => Ok(LabelMatcher::Equal(name.to_string(), value)),
+            };
+        }
+    }
+
+    Err(SelectorError(format!("expected an operator in {item:?}")))
+}
+
+fn unquote(value: &str) -> Result<String, SelectorError> {
+    let value = value.trim();
+
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return Ok(value.to_string());
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escaped = chars
+                .next()
+                .ok_or_else(|| SelectorError(format!("dangling escape in {value}")))?;
+            unquoted.push(escaped);
+        } else {
+            unquoted.push(c);
+        }
+    }
+
+    Ok(unquoted)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GroupingError {
+    #[error("invalid label name {0:?} in grouping")]
+    LabelName(String),
+    #[error("`by` and `without` cannot be combined")]
+    Conflict,
+}
+
+/// Which labels split an aggregation into separate series. Series are always
+/// split by metric name too; labels not kept by the grouping are aggregated away.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Grouping {
+    /// One series per metric name.
+    #[default]
+    Name,
+    /// One series per distinct combination of these labels.
+    By(Vec<String>),
+    /// One series per distinct combination of all labels except these.
+    Without(Vec<String>),
+}
+
+impl Grouping {
+    /// Builds a grouping from comma-separated `by` and `without` label lists.
+    pub fn from_params(by: Option<&str>, without: Option<&str>) -> Result<Self, GroupingError> {
+        match (by, without) {
+            (Some(_), Some(_)) => Err(GroupingError::Conflict),
+            (Some(by), None) => Ok(Grouping::By(label_list(by.split(','))?)),
+            (None, Some(without)) => Ok(Grouping::Without(label_list(without.split(','))?)),
+            (None, None) => Ok(Grouping::Name),
+        }
+    }
+
+    /// The labels that identify the series `labels` belongs to.
+    pub fn key(&self, labels: &Labels) -> Labels {
+        match self {
+            Grouping::Name => Labels::new(),
+            Grouping::By(keys) => labels
+                .iter()
+                .filter(|(name, _)| keys.contains(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            Grouping::Without(keys) => labels
+                .iter()
+                .filter(|(name, _)| !keys.contains(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Renders as `by(a,b)` or `without(a)`, empty for `Grouping::Name`.
+impl fmt::Display for Grouping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Grouping::Name => Ok(()),
+            Grouping::By(keys) => write!(f, "by({})", keys.join(",")),
+            Grouping::Without(keys) => write!(f, "without({})", keys.join(",")),
+        }
+    }
+}
+
+/// Validates, sorts and deduplicates label names so equal groupings compare equal.
+pub fn label_list<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<String>, GroupingError> {
+    let mut names = names
+        .into_iter()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            is_valid_label_name(name)
+                .then(|| name.to_string())
+                .ok_or_else(|| GroupingError::LabelName(name.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    names.sort_unstable();
+    names.dedup();
+    Ok(names)
+}
@@ -1,5 +1,11 @@
+use crate::models::{
+    Histogram, LabelMatcher, LabelSelector, Labels, MetricKind, PageCursor, SortField, SortOrder,
+    Summary,
+};
 use bson::{oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Metric {
@@ -8,28 +14,442 @@ pub struct Metric {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    /// Documents written before kinds existed are read as gauges.
+    #[serde(default)]
+    pub kind: MetricKind,
+    /// The sample value. For histograms and summaries this is the sum of observations.
     pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Histogram>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
     pub timestamp: DateTime,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct MetricFilter {
     pub name: Option<String>,
     pub tags: Option<Vec<String>>,
+    /// Label matchers, e.g. `env=production,region=~"eu-.*"`. See `LabelSelector`.
+    pub labels: Option<LabelSelector>,
+    pub kind: Option<MetricKind>,
+    #[serde(alias = "start")]
     pub start_date: Option<String>,
+    #[serde(alias = "end")]
     pub end_date: Option<String>,
+    #[serde(default)]
+    pub sort: SortField,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Maximum number of metrics to return; unlimited when unset. `GET /metrics`
+    /// always sets it, bounded by `PageOptions`.
+    pub limit: Option<usize>,
+    /// Continue after the last metric of a previous page.
+    pub cursor: Option<PageCursor>,
+    /// Conditions every selected value must satisfy. Set by the `POST /query`
+    /// DSL; not a query parameter.
+    #[serde(skip)]
+    pub values: Vec<ValuePredicate>,
+    /// Granularity the time bounds are widened to in the cache key, so relative
+    /// ranges evaluated moments apart share an entry. The store still filters on
+    /// the exact bounds. Not a query parameter.
+    #[serde(skip)]
+    pub cache_bucket: Option<chrono::Duration>,
+}
+
+impl MetricFilter {
+    /// Parses `start_date` and `end_date` as RFC 3339, ignoring values that do not parse.
+    pub fn time_bounds(&self) -> (Option<DateTime>, Option<DateTime>) {
+        let parse = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+                .map(|dt| DateTime::from_millis(dt.timestamp_millis()))
+        };
+
+        (parse(&self.start_date), parse(&self.end_date))
+    }
+
+    /// The same selection without `limit` and `cursor`, for aggregations.
+    pub fn unpaged(&self) -> MetricFilter {
+        MetricFilter {
+            limit: None,
+            cursor: None,
+            ..self.clone()
+        }
+    }
+
+    /// The same selection without paging, oldest first, as rate functions
+    /// read it.
+    pub fn time_ordered(&self) -> MetricFilter {
+        MetricFilter {
+            sort: SortField::Timestamp,
+            order: SortOrder::Asc,
+            ..self.unpaged()
+        }
+    }
+
+    /// A stable hash identifying the metrics this filter selects, for use as a
+    /// cache key. Filters that differ only in tag or matcher order, duplicate
+    /// entries, or how equal timestamps are written share a fingerprint, as do
+    /// bounds within the same `cache_bucket`.
+    pub fn fingerprint(&self) -> String {
+        let tags = self.tags.as_ref().map(|tags| {
+            let mut tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+            tags.sort_unstable();
+            tags.dedup();
+            tags
+        });
+
+        let mut matchers: Vec<_> = self
+            .labels
+            .iter()
+            .flat_map(|selector| &selector.0)
+            .map(LabelMatcher::canonical)
+            .collect();
+        matchers.sort_unstable();
+        matchers.dedup();
+
+        let mut values: Vec<String> = self.values.iter().map(ToString::to_string).collect();
+        values.sort_unstable();
+        values.dedup();
+
+        let (start, end) = self.time_bounds();
+        let bucket_millis = self
+            .cache_bucket
+            .map(|bucket| bucket.num_milliseconds())
+            .filter(|&millis| millis > 0);
+        // Bounds in the same bucket share a key
+        let bucketed = |dt: Option<DateTime>| {
+            let millis = dt?.timestamp_millis();
+            Some(bucket_millis.map_or(millis, |bucket| millis.div_euclid(bucket)))
+        };
+
+        // JSON quoting keeps the encoding unambiguous before hashing
+        let canonical = serde_json::json!({
+            "name": self.name,
+            "tags": tags,
+            "labels": matchers,
+            "kind": self.kind,
+            "values": values,
+            "start": bucketed(start),
+            "end": bucketed(end),
+            "bucket": bucket_millis,
+            "sort": self.sort,
+            "order": self.order,
+            "limit": self.limit,
+            "cursor": self.cursor.as_ref().map(ToString::to_string),
+        });
+
+        format!("{:x}", Sha256::digest(canonical.to_string()))
+    }
+
+    /// Returns whether `metric` satisfies this filter. Mirrors the MongoDB query
+    /// built by `MongoDb`: exact name, any matching tag, all label matchers and
+    /// value predicates, inclusive time bounds. Paging is applied separately.
+    pub fn matches(&self, metric: &Metric) -> bool {
+        if let Some(name) = &self.name {
+            if &metric.name != name {
+                return false;
+            }
+        }
+
+        if let Some(tags) = &self.tags {
+            let metric_tags = metric.tags.as_deref().unwrap_or_default();
+            if !tags.iter().any(|tag| metric_tags.contains(tag)) {
+                return false;
+            }
+        }
+
+        if let Some(selector) = &self.labels {
+            if !selector.matches(&metric.labels) {
+                return false;
+            }
+        }
+
+        if self.kind.is_some_and(|kind| metric.kind != kind) {
+            return false;
+        }
+
+        if !self
+            .values
+            .iter()
+            .all(|predicate| predicate.matches(metric.value))
+        {
+            return false;
+        }
+
+        let (start, end) = self.time_bounds();
+
+        if start.is_some_and(|start| metric.timestamp < start) {
+            return false;
+        }
+
+        if end.is_some_and(|end| metric.timestamp > end) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// A condition on metric values, e.g. `{"op": "gt", "value": 100}`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ValuePredicate {
+    pub op: ComparisonOp,
+    pub value: f64,
+}
+
+impl ValuePredicate {
+    pub fn matches(&self, value: f64) -> bool {
+        match self.op {
+            ComparisonOp::Eq => value == self.value,
+            ComparisonOp::Ne => value != self.value,
+            ComparisonOp::Gt => value > self.value,
+            ComparisonOp::Gte => value >= self.value,
+            ComparisonOp::Lt => value < self.value,
+            ComparisonOp::Lte => value <= self.value,
+        }
+    }
+}
+
+impl fmt::Display for ValuePredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.op.symbol(), self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparisonOp {
+    #[serde(alias = "==")]
+    Eq,
+    #[serde(alias = "!=")]
+    Ne,
+    #[serde(alias = ">")]
+    Gt,
+    #[serde(alias = ">=")]
+    Gte,
+    #[serde(alias = "<")]
+    Lt,
+    #[serde(alias = "<=")]
+    Lte,
+}
+
+impl ComparisonOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ComparisonOp::Eq => "==",
+            ComparisonOp::Ne => "!=",
+            ComparisonOp::Gt => ">",
+            ComparisonOp::Gte => ">=",
+            ComparisonOp::Lt => "<",
+            ComparisonOp::Lte => "<=",
+        }
+    }
+
+    /// The equivalent MongoDB query operator.
+    pub fn mongo_operator(self) -> &'static str {
+        match self {
+            ComparisonOp::Eq => "$eq",
+            ComparisonOp::Ne => "$ne",
+            ComparisonOp::Gt => "$gt",
+            ComparisonOp::Gte => "$gte",
+            ComparisonOp::Lt => "$lt",
+            ComparisonOp::Lte => "$lte",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateMetricRequest {
     pub name: String,
+    /// Free-form tags. Tags of the form `key:value` are also stored as labels.
     pub tags: Option<Vec<String>>,
-    pub value: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Labels>,
+    #[serde(default)]
+    pub kind: MetricKind,
+    /// Required for counters and gauges; defaults to the observation sum for
+    /// histograms and summaries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Histogram>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    /// When the point was observed. Defaults to the time the server receives it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<MetricTimestamp>,
+}
+
+/// Client-supplied timestamp: an RFC 3339 string, or an integer epoch.
+///
+/// Integers up to `1e15` are read as milliseconds and anything larger as
+/// nanoseconds, which keeps both unambiguous for any date after 1970-01-12.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetricTimestamp {
+    Rfc3339(String),
+    Epoch(i64),
+}
+
+impl MetricTimestamp {
+    const MAX_EPOCH_MILLIS: u64 = 1_000_000_000_000_000;
+
+    pub fn to_datetime(&self) -> Result<DateTime, InvalidMetric> {
+        match self {
+            MetricTimestamp::Rfc3339(s) => chrono::DateTime::parse_from_rfc3339(s)
+                .map(|dt| DateTime::from_millis(dt.timestamp_millis()))
+                .map_err(|_| InvalidMetric::Timestamp(s.clone())),
+            MetricTimestamp::Epoch(n) if n.unsigned_abs() <= Self::MAX_EPOCH_MILLIS => {
+                Ok(DateTime::from_millis(*n))
+            }
+            MetricTimestamp::Epoch(n) => Ok(DateTime::from_millis(n / 1_000_000)),
+        }
+    }
+}
+
+impl CreateMetricRequest {
+    /// Checks the payload against its `kind` and returns the value to store.
+    pub fn validated_value(&self) -> Result<f64, InvalidMetric> {
+        let invalid = |reason: &str| InvalidMetric::Kind {
+            kind: self.kind,
+            reason: reason.to_string(),
+        };
+
+        let value = match self.kind {
+            MetricKind::Counter | MetricKind::Gauge => {
+                if self.histogram.is_some() || self.summary.is_some() {
+                    return Err(invalid("only histograms and summaries carry distributions"));
+                }
+                self.value.ok_or_else(|| invalid("value is required"))?
+            }
+            MetricKind::Histogram => {
+                if self.summary.is_some() {
+                    return Err(invalid("summary is not allowed"));
+                }
+                let histogram = self
+                    .histogram
+                    .as_ref()
+                    .ok_or_else(|| invalid("histogram is required"))?;
+                histogram.validate().map_err(|e| invalid(&e))?;
+                self.value.unwrap_or(histogram.sum)
+            }
+            MetricKind::Summary => {
+                if self.histogram.is_some() {
+                    return Err(invalid("histogram is not allowed"));
+                }
+                let summary = self
+                    .summary
+                    .as_ref()
+                    .ok_or_else(|| invalid("summary is required"))?;
+                summary.validate().map_err(|e| invalid(&e))?;
+                self.value.unwrap_or(summary.sum)
+            }
+        };
+
+        check_value(self.kind, value)
+    }
+}
+
+/// Checks a value to store on a metric of `kind`, whether created or updated.
+fn check_value(kind: MetricKind, value: f64) -> Result<f64, InvalidMetric> {
+    let invalid = |reason: &str| InvalidMetric::Kind {
+        kind,
+        reason: reason.to_string(),
+    };
+
+    if !value.is_finite() {
+        return Err(invalid("value must be finite"));
+    }
+
+    if kind == MetricKind::Counter && value < 0.0 {
+        return Err(invalid("counters cannot be negative"));
+    }
+
+    Ok(value)
+}
+
+/// A metric that was well-formed JSON but cannot be accepted.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidMetric {
+    #[error("invalid {kind} metric: {reason}")]
+    Kind { kind: MetricKind, reason: String },
+    #[error("invalid label name {0:?}: expected [a-zA-Z_][a-zA-Z0-9_]*")]
+    LabelName(String),
+    #[error("invalid timestamp {0:?}: expected RFC 3339 or epoch milliseconds/nanoseconds")]
+    Timestamp(String),
+    #[error("timestamp {timestamp} is more than {max_age_secs}s in the past")]
+    TooOld {
+        timestamp: String,
+        max_age_secs: u64,
+    },
+    #[error("timestamp {timestamp} is more than {max_future_secs}s in the future")]
+    TooFarInFuture {
+        timestamp: String,
+        max_future_secs: u64,
+    },
+}
+
+/// Outcome of one item in a `POST /metrics/batch` request.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchItemResult {
+    Created { index: usize, metric: Metric },
+    Failed { index: usize, error: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub created: usize,
+    pub failed: usize,
+    pub results: Vec<BatchItemResult>,
+}
+
+impl BatchResponse {
+    pub fn new(mut results: Vec<BatchItemResult>) -> Self {
+        results.sort_by_key(|result| match result {
+            BatchItemResult::Created { index, .. } | BatchItemResult::Failed { index, .. } => {
+                *index
+            }
+        });
+
+        let created = results
+            .iter()
+            .filter(|result| matches!(result, BatchItemResult::Created { .. }))
+            .count();
+
+        BatchResponse {
+            created,
+            failed: results.len() - created,
+            results,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateMetricRequest {
     pub name: Option<String>,
     pub tags: Option<Vec<String>>,
+    /// Replaces all labels of the metric.
+    #[serde(default)]
+    pub labels: Option<Labels>,
     pub value: Option<f64>,
 }
+
+impl UpdateMetricRequest {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.tags.is_none() && self.labels.is_none() && self.value.is_none()
+    }
+
+    /// Checks the new value, if any, against the `kind` of the stored metric.
+    pub fn validate(&self, kind: MetricKind) -> Result<(), InvalidMetric> {
+        match self.value {
+            Some(value) => check_value(kind, value).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
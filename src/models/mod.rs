@@ -1,5 +1,17 @@
+pub mod kind;
+pub mod labels;
 pub mod metric;
+pub mod page;
 pub mod query;
+pub mod range;
+pub mod rate;
+pub mod sketch;
 
+pub use kind::*;
+pub use labels::*;
 pub use metric::*;
+pub use page::*;
 pub use query::*;
+pub use range::*;
+pub use rate::*;
+pub use sketch::*;
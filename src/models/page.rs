@@ -0,0 +1,199 @@
+use crate::models::Metric;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Field that `GET /metrics` results are ordered by. Ties are broken by `_id`
+/// so every metric has a unique position and pages never overlap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Timestamp,
+    Value,
+    Name,
+}
+
+impl SortField {
+    /// The document field to sort on in MongoDB.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortField::Timestamp => "timestamp",
+            SortField::Value => "value",
+            SortField::Name => "name",
+        }
+    }
+
+    pub fn key(self, metric: &Metric) -> SortKey {
+        match self {
+            SortField::Timestamp => SortKey::Timestamp(metric.timestamp.timestamp_millis()),
+            SortField::Value => SortKey::Value(metric.value),
+            SortField::Name => SortKey::Name(metric.name.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    /// Orients an ascending comparison in this direction.
+    pub fn apply(self, ordering: Ordering) -> Ordering {
+        match self {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+
+    /// The MongoDB sort direction.
+    pub fn direction(self) -> i32 {
+        match self {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        }
+    }
+}
+
+/// The sort field value of the last metric on a page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    /// Epoch milliseconds.
+    Timestamp(i64),
+    Value(f64),
+    Name(String),
+}
+
+impl SortKey {
+    pub fn field(&self) -> SortField {
+        match self {
+            SortKey::Timestamp(_) => SortField::Timestamp,
+            SortKey::Value(_) => SortField::Value,
+            SortKey::Name(_) => SortField::Name,
+        }
+    }
+
+    /// Ascending comparison; keys of different fields are never compared.
+    pub fn compare(&self, other: &SortKey) -> Ordering {
+        match (self, other) {
+            (SortKey::Timestamp(a), SortKey::Timestamp(b)) => a.cmp(b),
+            (SortKey::Value(a), SortKey::Value(b)) => a.total_cmp(b),
+            (SortKey::Name(a), SortKey::Name(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// Continuation token for `GET /metrics`: the position of the last metric on
+/// the previous page. Clients treat it as opaque; it is base64url-encoded JSON.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PageCursor {
+    pub order: SortOrder,
+    pub after: SortKey,
+    pub id: ObjectId,
+}
+
+impl PageCursor {
+    /// A cursor positioned at `metric`. Returns `None` for metrics that have not
+    /// been stored yet.
+    pub fn at(metric: &Metric, field: SortField, order: SortOrder) -> Option<Self> {
+        Some(Self {
+            order,
+            after: field.key(metric),
+            id: metric.id?,
+        })
+    }
+
+    /// Whether `metric` sorts after this cursor, i.e. belongs to a later page.
+    pub fn is_past(&self, metric: &Metric) -> bool {
+        let Some(id) = metric.id else {
+            return false;
+        };
+
+        let ordering = self
+            .after
+            .field()
+            .key(metric)
+            .compare(&self.after)
+            .then(id.cmp(&self.id));
+
+        self.order.apply(ordering) == Ordering::Greater
+    }
+}
+
+impl fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[derive(Serialize)]
+        struct Token<'a> {
+            order: SortOrder,
+            after: &'a SortKey,
+            id: String,
+        }
+
+        let token = Token {
+            order: self.order,
+            after: &self.after,
+            id: self.id.to_hex(),
+        };
+        let json = serde_json::to_vec(&token).map_err(|_| fmt::Error)?;
+
+        f.write_str(&URL_SAFE_NO_PAD.encode(json))
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = CursorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[derive(Deserialize)]
+        struct Token {
+            order: SortOrder,
+            after: SortKey,
+            id: String,
+        }
+
+        let json = URL_SAFE_NO_PAD
+            .decode(s.trim())
+            .map_err(|_| CursorError::Malformed)?;
+        let token: Token = serde_json::from_slice(&json).map_err(|_| CursorError::Malformed)?;
+
+        Ok(Self {
+            order: token.order,
+            after: token.after,
+            id: ObjectId::parse_str(&token.id).map_err(|_| CursorError::Malformed)?,
+        })
+    }
+}
+
+impl TryFrom<String> for PageCursor {
+    type Error = CursorError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CursorError {
+    #[error("malformed cursor")]
+    Malformed,
+    #[error("cursor was issued for a different sort; repeat its sort and order or drop it")]
+    SortMismatch,
+}
+
+/// One page of `GET /metrics` results.
+#[derive(Debug)]
+pub struct MetricPage {
+    pub metrics: Vec<Metric>,
+    /// Where the next page starts, or `None` on the last page.
+    pub next: Option<PageCursor>,
+}
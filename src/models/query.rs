@@ -1,29 +1,463 @@
-use serde::Deserialize;
+use crate::models::{
+    label_list, Distribution, GapFill, Grouping, GroupingError, Histogram, LabelSelector, Labels,
+    Metric, RangeFunction, RangeResult, RateFunction, SortField, SortOrder, Step, ValuePredicate,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 
 #[derive(Debug, Deserialize)]
 pub struct QueryPrompt {
     pub prompt: String,
 }
 
-#[derive(Debug)]
+/// A query as `QueryService` executes it, parsed from a prompt or converted
+/// from a `StructuredQuery`.
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct ParsedQuery {
     pub metric_name: Option<String>,
     pub tags: Option<Vec<String>>,
+    pub labels: Option<LabelSelector>,
+    pub values: Vec<ValuePredicate>,
     pub time_range: Option<TimeRange>,
+    /// Bucket the results `step` wide over the time range instead of reducing
+    /// them to one value.
+    pub step: Option<Step>,
+    pub fill: GapFill,
     pub aggregation: Option<AggregationType>,
+    /// How aggregations are split into series, from "by host" or "without host".
+    pub grouping: Grouping,
+    /// How raw metrics or aggregated series are sorted; `top N` sorts by value.
+    pub order: Option<QueryOrder>,
     pub limit: Option<i64>,
+    /// See `MetricFilter::cache_bucket`. Set for prompts, whose time ranges are
+    /// usually relative to now.
+    #[serde(skip)]
+    pub cache_bucket: Option<chrono::Duration>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryOrder {
+    pub by: SortField,
+    #[serde(default)]
+    pub direction: SortOrder,
+}
+
+/// The prompt parser rule that set a part of a `ParsedQuery`, reported by
+/// `GET /query/explain`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    /// The `ParsedQuery` field the rule set, e.g. `metric_name`.
+    pub part: &'static str,
+    /// The grammar rule that matched, with placeholders for what it reads, e.g.
+    /// `last [NUMBER] UNIT` or `NAME per second`.
+    pub rule: String,
+    /// The matched text of the lowercased prompt and its byte offsets.
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl RuleMatch {
+    pub fn new(part: &'static str, rule: &str, prompt: &str, start: usize, end: usize) -> Self {
+        Self {
+            part,
+            rule: rule.to_string(),
+            text: prompt[start..end].to_string(),
+            start,
+            end,
+        }
+    }
+}
+
+/// Body of `POST /query`, the typed alternative to a prompt:
+///
+/// ```json
+/// {
+///   "metric": "cpu_usage",
+///   "labels": "env=production,region=~\"eu-.*\"",
+///   "values": [{ "op": "gt", "value": 50 }],
+///   "start": "2024-01-01T00:00:00Z",
+///   "aggregation": { "percentile": 99 },
+///   "by": ["host"],
+///   "order": { "by": "value", "direction": "desc" },
+///   "limit": 5
+/// }
+/// ```
+///
+/// With a `step`, results are bucketed as by `GET /query/range`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructuredQuery {
+    pub metric: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub labels: Option<LabelSelector>,
+    #[serde(default)]
+    pub values: Vec<ValuePredicate>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub step: Option<Step>,
+    #[serde(default)]
+    pub fill: GapFill,
+    pub aggregation: Option<AggregationType>,
+    pub by: Option<Vec<String>>,
+    pub without: Option<Vec<String>>,
+    pub order: Option<QueryOrder>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("end must not be before start")]
+    EndBeforeStart,
+    #[error("percentile must be between 0 and 100")]
+    Percentile,
+    #[error("{0:?} cannot be computed per step")]
+    StepAggregation(AggregationType),
+    #[error(transparent)]
+    Grouping(#[from] GroupingError),
+}
+
+impl TryFrom<StructuredQuery> for ParsedQuery {
+    type Error = QueryError;
+
+    fn try_from(query: StructuredQuery) -> Result<Self, Self::Error> {
+        if let (Some(start), Some(end)) = (query.start, query.end) {
+            if end < start {
+                return Err(QueryError::EndBeforeStart);
+            }
+        }
+
+        if let Some(AggregationType::Percentile(p)) = query.aggregation {
+            if !(0.0..=100.0).contains(&p) {
+                return Err(QueryError::Percentile);
+            }
+        }
+
+        if let (Some(_), Some(aggregation)) = (query.step, query.aggregation) {
+            if aggregation.range_function().is_none() {
+                return Err(QueryError::StepAggregation(aggregation));
+            }
+        }
+
+        let grouping = match (query.by, query.without) {
+            (Some(_), Some(_)) => return Err(GroupingError::Conflict.into()),
+            (Some(by), None) => Grouping::By(label_list(by.iter().map(String::as_str))?),
+            (None, Some(without)) => {
+                Grouping::Without(label_list(without.iter().map(String::as_str))?)
+            }
+            (None, None) => Grouping::Name,
+        };
+
+        let time_range = (query.start.is_some() || query.end.is_some()).then_some(TimeRange {
+            start: query.start,
+            end: query.end,
+        });
+
+        Ok(ParsedQuery {
+            metric_name: query.metric,
+            tags: query.tags,
+            labels: query.labels.filter(|selector| !selector.is_empty()),
+            values: query.values,
+            time_range,
+            step: query.step,
+            fill: query.fill,
+            aggregation: query.aggregation,
+            grouping,
+            order: query.order,
+            limit: query.limit.map(|limit| limit as i64),
+            cache_bucket: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TimeRange {
-    pub start: Option<chrono::DateTime<chrono::Utc>>,
-    pub end: Option<chrono::DateTime<chrono::Utc>>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AggregationType {
     Top(usize),
+    #[serde(alias = "avg")]
     Average,
     Sum,
     Count,
+    Min,
+    Max,
+    /// Population standard deviation.
+    #[serde(alias = "stddev")]
+    StdDev,
+    Median,
+    /// A percentile between 0 and 100, e.g. 99 for p99.
+    Percentile(f64),
+    #[serde(untagged)]
+    Rate(RateFunction),
+}
+
+impl AggregationType {
+    /// The quantile between 0 and 1 for order statistics, which need the values
+    /// themselves rather than running totals.
+    pub fn quantile(self) -> Option<f64> {
+        match self {
+            AggregationType::Median => Some(0.5),
+            AggregationType::Percentile(p) => Some(p / 100.0),
+            _ => None,
+        }
+    }
+
+    /// The function reducing each bucket when the query has a step, if the
+    /// aggregation can be computed per bucket.
+    pub fn range_function(self) -> Option<RangeFunction> {
+        match self {
+            AggregationType::Average => Some(RangeFunction::Avg),
+            AggregationType::Sum => Some(RangeFunction::Sum),
+            AggregationType::Count => Some(RangeFunction::Count),
+            AggregationType::Min => Some(RangeFunction::Min),
+            AggregationType::Max => Some(RangeFunction::Max),
+            AggregationType::Rate(function) => Some(RangeFunction::Rate(function)),
+            _ => None,
+        }
+    }
+}
+
+/// Running totals for one series, as produced by `MetricStore::summarize`. A
+/// series is a metric name plus the labels kept by the grouping.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesSummary {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub sum: f64,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    /// Running mean and sum of squared deviations from it, for the standard
+    /// deviation. Unlike a sum of squares these stay accurate for large values
+    /// with a small spread.
+    pub mean: f64,
+    pub m2: f64,
+}
+
+impl SeriesSummary {
+    pub fn new(name: String, labels: Labels) -> Self {
+        Self {
+            name,
+            labels,
+            sum: 0.0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        // Welford's update, as in `RateAccumulator`
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn merge(&mut self, other: &SeriesSummary) {
+        let count = self.count + other.count;
+        if count > 0 {
+            // Chan et al.'s pairwise combination of means and squared deviations
+            let delta = other.mean - self.mean;
+            let weight = other.count as f64 / count as f64;
+            self.m2 += other.m2 + delta * delta * self.count as f64 * weight;
+            self.mean += delta * weight;
+        }
+
+        self.sum += other.sum;
+        self.count = count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn value(&self, aggregation: AggregationType) -> Option<f64> {
+        let count = self.count as f64;
+        match aggregation {
+            AggregationType::Sum => Some(self.sum),
+            AggregationType::Count => Some(count),
+            _ if self.count == 0 => None,
+            AggregationType::Average => Some(self.sum / count),
+            AggregationType::Min => Some(self.min),
+            AggregationType::Max => Some(self.max),
+            AggregationType::StdDev => Some((self.m2 / count).sqrt()),
+            _ => None,
+        }
+    }
+}
+
+/// The values of one series, as produced by `MetricStore::distributions`.
+#[derive(Debug, Clone)]
+pub struct SeriesDistribution {
+    pub name: String,
+    pub labels: Labels,
+    pub distribution: Distribution,
+    /// The merged buckets of the series' histogram metrics, whose quantiles are
+    /// taken from these rather than from their values.
+    pub histogram: Option<Histogram>,
+}
+
+impl SeriesDistribution {
+    fn quantile(&self, q: f64) -> Option<f64> {
+        match &self.histogram {
+            Some(histogram) => histogram.quantile(q),
+            None => self.distribution.quantile(q),
+        }
+    }
+
+    fn count(&self) -> u64 {
+        match &self.histogram {
+            Some(histogram) => histogram.count,
+            None => self.distribution.count(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeriesValue {
+    pub name: String,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub value: Option<f64>,
+    pub points: u64,
+}
+
+/// Result of reducing matching metrics with an `AggregationType`.
+///
+/// `value` aggregates every matching point, `series` breaks it down per metric
+/// name and grouping labels. The overall average is weighted by point count, not
+/// an average of averages.
+#[derive(Debug, Serialize)]
+pub struct AggregationResult {
+    pub aggregation: AggregationType,
+    pub value: Option<f64>,
+    pub points: u64,
+    pub series: Vec<SeriesValue>,
+}
+
+impl AggregationResult {
+    pub fn from_summaries(aggregation: AggregationType, summaries: Vec<SeriesSummary>) -> Self {
+        let mut total = SeriesSummary::new(String::new(), Labels::new());
+        summaries.iter().for_each(|summary| total.merge(summary));
+
+        let series = summaries
+            .iter()
+            .map(|summary| SeriesValue {
+                name: summary.name.clone(),
+                labels: summary.labels.clone(),
+                value: summary.value(aggregation),
+                points: summary.count,
+            })
+            .collect();
+
+        AggregationResult {
+            aggregation,
+            value: total.value(aggregation),
+            points: total.count,
+            series,
+        }
+    }
+
+    /// Like `from_summaries`, for quantile aggregations. The overall value is the
+    /// quantile of all series' values together, or of their merged buckets when
+    /// they are histograms; `None` when those buckets have different bounds.
+    pub fn from_distributions(
+        aggregation: AggregationType,
+        distributions: Vec<SeriesDistribution>,
+    ) -> Self {
+        let q = aggregation.quantile().unwrap_or(f64::NAN);
+        let mut total = SeriesDistribution {
+            name: String::new(),
+            labels: Labels::new(),
+            distribution: Distribution::new(),
+            histogram: None,
+        };
+        let mut mergeable = true;
+
+        let series = distributions
+            .iter()
+            .map(|series| {
+                total.distribution.merge(&series.distribution);
+                if let Some(histogram) = &series.histogram {
+                    match &mut total.histogram {
+                        Some(total) => mergeable &= total.merge(histogram).is_ok(),
+                        None => total.histogram = Some(histogram.clone()),
+                    }
+                }
+
+                SeriesValue {
+                    name: series.name.clone(),
+                    labels: series.labels.clone(),
+                    value: series.quantile(q),
+                    points: series.count(),
+                }
+            })
+            .collect();
+
+        AggregationResult {
+            aggregation,
+            value: total.quantile(q).filter(|_| mergeable),
+            points: total.count(),
+            series,
+        }
+    }
+}
+
+impl AggregationResult {
+    /// Like `from_summaries`, for rate functions over the whole time range, from
+    /// `MetricStore::rates`. The overall value sums the series, e.g. the total
+    /// request rate.
+    pub fn from_rates(function: RateFunction, series: Vec<SeriesValue>) -> Self {
+        let values: Vec<f64> = series.iter().filter_map(|series| series.value).collect();
+
+        AggregationResult {
+            aggregation: AggregationType::Rate(function),
+            value: (!values.is_empty()).then(|| values.iter().sum()),
+            points: series.iter().map(|series| series.points).sum(),
+            series,
+        }
+    }
+}
+
+impl AggregationResult {
+    /// Sorts the series by value, missing values last, or by name and labels.
+    /// Series have no timestamp, so sorting by it keeps their order.
+    pub fn sort_series(&mut self, order: QueryOrder) {
+        match order.by {
+            SortField::Value => self.series.sort_by(|a, b| match (a.value, b.value) {
+                (Some(a), Some(b)) => order.direction.apply(a.total_cmp(&b)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }),
+            SortField::Name => self.series.sort_by(|a, b| {
+                order
+                    .direction
+                    .apply((&a.name, &a.labels).cmp(&(&b.name, &b.labels)))
+            }),
+            SortField::Timestamp => {}
+        }
+    }
+}
+
+/// Response body of `/query`: raw metrics, a reduced value when the query
+/// asked for an aggregation other than `top N`, or buckets when it has a step.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum QueryResult {
+    Metrics(Vec<Metric>),
+    Aggregation(AggregationResult),
+    Range(RangeResult),
 }
@@ -0,0 +1,410 @@
+use crate::models::{
+    Grouping, GroupingError, Labels, MetricFilter, RateAccumulator, RateFunction, Sample,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Most buckets a single range query may produce per series.
+pub const MAX_RANGE_BUCKETS: i64 = 11_000;
+
+/// Buckets are aligned to multiples of the step counted from here, the reference
+/// MongoDB's `$dateTrunc` uses for `binSize`, so both backends agree.
+const BUCKET_ORIGIN_MS: i64 = 946_684_800_000;
+
+/// Width of a range query bucket, e.g. `30s`, `5m`, `1h`, `1d` or plain seconds.
+/// Serializes as seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Step(u32);
+
+impl Step {
+    pub fn from_secs(secs: u32) -> Option<Self> {
+        (secs > 0).then_some(Self(secs))
+    }
+
+    pub fn secs(self) -> u32 {
+        self.0
+    }
+
+    pub fn millis(self) -> i64 {
+        self.0 as i64 * 1000
+    }
+
+    /// Start of the bucket containing `millis`.
+    pub fn bucket_start(self, millis: i64) -> i64 {
+        BUCKET_ORIGIN_MS + (millis - BUCKET_ORIGIN_MS).div_euclid(self.millis()) * self.millis()
+    }
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let multiplier = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(format!("invalid step {s:?}: unknown unit {unit:?}")),
+        };
+
+        number
+            .parse::<u32>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .and_then(Step::from_secs)
+            .ok_or_else(|| format!("invalid step {s:?}: expected a positive duration like 5m"))
+    }
+}
+
+impl TryFrom<String> for Step {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// How the points of one series are combined within a bucket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangeFunction {
+    #[default]
+    #[serde(alias = "average")]
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    /// `rate`, `irate`, `increase`, `delta` or `derivative`, see `RateBuckets`.
+    #[serde(untagged)]
+    Rate(RateFunction),
+}
+
+impl fmt::Display for RangeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RangeFunction::Avg => "avg",
+            RangeFunction::Sum => "sum",
+            RangeFunction::Min => "min",
+            RangeFunction::Max => "max",
+            RangeFunction::Count => "count",
+            RangeFunction::Rate(function) => return function.fmt(f),
+        };
+        f.write_str(name)
+    }
+}
+
+/// What to report for buckets without any points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapFill {
+    /// Leave empty buckets out.
+    #[default]
+    None,
+    Null,
+    /// Repeat the last value before the gap.
+    Previous,
+    /// Interpolate between the values either side of the gap.
+    Linear,
+    Zero,
+}
+
+/// Query parameters of `GET /query/range` besides the metric filter.
+#[derive(Debug, Deserialize)]
+pub struct RangeParams {
+    pub step: Step,
+    #[serde(default)]
+    pub aggregation: RangeFunction,
+    #[serde(default)]
+    pub fill: GapFill,
+    /// Comma-separated labels to split series by, or to aggregate away.
+    pub by: Option<String>,
+    pub without: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RangeError {
+    #[error("start_date is required and must be RFC 3339")]
+    MissingStart,
+    #[error("end_date must not be before start_date")]
+    EndBeforeStart,
+    #[error("range spans {buckets} buckets, more than the maximum of {MAX_RANGE_BUCKETS}; use a larger step")]
+    TooManyBuckets { buckets: i64 },
+    #[error(transparent)]
+    Grouping(#[from] GroupingError),
+}
+
+/// A validated range query: the metrics `filter` selects, reduced with
+/// `function` into buckets `step` wide from `start` to `end`.
+#[derive(Debug, Clone)]
+pub struct RangeQuery {
+    pub filter: MetricFilter,
+    pub step: Step,
+    pub function: RangeFunction,
+    pub fill: GapFill,
+    pub grouping: Grouping,
+    /// Start of the first and last bucket, epoch milliseconds.
+    pub first_bucket: i64,
+    pub last_bucket: i64,
+}
+
+impl RangeQuery {
+    /// Without an end the range runs to the end of the current bucket, so
+    /// repeated dashboard queries select the same window and share a cache entry.
+    pub fn new(filter: MetricFilter, params: RangeParams) -> Result<Self, RangeError> {
+        let grouping = Grouping::from_params(params.by.as_deref(), params.without.as_deref())?;
+        Self::grouped(
+            filter,
+            params.step,
+            params.aggregation,
+            params.fill,
+            grouping,
+        )
+    }
+
+    /// Like `new`, with the grouping already built.
+    pub fn grouped(
+        mut filter: MetricFilter,
+        step: Step,
+        function: RangeFunction,
+        fill: GapFill,
+        grouping: Grouping,
+    ) -> Result<Self, RangeError> {
+        let (start, end) = filter.time_bounds();
+        let start = start.ok_or(RangeError::MissingStart)?.timestamp_millis();
+
+        let end = match end {
+            Some(end) => end.timestamp_millis(),
+            None => {
+                let end = step.bucket_start(Utc::now().timestamp_millis()) + step.millis() - 1;
+                filter.end_date = Some(to_utc(end).to_rfc3339());
+                end
+            }
+        };
+
+        if end < start {
+            return Err(RangeError::EndBeforeStart);
+        }
+
+        let first_bucket = step.bucket_start(start);
+        let last_bucket = step.bucket_start(end);
+        let buckets = (last_bucket - first_bucket) / step.millis() + 1;
+        if buckets > MAX_RANGE_BUCKETS {
+            return Err(RangeError::TooManyBuckets { buckets });
+        }
+
+        Ok(Self {
+            filter: filter.unpaged(),
+            step,
+            function,
+            fill,
+            grouping,
+            first_bucket,
+            last_bucket,
+        })
+    }
+}
+
+/// The reduced value of one series in one bucket, as produced by
+/// `MetricStore::aggregate_range`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketValue {
+    pub name: String,
+    #[serde(default)]
+    pub labels: Labels,
+    /// Bucket start, epoch milliseconds.
+    pub start: i64,
+    pub value: f64,
+}
+
+/// Running aggregate of the points in a bucket, for stores that reduce in process.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketAccumulator {
+    sum: f64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for BucketAccumulator {
+    fn default() -> Self {
+        Self {
+            sum: 0.0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl BucketAccumulator {
+    pub fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// `None` for rate functions, which need the samples in time order.
+    pub fn value(&self, function: RangeFunction) -> Option<f64> {
+        match function {
+            RangeFunction::Avg => Some(self.sum / self.count as f64),
+            RangeFunction::Sum => Some(self.sum),
+            RangeFunction::Min => Some(self.min),
+            RangeFunction::Max => Some(self.max),
+            RangeFunction::Count => Some(self.count as f64),
+            RangeFunction::Rate(_) => None,
+        }
+    }
+}
+
+/// Applies `function` to one series' samples, fed in time order, in each bucket
+/// of `step`. A bucket's window also takes the last sample before it, so a
+/// change between two buckets lands in the later one and increases add up
+/// across buckets. Buckets the function gives no value for are omitted.
+#[derive(Debug, Clone)]
+pub struct RateBuckets {
+    step: Step,
+    function: RateFunction,
+    start: Option<i64>,
+    window: RateAccumulator,
+}
+
+impl RateBuckets {
+    pub fn new(step: Step, function: RateFunction) -> Self {
+        Self {
+            step,
+            function,
+            start: None,
+            window: RateAccumulator::default(),
+        }
+    }
+
+    /// Adds the next sample. Returns the start and value of the previous
+    /// bucket when the sample is the first past it.
+    pub fn add(&mut self, sample: Sample) -> Option<(i64, f64)> {
+        let start = self.step.bucket_start(sample.0);
+        let mut finished = None;
+
+        if self.start != Some(start) {
+            finished = self.finish();
+            let mut window = RateAccumulator::default();
+            if let Some(last) = self.window.last() {
+                window.add(last);
+            }
+            self.window = window;
+            self.start = Some(start);
+        }
+
+        self.window.add(sample);
+        finished
+    }
+
+    /// The start and value of the bucket the latest sample fell in.
+    pub fn finish(&self) -> Option<(i64, f64)> {
+        Some((self.start?, self.window.value(self.function)?))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangePoint {
+    pub timestamp: DateTime<Utc>,
+    pub value: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeSeries {
+    pub name: String,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub points: Vec<RangePoint>,
+}
+
+/// Response body of `GET /query/range`: one series per metric name and grouping
+/// labels, each point labelled with the start of its bucket.
+#[derive(Debug, Serialize)]
+pub struct RangeResult {
+    pub aggregation: RangeFunction,
+    /// Bucket width in seconds.
+    pub step: u32,
+    pub fill: GapFill,
+    pub series: Vec<RangeSeries>,
+}
+
+impl RangeResult {
+    pub fn from_buckets(query: &RangeQuery, buckets: Vec<BucketValue>) -> Self {
+        let mut by_series: BTreeMap<(String, Labels), BTreeMap<i64, f64>> = BTreeMap::new();
+        for bucket in buckets {
+            by_series
+                .entry((bucket.name, bucket.labels))
+                .or_default()
+                .insert(bucket.start, bucket.value);
+        }
+
+        let series = by_series
+            .into_iter()
+            .map(|((name, labels), values)| RangeSeries {
+                name,
+                labels,
+                points: fill_gaps(query, &values),
+            })
+            .collect();
+
+        RangeResult {
+            aggregation: query.function,
+            step: query.step.secs(),
+            fill: query.fill,
+            series,
+        }
+    }
+}
+
+fn fill_gaps(query: &RangeQuery, values: &BTreeMap<i64, f64>) -> Vec<RangePoint> {
+    let point = |start: i64, value: Option<f64>| RangePoint {
+        timestamp: to_utc(start),
+        value,
+    };
+
+    if query.fill == GapFill::None {
+        return values
+            .iter()
+            .map(|(&start, &value)| point(start, Some(value)))
+            .collect();
+    }
+
+    let starts = (query.first_bucket..=query.last_bucket).step_by(query.step.millis() as usize);
+
+    starts
+        .map(|start| {
+            let value = values.get(&start).copied().or_else(|| {
+                let before = values.range(..start).next_back();
+                match query.fill {
+                    GapFill::None | GapFill::Null => None,
+                    GapFill::Zero => Some(0.0),
+                    GapFill::Previous => before.map(|(_, &value)| value),
+                    GapFill::Linear => {
+                        let (&t0, &v0) = before?;
+                        let (&t1, &v1) = values.range(start..).next()?;
+                        Some(v0 + (v1 - v0) * (start - t0) as f64 / (t1 - t0) as f64)
+                    }
+                }
+            });
+            point(start, value)
+        })
+        .collect()
+}
+
+fn to_utc(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .unwrap_or_default()
+}
@@ -0,0 +1,124 @@
+use crate::models::counter_increase;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A point of one series: epoch milliseconds and value.
+pub type Sample = (i64, f64);
+
+/// Functions of how a series changes over time rather than of its values.
+///
+/// All of them take samples in time order and divide by the actual time between
+/// samples, so irregular spacing does not skew the result. `rate`, `irate` and
+/// `increase` are for counters and treat a drop as a reset to zero, like
+/// `counter_increase`; `delta` and `derivative` are for gauges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateFunction {
+    /// Per-second increase between the first and last sample.
+    Rate,
+    /// Per-second increase between the last two samples, for fast-moving counters.
+    Irate,
+    /// Total increase, allowing for resets.
+    Increase,
+    /// Last value minus first value.
+    Delta,
+    /// Per-second slope of a least-squares fit through the samples.
+    Derivative,
+}
+
+impl RateFunction {
+    /// Applies the function to `samples` sorted by time. `None` for fewer than
+    /// two samples, or when they share one timestamp and a per-second value is
+    /// asked for.
+    pub fn apply(self, samples: &[Sample]) -> Option<f64> {
+        let mut accumulator = RateAccumulator::default();
+        for &sample in samples {
+            accumulator.add(sample);
+        }
+        accumulator.value(self)
+    }
+}
+
+/// `RateFunction::apply` as a fold over samples in time order, keeping what the
+/// functions need instead of the samples themselves.
+#[derive(Debug, Clone, Default)]
+pub struct RateAccumulator {
+    count: u64,
+    first: Option<Sample>,
+    previous: Option<Sample>,
+    last: Option<Sample>,
+    increase: f64,
+    /// Running means and co-moments for the least-squares fit, with time in
+    /// seconds since the first sample.
+    mean_t: f64,
+    mean_v: f64,
+    covariance: f64,
+    variance: f64,
+}
+
+impl RateAccumulator {
+    /// Adds the next sample. Samples must come in time order.
+    pub fn add(&mut self, sample: Sample) {
+        let (ms, value) = sample;
+        let first_ms = self.first.get_or_insert(sample).0;
+        if let Some((_, last)) = self.last {
+            self.increase += counter_increase([last, value]);
+        }
+        self.previous = self.last.replace(sample);
+        self.count += 1;
+
+        // Welford's update, which stays accurate over long series
+        let t = (ms - first_ms) as f64 / 1000.0;
+        let n = self.count as f64;
+        let dt = t - self.mean_t;
+        self.mean_t += dt / n;
+        self.mean_v += (value - self.mean_v) / n;
+        self.covariance += dt * (value - self.mean_v);
+        self.variance += dt * (t - self.mean_t);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The latest sample added.
+    pub fn last(&self) -> Option<Sample> {
+        self.last
+    }
+
+    /// What `function` gives for the samples added so far, see `RateFunction::apply`.
+    pub fn value(&self, function: RateFunction) -> Option<f64> {
+        // A previous sample means there are at least two
+        let ((first_ms, first), (previous_ms, previous), (last_ms, last)) =
+            (self.first?, self.previous?, self.last?);
+
+        let per_second = |value: f64, from_ms: i64, to_ms: i64| {
+            (to_ms > from_ms).then(|| value / ((to_ms - from_ms) as f64 / 1000.0))
+        };
+
+        match function {
+            RateFunction::Increase => Some(self.increase),
+            RateFunction::Rate => per_second(self.increase, first_ms, last_ms),
+            RateFunction::Irate => {
+                per_second(counter_increase([previous, last]), previous_ms, last_ms)
+            }
+            RateFunction::Delta => Some(last - first),
+            RateFunction::Derivative => {
+                (self.variance > 0.0).then(|| self.covariance / self.variance)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RateFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RateFunction::Rate => "rate",
+            RateFunction::Irate => "irate",
+            RateFunction::Increase => "increase",
+            RateFunction::Delta => "delta",
+            RateFunction::Derivative => "derivative",
+        };
+        f.write_str(name)
+    }
+}
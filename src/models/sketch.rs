@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+/// Relative error bound of `DDSketch` quantiles.
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Magnitudes below this are counted as zero, which bounds the number of bins.
+const MIN_INDEXABLE: f64 = 1e-9;
+
+/// A `Distribution` keeps at most this many values before switching to a sketch.
+pub const EXACT_QUANTILE_POINTS: usize = 10_000;
+
+/// Quantile sketch with relative error guarantees (DDSketch, Masson et al. 2019).
+///
+/// Values are counted in logarithmically sized bins, so memory grows with the
+/// range of magnitudes rather than with the number of values, and any quantile
+/// is within `RELATIVE_ACCURACY` of an actual value. Sketches built separately,
+/// e.g. per series, merge into the sketch of their union.
+#[derive(Debug, Clone)]
+pub struct DDSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for DDSketch {
+    fn default() -> Self {
+        let gamma = (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY);
+        Self {
+            gamma,
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl DDSketch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        if value > MIN_INDEXABLE {
+            *self.positive.entry(self.key(value)).or_default() += 1;
+        } else if value < -MIN_INDEXABLE {
+            *self.negative.entry(self.key(-value)).or_default() += 1;
+        } else {
+            self.zero += 1;
+        }
+
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &DDSketch) {
+        for (&key, &count) in &other.positive {
+            *self.positive.entry(key).or_default() += count;
+        }
+        for (&key, &count) in &other.negative {
+            *self.negative.entry(key).or_default() += count;
+        }
+
+        self.zero += other.zero;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Estimates the `q` quantile, `None` when empty or `q` is outside [0, 1].
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+
+        // The extremes are tracked exactly
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        if rank == 0 {
+            return Some(self.min);
+        }
+        if rank == self.count - 1 {
+            return Some(self.max);
+        }
+
+        let mut seen = 0;
+
+        // Ascending order: most negative first, then zero, then positive
+        let bins = self
+            .negative
+            .iter()
+            .rev()
+            .map(|(&key, &count)| (-self.value(key), count))
+            .chain(std::iter::once((0.0, self.zero)))
+            .chain(
+                self.positive
+                    .iter()
+                    .map(|(&key, &count)| (self.value(key), count)),
+            );
+
+        for (value, count) in bins {
+            seen += count;
+            if seen > rank {
+                return Some(value.clamp(self.min, self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    fn key(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// The value a bin reports, within `RELATIVE_ACCURACY` of everything in it.
+    fn value(&self, key: i32) -> f64 {
+        2.0 * self.gamma.powi(key) / (self.gamma + 1.0)
+    }
+}
+
+/// The values of one series, kept exactly up to `EXACT_QUANTILE_POINTS` and
+/// summarized in a `DDSketch` once there are more.
+#[derive(Debug, Clone)]
+pub enum Distribution {
+    Exact(Vec<f64>),
+    Sketch(DDSketch),
+}
+
+impl Default for Distribution {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Distribution {
+    pub fn new() -> Self {
+        Distribution::Exact(Vec::new())
+    }
+
+    pub fn count(&self) -> u64 {
+        match self {
+            Distribution::Exact(values) => values.len() as u64,
+            Distribution::Sketch(sketch) => sketch.count(),
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        match self {
+            Distribution::Exact(values) => values.push(value),
+            Distribution::Sketch(sketch) => sketch.add(value),
+        }
+        self.limit();
+    }
+
+    /// Switches to a sketch once the exact values pass `EXACT_QUANTILE_POINTS`.
+    fn limit(&mut self) {
+        if let Distribution::Exact(values) = self {
+            if values.len() > EXACT_QUANTILE_POINTS {
+                let mut sketch = DDSketch::new();
+                values.iter().for_each(|&value| sketch.add(value));
+                *self = Distribution::Sketch(sketch);
+            }
+        }
+    }
+
+    /// Merging anything with a sketch yields a sketch.
+    pub fn merge(&mut self, other: &Distribution) {
+        match (&mut *self, other) {
+            (Distribution::Exact(values), Distribution::Exact(others)) => {
+                values.extend_from_slice(others);
+                self.limit();
+            }
+            (Distribution::Sketch(sketch), Distribution::Exact(others)) => {
+                others.iter().for_each(|&value| sketch.add(value))
+            }
+            (Distribution::Sketch(sketch), Distribution::Sketch(other)) => sketch.merge(other),
+            (Distribution::Exact(values), Distribution::Sketch(other)) => {
+                let mut sketch = other.clone();
+                values.iter().for_each(|&value| sketch.add(value));
+                *self = Distribution::Sketch(sketch);
+            }
+        }
+    }
+
+    /// The `q` quantile. Exact distributions interpolate linearly between the
+    /// two closest ranks.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        match self {
+            Distribution::Sketch(sketch) => sketch.quantile(q),
+            Distribution::Exact(values) => {
+                if values.is_empty() || !(0.0..=1.0).contains(&q) {
+                    return None;
+                }
+
+                let mut sorted = values.clone();
+                sorted.sort_by(f64::total_cmp);
+
+                let rank = q * (sorted.len() - 1) as f64;
+                let lower = sorted[rank.floor() as usize];
+                let upper = sorted[rank.ceil() as usize];
+                Some(lower + (upper - lower) * rank.fract())
+            }
+        }
+    }
+}
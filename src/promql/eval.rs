@@ -0,0 +1,541 @@
+use crate::models::{bucket_quantile, BucketAccumulator, Grouping, Labels, RangeFunction, Sample};
+use crate::promql::{BinaryExpr, BinaryOp, Expr, Function, PromqlError, Selector, NAME_LABEL};
+use crate::services::TelemetryService;
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How far back an instant selector looks for a series' latest sample.
+pub const LOOKBACK_MS: i64 = 5 * 60 * 1000;
+
+/// Most evaluation steps a range query may take, as in Prometheus.
+pub const MAX_STEPS: i64 = 11_000;
+
+/// Most samples a query may load across all its selectors, bounding its memory.
+pub const MAX_SAMPLES: usize = 5_000_000;
+
+/// A series with its labels, the metric name under `__name__`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    pub samples: Vec<Sample>,
+}
+
+/// One series' value at the evaluation time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub labels: Labels,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<Element>),
+    Matrix(Vec<Series>),
+}
+
+/// The series each selector of an expression selects, keyed by the selector's
+/// `Display` form and loaded once for every evaluation step. Samples are in
+/// time order.
+type Data = HashMap<String, Vec<Series>>;
+
+/// Evaluates PromQL expressions against the metrics of a `TelemetryService`.
+///
+/// Every selector is streamed from the store once for the whole query, past the
+/// cache, then each step is evaluated in memory. Rates divide by the time
+/// between the samples in the window rather than extrapolating to its edges as
+/// Prometheus does.
+pub struct Engine<'a> {
+    service: &'a TelemetryService,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(service: &'a TelemetryService) -> Self {
+        Self { service }
+    }
+
+    /// Evaluates `expr` at `time`, epoch milliseconds.
+    pub async fn instant(&self, expr: &Expr, time: i64) -> Result<Value, PromqlError> {
+        let data = self.load(expr, time, time).await?;
+        evaluate(expr, time, &data)
+    }
+
+    /// Evaluates `expr` every `step` milliseconds from `start` to `end`. The
+    /// expression must give a scalar or an instant vector.
+    pub async fn range(
+        &self,
+        expr: &Expr,
+        start: i64,
+        end: i64,
+        step: i64,
+    ) -> Result<Vec<Series>, PromqlError> {
+        if step <= 0 {
+            return Err(PromqlError::Limit("step must be positive".to_string()));
+        }
+        if end < start {
+            return Err(PromqlError::Limit(
+                "end must not be before start".to_string(),
+            ));
+        }
+        let steps = end
+            .checked_sub(start)
+            .map_or(i64::MAX, |span| span / step + 1);
+        if steps > MAX_STEPS {
+            return Err(PromqlError::Limit(format!(
+                "range spans {steps} steps, more than the maximum of {MAX_STEPS}; use a larger step"
+            )));
+        }
+
+        let data = self.load(expr, start, end).await?;
+        let mut series: BTreeMap<Labels, Vec<Sample>> = BTreeMap::new();
+
+        for time in (start..=end).step_by(step as usize) {
+            match evaluate(expr, time, &data)? {
+                Value::Scalar(value) => {
+                    series.entry(Labels::new()).or_default().push((time, value))
+                }
+                Value::Vector(elements) => {
+                    for element in elements {
+                        series
+                            .entry(element.labels)
+                            .or_default()
+                            .push((time, element.value));
+                    }
+                }
+                Value::Matrix(_) => {
+                    return Err(PromqlError::Type(
+                        "range queries need a scalar or instant vector expression".to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(series
+            .into_iter()
+            .map(|(labels, samples)| Series { labels, samples })
+            .collect())
+    }
+
+    /// Series matching `selector` with their samples between `start` and `end`,
+    /// in time order. `loaded` counts samples across the query; the query fails
+    /// once it passes `MAX_SAMPLES`.
+    async fn select(
+        &self,
+        selector: &Selector,
+        start: Option<i64>,
+        end: Option<i64>,
+        loaded: &mut usize,
+    ) -> Result<Vec<Series>, PromqlError> {
+        let mut metrics = self
+            .service
+            .stream_metrics(selector.filter(start, end).time_ordered())
+            .await
+            .map_err(|e| PromqlError::Storage(e.to_string()))?;
+
+        let mut series: BTreeMap<(String, Labels), Vec<Sample>> = BTreeMap::new();
+        while let Some(metric) = metrics.next().await {
+            let metric = metric.map_err(|e| PromqlError::Storage(e.to_string()))?;
+            if !selector.matches(&metric.name, &metric.labels) {
+                continue;
+            }
+
+            *loaded += 1;
+            if *loaded > MAX_SAMPLES {
+                return Err(PromqlError::Limit(format!(
+                    "query loads more than {MAX_SAMPLES} samples; narrow the selectors or the time range"
+                )));
+            }
+            series
+                .entry((metric.name, metric.labels))
+                .or_default()
+                .push((metric.timestamp.timestamp_millis(), metric.value));
+        }
+
+        Ok(series
+            .into_iter()
+            .map(|((name, mut labels), samples)| {
+                labels.insert(NAME_LABEL.to_string(), name);
+                Series { labels, samples }
+            })
+            .collect())
+    }
+
+    async fn load(&self, expr: &Expr, start: i64, end: i64) -> Result<Data, PromqlError> {
+        let mut selectors = Vec::new();
+        collect_selectors(expr, &mut selectors);
+
+        let mut loaded = 0;
+        let mut data = Data::new();
+        for selector in selectors {
+            let key = selector.to_string();
+            if data.contains_key(&key) {
+                continue;
+            }
+
+            // A window reaching before any representable time has no lower bound
+            let window = selector.range.unwrap_or(LOOKBACK_MS);
+            let series = self
+                .select(selector, start.checked_sub(window), Some(end), &mut loaded)
+                .await?;
+            data.insert(key, series);
+        }
+
+        Ok(data)
+    }
+}
+
+fn collect_selectors<'e>(expr: &'e Expr, selectors: &mut Vec<&'e Selector>) {
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Selector(selector) => selectors.push(selector),
+        Expr::Call(_, args) => args
+            .iter()
+            .for_each(|arg| collect_selectors(arg, selectors)),
+        Expr::Aggregate(_, _, expr) | Expr::Negate(expr) => collect_selectors(expr, selectors),
+        Expr::Binary(binary) => {
+            collect_selectors(&binary.lhs, selectors);
+            collect_selectors(&binary.rhs, selectors);
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, time: i64, data: &Data) -> Result<Value, PromqlError> {
+    match expr {
+        Expr::Number(n) => Ok(Value::Scalar(*n)),
+        Expr::Selector(selector) => Ok(select_at(selector, time, data)),
+        Expr::Negate(expr) => match evaluate(expr, time, data)? {
+            Value::Scalar(value) => Ok(Value::Scalar(-value)),
+            value => Ok(Value::Vector(map_values(vector(value, "-")?, |v| -v))),
+        },
+        Expr::Call(function, args) => call(*function, args, time, data),
+        Expr::Aggregate(function, grouping, expr) => {
+            let elements = vector(evaluate(expr, time, data)?, "aggregation")?;
+            Ok(Value::Vector(aggregate(*function, grouping, elements)))
+        }
+        Expr::Binary(binary) => binary_op(binary, time, data),
+    }
+}
+
+/// An instant selector takes each series' latest sample within `LOOKBACK_MS`,
+/// a range selector every sample in its window.
+fn select_at(selector: &Selector, time: i64, data: &Data) -> Value {
+    let series = data
+        .get(&selector.to_string())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    // Samples are in time order, so a window is found by binary search
+    let window = |samples: &[Sample], range: i64| -> std::ops::Range<usize> {
+        let start = time.saturating_sub(range);
+        samples.partition_point(|&(ms, _)| ms <= start)
+            ..samples.partition_point(|&(ms, _)| ms <= time)
+    };
+
+    match selector.range {
+        Some(range) => Value::Matrix(
+            series
+                .iter()
+                .map(|series| Series {
+                    labels: series.labels.clone(),
+                    samples: series.samples[window(&series.samples, range)].to_vec(),
+                })
+                .filter(|series| !series.samples.is_empty())
+                .collect(),
+        ),
+        None => Value::Vector(
+            series
+                .iter()
+                .filter_map(|series| {
+                    let &(_, value) =
+                        series.samples[window(&series.samples, LOOKBACK_MS)].last()?;
+                    Some(Element {
+                        labels: series.labels.clone(),
+                        value,
+                    })
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn call(function: Function, args: &[Expr], time: i64, data: &Data) -> Result<Value, PromqlError> {
+    let arg = |index: usize| evaluate(&args[index], time, data);
+
+    let elements = match function {
+        Function::Time => return Ok(Value::Scalar(time as f64 / 1000.0)),
+        Function::Rate(rate) => matrix(arg(0)?, "rate functions")?
+            .into_iter()
+            .filter_map(|series| {
+                Some(Element {
+                    value: rate.apply(&series.samples)?,
+                    labels: without_name(series.labels),
+                })
+            })
+            .collect(),
+        Function::OverTime(reduce) => matrix(arg(0)?, "*_over_time functions")?
+            .into_iter()
+            .filter_map(|series| {
+                let mut accumulator = BucketAccumulator::default();
+                series
+                    .samples
+                    .iter()
+                    .for_each(|&(_, value)| accumulator.add(value));
+                Some(Element {
+                    value: accumulator.value(reduce)?,
+                    labels: without_name(series.labels),
+                })
+            })
+            .collect(),
+        Function::HistogramQuantile => {
+            let Value::Scalar(q) = arg(0)? else {
+                return Err(PromqlError::Type(
+                    "histogram_quantile expects a scalar quantile".to_string(),
+                ));
+            };
+            histogram_quantile(q, vector(arg(1)?, "histogram_quantile")?)
+        }
+        Function::Abs => map_values(vector(arg(0)?, "abs")?, f64::abs),
+        Function::Ceil => map_values(vector(arg(0)?, "ceil")?, f64::ceil),
+        Function::Floor => map_values(vector(arg(0)?, "floor")?, f64::floor),
+    };
+
+    Ok(Value::Vector(elements))
+}
+
+fn aggregate(function: RangeFunction, grouping: &Grouping, elements: Vec<Element>) -> Vec<Element> {
+    let mut groups: BTreeMap<Labels, BucketAccumulator> = BTreeMap::new();
+
+    for element in elements {
+        groups
+            .entry(grouping.key(&without_name(element.labels)))
+            .or_default()
+            .add(element.value);
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|(labels, accumulator)| {
+            Some(Element {
+                labels,
+                value: accumulator.value(function)?,
+            })
+        })
+        .collect()
+}
+
+/// Estimates the `q` quantile of each histogram in `elements`, whose `le`
+/// labels are bucket bounds and whose values are cumulative counts, by linear
+/// interpolation within the bucket that holds it, as Prometheus does.
+fn histogram_quantile(q: f64, elements: Vec<Element>) -> Vec<Element> {
+    let mut histograms: BTreeMap<Labels, Vec<(f64, f64)>> = BTreeMap::new();
+
+    for mut element in elements {
+        let Some(le) = element.labels.remove("le") else {
+            continue;
+        };
+        let Ok(bound) = le.parse::<f64>() else {
+            continue;
+        };
+        histograms
+            .entry(without_name(element.labels))
+            .or_default()
+            .push((bound, element.value));
+    }
+
+    histograms
+        .into_iter()
+        .map(|(labels, mut buckets)| Element {
+            labels,
+            value: bucket_quantile(q, &mut buckets),
+        })
+        .collect()
+}
+
+fn binary_op(binary: &BinaryExpr, time: i64, data: &Data) -> Result<Value, PromqlError> {
+    let op = binary.op;
+    let lhs = evaluate(&binary.lhs, time, data)?;
+    let rhs = evaluate(&binary.rhs, time, data)?;
+
+    match (lhs, rhs) {
+        (Value::Scalar(a), Value::Scalar(b)) => {
+            if op.is_set() {
+                return Err(PromqlError::Type(
+                    "set operators need instant vectors on both sides".to_string(),
+                ));
+            }
+            if op.is_comparison() && !binary.return_bool {
+                return Err(PromqlError::Type(
+                    "comparisons between scalars must use bool".to_string(),
+                ));
+            }
+            Ok(Value::Scalar(arithmetic(op, a, b)))
+        }
+        (Value::Vector(elements), Value::Scalar(scalar)) => {
+            scalar_op(binary, elements, |v| (v, scalar))
+        }
+        (Value::Scalar(scalar), Value::Vector(elements)) => {
+            scalar_op(binary, elements, |v| (scalar, v))
+        }
+        (Value::Vector(lhs), Value::Vector(rhs)) if op.is_set() => {
+            Ok(Value::Vector(set_op(binary, lhs, rhs)))
+        }
+        (Value::Vector(lhs), Value::Vector(rhs)) => vector_op(binary, lhs, rhs).map(Value::Vector),
+        _ => Err(PromqlError::Type(
+            "binary operators need scalars or instant vectors".to_string(),
+        )),
+    }
+}
+
+/// Applies `binary` between each element and a scalar; `operands` orders the
+/// element's value and the scalar as they appear in the expression.
+fn scalar_op(
+    binary: &BinaryExpr,
+    elements: Vec<Element>,
+    operands: impl Fn(f64) -> (f64, f64),
+) -> Result<Value, PromqlError> {
+    if binary.op.is_set() {
+        return Err(PromqlError::Type(
+            "set operators need instant vectors on both sides".to_string(),
+        ));
+    }
+
+    Ok(Value::Vector(
+        elements
+            .into_iter()
+            .filter_map(|element| {
+                let (a, b) = operands(element.value);
+                let result = arithmetic(binary.op, a, b);
+                if binary.op.is_comparison() && !binary.return_bool {
+                    (result == 1.0).then_some(element)
+                } else {
+                    Some(Element {
+                        labels: without_name(element.labels),
+                        value: result,
+                    })
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// One-to-one matching: each element pairs with the element on the other side
+/// that has the same labels, as narrowed by `on` or `ignoring`.
+fn vector_op(
+    binary: &BinaryExpr,
+    lhs: Vec<Element>,
+    rhs: Vec<Element>,
+) -> Result<Vec<Element>, PromqlError> {
+    let key = |element: &Element| binary.matching.key(&without_name(element.labels.clone()));
+
+    let mut right: HashMap<Labels, f64> = HashMap::new();
+    for element in &rhs {
+        if right.insert(key(element), element.value).is_some() {
+            return Err(PromqlError::Type(format!(
+                "many-to-many matching not allowed: duplicate series {:?} on the right-hand side",
+                key(element)
+            )));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+
+    for element in lhs {
+        let key = key(&element);
+        let Some(&b) = right.get(&key) else {
+            continue;
+        };
+        if !seen.insert(key.clone()) {
+            return Err(PromqlError::Type(format!(
+                "many-to-many matching not allowed: duplicate series {key:?} on the left-hand side"
+            )));
+        }
+
+        let value = arithmetic(binary.op, element.value, b);
+        if binary.op.is_comparison() && !binary.return_bool {
+            if value == 1.0 {
+                result.push(element);
+            }
+        } else {
+            result.push(Element { labels: key, value });
+        }
+    }
+
+    Ok(result)
+}
+
+/// `and`, `or` and `unless`, which keep whole elements of either side.
+fn set_op(binary: &BinaryExpr, lhs: Vec<Element>, rhs: Vec<Element>) -> Vec<Element> {
+    let key = |element: &Element| binary.matching.key(&without_name(element.labels.clone()));
+    let right: HashSet<Labels> = rhs.iter().map(key).collect();
+
+    match binary.op {
+        BinaryOp::And => lhs
+            .into_iter()
+            .filter(|e| right.contains(&key(e)))
+            .collect(),
+        BinaryOp::Unless => lhs
+            .into_iter()
+            .filter(|e| !right.contains(&key(e)))
+            .collect(),
+        _ => {
+            let left: HashSet<Labels> = lhs.iter().map(key).collect();
+            let extra = rhs.into_iter().filter(|e| !left.contains(&key(e)));
+            lhs.into_iter().chain(extra).collect()
+        }
+    }
+}
+
+/// Arithmetic result, or 1 and 0 for comparisons.
+fn arithmetic(op: BinaryOp, a: f64, b: f64) -> f64 {
+    let holds = |condition: bool| if condition { 1.0 } else { 0.0 };
+
+    match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        BinaryOp::Div => a / b,
+        BinaryOp::Mod => a % b,
+        BinaryOp::Pow => a.powf(b),
+        BinaryOp::Eq => holds(a == b),
+        BinaryOp::Ne => holds(a != b),
+        BinaryOp::Gt => holds(a > b),
+        BinaryOp::Lt => holds(a < b),
+        BinaryOp::Ge => holds(a >= b),
+        BinaryOp::Le => holds(a <= b),
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Unless => f64::NAN,
+    }
+}
+
+fn vector(value: Value, context: &str) -> Result<Vec<Element>, PromqlError> {
+    match value {
+        Value::Vector(elements) => Ok(elements),
+        _ => Err(PromqlError::Type(format!(
+            "{context} expects an instant vector"
+        ))),
+    }
+}
+
+fn matrix(value: Value, context: &str) -> Result<Vec<Series>, PromqlError> {
+    match value {
+        Value::Matrix(series) => Ok(series),
+        _ => Err(PromqlError::Type(format!(
+            "{context} expect a range vector such as metric[5m]"
+        ))),
+    }
+}
+
+/// Functions and arithmetic change what a value means, so the name is dropped.
+fn map_values(elements: Vec<Element>, f: impl Fn(f64) -> f64) -> Vec<Element> {
+    elements
+        .into_iter()
+        .map(|element| Element {
+            labels: without_name(element.labels),
+            value: f(element.value),
+        })
+        .collect()
+}
+
+fn without_name(mut labels: Labels) -> Labels {
+    labels.remove(NAME_LABEL);
+    labels
+}
@@ -0,0 +1,18 @@
+pub mod eval;
+pub mod parser;
+
+pub use eval::*;
+pub use parser::*;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PromqlError {
+    #[error("parse error: {0}")]
+    Parse(String),
+    /// The expression parsed but cannot be evaluated, e.g. `rate` of an instant vector.
+    #[error("{0}")]
+    Type(String),
+    #[error("{0}")]
+    Limit(String),
+    #[error("storage error: {0}")]
+    Storage(String),
+}
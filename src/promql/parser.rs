@@ -0,0 +1,699 @@
+use crate::models::{
+    is_valid_label_name, Grouping, LabelMatcher, LabelSelector, Labels, MetricFilter,
+    RangeFunction, RateFunction,
+};
+use crate::promql::PromqlError;
+use chrono::{TimeZone, Utc};
+use std::fmt;
+
+/// A parsed PromQL expression.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Call(Function, Vec<Expr>),
+    /// `sum by (host) (...)`. A plain `sum(...)` groups by nothing.
+    Aggregate(RangeFunction, Grouping, Box<Expr>),
+    Binary(Box<BinaryExpr>),
+    Negate(Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryExpr {
+    pub op: BinaryOp,
+    pub lhs: Expr,
+    pub rhs: Expr,
+    /// Labels two vector elements must agree on, from `on (...)` or `ignoring (...)`.
+    /// By default all labels but the metric name.
+    pub matching: Grouping,
+    /// `bool` modifier: comparisons return 0 or 1 instead of filtering.
+    pub return_bool: bool,
+}
+
+/// A series selector such as `http_requests_total{job="api"}[5m]`.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// Matchers including any on `__name__`; a leading metric name becomes one.
+    pub matchers: Vec<LabelMatcher>,
+    /// Window of a range vector selector, in milliseconds.
+    pub range: Option<i64>,
+}
+
+impl Selector {
+    /// The metric name when the selector pins it with `name` or `__name__="name"`.
+    pub fn metric_name(&self) -> Option<&str> {
+        self.matchers.iter().find_map(|matcher| match matcher {
+            LabelMatcher::Equal(name, value) if name == NAME_LABEL => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The store filter for this selector between `start` and `end`, epoch
+    /// milliseconds. Matchers on `__name__` other than equality cannot be pushed
+    /// down and are left to `matches`.
+    pub fn filter(&self, start: Option<i64>, end: Option<i64>) -> MetricFilter {
+        let rfc3339 = |ms: i64| {
+            Utc.timestamp_millis_opt(ms)
+                .single()
+                .map(|dt| dt.to_rfc3339())
+        };
+        let labels: Vec<LabelMatcher> = self
+            .matchers
+            .iter()
+            .filter(|matcher| matcher.name() != NAME_LABEL)
+            .cloned()
+            .collect();
+
+        MetricFilter {
+            name: self.metric_name().map(str::to_string),
+            labels: (!labels.is_empty()).then_some(LabelSelector(labels)),
+            start_date: start.and_then(rfc3339),
+            end_date: end.and_then(rfc3339),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the series named `name` with `labels` satisfies every matcher.
+    pub fn matches(&self, name: &str, labels: &Labels) -> bool {
+        let mut labels = labels.clone();
+        labels.insert(NAME_LABEL.to_string(), name.to_string());
+        self.matchers.iter().all(|matcher| matcher.matches(&labels))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matchers: Vec<String> = self.matchers.iter().map(ToString::to_string).collect();
+        write!(f, "{{{}}}", matchers.join(","))?;
+        if let Some(range) = self.range {
+            write!(f, "[{range}ms]")?;
+        }
+        Ok(())
+    }
+}
+
+/// The label PromQL keeps the metric name in.
+pub const NAME_LABEL: &str = "__name__";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    /// `rate`, `irate`, `increase`, `delta` and `deriv` over a range vector.
+    Rate(RateFunction),
+    /// `avg_over_time`, `sum_over_time` and the like.
+    OverTime(RangeFunction),
+    HistogramQuantile,
+    Abs,
+    Ceil,
+    Floor,
+    Time,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        let function = match name {
+            "rate" => Function::Rate(RateFunction::Rate),
+            "irate" => Function::Rate(RateFunction::Irate),
+            "increase" => Function::Rate(RateFunction::Increase),
+            "delta" => Function::Rate(RateFunction::Delta),
+            "deriv" => Function::Rate(RateFunction::Derivative),
+            "avg_over_time" => Function::OverTime(RangeFunction::Avg),
+            "sum_over_time" => Function::OverTime(RangeFunction::Sum),
+            "min_over_time" => Function::OverTime(RangeFunction::Min),
+            "max_over_time" => Function::OverTime(RangeFunction::Max),
+            "count_over_time" => Function::OverTime(RangeFunction::Count),
+            "histogram_quantile" => Function::HistogramQuantile,
+            "abs" => Function::Abs,
+            "ceil" => Function::Ceil,
+            "floor" => Function::Floor,
+            "time" => Function::Time,
+            _ => return None,
+        };
+        Some(function)
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Time => 0,
+            Function::HistogramQuantile => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    And,
+    Or,
+    Unless,
+}
+
+impl BinaryOp {
+    /// Binding strength, higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And | BinaryOp::Unless => 2,
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Gt
+            | BinaryOp::Lt
+            | BinaryOp::Ge
+            | BinaryOp::Le => 3,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 5,
+            BinaryOp::Pow => 6,
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        self.precedence() == 3
+    }
+
+    pub fn is_set(self) -> bool {
+        matches!(self, BinaryOp::And | BinaryOp::Or | BinaryOp::Unless)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    /// A duration such as `5m` or `1h30m`, in milliseconds.
+    Duration(i64),
+    Ident(String),
+    Str(String),
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{n}"),
+            Token::Duration(ms) => write!(f, "{ms}ms"),
+            Token::Ident(ident) => f.write_str(ident),
+            Token::Str(s) => write!(f, "{s:?}"),
+            Token::Punct(p) => f.write_str(p),
+        }
+    }
+}
+
+/// Longest first, so `!=` is not read as `!` and `=`.
+const PUNCTUATION: &[&str] = &[
+    "=~", "!~", "!=", "==", ">=", "<=", "(", ")", "{", "}", "[", "]", ",", "=", ">", "<", "+", "-",
+    "*", "/", "%", "^",
+];
+
+fn lex(input: &str) -> Result<Vec<Token>, PromqlError> {
+    let mut tokens = Vec::new();
+    let mut rest = input;
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            let (string, remaining) = lex_string(rest, c)?;
+            tokens.push(Token::Str(string));
+            rest = remaining;
+            continue;
+        }
+
+        if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(rest.len());
+            let (literal, remaining) = rest.split_at(end);
+            // Exponents like `1e-3` continue past the sign
+            let (literal, remaining) = match remaining.strip_prefix(['-', '+']) {
+                Some(after) if literal.ends_with(['e', 'E']) => {
+                    let digits = after
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(after.len());
+                    let len = literal.len() + 1 + digits;
+                    (&rest[..len], &rest[len..])
+                }
+                _ => (literal, remaining),
+            };
+
+            tokens.push(match literal.parse::<f64>() {
+                Ok(number) => Token::Number(number),
+                Err(_) => Token::Duration(parse_duration(literal)?),
+            });
+            rest = remaining;
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+            continue;
+        }
+
+        let punct = PUNCTUATION
+            .iter()
+            .find(|p| rest.starts_with(**p))
+            .ok_or_else(|| PromqlError::Parse(format!("unexpected character {c:?}")))?;
+        tokens.push(Token::Punct(punct));
+        rest = &rest[punct.len()..];
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a quoted string starting at `input`, returning it unescaped and the
+/// input after the closing quote.
+fn lex_string(input: &str, quote: char) -> Result<(String, &str), PromqlError> {
+    let mut string = String::new();
+    let mut chars = input.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let (_, escaped) = chars
+                    .next()
+                    .ok_or_else(|| PromqlError::Parse("unterminated string".to_string()))?;
+                string.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    other => other,
+                });
+            }
+            c if c == quote => return Ok((string, &input[i + 1..])),
+            c => string.push(c),
+        }
+    }
+
+    Err(PromqlError::Parse("unterminated string".to_string()))
+}
+
+/// Parses a PromQL duration such as `30s`, `5m` or `1h30m` into milliseconds.
+pub fn parse_duration(s: &str) -> Result<i64, PromqlError> {
+    let invalid = || PromqlError::Parse(format!("invalid duration {s:?}"));
+    let mut total = 0i64;
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let millis = match &rest[..unit_len] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "w" => 7 * 24 * 60 * 60 * 1000,
+            "y" => 365 * 24 * 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_len..];
+
+        total = number
+            .checked_mul(millis)
+            .and_then(|ms| total.checked_add(ms))
+            .ok_or_else(invalid)?;
+    }
+
+    match total {
+        0 => Err(invalid()),
+        total => Ok(total),
+    }
+}
+
+/// Deepest nesting of subexpressions `parse` accepts, counting parentheses,
+/// function calls, unary operators and chained `^`.
+const MAX_DEPTH: usize = 256;
+
+/// Parses a PromQL expression.
+pub fn parse(input: &str) -> Result<Expr, PromqlError> {
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        position: 0,
+        depth: 0,
+    };
+
+    let expr = parser.expr(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(PromqlError::Parse(format!("unexpected {token}"))),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// How many `expr` calls are in progress. Every recursion passes through it.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword)
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), PromqlError> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == punct => Ok(()),
+            Some(token) => Err(PromqlError::Parse(format!(
+                "expected {punct:?}, found {token}"
+            ))),
+            None => Err(PromqlError::Parse(format!(
+                "expected {punct:?}, found end of input"
+            ))),
+        }
+    }
+
+    fn peek_binary_op(&self) -> Option<BinaryOp> {
+        let op = match self.peek()? {
+            Token::Punct("+") => BinaryOp::Add,
+            Token::Punct("-") => BinaryOp::Sub,
+            Token::Punct("*") => BinaryOp::Mul,
+            Token::Punct("/") => BinaryOp::Div,
+            Token::Punct("%") => BinaryOp::Mod,
+            Token::Punct("^") => BinaryOp::Pow,
+            Token::Punct("==") => BinaryOp::Eq,
+            Token::Punct("!=") => BinaryOp::Ne,
+            Token::Punct(">") => BinaryOp::Gt,
+            Token::Punct("<") => BinaryOp::Lt,
+            Token::Punct(">=") => BinaryOp::Ge,
+            Token::Punct("<=") => BinaryOp::Le,
+            Token::Ident(ident) if ident == "and" => BinaryOp::And,
+            Token::Ident(ident) if ident == "or" => BinaryOp::Or,
+            Token::Ident(ident) if ident == "unless" => BinaryOp::Unless,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// Precedence climbing over binary operators binding at least `min_precedence`.
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, PromqlError> {
+        if self.depth == MAX_DEPTH {
+            return Err(PromqlError::Parse(format!(
+                "expression nests deeper than {MAX_DEPTH} levels"
+            )));
+        }
+
+        self.depth += 1;
+        let expr = self.binary(min_precedence);
+        self.depth -= 1;
+        expr
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, PromqlError> {
+        let mut lhs = self.unary()?;
+
+        while let Some(op) = self.peek_binary_op() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.position += 1;
+
+            let return_bool = self.peek_keyword("bool");
+            if return_bool {
+                if !op.is_comparison() {
+                    return Err(PromqlError::Parse(
+                        "bool modifier is only allowed on comparisons".to_string(),
+                    ));
+                }
+                self.position += 1;
+            }
+
+            let matching = if self.peek_keyword("on") {
+                self.position += 1;
+                Grouping::By(self.label_list()?)
+            } else if self.peek_keyword("ignoring") {
+                self.position += 1;
+                Grouping::Without(self.label_list()?)
+            } else {
+                Grouping::Without(Vec::new())
+            };
+
+            // `^` is right-associative, everything else left-associative
+            let next_precedence = match op {
+                BinaryOp::Pow => op.precedence(),
+                _ => op.precedence() + 1,
+            };
+            let rhs = self.expr(next_precedence)?;
+
+            lhs = Expr::Binary(Box::new(BinaryExpr {
+                op,
+                lhs,
+                rhs,
+                matching,
+                return_bool,
+            }));
+        }
+
+        Ok(lhs)
+    }
+
+    /// Unary minus binds tighter than everything but `^`, so `-a ^ b` is `-(a ^ b)`.
+    fn unary(&mut self) -> Result<Expr, PromqlError> {
+        if self.peek_punct("-") || self.peek_punct("+") {
+            let negate = self.peek_punct("-");
+            self.position += 1;
+            let operand = self.expr(BinaryOp::Pow.precedence())?;
+            return Ok(match (negate, operand) {
+                (true, Expr::Number(n)) => Expr::Number(-n),
+                (true, operand) => Expr::Negate(Box::new(operand)),
+                (false, operand) => operand,
+            });
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, PromqlError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Punct("(")) => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Punct("{")) => self.selector(Vec::new()),
+            Some(Token::Ident(ident)) => self.identifier(ident),
+            Some(token) => Err(PromqlError::Parse(format!("unexpected {token}"))),
+            None => Err(PromqlError::Parse("unexpected end of input".to_string())),
+        }
+    }
+
+    fn identifier(&mut self, ident: String) -> Result<Expr, PromqlError> {
+        match ident.to_lowercase().as_str() {
+            "inf" => return Ok(Expr::Number(f64::INFINITY)),
+            "nan" => return Ok(Expr::Number(f64::NAN)),
+            _ => {}
+        }
+
+        if let Some(function) = aggregation_op(&ident) {
+            return self.aggregation(function);
+        }
+
+        if self.peek_punct("(") {
+            let function = Function::from_name(&ident)
+                .ok_or_else(|| PromqlError::Parse(format!("unknown function {ident:?}")))?;
+            self.position += 1;
+            let args = self.arguments()?;
+            if args.len() != function.arity() {
+                return Err(PromqlError::Parse(format!(
+                    "{ident} expects {} argument(s), got {}",
+                    function.arity(),
+                    args.len()
+                )));
+            }
+            return Ok(Expr::Call(function, args));
+        }
+
+        let name = LabelMatcher::Equal(NAME_LABEL.to_string(), ident);
+        if self.peek_punct("{") {
+            self.position += 1;
+            return self.selector(vec![name]);
+        }
+        self.range(vec![name])
+    }
+
+    /// `sum by (a) (expr)` or `sum (expr) by (a)`.
+    fn aggregation(&mut self, function: RangeFunction) -> Result<Expr, PromqlError> {
+        let mut grouping = self.grouping()?;
+
+        self.expect("(")?;
+        let expr = self.expr(0)?;
+        self.expect(")")?;
+
+        if grouping == Grouping::Name {
+            grouping = self.grouping()?;
+        }
+
+        Ok(Expr::Aggregate(function, grouping, Box::new(expr)))
+    }
+
+    fn grouping(&mut self) -> Result<Grouping, PromqlError> {
+        if self.peek_keyword("by") {
+            self.position += 1;
+            Ok(Grouping::By(self.label_list()?))
+        } else if self.peek_keyword("without") {
+            self.position += 1;
+            Ok(Grouping::Without(self.label_list()?))
+        } else {
+            Ok(Grouping::Name)
+        }
+    }
+
+    /// A parenthesized, comma-separated list of label names.
+    fn label_list(&mut self) -> Result<Vec<String>, PromqlError> {
+        self.expect("(")?;
+        let mut labels = Vec::new();
+
+        while !self.peek_punct(")") {
+            match self.next() {
+                Some(Token::Ident(label)) if is_valid_label_name(&label) => labels.push(label),
+                Some(token) => {
+                    return Err(PromqlError::Parse(format!(
+                        "expected a label name, found {token}"
+                    )))
+                }
+                None => return Err(PromqlError::Parse("unterminated label list".to_string())),
+            }
+            if !self.peek_punct(")") {
+                self.expect(",")?;
+            }
+        }
+        self.expect(")")?;
+
+        labels.sort_unstable();
+        labels.dedup();
+        Ok(labels)
+    }
+
+    /// Function arguments after the opening parenthesis.
+    fn arguments(&mut self) -> Result<Vec<Expr>, PromqlError> {
+        let mut args = Vec::new();
+
+        while !self.peek_punct(")") {
+            args.push(self.expr(0)?);
+            if !self.peek_punct(")") {
+                self.expect(",")?;
+            }
+        }
+        self.expect(")")?;
+
+        Ok(args)
+    }
+
+    /// Label matchers after the opening brace, then an optional range.
+    fn selector(&mut self, mut matchers: Vec<LabelMatcher>) -> Result<Expr, PromqlError> {
+        while !self.peek_punct("}") {
+            let name = match self.next() {
+                Some(Token::Ident(name)) if is_valid_label_name(&name) => name,
+                Some(token) => {
+                    return Err(PromqlError::Parse(format!(
+                        "expected a label name, found {token}"
+                    )))
+                }
+                None => return Err(PromqlError::Parse("unterminated selector".to_string())),
+            };
+
+            let op = match self.next() {
+                Some(Token::Punct(op @ ("=" | "!=" | "=~" | "!~"))) => op,
+                _ => {
+                    return Err(PromqlError::Parse(format!(
+                        "expected a matcher operator after {name}"
+                    )))
+                }
+            };
+
+            let Some(Token::Str(value)) = self.next() else {
+                return Err(PromqlError::Parse(format!(
+                    "expected a quoted value for {name}"
+                )));
+            };
+
+            matchers.push(match op {
+                "=" => LabelMatcher::Equal(name, value),
+                "!=" => LabelMatcher::NotEqual(name, value),
+                op => LabelMatcher::regex(&name, &value, op == "!~")
+                    .map_err(|e| PromqlError::Parse(e.to_string()))?,
+            });
+
+            if !self.peek_punct("}") {
+                self.expect(",")?;
+            }
+        }
+        self.expect("}")?;
+
+        if matchers.iter().all(LabelMatcher::is_negative) {
+            return Err(PromqlError::Parse(
+                "a selector needs a metric name or a positive matcher".to_string(),
+            ));
+        }
+
+        self.range(matchers)
+    }
+
+    fn range(&mut self, matchers: Vec<LabelMatcher>) -> Result<Expr, PromqlError> {
+        let mut range = None;
+
+        if self.peek_punct("[") {
+            self.position += 1;
+            range = match self.next() {
+                Some(Token::Duration(ms)) => Some(ms),
+                _ => {
+                    return Err(PromqlError::Parse(
+                        "expected a duration such as 5m in [...]".to_string(),
+                    ))
+                }
+            };
+            self.expect("]")?;
+        }
+
+        Ok(Expr::Selector(Selector { matchers, range }))
+    }
+}
+
+fn aggregation_op(ident: &str) -> Option<RangeFunction> {
+    let function = match ident {
+        "sum" => RangeFunction::Sum,
+        "avg" => RangeFunction::Avg,
+        "min" => RangeFunction::Min,
+        "max" => RangeFunction::Max,
+        "count" => RangeFunction::Count,
+        _ => return None,
+    };
+    Some(function)
+}
@@ -0,0 +1,26 @@
+use crate::services::TelemetryService;
+use actix_web::{web, HttpResponse, Result};
+
+/// Query cache hit and miss counts since startup, and the health of Redis.
+pub async fn cache_stats(service: web::Data<TelemetryService>) -> Result<HttpResponse> {
+    let Some(stats) = service.cache_stats() else {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "enabled": false })));
+    };
+
+    let lookups = stats.hits + stats.misses;
+    let hit_ratio = if lookups > 0 {
+        stats.hits as f64 / lookups as f64
+    } else {
+        0.0
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": true,
+        "hits": stats.hits,
+        "local_hits": stats.local_hits,
+        "misses": stats.misses,
+        "hit_ratio": hit_ratio,
+        "redis_errors": stats.redis_errors,
+        "circuit_open": stats.circuit_open,
+    })))
+}
@@ -0,0 +1,67 @@
+use crate::ingest::influx::{self, LineError, Precision};
+use crate::models::BatchItemResult;
+use crate::services::TelemetryService;
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct WriteParams {
+    pub precision: Option<String>,
+}
+
+/// InfluxDB line protocol receiver. Valid lines are written even when others
+/// fail; like InfluxDB, a partial write answers 400 listing the failed lines.
+pub async fn write(
+    service: web::Data<TelemetryService>,
+    params: web::Query<WriteParams>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let precision = match params.precision.as_deref().map(str::parse::<Precision>) {
+        None => Precision::default(),
+        Some(Ok(precision)) => precision,
+        Some(Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": e })));
+        }
+    };
+
+    let Ok(body) = std::str::from_utf8(&body) else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "body is not valid UTF-8"
+        })));
+    };
+
+    let (points, mut errors) = influx::parse(body, precision);
+    let lines: Vec<usize> = points.iter().map(|point| point.line).collect();
+    let requests = points.into_iter().map(|point| Ok(point.request)).collect();
+
+    let response = match service.create_metrics(requests).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Failed to store line protocol points: {e}");
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to store points"
+            })));
+        }
+    };
+
+    for result in response.results {
+        if let BatchItemResult::Failed { index, error } = result {
+            errors.push(LineError {
+                line: lines[index],
+                error,
+            });
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+
+    errors.sort_by_key(|e| e.line);
+    let summary: Vec<String> = errors.iter().map(ToString::to_string).collect();
+
+    Ok(HttpResponse::BadRequest().json(serde_json::json!({
+        "error": format!("partial write: {}", summary.join("; ")),
+        "errors": errors,
+    })))
+}
@@ -1,6 +1,12 @@
-use crate::models::{CreateMetricRequest, MetricFilter, UpdateMetricRequest};
+use crate::models::{
+    CreateMetricRequest, CursorError, InvalidMetric, MetricFilter, PageCursor, UpdateMetricRequest,
+};
+use crate::routes::stream::{self, StreamParams};
 use crate::services::TelemetryService;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, Result,
+};
 
 pub async fn create_metric(
     service: web::Data<TelemetryService>,
@@ -8,6 +14,11 @@ pub async fn create_metric(
 ) -> Result<HttpResponse> {
     match service.create_metric(request.into_inner()).await {
         Ok(metric) => Ok(HttpResponse::Created().json(metric)),
+        Err(e) if e.is::<InvalidMetric>() => {
+            Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
         Err(e) => {
             log::error!("Failed to create metric: {e}");
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({
@@ -17,12 +28,114 @@ pub async fn create_metric(
     }
 }
 
+pub async fn create_metrics_batch(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let requests = match parse_batch(&req, &body) {
+        Ok(requests) => requests,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid batch: {e}")
+            })))
+        }
+    };
+
+    let max_batch_size = service.ingest_options().max_batch_size;
+    if requests.len() > max_batch_size {
+        return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": format!("Batch of {} metrics exceeds the maximum of {max_batch_size}", requests.len())
+        })));
+    }
+
+    match service.create_metrics(requests).await {
+        Ok(response) => {
+            let status = if response.failed == 0 {
+                StatusCode::CREATED
+            } else {
+                StatusCode::MULTI_STATUS
+            };
+            Ok(HttpResponse::build(status).json(response))
+        }
+        Err(e) => {
+            log::error!("Failed to create metrics batch: {e}");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to create metrics"
+            })))
+        }
+    }
+}
+
+/// Splits a batch body into individual metric requests. Accepts a JSON array, or
+/// newline-delimited JSON when the content type is `application/x-ndjson`. Items
+/// that fail to deserialize are returned as `Err` so they can be reported per item.
+fn parse_batch(
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<Vec<Result<CreateMetricRequest, String>>, String> {
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let items: Vec<Result<serde_json::Value, String>> =
+        if content_type.starts_with("application/x-ndjson") {
+            let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+            body.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+                .collect()
+        } else {
+            serde_json::from_slice::<Vec<serde_json::Value>>(body)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(Ok)
+                .collect()
+        };
+
+    Ok(items
+        .into_iter()
+        .map(|item| item.and_then(|value| serde_json::from_value(value).map_err(|e| e.to_string())))
+        .collect())
+}
+
+/// Lists one page of matching metrics. When there are more, a `Link` header
+/// with `rel="next"` points at the next page. With `?stream=ndjson|json` every
+/// match is streamed instead, unpaged unless `limit` is given.
 pub async fn get_metrics(
     service: web::Data<TelemetryService>,
+    req: HttpRequest,
     query: web::Query<MetricFilter>,
+    params: web::Query<StreamParams>,
 ) -> Result<HttpResponse> {
-    match service.get_metrics(query.into_inner()).await {
-        Ok(metrics) => Ok(HttpResponse::Ok().json(metrics)),
+    if let Some(format) = params.format(&req) {
+        return match service.stream_metrics(query.into_inner()).await {
+            Ok(metrics) => Ok(stream::metrics_response(metrics, format)),
+            Err(e) => {
+                log::error!("Failed to stream metrics: {e}");
+                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Failed to get metrics"
+                })))
+            }
+        };
+    }
+
+    match service.list_metrics(query.into_inner()).await {
+        Ok(page) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(cursor) = &page.next {
+                response.insert_header((
+                    header::LINK,
+                    format!("<{}>; rel=\"next\"", next_page_uri(&req, cursor)),
+                ));
+            }
+            Ok(response.json(page.metrics))
+        }
+        Err(e) if e.is::<CursorError>() => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        }))),
         Err(e) => {
             log::error!("Failed to get metrics: {e}");
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({
@@ -32,6 +145,40 @@ pub async fn get_metrics(
     }
 }
 
+/// The request URI with its `cursor` parameter replaced by `cursor`.
+fn next_page_uri(req: &HttpRequest, cursor: &PageCursor) -> String {
+    let mut params: Vec<String> = req
+        .query_string()
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("cursor="))
+        .map(str::to_string)
+        .collect();
+    // Base64url needs no percent-encoding
+    params.push(format!("cursor={cursor}"));
+
+    format!("{}?{}", req.path(), params.join("&"))
+}
+
+pub async fn get_metric(
+    service: web::Data<TelemetryService>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+
+    match service.get_metric(&id).await {
+        Ok(Some(metric)) => Ok(HttpResponse::Ok().json(metric)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Metric not found"
+        }))),
+        Err(e) => {
+            log::error!("Failed to get metric: {e}");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to get metric"
+            })))
+        }
+    }
+}
+
 pub async fn update_metric(
     service: web::Data<TelemetryService>,
     path: web::Path<String>,
@@ -44,6 +191,11 @@ pub async fn update_metric(
         Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
             "error": "Metric not found"
         }))),
+        Err(e) if e.is::<InvalidMetric>() => {
+            Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
         Err(e) => {
             log::error!("Failed to update metric: {e}");
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({
@@ -1,15 +1,75 @@
+pub mod cache;
+pub mod influx;
 pub mod metrics;
+pub mod otlp;
+pub mod prometheus;
 pub mod query;
+pub mod stream;
 
 use actix_web::web;
 
+/// Upper bound on bulk ingestion bodies. For `/metrics/batch` the item count is
+/// limited separately by `MAX_BATCH_SIZE`.
+const BATCH_PAYLOAD_LIMIT: usize = 16 * 1024 * 1024;
+
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/metrics")
             .route("", web::get().to(metrics::get_metrics))
             .route("", web::post().to(metrics::create_metric))
+            .service(
+                web::resource("/batch")
+                    .app_data(web::PayloadConfig::new(BATCH_PAYLOAD_LIMIT))
+                    .route(web::post().to(metrics::create_metrics_batch)),
+            )
+            .route("/{id}", web::get().to(metrics::get_metric))
             .route("/{id}", web::put().to(metrics::update_metric))
             .route("/{id}", web::delete().to(metrics::delete_metric)),
     )
-    .service(web::resource("/query").route(web::get().to(query::query_metrics)));
+    .service(
+        web::resource("/query")
+            .route(web::get().to(query::query_metrics))
+            .route(web::post().to(query::structured_query)),
+    )
+    .service(web::resource("/query/explain").route(web::get().to(query::explain_query)))
+    .service(web::resource("/query/range").route(web::get().to(query::query_range)))
+    .service(web::resource("/cache/stats").route(web::get().to(cache::cache_stats)))
+    .service(
+        web::resource("/api/v1/write")
+            .app_data(web::PayloadConfig::new(BATCH_PAYLOAD_LIMIT))
+            .route(web::post().to(prometheus::remote_write)),
+    )
+    .service(
+        web::resource("/api/v1/query")
+            .route(web::get().to(prometheus::query))
+            .route(web::post().to(prometheus::query)),
+    )
+    .service(
+        web::resource("/api/v1/query_range")
+            .route(web::get().to(prometheus::query_range))
+            .route(web::post().to(prometheus::query_range)),
+    )
+    .service(
+        web::resource("/api/v1/series")
+            .route(web::get().to(prometheus::series))
+            .route(web::post().to(prometheus::series)),
+    )
+    .service(
+        web::resource("/api/v1/labels")
+            .route(web::get().to(prometheus::labels))
+            .route(web::post().to(prometheus::labels)),
+    )
+    .service(
+        web::resource("/api/v1/label/{name}/values").route(web::get().to(prometheus::label_values)),
+    )
+    .service(
+        web::resource("/v1/metrics")
+            .app_data(web::PayloadConfig::new(BATCH_PAYLOAD_LIMIT))
+            .route(web::post().to(otlp::export_metrics)),
+    )
+    .service(
+        web::resource("/write")
+            .app_data(web::PayloadConfig::new(BATCH_PAYLOAD_LIMIT))
+            .route(web::post().to(influx::write)),
+    );
 }
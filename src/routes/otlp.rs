@@ -0,0 +1,67 @@
+use crate::ingest::otlp::{self, ExportMetricsPartialSuccess, ExportMetricsServiceResponse};
+use crate::models::BatchItemResult;
+use crate::services::TelemetryService;
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Result};
+use prost::Message;
+
+/// OTLP/HTTP metrics receiver. Accepts `application/x-protobuf` and
+/// `application/json` and answers in the same encoding. Rejected data points are
+/// reported through `partial_success`; store failures return 503 so exporters retry.
+/// Payloads that cannot be decoded or converted are rejected with 400.
+pub async fn export_metrics(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let decoded = if json {
+        otlp::decode_json(&body)
+    } else {
+        otlp::decode_protobuf(&body)
+    };
+
+    let requests = match decoded.and_then(otlp::to_metric_requests) {
+        Ok(requests) => requests.into_iter().map(Ok).collect(),
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
+    };
+
+    let response = match service.create_metrics(requests).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Failed to store OTLP data points: {e}");
+            return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "error": "Failed to store data points"
+            })));
+        }
+    };
+
+    let partial_success = (response.failed > 0).then(|| ExportMetricsPartialSuccess {
+        rejected_data_points: response.failed as i64,
+        error_message: response
+            .results
+            .iter()
+            .find_map(|result| match result {
+                BatchItemResult::Failed { error, .. } => Some(error.clone()),
+                _ => None,
+            })
+            .unwrap_or_default(),
+    });
+    let export_response = ExportMetricsServiceResponse { partial_success };
+
+    if json {
+        Ok(HttpResponse::Ok().json(export_response))
+    } else {
+        Ok(HttpResponse::Ok()
+            .content_type("application/x-protobuf")
+            .body(export_response.encode_to_vec()))
+    }
+}
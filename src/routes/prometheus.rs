@@ -0,0 +1,389 @@
+use crate::ingest::prometheus::{self, RemoteWriteError};
+use crate::models::{Labels, MetricFilter};
+use crate::promql::{self, Engine, Expr, PromqlError, Selector, Series, Value, NAME_LABEL};
+use crate::services::TelemetryService;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Prometheus `remote_write` receiver. Responds with 204 when every sample was
+/// stored, 400 (which Prometheus does not retry) when samples were rejected, and
+/// 500 (which it retries) when the store failed.
+pub async fn remote_write(
+    service: web::Data<TelemetryService>,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let write_request = match prometheus::decode(&body, super::BATCH_PAYLOAD_LIMIT) {
+        Ok(write_request) => write_request,
+        Err(e @ RemoteWriteError::TooLarge { .. }) => {
+            return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
+    };
+
+    let requests = prometheus::to_metric_requests(write_request)
+        .into_iter()
+        .map(Ok)
+        .collect();
+
+    match service.create_metrics(requests).await {
+        Ok(response) if response.failed == 0 => Ok(HttpResponse::NoContent().finish()),
+        Ok(response) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("{} of {} samples rejected", response.failed, response.results.len()),
+            "results": response.results,
+        }))),
+        Err(e) => {
+            log::error!("Failed to store remote write samples: {e}");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to store samples"
+            })))
+        }
+    }
+}
+
+/// Parameters of the `/api/v1` query endpoints, from the query string and, for
+/// form-encoded POSTs as Grafana sends them, the body. `match[]` may repeat.
+struct ApiParams(Vec<(String, String)>);
+
+impl ApiParams {
+    fn from_request(req: &HttpRequest, body: &[u8]) -> Result<Self, ApiError> {
+        let mut pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+            .map_err(|e| ApiError::bad_data(e.to_string()))?
+            .into_inner();
+
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        if is_form {
+            // Form bodies use the same encoding as query strings
+            let body = std::str::from_utf8(body).map_err(|e| ApiError::bad_data(e.to_string()))?;
+            pairs.extend(
+                web::Query::<Vec<(String, String)>>::from_query(body)
+                    .map_err(|e| ApiError::bad_data(e.to_string()))?
+                    .into_inner(),
+            );
+        }
+
+        Ok(Self(pairs))
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, ApiError> {
+        self.get(name)
+            .ok_or_else(|| ApiError::bad_data(format!("missing parameter {name:?}")))
+    }
+
+    fn time(&self, name: &str) -> Result<Option<i64>, ApiError> {
+        self.get(name).map(parse_time).transpose()
+    }
+
+    /// The `match[]` selectors.
+    fn selectors(&self) -> Result<Vec<Selector>, ApiError> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == "match[]")
+            .map(|(_, value)| match promql::parse(value)? {
+                Expr::Selector(selector) if selector.range.is_none() => Ok(selector),
+                _ => Err(ApiError::bad_data(format!(
+                    "match[] must be an instant vector selector, got {value:?}"
+                ))),
+            })
+            .collect()
+    }
+}
+
+/// A failed `/api/v1` request, reported in Prometheus' error format.
+struct ApiError {
+    status: StatusCode,
+    error_type: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_data(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error_type: "bad_data",
+            message: message.into(),
+        }
+    }
+
+    fn response(self) -> HttpResponse {
+        HttpResponse::build(self.status).json(serde_json::json!({
+            "status": "error",
+            "errorType": self.error_type,
+            "error": self.message,
+        }))
+    }
+}
+
+impl From<PromqlError> for ApiError {
+    fn from(e: PromqlError) -> Self {
+        let (status, error_type) = match e {
+            PromqlError::Parse(_) | PromqlError::Limit(_) => (StatusCode::BAD_REQUEST, "bad_data"),
+            PromqlError::Type(_) => (StatusCode::UNPROCESSABLE_ENTITY, "execution"),
+            PromqlError::Storage(_) => {
+                log::error!("Failed to evaluate PromQL query: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal")
+            }
+        };
+
+        Self {
+            status,
+            error_type,
+            message: e.to_string(),
+        }
+    }
+}
+
+fn success(data: impl Serialize) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "success", "data": data }))
+}
+
+/// Accepts Unix seconds with an optional fraction, or RFC 3339. Times chrono
+/// cannot represent are rejected rather than clamped.
+fn parse_time(value: &str) -> Result<i64, ApiError> {
+    let invalid = || ApiError::bad_data(format!("invalid time {value:?}"));
+    if let Ok(seconds) = value.parse::<f64>() {
+        let min = chrono::DateTime::<chrono::Utc>::MIN_UTC.timestamp_millis() as f64;
+        let max = chrono::DateTime::<chrono::Utc>::MAX_UTC.timestamp_millis() as f64;
+        let ms = (seconds * 1000.0).round();
+        // NaN fails both comparisons
+        return (ms >= min && ms <= max)
+            .then_some(ms as i64)
+            .ok_or_else(invalid);
+    }
+
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.timestamp_millis())
+        .map_err(|_| invalid())
+}
+
+/// Accepts seconds with an optional fraction, or a duration such as `15s`.
+fn parse_step(value: &str) -> Result<i64, ApiError> {
+    match value.parse::<f64>() {
+        Ok(seconds) if (seconds * 1000.0).is_finite() => Ok((seconds * 1000.0).round() as i64),
+        Ok(_) => Err(ApiError::bad_data(format!("invalid step {value:?}"))),
+        Err(_) => Ok(promql::parse_duration(value)?),
+    }
+}
+
+/// A `[timestamp, "value"]` pair; Prometheus sends values as strings so that
+/// `NaN` and infinities survive JSON.
+fn point(ms: i64, value: f64) -> (f64, String) {
+    let value = match value {
+        v if v == f64::INFINITY => "+Inf".to_string(),
+        v if v == f64::NEG_INFINITY => "-Inf".to_string(),
+        v => v.to_string(),
+    };
+    (ms as f64 / 1000.0, value)
+}
+
+fn matrix(series: Vec<Series>) -> serde_json::Value {
+    let result: Vec<serde_json::Value> = series
+        .into_iter()
+        .map(|series| {
+            let values: Vec<_> = series
+                .samples
+                .into_iter()
+                .map(|(ms, value)| point(ms, value))
+                .collect();
+            serde_json::json!({ "metric": series.labels, "values": values })
+        })
+        .collect();
+
+    serde_json::json!({ "resultType": "matrix", "result": result })
+}
+
+/// PromQL instant query: `query` evaluated at `time`, by default now.
+pub async fn query(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let result: Result<_, ApiError> = async {
+        let params = ApiParams::from_request(&req, &body)?;
+        let expr = promql::parse(params.required("query")?)?;
+        let time = params
+            .time("time")?
+            .unwrap_or_else(|| Utc::now().timestamp_millis());
+
+        let data = match Engine::new(&service).instant(&expr, time).await? {
+            Value::Scalar(value) => {
+                serde_json::json!({ "resultType": "scalar", "result": point(time, value) })
+            }
+            Value::Vector(elements) => {
+                let result: Vec<serde_json::Value> = elements
+                    .into_iter()
+                    .map(|element| {
+                        serde_json::json!({
+                            "metric": element.labels,
+                            "value": point(time, element.value),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({ "resultType": "vector", "result": result })
+            }
+            Value::Matrix(series) => matrix(series),
+        };
+
+        Ok(data)
+    }
+    .await;
+
+    Ok(result.map_or_else(ApiError::response, success))
+}
+
+/// PromQL range query: `query` evaluated every `step` from `start` to `end`.
+pub async fn query_range(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let result: Result<_, ApiError> = async {
+        let params = ApiParams::from_request(&req, &body)?;
+        let expr = promql::parse(params.required("query")?)?;
+        let start = parse_time(params.required("start")?)?;
+        let end = parse_time(params.required("end")?)?;
+        let step = parse_step(params.required("step")?)?;
+
+        let series = Engine::new(&service).range(&expr, start, end, step).await?;
+        Ok(matrix(series))
+    }
+    .await;
+
+    Ok(result.map_or_else(ApiError::response, success))
+}
+
+/// Label sets of the series matching any `match[]` selector.
+pub async fn series(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let result: Result<_, ApiError> = async {
+        let params = ApiParams::from_request(&req, &body)?;
+        if params.get("match[]").is_none() {
+            return Err(ApiError::bad_data("no match[] parameter provided"));
+        }
+
+        let series = matching_series(&service, &params).await?;
+        Ok(series
+            .into_iter()
+            .map(|(name, mut labels)| {
+                labels.insert(NAME_LABEL.to_string(), name);
+                labels
+            })
+            .collect::<Vec<_>>())
+    }
+    .await;
+
+    Ok(result.map_or_else(ApiError::response, success))
+}
+
+/// Every label name in use, optionally only on series matching `match[]`.
+pub async fn labels(
+    service: web::Data<TelemetryService>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse> {
+    let result: Result<_, ApiError> = async {
+        let params = ApiParams::from_request(&req, &body)?;
+        let series = matching_series(&service, &params).await?;
+
+        let mut names: BTreeSet<&str> = series
+            .iter()
+            .flat_map(|(_, labels)| labels.keys().map(String::as_str))
+            .collect();
+        if !series.is_empty() {
+            names.insert(NAME_LABEL);
+        }
+
+        Ok(names.into_iter().map(str::to_string).collect::<Vec<_>>())
+    }
+    .await;
+
+    Ok(result.map_or_else(ApiError::response, success))
+}
+
+/// Every value of the label `name`, or every metric name for `__name__`.
+pub async fn label_values(
+    service: web::Data<TelemetryService>,
+    name: web::Path<String>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let result: Result<_, ApiError> = async {
+        let params = ApiParams::from_request(&req, &[])?;
+        let series = matching_series(&service, &params).await?;
+
+        let values: BTreeSet<&str> = series
+            .iter()
+            .filter_map(|(metric, labels)| match name.as_str() {
+                NAME_LABEL => Some(metric.as_str()),
+                name => labels.get(name).map(String::as_str),
+            })
+            .collect();
+
+        Ok(values.into_iter().map(str::to_string).collect::<Vec<_>>())
+    }
+    .await;
+
+    Ok(result.map_or_else(ApiError::response, success))
+}
+
+/// Series matching any `match[]` selector between `start` and `end`, or every
+/// series when there is no selector.
+async fn matching_series(
+    service: &TelemetryService,
+    params: &ApiParams,
+) -> Result<Vec<(String, Labels)>, ApiError> {
+    let (start, end) = (params.time("start")?, params.time("end")?);
+    let selectors = params.selectors()?;
+
+    let list = |filter: MetricFilter| async {
+        service.list_series(filter).await.map_err(|e| {
+            log::error!("Failed to list series: {e}");
+            ApiError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                error_type: "internal",
+                message: "Failed to list series".to_string(),
+            }
+        })
+    };
+
+    if selectors.is_empty() {
+        let filter = Selector {
+            matchers: Vec::new(),
+            range: None,
+        }
+        .filter(start, end);
+        return list(filter).await;
+    }
+
+    let mut series = BTreeSet::new();
+    for selector in &selectors {
+        for (name, labels) in list(selector.filter(start, end)).await? {
+            if selector.matches(&name, &labels) {
+                series.insert((name, labels));
+            }
+        }
+    }
+
+    Ok(series.into_iter().collect())
+}
@@ -1,13 +1,109 @@
-use crate::models::QueryPrompt;
-use crate::services::QueryService;
-use actix_web::{web, HttpResponse, Result};
+use crate::models::{
+    MetricFilter, ParsedQuery, QueryPrompt, RangeError, RangeParams, RangeQuery, StructuredQuery,
+};
+use crate::routes::stream::{self, StreamParams};
+use crate::services::prompt::PromptError;
+use crate::services::{QueryService, QueryStream, TelemetryService};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 
 pub async fn query_metrics(
     service: web::Data<QueryService>,
+    req: HttpRequest,
     query: web::Query<QueryPrompt>,
+    params: web::Query<StreamParams>,
 ) -> Result<HttpResponse> {
-    match service.execute_query(query.into_inner()).await {
-        Ok(metrics) => Ok(HttpResponse::Ok().json(metrics)),
+    let result = match params.format(&req) {
+        Some(format) => service
+            .stream_query(query.into_inner())
+            .await
+            .map(|result| match result {
+                QueryStream::Metrics(metrics) => stream::metrics_response(metrics, format),
+                QueryStream::Aggregation(result) => stream::value_response(&result, format),
+                QueryStream::Range(result) => stream::value_response(&result, format),
+            }),
+        None => service
+            .execute_query(query.into_inner())
+            .await
+            .map(|result| HttpResponse::Ok().json(result)),
+    };
+
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => match e.downcast::<PromptError>() {
+            Ok(e) => Ok(prompt_error_response(&e)),
+            Err(e) => {
+                log::error!("Failed to execute query: {e}");
+                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Failed to execute query"
+                })))
+            }
+        },
+    }
+}
+
+/// 422 with the message and what might help rephrase the prompt, e.g.
+/// `{"error": ..., "reason": "ambiguous", "phrase": "cpu", "candidates": [...]}`.
+fn prompt_error_response(e: &PromptError) -> HttpResponse {
+    let mut body = serde_json::json!({ "error": e.to_string() });
+    if let (Some(body), Ok(serde_json::Value::Object(details))) =
+        (body.as_object_mut(), serde_json::to_value(e))
+    {
+        body.extend(details);
+    }
+    HttpResponse::UnprocessableEntity().json(body)
+}
+
+/// Shows how a prompt is parsed and what would run for it, without running it.
+pub async fn explain_query(
+    service: web::Data<QueryService>,
+    query: web::Query<QueryPrompt>,
+) -> Result<HttpResponse> {
+    match service.explain(&query.prompt).await {
+        Ok(explanation) => Ok(HttpResponse::Ok().json(explanation)),
+        Err(e) => {
+            log::error!("Failed to explain query: {e}");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to explain query"
+            })))
+        }
+    }
+}
+
+/// Runs a `StructuredQuery`, the JSON alternative to a prompt. Responds like
+/// `GET /query`, or like `GET /query/range` when the body has a `step`.
+pub async fn structured_query(
+    service: web::Data<QueryService>,
+    req: HttpRequest,
+    body: web::Json<StructuredQuery>,
+    params: web::Query<StreamParams>,
+) -> Result<HttpResponse> {
+    let parsed = match ParsedQuery::try_from(body.into_inner()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
+    };
+
+    let result = match params.format(&req) {
+        Some(format) => service.stream(parsed).await.map(|result| match result {
+            QueryStream::Metrics(metrics) => stream::metrics_response(metrics, format),
+            QueryStream::Aggregation(result) => stream::value_response(&result, format),
+            QueryStream::Range(result) => stream::value_response(&result, format),
+        }),
+        None => service
+            .execute(parsed)
+            .await
+            .map(|result| HttpResponse::Ok().json(result)),
+    };
+
+    match result {
+        Ok(response) => Ok(response),
+        // The range is only checked against the step once the query runs
+        Err(e) if e.is::<RangeError>() => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        }))),
         Err(e) => {
             log::error!("Failed to execute query: {e}");
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({
@@ -16,3 +112,30 @@ pub async fn query_metrics(
         }
     }
 }
+
+/// Aggregates matching metrics into time buckets `step` wide, one series per
+/// metric name. See `RangeParams` for the parameters besides the metric filter.
+pub async fn query_range(
+    service: web::Data<TelemetryService>,
+    filter: web::Query<MetricFilter>,
+    params: web::Query<RangeParams>,
+) -> Result<HttpResponse> {
+    let query = match RangeQuery::new(filter.into_inner(), params.into_inner()) {
+        Ok(query) => query,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })))
+        }
+    };
+
+    match service.query_range(query).await {
+        Ok(result) => Ok(HttpResponse::Ok().json(result)),
+        Err(e) => {
+            log::error!("Failed to execute range query: {e}");
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to execute range query"
+            })))
+        }
+    }
+}
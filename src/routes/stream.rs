@@ -0,0 +1,87 @@
+use crate::db::MetricStream;
+use actix_web::{
+    error::ErrorInternalServerError, http::header, web::Bytes, HttpRequest, HttpResponse,
+};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+
+const NDJSON: &str = "application/x-ndjson";
+
+/// How a streamed response body is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+    /// One JSON document per line.
+    Ndjson,
+    /// A single JSON array, written element by element.
+    Json,
+}
+
+/// The `stream` query parameter shared by `GET /metrics` and `/query`.
+#[derive(Debug, Deserialize)]
+pub struct StreamParams {
+    pub stream: Option<StreamFormat>,
+}
+
+impl StreamParams {
+    /// The requested format, from `?stream=` or else an `Accept: application/x-ndjson`
+    /// header. `None` means a regular buffered response.
+    pub fn format(&self, req: &HttpRequest) -> Option<StreamFormat> {
+        self.stream.or_else(|| {
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .filter(|accept| accept.contains(NDJSON))
+                .map(|_| StreamFormat::Ndjson)
+        })
+    }
+}
+
+/// Writes metrics to the client as they come out of `metrics`. The body is only
+/// polled as fast as the client reads it, so memory stays constant however many
+/// metrics match. A store error after the headers are sent aborts the response,
+/// which the client sees as a truncated body.
+pub fn metrics_response(metrics: MetricStream, format: StreamFormat) -> HttpResponse {
+    let records = metrics.enumerate().map(move |(index, metric)| {
+        let metric = metric.map_err(|e| {
+            log::error!("Failed to stream metrics: {e}");
+            ErrorInternalServerError("Failed to stream metrics")
+        })?;
+
+        let mut buf = Vec::new();
+        if format == StreamFormat::Json && index > 0 {
+            buf.push(b',');
+        }
+        serde_json::to_writer(&mut buf, &metric).map_err(ErrorInternalServerError)?;
+        if format == StreamFormat::Ndjson {
+            buf.push(b'\n');
+        }
+
+        Ok::<_, actix_web::Error>(Bytes::from(buf))
+    });
+
+    match format {
+        StreamFormat::Ndjson => HttpResponse::Ok().content_type(NDJSON).streaming(records),
+        StreamFormat::Json => {
+            let open = stream::once(async { Ok(Bytes::from_static(b"[")) });
+            let close = stream::once(async { Ok(Bytes::from_static(b"]")) });
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .streaming(open.chain(records).chain(close))
+        }
+    }
+}
+
+/// Responds with a single value in `format`, for results that are not worth
+/// streaming but were requested as a stream.
+pub fn value_response<T: Serialize>(value: &T, format: StreamFormat) -> HttpResponse {
+    match format {
+        StreamFormat::Ndjson => match serde_json::to_string(value) {
+            Ok(json) => HttpResponse::Ok().content_type(NDJSON).body(json + "\n"),
+            Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            })),
+        },
+        StreamFormat::Json => HttpResponse::Ok().json(value),
+    }
+}
@@ -0,0 +1,585 @@
+use crate::config::Config;
+use crate::db::RedisDb;
+use crate::models::MetricFilter;
+use futures::StreamExt;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisResult};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a query result is served from Redis.
+const REDIS_TTL_SECS: u64 = 300;
+
+/// Generation of queries that do not filter by name; bumped by every write.
+const ALL_GENERATION_KEY: &str = "metrics:generation";
+
+/// Channel on which writes announce the metric names they touched, so every
+/// instance can evict them from its in-process tier.
+const INVALIDATION_CHANNEL: &str = "metrics:invalidations";
+
+/// Bumped to discard every Redis entry at once, after invalidations were missed.
+const EPOCH_KEY: &str = "metrics:epoch";
+
+/// Delay before resubscribing after the invalidation subscription drops.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest a Redis command may take before it counts as a failure, so a hung
+/// Redis cannot stall reads.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Consecutive Redis failures that open the circuit breaker.
+const BREAKER_THRESHOLD: u32 = 5;
+
+/// How long the breaker stays open before Redis is tried again.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Limits for the in-process cache tier. A `max_entries` of zero disables it.
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    pub max_entries: usize,
+    /// Upper bound on the total size of cached results, as serialized JSON.
+    pub max_bytes: usize,
+    pub ttl: Duration,
+}
+
+impl CacheOptions {
+    /// No in-process tier; results are cached in Redis only, if at all.
+    pub fn disabled() -> Self {
+        Self {
+            max_entries: 0,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+impl From<&Config> for CacheOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_entries: config.local_cache_max_entries,
+            max_bytes: config.local_cache_max_bytes,
+            ttl: Duration::from_secs(config.local_cache_ttl_secs),
+        }
+    }
+}
+
+/// Cache lookups since startup. `hits` counts both tiers; `local_hits` is the
+/// share served without leaving the process.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub local_hits: u64,
+    pub misses: u64,
+    /// Failed or timed out Redis commands. None of them failed a request.
+    pub redis_errors: u64,
+    /// Whether Redis is currently being bypassed after repeated failures.
+    pub circuit_open: bool,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    local_hits: AtomicU64,
+    misses: AtomicU64,
+    redis_errors: AtomicU64,
+}
+
+/// Stops calling Redis after `BREAKER_THRESHOLD` consecutive failures. Once
+/// `BREAKER_COOLDOWN` has passed calls are let through again; the first success
+/// closes the breaker and another failure reopens it.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    consecutive_failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self) -> bool {
+        self.open_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.open_until.lock().unwrap().take().is_some() {
+            log::info!("Redis cache recovered; circuit breaker closed");
+        }
+    }
+
+    fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= BREAKER_THRESHOLD {
+            let mut open_until = self.open_until.lock().unwrap();
+            if open_until.is_none() {
+                log::warn!(
+                    "Bypassing Redis cache for {}s after {failures} consecutive failures",
+                    BREAKER_COOLDOWN.as_secs()
+                );
+            }
+            *open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
+}
+
+struct LocalEntry {
+    value: Arc<str>,
+    /// Metric name the query filtered on, used for targeted eviction.
+    name: Option<String>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Bounded LRU map with per-entry expiry. Recency is tracked with a logical clock
+/// so the least recently used entry is the first in `recency`.
+struct LocalCache {
+    options: CacheOptions,
+    entries: HashMap<String, LocalEntry>,
+    recency: BTreeMap<u64, String>,
+    bytes: usize,
+    clock: u64,
+    /// Bumped on every invalidation so loads that raced a write are not cached.
+    version: u64,
+}
+
+impl LocalCache {
+    fn new(options: CacheOptions) -> Self {
+        Self {
+            options,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            bytes: 0,
+            clock: 0,
+            version: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        let expired = self.entries.get(key)?.expires_at <= Instant::now();
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(clock, key.to_string());
+        entry.last_used = clock;
+
+        Some(entry.value.clone())
+    }
+
+    /// Stores `value` unless an invalidation happened since `version` was read,
+    /// evicting least recently used entries to stay within the limits.
+    fn insert(&mut self, version: u64, key: String, name: Option<String>, value: Arc<str>) {
+        if version != self.version || value.len() > self.options.max_bytes {
+            return;
+        }
+
+        self.remove(&key);
+        while !self.entries.is_empty()
+            && (self.entries.len() >= self.options.max_entries
+                || self.bytes + value.len() > self.options.max_bytes)
+        {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+
+        self.clock += 1;
+        self.bytes += value.len();
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            LocalEntry {
+                value,
+                name,
+                expires_at: Instant::now() + self.options.ttl,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.value.len();
+        }
+    }
+
+    /// Drops entries for queries over `names` and every query not filtered by
+    /// name. `None` drops everything.
+    fn invalidate(&mut self, names: Option<&BTreeSet<String>>) {
+        self.version += 1;
+
+        let stale: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| match (names, &entry.name) {
+                (Some(names), Some(name)) => names.contains(name),
+                _ => true,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+}
+
+/// Two-tier cache for query results: a bounded in-process LRU in front of Redis.
+///
+/// Redis keys are a `MetricFilter::fingerprint` plus a generation number: the
+/// per-name generation when the filter selects one metric name, otherwise the
+/// global one. Writes increment the generations of the names they touch plus the
+/// global one, so later reads use new keys and stale entries simply age out.
+///
+/// The in-process tier cannot afford a Redis round trip to read generations, so
+/// writes also publish the touched names on `INVALIDATION_CHANNEL` and every
+/// instance evicts matching entries. Its short TTL bounds staleness should a
+/// message be lost.
+///
+/// Concurrent misses for the same query are coalesced: the first caller loads
+/// the result while the others wait for it and then read it from the cache.
+///
+/// Redis is strictly optional. Failed commands are logged and counted, reads fall
+/// through to the store, and a circuit breaker stops calling Redis while it is
+/// down. A Redis that was down at startup is connected to once it is back.
+/// Invalidations that could not be written mark the Redis tier stale, and the
+/// first command after recovery bumps `EPOCH_KEY` to discard every entry before
+/// anything is read from it.
+#[derive(Clone)]
+pub(crate) struct MetricCache {
+    redis: Option<RedisDb>,
+    local: Option<Arc<Mutex<LocalCache>>>,
+    inflight: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    counters: Arc<Counters>,
+    breaker: Arc<CircuitBreaker>,
+    redis_stale: Arc<AtomicBool>,
+}
+
+impl MetricCache {
+    pub(crate) fn new(redis: Option<RedisDb>, options: CacheOptions) -> Self {
+        let local =
+            (options.max_entries > 0).then(|| Arc::new(Mutex::new(LocalCache::new(options))));
+
+        Self {
+            redis,
+            local,
+            inflight: Arc::default(),
+            counters: Arc::default(),
+            breaker: Arc::default(),
+            redis_stale: Arc::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.redis.is_some() || self.local.is_some()
+    }
+
+    pub(crate) fn redis(&self) -> Option<&RedisDb> {
+        self.redis.as_ref()
+    }
+
+    /// Returns the cached JSON for `namespace` results of `filter`, or runs `load`
+    /// and caches what it returns.
+    pub(crate) async fn get_or_load<F>(
+        &self,
+        namespace: &str,
+        filter: &MetricFilter,
+        load: F,
+    ) -> Result<Arc<str>, Box<dyn std::error::Error>>
+    where
+        F: std::future::Future<Output = Result<String, Box<dyn std::error::Error>>>,
+    {
+        let fingerprint = filter.fingerprint();
+        let local_key = format!("{namespace}:{fingerprint}");
+
+        if let Some(value) = self.local_get(&local_key) {
+            return Ok(value);
+        }
+
+        let inflight = self.inflight_lock(&local_key);
+        let _guard = inflight.lock.lock().await;
+        self.load_once(namespace, filter, &fingerprint, &local_key, load)
+            .await
+    }
+
+    async fn load_once<F>(
+        &self,
+        namespace: &str,
+        filter: &MetricFilter,
+        fingerprint: &str,
+        local_key: &str,
+        load: F,
+    ) -> Result<Arc<str>, Box<dyn std::error::Error>>
+    where
+        F: std::future::Future<Output = Result<String, Box<dyn std::error::Error>>>,
+    {
+        // A concurrent caller may have filled the cache while this one waited
+        if let Some(value) = self.local_get(local_key) {
+            return Ok(value);
+        }
+
+        let version = self.local_version();
+        let redis_key = self.redis_key(namespace, filter, fingerprint).await;
+
+        if let Some(key) = &redis_key {
+            let cached = self
+                .redis_call(|mut conn| async move { conn.get::<_, Option<String>>(key).await })
+                .await;
+            if let Some(Some(cached)) = cached {
+                log::debug!("Cache hit for key: {key}");
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                let value: Arc<str> = cached.into();
+                self.local_insert(version, local_key, filter, value.clone());
+                return Ok(value);
+            }
+        }
+
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let value = load.await?;
+
+        if let Some(key) = &redis_key {
+            let value = &value;
+            self.redis_call(|mut conn| async move {
+                conn.set_ex::<_, _, ()>(key, value, REDIS_TTL_SECS).await
+            })
+            .await;
+        }
+
+        let value: Arc<str> = value.into();
+        self.local_insert(version, local_key, filter, value.clone());
+        Ok(value)
+    }
+
+    /// Resolves the versioned Redis key for `namespace` results of `filter`, or
+    /// `None` when Redis should not be used for this request.
+    async fn redis_key(
+        &self,
+        namespace: &str,
+        filter: &MetricFilter,
+        fingerprint: &str,
+    ) -> Option<String> {
+        self.redis.as_ref()?;
+
+        if self.redis_stale.load(Ordering::Relaxed) {
+            self.redis_call(|mut conn| async move { conn.incr::<_, _, u64>(EPOCH_KEY, 1).await })
+                .await?;
+            self.redis_stale.store(false, Ordering::Relaxed);
+        }
+
+        let generation_key = match &filter.name {
+            Some(name) => name_generation_key(name),
+            None => ALL_GENERATION_KEY.to_string(),
+        };
+
+        let (epoch, generation): (Option<u64>, Option<u64>) = self
+            .redis_call(|mut conn| async move {
+                redis::cmd("MGET")
+                    .arg(EPOCH_KEY)
+                    .arg(generation_key)
+                    .query_async(&mut conn)
+                    .await
+            })
+            .await?;
+
+        Some(format!(
+            "metrics:{namespace}:{}.{}:{fingerprint}",
+            epoch.unwrap_or(0),
+            generation.unwrap_or(0)
+        ))
+    }
+
+    /// Runs a Redis command through the circuit breaker, connecting first if
+    /// Redis was unreachable so far. Failures and timeouts are logged and
+    /// counted and come back as `None`, as does every call without Redis.
+    async fn redis_call<T, F, Fut>(&self, call: F) -> Option<T>
+    where
+        F: FnOnce(ConnectionManager) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let redis = self.redis.as_ref()?;
+        if self.breaker.is_open() {
+            return None;
+        }
+
+        let call = async { call(redis.connection().await?).await };
+        let error = match tokio::time::timeout(REDIS_TIMEOUT, call).await {
+            Ok(Ok(value)) => {
+                self.breaker.record_success();
+                return Some(value);
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+
+        log::warn!("Redis cache command failed: {error}");
+        self.counters.redis_errors.fetch_add(1, Ordering::Relaxed);
+        self.breaker.record_failure();
+        None
+    }
+
+    fn local_get(&self, key: &str) -> Option<Arc<str>> {
+        let value = self.local.as_ref()?.lock().unwrap().get(key)?;
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        self.counters.local_hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    fn local_version(&self) -> u64 {
+        self.local
+            .as_ref()
+            .map_or(0, |local| local.lock().unwrap().version)
+    }
+
+    fn local_insert(&self, version: u64, key: &str, filter: &MetricFilter, value: Arc<str>) {
+        if let Some(local) = &self.local {
+            local
+                .lock()
+                .unwrap()
+                .insert(version, key.to_string(), filter.name.clone(), value);
+        }
+    }
+
+    fn inflight_lock(&self, key: &str) -> InflightLock {
+        let lock = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        InflightLock {
+            inflight: self.inflight.clone(),
+            key: key.to_string(),
+            lock,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            local_hits: self.counters.local_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            redis_errors: self.counters.redis_errors.load(Ordering::Relaxed),
+            circuit_open: self.breaker.is_open(),
+        }
+    }
+
+    /// Evicts every query over `names`, and every query not filtered by name,
+    /// from this instance and, through Redis, from all others.
+    pub(crate) async fn invalidate<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        let names: BTreeSet<String> = names.into_iter().map(str::to_string).collect();
+        if names.is_empty() {
+            return;
+        }
+
+        if let Some(local) = &self.local {
+            local.lock().unwrap().invalidate(Some(&names));
+        }
+
+        if self.redis.is_none() {
+            return;
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.incr(ALL_GENERATION_KEY, 1).ignore();
+        for name in &names {
+            pipe.incr(name_generation_key(name), 1).ignore();
+        }
+        // Other instances may have an in-process tier even if this one does not
+        let message = serde_json::to_string(&names).unwrap_or_default();
+        pipe.publish(INVALIDATION_CHANNEL, message).ignore();
+
+        if self
+            .redis_call(|mut conn| async move { pipe.query_async::<_, ()>(&mut conn).await })
+            .await
+            .is_none()
+        {
+            log::warn!("Failed to invalidate cached queries for {names:?}");
+            self.redis_stale.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Applies invalidations published by other instances to the in-process
+    /// tier. Resubscribes after connection failures, clearing the tier since
+    /// messages may have been missed. Returns at once if either tier is absent.
+    pub(crate) async fn listen_for_invalidations(&self) {
+        let (Some(redis), Some(local)) = (&self.redis, &self.local) else {
+            return;
+        };
+
+        loop {
+            let mut pubsub = match redis.client.get_async_connection().await {
+                Ok(conn) => conn.into_pubsub(),
+                Err(e) => {
+                    log::warn!("Cache invalidation subscription failed: {e}");
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+            };
+
+            if let Err(e) = pubsub.subscribe(INVALIDATION_CHANNEL).await {
+                log::warn!("Cache invalidation subscription failed: {e}");
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                continue;
+            }
+
+            local.lock().unwrap().invalidate(None);
+
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                let names = message
+                    .get_payload::<String>()
+                    .ok()
+                    .and_then(|payload| serde_json::from_str::<BTreeSet<String>>(&payload).ok());
+                // An unreadable message could have named anything
+                local.lock().unwrap().invalidate(names.as_ref());
+            }
+
+            log::warn!("Cache invalidation subscription dropped; resubscribing");
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+/// A caller's share of the lock coalescing loads of one key. Dropping the last
+/// share removes the key from the map, even when the caller's future is dropped
+/// mid-load.
+struct InflightLock {
+    inflight: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for InflightLock {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        // Only the map and this caller hold the lock: nobody else is waiting
+        if Arc::strong_count(&self.lock) == 2 {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+fn name_generation_key(name: &str) -> String {
+    format!("metrics:generation:{name}")
+}
@@ -1,5 +1,8 @@
+mod cache;
+pub mod prompt;
 pub mod query_service;
 pub mod telemetry_service;
 
-pub use query_service::QueryService;
-pub use telemetry_service::TelemetryService;
+pub use cache::{CacheOptions, CacheStats};
+pub use query_service::{QueryService, QueryStream};
+pub use telemetry_service::{IngestOptions, PageOptions, TelemetryService};
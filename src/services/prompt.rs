@@ -0,0 +1,1160 @@
+//! A tokenizer and small grammar for natural-language query prompts.
+//!
+//! A prompt is a sequence of clauses in any order: an aggregation ("p99",
+//! "average of", "top 5", "requests per second"), a metric ("cpu_usage
+//! metrics", "events named logins"), a time range ("last 2 hours", "today"), a
+//! grouping ("by host and service"), tags ("tagged with production") and a limit
+//! ("limit 10"). Filler such as "show me the" is skipped. The remaining words
+//! name the metric and are resolved against the names in the store, so "show me
+//! memory" finds `memory_usage` and "memroy_usage" is read as a typo.
+
+use crate::models::{
+    is_valid_label_name, label_list, AggregationType, Grouping, ParsedQuery, RateFunction,
+    RuleMatch, TimeRange,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// Interpretations less certain than this are rejected.
+pub const MIN_CONFIDENCE: f64 = 0.5;
+
+/// Interpretations closer than this in confidence are too close to choose between.
+const AMBIGUITY_MARGIN: f64 = 0.05;
+
+/// Longest prompt accepted, in characters and in words. Resolving a metric
+/// name tries runs of words against every stored name, so the cost of a prompt
+/// grows with both.
+pub const MAX_PROMPT_CHARS: usize = 500;
+pub const MAX_PROMPT_WORDS: usize = 50;
+
+/// Most consecutive words tried as one metric name.
+const MAX_NAME_WORDS: usize = 4;
+
+/// Words that mean nothing on their own in a prompt.
+const FILLER_WORDS: &[&str] = &[
+    "a", "all", "an", "and", "any", "anything", "are", "as", "at", "display", "during", "each",
+    "find", "for", "from", "get", "give", "how", "i", "in", "is", "list", "me", "of", "on", "over",
+    "please", "see", "show", "tell", "that", "the", "to", "value", "values", "want", "was", "were",
+    "what", "which", "with",
+];
+
+/// Words that start a clause, so never end up in a metric name or label list.
+const KEYWORDS: &[&str] = &[
+    "average",
+    "avg",
+    "by",
+    "called",
+    "change",
+    "count",
+    "deriv",
+    "derivative",
+    "delta",
+    "highest",
+    "increase",
+    "instant",
+    "instantaneous",
+    "irate",
+    "largest",
+    "last",
+    "limit",
+    "lowest",
+    "max",
+    "maximum",
+    "mean",
+    "median",
+    "metric",
+    "metrics",
+    "min",
+    "minimum",
+    "named",
+    "past",
+    "peak",
+    "per",
+    "percentile",
+    "rate",
+    "since",
+    "smallest",
+    "standard",
+    "stddev",
+    "stdev",
+    "sum",
+    "tag",
+    "tagged",
+    "tags",
+    "today",
+    "top",
+    "total",
+    "where",
+    "without",
+    "yesterday",
+];
+
+/// A word or punctuation mark of a lowercased prompt, with its byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Splits a lowercased prompt into words and the punctuation the grammar uses.
+/// Words keep inner dots, colons and dashes, as in `p99.9`, `cpu.usage` or `web-1`.
+pub fn tokenize(prompt: &str) -> Vec<Token<'_>> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut chars = prompt.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if is_word(c) {
+            let mut end = start + c.len_utf8();
+            while let Some(&(i, c)) = chars.peek() {
+                if is_word(c) {
+                    end = i + c.len_utf8();
+                } else if matches!(c, '.' | ':' | '-') && prompt[i + 1..].starts_with(is_word) {
+                    end = i + 1;
+                } else {
+                    break;
+                }
+                chars.next();
+            }
+            tokens.push(Token {
+                text: &prompt[start..end],
+                start,
+                end,
+            });
+        } else if matches!(c, '[' | ']' | ',' | '=' | ':') {
+            tokens.push(Token {
+                text: &prompt[start..start + 1],
+                start,
+                end: start + 1,
+            });
+        }
+    }
+
+    tokens
+}
+
+/// One reading of a prompt and how sure the parser is of it, between 0 and 1.
+#[derive(Debug, Clone, Serialize)]
+pub struct Interpretation {
+    pub query: ParsedQuery,
+    pub confidence: f64,
+}
+
+/// The result of parsing a prompt: the most likely query, other readings of it,
+/// and which clause of the grammar produced each part.
+#[derive(Debug)]
+pub struct PromptParse {
+    pub best: Interpretation,
+    /// Other metrics the words could name, or other aggregations the prompt
+    /// mentions, most likely first.
+    pub alternatives: Vec<Interpretation>,
+    /// Recognized clauses in prompt order.
+    pub matches: Vec<RuleMatch>,
+    /// Words no clause or metric name accounts for.
+    pub unrecognized: Vec<String>,
+    /// Why the prompt cannot be run as is.
+    pub error: Option<PromptError>,
+}
+
+impl PromptParse {
+    /// The query to run, or why the prompt was not understood.
+    pub fn into_query(self) -> Result<ParsedQuery, PromptError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.best.query),
+        }
+    }
+}
+
+/// Why a prompt was rejected, with what might help the caller rephrase it.
+#[derive(Debug, Clone, PartialEq, Serialize, thiserror::Error)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum PromptError {
+    #[error("the prompt is empty")]
+    Empty,
+    #[error("the prompt is too long; keep it under {MAX_PROMPT_CHARS} characters and {MAX_PROMPT_WORDS} words")]
+    TooLong,
+    #[error("the prompt does not ask for anything; name a metric, e.g. \"average cpu_usage last 1 hours\"")]
+    NoQuery,
+    #[error("could not understand {}{}", quoted(.words), did_you_mean(.suggestions))]
+    Unrecognized {
+        words: Vec<String>,
+        suggestions: Vec<String>,
+    },
+    #[error("{phrase:?} could mean any of {}; name one of them", quoted(.candidates))]
+    Ambiguous {
+        phrase: String,
+        candidates: Vec<String>,
+    },
+    #[error("not confident enough in any reading of the prompt ({confidence:.2})")]
+    LowConfidence { confidence: f64 },
+}
+
+fn quoted(words: &[String]) -> String {
+    let quoted: Vec<String> = words.iter().map(|word| format!("{word:?}")).collect();
+    quoted.join(", ")
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!("; did you mean {}?", quoted(suggestions))
+    }
+}
+
+/// Parses `prompt`, resolving metric names against `catalog`, the names of all
+/// stored metrics. Without a catalog, or with an empty one, the words naming the
+/// metric are taken as they are. Prompts longer than `MAX_PROMPT_CHARS` or
+/// `MAX_PROMPT_WORDS` are rejected unread.
+pub fn parse(prompt: &str, catalog: Option<&[String]>) -> PromptParse {
+    let too_long = || {
+        let mut parse = Reading::new("", Vec::new()).finish("", true, None);
+        parse.error = Some(PromptError::TooLong);
+        parse
+    };
+    if prompt.chars().count() > MAX_PROMPT_CHARS {
+        return too_long();
+    }
+
+    let prompt = prompt.to_lowercase();
+    let catalog = catalog.filter(|names| !names.is_empty());
+    let tokens = tokenize(&prompt);
+    if tokens.len() > MAX_PROMPT_WORDS {
+        return too_long();
+    }
+
+    let items = Parser {
+        tokens: &tokens,
+        pos: 0,
+        now: Utc::now(),
+        items: Vec::new(),
+    }
+    .parse();
+
+    Reading::new(&prompt, items).finish(&prompt, tokens.is_empty(), catalog)
+}
+
+/// What a run of tokens was recognized as.
+#[derive(Debug)]
+enum Item<'a> {
+    Clause(Clause),
+    /// A word that may be part of a metric name.
+    Word(Token<'a>),
+    /// The words that follow name a metric: "named", "metrics called", or "of"
+    /// after an aggregation.
+    NameNext(Anchor),
+    /// The words before name a metric: "metrics" or "per second".
+    NameBefore(Anchor),
+    /// "metrics" or "everything" without a name: a query over all metrics.
+    AllMetrics,
+    Filler,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    rule: &'static str,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug)]
+struct Clause {
+    rule: &'static str,
+    start: usize,
+    end: usize,
+    value: ClauseValue,
+}
+
+#[derive(Debug)]
+enum ClauseValue {
+    Aggregation(AggregationType),
+    TimeRange(TimeRange),
+    Grouping(Grouping),
+    Tags(Vec<String>),
+    Limit(i64),
+}
+
+impl ClauseValue {
+    fn part(&self) -> &'static str {
+        match self {
+            ClauseValue::Aggregation(_) => "aggregation",
+            ClauseValue::TimeRange(_) => "time_range",
+            ClauseValue::Grouping(_) => "grouping",
+            ClauseValue::Tags(_) => "tags",
+            ClauseValue::Limit(_) => "limit",
+        }
+    }
+}
+
+/// Recursive descent over tokens. Each rule either consumes the tokens of one
+/// clause and returns `true`, or leaves the position alone.
+struct Parser<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    pos: usize,
+    now: DateTime<Utc>,
+    items: Vec<Item<'a>>,
+}
+
+impl<'a> Parser<'_, 'a> {
+    fn parse(mut self) -> Vec<Item<'a>> {
+        while self.pos < self.tokens.len() {
+            let matched = self.limit()
+                || self.top()
+                || self.percentile()
+                || self.rate()
+                || self.reduction()
+                || self.time_range()
+                || self.grouping()
+                || self.tags()
+                || self.anchor();
+
+            if !matched {
+                let token = self.tokens[self.pos];
+                let is_word = token
+                    .text
+                    .starts_with(|c: char| c.is_alphanumeric() || c == '_');
+                self.items.push(match token.text {
+                    "everything" => Item::AllMetrics,
+                    text if !is_word || FILLER_WORDS.contains(&text) => Item::Filler,
+                    _ => Item::Word(token),
+                });
+                self.pos += 1;
+            }
+        }
+
+        self.items
+    }
+
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.tokens.get(self.pos + offset).map(|token| token.text)
+    }
+
+    /// The byte span of the next `len` tokens.
+    fn span(&self, len: usize) -> (usize, usize) {
+        (
+            self.tokens[self.pos].start,
+            self.tokens[self.pos + len - 1].end,
+        )
+    }
+
+    /// Records a clause over the next `len` tokens and moves past them.
+    fn clause(&mut self, len: usize, rule: &'static str, value: ClauseValue) -> bool {
+        let (start, end) = self.span(len);
+        self.items.push(Item::Clause(Clause {
+            rule,
+            start,
+            end,
+            value,
+        }));
+        self.pos += len;
+        true
+    }
+
+    fn anchor_over(&mut self, len: usize, rule: &'static str) -> Anchor {
+        let (start, end) = self.span(len);
+        self.pos += len;
+        Anchor { rule, start, end }
+    }
+
+    /// `limit NUMBER`
+    fn limit(&mut self) -> bool {
+        match (
+            self.peek(0),
+            self.peek(1).and_then(|n| n.parse::<i64>().ok()),
+        ) {
+            (Some("limit"), Some(n)) if n >= 0 => {
+                self.clause(2, "limit NUMBER", ClauseValue::Limit(n))
+            }
+            _ => false,
+        }
+    }
+
+    /// `top NUMBER`
+    fn top(&mut self) -> bool {
+        match (
+            self.peek(0),
+            self.peek(1).and_then(|n| n.parse::<usize>().ok()),
+        ) {
+            (Some("top"), Some(n)) => self.clause(
+                2,
+                "top NUMBER",
+                ClauseValue::Aggregation(AggregationType::Top(n)),
+            ),
+            _ => false,
+        }
+    }
+
+    /// `pNUMBER`, `NUMBER[th] percentile` or `percentile NUMBER`, between 0 and 100.
+    fn percentile(&mut self) -> bool {
+        let value = |s: &str| {
+            s.parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(|p| ClauseValue::Aggregation(AggregationType::Percentile(p)))
+        };
+        let ordinal = |s: &'a str| {
+            ["st", "nd", "rd", "th"]
+                .iter()
+                .find_map(|suffix| s.strip_suffix(suffix))
+                .unwrap_or(s)
+        };
+        let Some(first) = self.peek(0) else {
+            return false;
+        };
+
+        if let Some(p) = first.strip_prefix('p').and_then(value) {
+            return self.clause(1, "pNUMBER", p);
+        }
+
+        if self.peek(1) == Some("percentile") {
+            if let Some(p) = value(ordinal(first)) {
+                return self.clause(2, "NUMBER[th] percentile", p);
+            }
+        }
+
+        match (first, self.peek(1).and_then(value)) {
+            ("percentile", Some(p)) => self.clause(2, "percentile NUMBER", p),
+            _ => false,
+        }
+    }
+
+    /// Functions of how a counter or gauge changes: `rate`, `irate`, `increase`,
+    /// `delta`, `derivative`, and `NAME per second`.
+    fn rate(&mut self) -> bool {
+        let (len, rule, function) = match (self.peek(0), self.peek(1)) {
+            (Some("irate"), _) => (1, "irate", RateFunction::Irate),
+            (Some("instant" | "instantaneous"), Some("rate")) => {
+                (2, "instant rate", RateFunction::Irate)
+            }
+            (Some("derivative" | "deriv"), _) => (1, "derivative", RateFunction::Derivative),
+            (Some("rate"), _) => (1, "rate", RateFunction::Rate),
+            (Some("per"), Some("second" | "sec" | "s")) => {
+                let anchor = Anchor {
+                    rule: "NAME per second",
+                    start: self.tokens[self.pos].start,
+                    end: self.tokens[self.pos + 1].end,
+                };
+                self.items.push(Item::NameBefore(anchor));
+                (2, "per second", RateFunction::Rate)
+            }
+            (Some("increase"), _) => (1, "increase", RateFunction::Increase),
+            (Some("delta"), _) => (1, "delta", RateFunction::Delta),
+            (Some("change"), Some("in" | "of")) => (1, "change in", RateFunction::Delta),
+            _ => return false,
+        };
+
+        self.clause(
+            len,
+            rule,
+            ClauseValue::Aggregation(AggregationType::Rate(function)),
+        )
+    }
+
+    /// Reductions of all matching values to one.
+    fn reduction(&mut self) -> bool {
+        let (len, rule, aggregation) = match (self.peek(0), self.peek(1)) {
+            (Some("median"), _) => (1, "median", AggregationType::Median),
+            (Some("stddev" | "stdev"), _) => (1, "stddev", AggregationType::StdDev),
+            (Some("std"), Some("dev")) => (2, "std dev", AggregationType::StdDev),
+            (Some("standard"), Some("deviation")) => {
+                (2, "standard deviation", AggregationType::StdDev)
+            }
+            (Some("min" | "minimum" | "lowest" | "smallest"), _) => {
+                (1, "min | minimum | lowest | smallest", AggregationType::Min)
+            }
+            (Some("max" | "maximum" | "highest" | "largest" | "peak"), _) => (
+                1,
+                "max | maximum | highest | largest | peak",
+                AggregationType::Max,
+            ),
+            (Some("average" | "avg" | "mean"), _) => {
+                (1, "average | avg | mean", AggregationType::Average)
+            }
+            (Some("sum" | "total"), _) => (1, "sum | total", AggregationType::Sum),
+            (Some("count"), _) => (1, "count", AggregationType::Count),
+            // Only "number of", so a metric like "error number" is not a count
+            (Some("number"), Some("of")) => (1, "number of", AggregationType::Count),
+            (Some("how"), Some("many")) => (2, "how many", AggregationType::Count),
+            _ => return false,
+        };
+
+        self.clause(len, rule, ClauseValue::Aggregation(aggregation))
+    }
+
+    /// `today`, `yesterday`, or `last [NUMBER] UNIT`.
+    fn time_range(&mut self) -> bool {
+        let now = self.now;
+        let midnight =
+            |day: DateTime<Utc>| day.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+
+        match self.peek(0) {
+            Some("today") => self.clause(
+                1,
+                "today",
+                ClauseValue::TimeRange(TimeRange {
+                    start: Some(midnight(now)),
+                    end: Some(now),
+                }),
+            ),
+            Some("yesterday") => {
+                let today = midnight(now);
+                self.clause(
+                    1,
+                    "yesterday",
+                    ClauseValue::TimeRange(TimeRange {
+                        start: Some(today - Duration::days(1)),
+                        end: Some(today - Duration::seconds(1)),
+                    }),
+                )
+            }
+            Some("last" | "past") => {
+                let (amount, unit) = match self.peek(1).and_then(|n| n.parse::<i64>().ok()) {
+                    Some(amount) => (amount, 2),
+                    None => (1, 1),
+                };
+
+                let duration = match self.peek(unit) {
+                    Some("minute" | "minutes" | "min" | "mins") => Duration::try_minutes(amount),
+                    Some("hour" | "hours" | "hr" | "hrs") => Duration::try_hours(amount),
+                    Some("day" | "days") => Duration::try_days(amount),
+                    Some("week" | "weeks") => Duration::try_weeks(amount),
+                    Some("month" | "months") => amount.checked_mul(30).and_then(Duration::try_days),
+                    _ => None,
+                };
+
+                match duration.and_then(|duration| now.checked_sub_signed(duration)) {
+                    Some(start) => self.clause(
+                        unit + 1,
+                        "last [NUMBER] UNIT",
+                        ClauseValue::TimeRange(TimeRange {
+                            start: Some(start),
+                            end: Some(now),
+                        }),
+                    ),
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// `by LABEL[, LABEL | and LABEL]...` or the same with `without`.
+    fn grouping(&mut self) -> bool {
+        let (rule, by) = match self.peek(0) {
+            Some("by") => ("by LABEL[, LABEL]", true),
+            Some("without") => ("without LABEL[, LABEL]", false),
+            _ => return false,
+        };
+
+        let mut keys = Vec::new();
+        let mut len = 0;
+        let mut offset = 1;
+        while let Some(word) = self.peek(offset) {
+            match word {
+                "," | "and" => {}
+                _ if is_valid_label_name(word) && !is_keyword(word) => {
+                    keys.push(word);
+                    len = offset + 1;
+                }
+                _ => break,
+            }
+            offset += 1;
+        }
+
+        match label_list(keys) {
+            Ok(keys) if !keys.is_empty() => {
+                let grouping = if by {
+                    Grouping::By(keys)
+                } else {
+                    Grouping::Without(keys)
+                };
+                self.clause(len, rule, ClauseValue::Grouping(grouping))
+            }
+            _ => false,
+        }
+    }
+
+    /// `tagged with TAG[, TAG]...` or `tags [TAG, ...]`, optionally `tags: [...]`.
+    fn tags(&mut self) -> bool {
+        let (rule, mut offset, bracketed) = match (self.peek(0), self.peek(1), self.peek(2)) {
+            (Some("tagged" | "tag"), Some("with"), _) => ("tagged with TAG[, TAG]", 2, false),
+            (Some("tag" | "tags"), Some("[" | "=" | ":"), _) => {
+                let offset = if self.peek(1) == Some("[") { 2 } else { 3 };
+                if self.peek(offset - 1) != Some("[") {
+                    return false;
+                }
+                ("tags [TAG, ...]", offset, true)
+            }
+            _ => return false,
+        };
+
+        let mut tags = Vec::new();
+        let mut len = 0;
+        while let Some(word) = self.peek(offset) {
+            match word {
+                "," => {}
+                "]" if bracketed => {
+                    len = offset + 1;
+                    break;
+                }
+                "[" | "]" | "=" | ":" => break,
+                // A list without brackets ends at the next word not after a comma
+                _ if !bracketed && !tags.is_empty() && self.peek(offset - 1) != Some(",") => break,
+                _ => {
+                    tags.push(word.to_string());
+                    if !bracketed {
+                        len = offset + 1;
+                    }
+                }
+            }
+            offset += 1;
+        }
+
+        if tags.is_empty() || len == 0 {
+            return false;
+        }
+        self.clause(len, rule, ClauseValue::Tags(tags))
+    }
+
+    /// Words that mark the words around them as a metric name.
+    fn anchor(&mut self) -> bool {
+        let after_aggregation = matches!(
+            self.items.last(),
+            Some(Item::Clause(Clause {
+                value: ClauseValue::Aggregation(_),
+                ..
+            }))
+        );
+
+        match (self.peek(0), self.peek(1)) {
+            (Some("metric" | "metrics" | "event" | "events"), Some("named" | "called")) => {
+                let anchor = self.anchor_over(2, "metrics named NAME");
+                self.named(anchor)
+            }
+            (Some("named" | "called"), _) => {
+                let anchor = self.anchor_over(1, "named NAME");
+                self.named(anchor)
+            }
+            (Some("metric" | "metrics"), _) => {
+                let anchor = self.anchor_over(1, "NAME metrics");
+                let item = match self.items.last() {
+                    Some(Item::Word(_)) => Item::NameBefore(anchor),
+                    _ => Item::AllMetrics,
+                };
+                self.items.push(item);
+                true
+            }
+            (Some("of" | "in"), _) if after_aggregation => {
+                let anchor = self.anchor_over(1, "AGGREGATION of NAME");
+                self.items.push(Item::NameNext(anchor));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The word after "named" is the name, even if it is also a keyword.
+    fn named(&mut self, anchor: Anchor) -> bool {
+        self.items.push(Item::NameNext(anchor));
+        if let Some(&token) = self.tokens.get(self.pos) {
+            if token
+                .text
+                .starts_with(|c: char| c.is_alphanumeric() || c == '_')
+            {
+                self.items.push(Item::Word(token));
+                self.pos += 1;
+            }
+        }
+        true
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&word) || FILLER_WORDS.contains(&word)
+}
+
+/// Consecutive words that may name a metric.
+#[derive(Debug)]
+struct Phrase<'a> {
+    words: Vec<Token<'a>>,
+    /// The clause marking the words as a metric name, if any.
+    anchor: Option<Anchor>,
+}
+
+impl Phrase<'_> {
+    fn text(&self) -> Vec<&str> {
+        self.words.iter().map(|word| word.text).collect()
+    }
+
+    /// The span of the words and their anchor, for `RuleMatch`.
+    fn span(&self) -> (usize, usize) {
+        let start = self.words.first().map_or(0, |word| word.start);
+        let end = self.words.last().map_or(0, |word| word.end);
+        match self.anchor {
+            Some(anchor) => (start.min(anchor.start), end.max(anchor.end)),
+            None => (start, end),
+        }
+    }
+}
+
+/// A metric a phrase may name: the catalog name, or the words themselves, with
+/// the score of the match and which of the phrase's words it used.
+#[derive(Debug, Clone)]
+struct Candidate {
+    name: String,
+    score: f64,
+    used: std::ops::Range<usize>,
+}
+
+/// The parts of a prompt gathered from its items.
+struct Reading<'a> {
+    aggregations: Vec<(AggregationType, &'static str)>,
+    time_range: Option<TimeRange>,
+    grouping: Option<Grouping>,
+    tags: Option<Vec<String>>,
+    limit: Option<i64>,
+    matches: Vec<RuleMatch>,
+    phrases: Vec<Phrase<'a>>,
+    all_metrics: bool,
+}
+
+impl<'a> Reading<'a> {
+    fn new(prompt: &str, items: Vec<Item<'a>>) -> Self {
+        let mut reading = Reading {
+            aggregations: Vec::new(),
+            time_range: None,
+            grouping: None,
+            tags: None,
+            limit: None,
+            matches: Vec::new(),
+            phrases: Vec::new(),
+            all_metrics: false,
+        };
+
+        let mut words: Vec<Token<'a>> = Vec::new();
+        let mut before: Option<Anchor> = None;
+
+        for item in items {
+            if let Item::Word(token) = item {
+                words.push(token);
+                continue;
+            }
+
+            if !words.is_empty() {
+                let anchor = match &item {
+                    Item::NameBefore(anchor) => Some(*anchor),
+                    _ => before,
+                };
+                reading.phrases.push(Phrase {
+                    words: std::mem::take(&mut words),
+                    anchor,
+                });
+            }
+
+            before = None;
+            match item {
+                Item::Clause(clause) => reading.add(prompt, clause),
+                Item::NameNext(anchor) => before = Some(anchor),
+                Item::AllMetrics => reading.all_metrics = true,
+                Item::Word(_) | Item::NameBefore(_) | Item::Filler => {}
+            }
+        }
+
+        if !words.is_empty() {
+            reading.phrases.push(Phrase {
+                words,
+                anchor: before,
+            });
+        }
+
+        // Words marked as a name are the metric before any others
+        reading
+            .phrases
+            .sort_by_key(|phrase| phrase.anchor.is_none());
+        reading
+    }
+
+    fn add(&mut self, prompt: &str, clause: Clause) {
+        self.matches.push(RuleMatch::new(
+            clause.value.part(),
+            clause.rule,
+            prompt,
+            clause.start,
+            clause.end,
+        ));
+
+        match clause.value {
+            ClauseValue::Aggregation(aggregation) => {
+                if !self.aggregations.iter().any(|(a, _)| *a == aggregation) {
+                    self.aggregations.push((aggregation, clause.rule));
+                }
+            }
+            ClauseValue::TimeRange(range) => {
+                self.time_range.get_or_insert(range);
+            }
+            ClauseValue::Grouping(grouping) => {
+                self.grouping.get_or_insert(grouping);
+            }
+            ClauseValue::Tags(tags) => {
+                self.tags.get_or_insert(tags);
+            }
+            ClauseValue::Limit(limit) => {
+                self.limit.get_or_insert(limit);
+            }
+        }
+    }
+
+    fn finish(mut self, prompt: &str, empty: bool, catalog: Option<&[String]>) -> PromptParse {
+        // The most specific aggregation wins; the others become alternatives
+        self.aggregations
+            .sort_by_key(|(aggregation, _)| priority(*aggregation));
+        let aggregation = self
+            .aggregations
+            .first()
+            .map(|(aggregation, _)| *aggregation);
+
+        let mut unrecognized: Vec<String> = Vec::new();
+        let mut metric: Option<(Candidate, &Phrase)> = None;
+        let mut other_metrics: Vec<Candidate> = Vec::new();
+
+        for phrase in &self.phrases {
+            let candidates = resolve(phrase, catalog, metric.is_none());
+            let Some(best) = candidates.first().cloned() else {
+                unrecognized.extend(phrase.text().into_iter().map(str::to_string));
+                continue;
+            };
+
+            // Words of the phrase outside the matched name
+            let words = phrase.text();
+            unrecognized.extend(
+                words[..best.used.start]
+                    .iter()
+                    .chain(&words[best.used.end..])
+                    .map(|word| word.to_string()),
+            );
+
+            match &metric {
+                None => {
+                    other_metrics.extend(candidates.into_iter().skip(1));
+                    metric = Some((best, phrase));
+                }
+                Some(_) => other_metrics.extend(candidates),
+            }
+        }
+
+        let mut matches = self.matches;
+        if let Some((_, phrase)) = &metric {
+            let (start, end) = phrase.span();
+            let rule = phrase.anchor.map_or("NAME", |anchor| anchor.rule);
+            matches.push(RuleMatch::new("metric_name", rule, prompt, start, end));
+        }
+        matches.sort_by_key(|rule| (rule.start, rule.end));
+
+        let penalty = 0.5f64.powi(unrecognized.len() as i32)
+            * if self.aggregations.len() > 1 {
+                0.9
+            } else {
+                1.0
+            };
+        let query =
+            |metric_name: Option<String>, aggregation: Option<AggregationType>| ParsedQuery {
+                metric_name,
+                tags: self.tags.clone(),
+                time_range: self.time_range.clone(),
+                aggregation,
+                grouping: self.grouping.clone().unwrap_or_default(),
+                limit: self.limit,
+                ..Default::default()
+            };
+
+        let metric_score = metric
+            .as_ref()
+            .map_or(1.0, |(candidate, _)| candidate.score);
+        let metric_name = metric.as_ref().map(|(candidate, _)| candidate.name.clone());
+        let best = Interpretation {
+            query: query(metric_name.clone(), aggregation),
+            confidence: metric_score * penalty,
+        };
+
+        let mut alternatives: Vec<Interpretation> = other_metrics
+            .iter()
+            .map(|candidate| Interpretation {
+                query: query(Some(candidate.name.clone()), aggregation),
+                confidence: candidate.score * penalty,
+            })
+            .chain(
+                self.aggregations
+                    .iter()
+                    .skip(1)
+                    .map(|(other, _)| Interpretation {
+                        query: query(metric_name.clone(), Some(*other)),
+                        confidence: metric_score * penalty * 0.6,
+                    }),
+            )
+            .collect();
+        alternatives.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        let mut seen = vec![(metric_name.clone(), aggregation)];
+        alternatives.retain(|alternative| {
+            let key = (
+                alternative.query.metric_name.clone(),
+                alternative.query.aggregation,
+            );
+            let new = !seen.contains(&key);
+            seen.push(key);
+            new
+        });
+
+        // Other metrics about as likely as the chosen one
+        let rivals: Vec<String> = alternatives
+            .iter()
+            .filter(|alternative| alternative.query.metric_name != metric_name)
+            .filter(|alternative| best.confidence - alternative.confidence < AMBIGUITY_MARGIN)
+            .filter_map(|alternative| alternative.query.metric_name.clone())
+            .collect();
+
+        let understood = !matches.is_empty() || self.all_metrics;
+        let error = if empty {
+            Some(PromptError::Empty)
+        } else if !unrecognized.is_empty() {
+            Some(PromptError::Unrecognized {
+                suggestions: suggestions(&unrecognized, catalog),
+                words: unrecognized.clone(),
+            })
+        } else if !rivals.is_empty() {
+            let phrase = metric
+                .as_ref()
+                .map(|(_, phrase)| phrase.text().join(" "))
+                .unwrap_or_default();
+            let mut candidates: Vec<String> = metric_name.iter().cloned().collect();
+            candidates.extend(rivals);
+            Some(PromptError::Ambiguous { phrase, candidates })
+        } else if !understood {
+            Some(PromptError::NoQuery)
+        } else if best.confidence < MIN_CONFIDENCE {
+            Some(PromptError::LowConfidence {
+                confidence: best.confidence,
+            })
+        } else {
+            None
+        };
+
+        PromptParse {
+            best,
+            alternatives,
+            matches,
+            unrecognized,
+            error,
+        }
+    }
+}
+
+/// Lower is more specific: `top 5 highest` is a top 5, `total requests per
+/// second` a rate.
+fn priority(aggregation: AggregationType) -> u8 {
+    match aggregation {
+        AggregationType::Top(_) => 0,
+        AggregationType::Percentile(_) => 1,
+        AggregationType::Rate(RateFunction::Irate) => 2,
+        AggregationType::Rate(RateFunction::Derivative) => 3,
+        AggregationType::Rate(RateFunction::Rate) => 4,
+        AggregationType::Rate(RateFunction::Increase) => 5,
+        AggregationType::Rate(RateFunction::Delta) => 6,
+        AggregationType::Median => 7,
+        AggregationType::StdDev => 8,
+        AggregationType::Min => 9,
+        AggregationType::Max => 10,
+        AggregationType::Average => 11,
+        AggregationType::Sum => 12,
+        AggregationType::Count => 13,
+    }
+}
+
+/// The metrics `phrase` may name, best first. Against a catalog every run of up
+/// to `MAX_NAME_WORDS` consecutive words is tried, so stray words around a name
+/// are left over.
+/// Without one, the words joined by `_` are the name when the phrase is marked
+/// as one, or when it is the first phrase and `first` says so.
+fn resolve(phrase: &Phrase, catalog: Option<&[String]>, first: bool) -> Vec<Candidate> {
+    let words = phrase.text();
+
+    let Some(catalog) = catalog else {
+        let score = match (phrase.anchor, first) {
+            (Some(_), _) => 0.9,
+            (None, true) => 0.6,
+            (None, false) => return Vec::new(),
+        };
+        return vec![Candidate {
+            name: words.join("_"),
+            score,
+            used: 0..words.len(),
+        }];
+    };
+
+    // Split once rather than for every run of words
+    let names: Vec<(&String, Vec<&str>)> = catalog
+        .iter()
+        .map(|name| (name, name_parts(name)))
+        .collect();
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for start in 0..words.len() {
+        for end in start + 1..=words.len().min(start + MAX_NAME_WORDS) {
+            let run = NameWords::new(&words[start..end]);
+            for &(name, ref parts) in &names {
+                let score = name_score(&run, name, parts);
+                if score > 0.0 {
+                    candidates.push(Candidate {
+                        name: name.clone(),
+                        score,
+                        used: start..end,
+                    });
+                }
+            }
+        }
+    }
+
+    // Best score, then most words used, then catalog order
+    candidates.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| b.used.len().cmp(&a.used.len()))
+    });
+    let mut seen = std::collections::HashSet::new();
+    candidates.retain(|candidate| seen.insert(candidate.name.clone()));
+
+    if candidates.is_empty() && phrase.anchor.is_some() {
+        // Named explicitly, so taken at its word even though nothing is stored under it
+        candidates.push(Candidate {
+            name: words.join("_"),
+            score: MIN_CONFIDENCE,
+            used: 0..words.len(),
+        });
+    }
+
+    candidates
+}
+
+/// The components of a metric name.
+fn name_parts(name: &str) -> Vec<&str> {
+    name.split(['_', '.', ':', '-']).collect()
+}
+
+/// A run of words tried as a metric name, in the forms a name may join them.
+struct NameWords<'a> {
+    words: &'a [&'a str],
+    joined: String,
+    dotted: String,
+    concat: String,
+}
+
+impl<'a> NameWords<'a> {
+    fn new(words: &'a [&'a str]) -> Self {
+        Self {
+            words,
+            joined: words.join("_"),
+            dotted: words.join("."),
+            concat: words.concat(),
+        }
+    }
+}
+
+/// How well `words` name the metric `name`, split into `parts`: 1 for the name
+/// itself, less for a plural, for words that are components of a longer name,
+/// or for a typo.
+fn name_score(words: &NameWords, name: &str, parts: &[&str]) -> f64 {
+    let joined = words.joined.as_str();
+    if name == joined || name == words.dotted || name == words.concat {
+        return 1.0;
+    }
+
+    if name.strip_suffix('s') == Some(joined) || joined.strip_suffix('s') == Some(name) {
+        return 0.9;
+    }
+
+    // "cpu" in cpu_usage, "http requests" in http_requests_total
+    let mut remaining = parts.iter();
+    if words
+        .words
+        .iter()
+        .all(|word| remaining.any(|part| part == word || part.strip_suffix('s') == Some(word)))
+    {
+        return 0.6 + 0.3 * words.words.len() as f64 / parts.len() as f64;
+    }
+
+    // One typo allowed per four characters
+    match edits_within(joined, name, (joined.chars().count() / 4).max(1)) {
+        Some(distance) => 0.85 - 0.1 * distance as f64,
+        None => 0.0,
+    }
+}
+
+/// Catalog names close to the unrecognized words, closest first.
+fn suggestions(words: &[String], catalog: Option<&[String]>) -> Vec<String> {
+    let Some(catalog) = catalog else {
+        return Vec::new();
+    };
+
+    let mut unique: Vec<&String> = words.iter().collect();
+    unique.sort();
+    unique.dedup();
+
+    let mut close: Vec<(usize, &String)> = unique
+        .into_iter()
+        .flat_map(|word| {
+            catalog.iter().filter_map(move |name| {
+                // Against the whole name or one of its components, so "memroy"
+                // suggests memory_usage
+                let budget = (word.chars().count() / 3).max(2);
+                let distance = name_parts(name)
+                    .into_iter()
+                    .chain([name.as_str()])
+                    .filter_map(|part| edits_within(word, part, budget))
+                    .min()
+                    .unwrap_or(usize::MAX);
+                let related = distance <= budget
+                    || (word.chars().count() >= 3 && name.contains(word.as_str()));
+                related.then_some((distance, name))
+            })
+        })
+        .collect();
+
+    close.sort();
+    let mut names: Vec<String> = Vec::new();
+    for (_, name) in close {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names.truncate(3);
+    names
+}
+
+/// The edit distance in characters between `a` and `b`, if it is at most
+/// `budget`. Strings whose lengths differ by more are not compared, and the
+/// comparison stops as soon as every alignment is over budget.
+fn edits_within(a: &str, b: &str, budget: usize) -> Option<usize> {
+    if a.chars().count().abs_diff(b.chars().count()) > budget {
+        return None;
+    }
+    let b: Vec<char> = b.chars().collect();
+
+    // Levenshtein, one row at a time
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        // No row has a smaller minimum than the one before it
+        if current.iter().all(|&distance| distance > budget) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    Some(previous[b.len()]).filter(|&distance| distance <= budget)
+}
@@ -1,7 +1,16 @@
-use crate::models::{AggregationType, Metric, ParsedQuery, QueryPrompt, TimeRange};
+use crate::db::mongo::{self, MongoCommand};
+use crate::db::MetricStream;
+use crate::models::{
+    AggregationResult, AggregationType, MetricFilter, ParsedQuery, QueryPrompt, QueryResult,
+    RangeError, RangeQuery, RangeResult, RuleMatch, SortField, SortOrder, Step,
+};
+use crate::services::prompt::{self, Interpretation, PromptParse};
 use crate::services::TelemetryService;
-use chrono::{Duration, Utc};
-use regex::Regex;
+use chrono::Duration;
+use serde::Serialize;
+
+/// Prompt time ranges are rounded to this granularity in cache keys.
+const TIME_BUCKET_SECS: i64 = 60;
 
 #[derive(Clone)]
 pub struct QueryService {
@@ -13,209 +22,284 @@ impl QueryService {
         Self { telemetry_service }
     }
 
+    /// Parses a prompt without checking its metric against the stored names.
+    /// Words the grammar does not understand are ignored.
     pub fn parse_prompt(&self, prompt: &str) -> ParsedQuery {
-        let prompt_lower = prompt.to_lowercase();
-
-        let metric_name = self.extract_metric_name(&prompt_lower);
-        let tags = self.extract_tags(&prompt_lower);
-        let time_range = self.extract_time_range(&prompt_lower);
-        let aggregation = self.extract_aggregation(&prompt_lower);
-        let limit = self.extract_limit(&prompt_lower);
-
-        ParsedQuery {
-            metric_name,
-            tags,
-            time_range,
-            aggregation,
-            limit,
-        }
+        let mut query = prompt::parse(prompt, None).best.query;
+        query.cache_bucket = Some(Duration::seconds(TIME_BUCKET_SECS));
+        query
     }
 
-    fn extract_metric_name(&self, prompt: &str) -> Option<String> {
-        let patterns = vec![
-            r"metric[s]?\s+(?:named?|called?)\s+(\w+)",
-            r"(\w+)\s+metric[s]?",
-            r"event[s]?\s+(?:named?|called?)\s+(\w+)",
-        ];
-
-        for pattern in patterns {
-            if let Ok(re) = Regex::new(pattern) {
-                if let Some(captures) = re.captures(prompt) {
-                    if let Some(name) = captures.get(1) {
-                        return Some(name.as_str().to_string());
-                    }
-                }
-            }
+    /// Parses a prompt, resolving its metric against the names in the store.
+    pub async fn interpret(&self, prompt: &str) -> Result<PromptParse, Box<dyn std::error::Error>> {
+        let names = self.telemetry_service.metric_names().await?;
+        let mut parse = prompt::parse(prompt, Some(&names));
+
+        for interpretation in std::iter::once(&mut parse.best).chain(&mut parse.alternatives) {
+            interpretation.query.cache_bucket = Some(Duration::seconds(TIME_BUCKET_SECS));
         }
 
-        None
+        Ok(parse)
     }
 
-    fn extract_tags(&self, prompt: &str) -> Option<Vec<String>> {
-        let patterns = vec![
-            r"tag[s]?\s+(?:=|:)\s*\[([^\]]+)\]",
-            r"tagged?\s+with\s+(\w+(?:\s*,\s*\w+)*)",
-        ];
-
-        for pattern in patterns {
-            if let Ok(re) = Regex::new(pattern) {
-                if let Some(captures) = re.captures(prompt) {
-                    if let Some(tags_str) = captures.get(1) {
-                        let tags: Vec<String> = tags_str
-                            .as_str()
-                            .split(',')
-                            .map(|s| s.trim().to_string())
-                            .filter(|s| !s.is_empty())
-                            .collect();
-
-                        if !tags.is_empty() {
-                            return Some(tags);
-                        }
-                    }
-                }
-            }
-        }
-
-        None
+    pub async fn execute_query(
+        &self,
+        prompt: QueryPrompt,
+    ) -> Result<QueryResult, Box<dyn std::error::Error>> {
+        let parsed = self.interpret(&prompt.prompt).await?.into_query()?;
+        self.execute(parsed).await
     }
 
-    fn extract_time_range(&self, prompt: &str) -> Option<TimeRange> {
-        let now = Utc::now();
+    /// Runs a parsed prompt or a `StructuredQuery`.
+    pub async fn execute(
+        &self,
+        parsed: ParsedQuery,
+    ) -> Result<QueryResult, Box<dyn std::error::Error>> {
+        let filter = self.build_filter(&parsed);
 
-        if prompt.contains("today") {
-            return Some(TimeRange {
-                start: Some(now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc()),
-                end: Some(now),
-            });
+        if let Some(step) = parsed.step {
+            return Ok(QueryResult::Range(self.range(step, filter, &parsed).await?));
         }
 
-        if prompt.contains("yesterday") {
-            let yesterday = now - Duration::days(1);
-            return Some(TimeRange {
-                start: Some(
-                    yesterday
-                        .date_naive()
-                        .and_hms_opt(0, 0, 0)
-                        .unwrap()
-                        .and_utc(),
-                ),
-                end: Some(
-                    yesterday
-                        .date_naive()
-                        .and_hms_opt(23, 59, 59)
-                        .unwrap()
-                        .and_utc(),
-                ),
-            });
+        match parsed.aggregation {
+            Some(AggregationType::Top(_)) | None => Ok(QueryResult::Metrics(
+                self.telemetry_service.get_metrics(filter).await?,
+            )),
+            Some(aggregation) => Ok(QueryResult::Aggregation(
+                self.aggregate(aggregation, filter, &parsed).await?,
+            )),
         }
+    }
 
-        if let Ok(re) = Regex::new(r"last\s+(\d+)\s+(hour|day|week|month)s?") {
-            if let Some(captures) = re.captures(prompt) {
-                if let (Some(num_str), Some(unit)) = (captures.get(1), captures.get(2)) {
-                    if let Ok(num) = num_str.as_str().parse::<i64>() {
-                        let duration = match unit.as_str() {
-                            "hour" => Duration::hours(num),
-                            "day" => Duration::days(num),
-                            "week" => Duration::weeks(num),
-                            "month" => Duration::days(num * 30),
-                            _ => return None,
-                        };
-
-                        return Some(TimeRange {
-                            start: Some(now - duration),
-                            end: Some(now),
-                        });
-                    }
-                }
-            }
+    /// Like `execute_query`, but raw metrics are streamed from the store instead
+    /// of being collected. Aggregations are small and computed as usual.
+    pub async fn stream_query(
+        &self,
+        prompt: QueryPrompt,
+    ) -> Result<QueryStream, Box<dyn std::error::Error>> {
+        let parsed = self.interpret(&prompt.prompt).await?.into_query()?;
+        self.stream(parsed).await
+    }
+
+    /// Like `execute`, streaming raw metrics.
+    pub async fn stream(
+        &self,
+        parsed: ParsedQuery,
+    ) -> Result<QueryStream, Box<dyn std::error::Error>> {
+        let filter = self.build_filter(&parsed);
+
+        if let Some(step) = parsed.step {
+            return Ok(QueryStream::Range(self.range(step, filter, &parsed).await?));
         }
 
-        None
+        match parsed.aggregation {
+            Some(AggregationType::Top(_)) | None => Ok(QueryStream::Metrics(
+                self.telemetry_service.stream_metrics(filter).await?,
+            )),
+            Some(aggregation) => Ok(QueryStream::Aggregation(
+                self.aggregate(aggregation, filter, &parsed).await?,
+            )),
+        }
     }
 
-    fn extract_aggregation(&self, prompt: &str) -> Option<AggregationType> {
-        if let Ok(re) = Regex::new(r"top\s+(\d+)") {
-            if let Some(captures) = re.captures(prompt) {
-                if let Some(num_str) = captures.get(1) {
-                    if let Ok(num) = num_str.as_str().parse::<usize>() {
-                        return Some(AggregationType::Top(num));
-                    }
-                }
-            }
-        }
+    /// Translates a parsed query into a store filter. `top N`, `limit` and the
+    /// order are pushed down as a sort and limit so only the returned metrics
+    /// are read.
+    fn build_filter(&self, parsed: &ParsedQuery) -> MetricFilter {
+        let mut filter = MetricFilter {
+            name: parsed.metric_name.clone(),
+            tags: parsed.tags.clone(),
+            labels: parsed.labels.clone(),
+            values: parsed.values.clone(),
+            limit: parsed.limit.map(|limit| limit as usize),
+            cache_bucket: parsed.cache_bucket,
+            ..Default::default()
+        };
 
-        if prompt.contains("average") || prompt.contains("avg") {
-            return Some(AggregationType::Average);
+        if let Some(time_range) = &parsed.time_range {
+            filter.start_date = time_range.start.map(|dt| dt.to_rfc3339());
+            filter.end_date = time_range.end.map(|dt| dt.to_rfc3339());
         }
 
-        if prompt.contains("sum") || prompt.contains("total") {
-            return Some(AggregationType::Sum);
+        if let Some(order) = parsed.order {
+            filter.sort = order.by;
+            filter.order = order.direction;
         }
 
-        if prompt.contains("count") || prompt.contains("number") {
-            return Some(AggregationType::Count);
+        if let Some(AggregationType::Top(n)) = parsed.aggregation {
+            filter.sort = SortField::Value;
+            filter.order = SortOrder::Desc;
+            filter.limit = Some(filter.limit.map_or(n, |limit| limit.min(n)));
         }
 
-        None
+        filter
     }
 
-    fn extract_limit(&self, prompt: &str) -> Option<i64> {
-        if let Ok(re) = Regex::new(r"limit\s+(\d+)") {
-            if let Some(captures) = re.captures(prompt) {
-                if let Some(num_str) = captures.get(1) {
-                    if let Ok(num) = num_str.as_str().parse::<i64>() {
-                        return Some(num);
-                    }
-                }
-            }
+    /// Buckets the results `step` wide, as `GET /query/range` does.
+    async fn range(
+        &self,
+        step: Step,
+        filter: MetricFilter,
+        parsed: &ParsedQuery,
+    ) -> Result<RangeResult, Box<dyn std::error::Error>> {
+        let query = self.range_query(step, filter, parsed)?;
+        let mut result = self.telemetry_service.query_range(query).await?;
+
+        if let Some(limit) = parsed.limit {
+            result.series.truncate(limit as usize);
         }
 
-        None
+        Ok(result)
     }
 
-    pub async fn execute_query(
+    /// Aggregations that cannot be computed per bucket are rejected by
+    /// `StructuredQuery`, so they are not expected here.
+    fn range_query(
         &self,
-        prompt: QueryPrompt,
-    ) -> Result<Vec<Metric>, Box<dyn std::error::Error>> {
-        let parsed = self.parse_prompt(&prompt.prompt);
-
-        let mut filter = crate::models::MetricFilter {
-            name: parsed.metric_name,
-            tags: parsed.tags,
-            start_date: None,
-            end_date: None,
-        };
+        step: Step,
+        filter: MetricFilter,
+        parsed: &ParsedQuery,
+    ) -> Result<RangeQuery, RangeError> {
+        let function = parsed
+            .aggregation
+            .and_then(AggregationType::range_function)
+            .unwrap_or_default();
+        RangeQuery::grouped(filter, step, function, parsed.fill, parsed.grouping.clone())
+    }
 
-        if let Some(time_range) = parsed.time_range {
-            filter.start_date = time_range.start.map(|dt| dt.to_rfc3339());
-            filter.end_date = time_range.end.map(|dt| dt.to_rfc3339());
+    /// What `execute_query` would do for `prompt`, without running it.
+    pub async fn explain(
+        &self,
+        prompt: &str,
+    ) -> Result<QueryExplanation, Box<dyn std::error::Error>> {
+        let parse = self.interpret(prompt).await?;
+        let mongo = self.mongo_commands(&parse.best.query);
+
+        Ok(QueryExplanation {
+            prompt: prompt.to_string(),
+            parsed: parse.best.query,
+            confidence: parse.best.confidence,
+            alternatives: parse.alternatives,
+            matches: parse.matches,
+            unrecognized: parse.unrecognized,
+            error: parse.error.map(|error| error.to_string()),
+            mongo,
+        })
+    }
+
+    /// The commands `MongoDb` would run for `parsed`, in the order `execute`
+    /// issues them.
+    fn mongo_commands(&self, parsed: &ParsedQuery) -> Vec<MongoCommand> {
+        let filter = self.build_filter(parsed);
+
+        if let Some(step) = parsed.step {
+            return match self.range_query(step, filter, parsed) {
+                Ok(query) => vec![mongo::range_pipeline(&query).map_or_else(
+                    || MongoCommand::find(&query.filter),
+                    MongoCommand::aggregate,
+                )],
+                Err(_) => Vec::new(),
+            };
+        }
+
+        match parsed.aggregation {
+            // `MongoDb` answers a limit of 0 without a query
+            Some(AggregationType::Top(_)) | None if filter.limit == Some(0) => Vec::new(),
+            Some(AggregationType::Top(_)) | None => vec![MongoCommand::find(&filter)],
+            Some(AggregationType::Rate(_)) => vec![MongoCommand::find(&filter.time_ordered())],
+            Some(aggregation) => {
+                let filter = filter.unpaged();
+                let summarize =
+                    MongoCommand::aggregate(mongo::summarize_pipeline(&filter, &parsed.grouping));
+
+                // Quantiles read the values themselves once they are counted
+                match aggregation.quantile() {
+                    Some(_) => vec![summarize, MongoCommand::find(&filter)],
+                    None => vec![summarize],
+                }
+            }
         }
+    }
 
-        let mut metrics = self.telemetry_service.get_metrics(filter).await?;
+    async fn aggregate(
+        &self,
+        aggregation: AggregationType,
+        filter: MetricFilter,
+        parsed: &ParsedQuery,
+    ) -> Result<AggregationResult, Box<dyn std::error::Error>> {
+        let mut result = match aggregation {
+            AggregationType::Rate(function) => {
+                let series = self
+                    .telemetry_service
+                    .metric_rates(filter, &parsed.grouping, function)
+                    .await?;
+                AggregationResult::from_rates(function, series)
+            }
+            _ => self.summarize(aggregation, filter, parsed).await?,
+        };
 
-        if let Some(aggregation) = parsed.aggregation {
-            metrics = self.apply_aggregation(metrics, aggregation);
+        if let Some(order) = parsed.order {
+            result.sort_series(order);
         }
 
         if let Some(limit) = parsed.limit {
-            metrics.truncate(limit as usize);
+            result.series.truncate(limit as usize);
         }
 
-        Ok(metrics)
+        Ok(result)
     }
 
-    fn apply_aggregation(
+    /// Reduces with running totals per series, or with their values for quantiles.
+    async fn summarize(
         &self,
-        mut metrics: Vec<Metric>,
         aggregation: AggregationType,
-    ) -> Vec<Metric> {
-        match aggregation {
-            AggregationType::Top(n) => {
-                metrics.sort_by(|a, b| b.value.partial_cmp(&a.value).unwrap());
-                metrics.truncate(n);
-                metrics
-            }
-            _ => metrics,
+        filter: MetricFilter,
+        parsed: &ParsedQuery,
+    ) -> Result<AggregationResult, Box<dyn std::error::Error>> {
+        if aggregation.quantile().is_none() {
+            let summaries = self
+                .telemetry_service
+                .summarize_metrics(filter.unpaged(), &parsed.grouping)
+                .await?;
+            return Ok(AggregationResult::from_summaries(aggregation, summaries));
         }
+
+        let distributions = self
+            .telemetry_service
+            .metric_distributions(filter.unpaged(), &parsed.grouping)
+            .await?;
+
+        Ok(AggregationResult::from_distributions(
+            aggregation,
+            distributions,
+        ))
     }
 }
+
+/// Response body of `GET /query/explain`.
+#[derive(Debug, Serialize)]
+pub struct QueryExplanation {
+    pub prompt: String,
+    /// Relative time ranges resolved to absolute bounds.
+    pub parsed: ParsedQuery,
+    /// How sure the parser is of `parsed`, between 0 and 1.
+    pub confidence: f64,
+    /// Other readings of the prompt, most likely first.
+    pub alternatives: Vec<Interpretation>,
+    /// The grammar rule behind each part of `parsed`, with the text it matched.
+    pub matches: Vec<RuleMatch>,
+    /// Words of the prompt no rule matched, besides filler like "the" or "show".
+    pub unrecognized: Vec<String>,
+    /// Why `GET /query` would reject the prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What would run against MongoDB, whichever store is configured.
+    pub mongo: Vec<MongoCommand>,
+}
+
+/// `/query` results for streaming responses.
+pub enum QueryStream {
+    Metrics(MetricStream),
+    Aggregation(AggregationResult),
+    Range(RangeResult),
+}
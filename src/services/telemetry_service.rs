@@ -1,155 +1,471 @@
-use crate::db::{MongoDb, RedisDb};
-use crate::models::{CreateMetricRequest, Metric, MetricFilter, UpdateMetricRequest};
-use bson::{doc, oid::ObjectId, DateTime};
-use futures::stream::TryStreamExt;
-use mongodb::options::FindOptions;
-use redis::AsyncCommands;
-use serde_json;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::Config;
+use crate::db::StoreError;
+use crate::db::{MetricStore, MetricStream, RedisDb};
+use crate::models::{
+    is_valid_label_name, labels_from_tags, BatchItemResult, BatchResponse, CreateMetricRequest,
+    CursorError, Grouping, InvalidMetric, Labels, Metric, MetricFilter, MetricPage, PageCursor,
+    RangeQuery, RangeResult, RateFunction, SeriesDistribution, SeriesSummary, SeriesValue,
+    UpdateMetricRequest,
+};
+use crate::services::cache::{CacheOptions, CacheStats, MetricCache};
+use bson::DateTime;
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Limits applied to incoming metrics.
+#[derive(Debug, Clone)]
+pub struct IngestOptions {
+    pub max_batch_size: usize,
+    /// How far in the past a client-supplied timestamp may be.
+    pub max_timestamp_age: Duration,
+    /// How far in the future a client-supplied timestamp may be, to allow for clock skew.
+    pub max_timestamp_future: Duration,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 1000,
+            max_timestamp_age: Duration::from_secs(30 * 24 * 60 * 60),
+            max_timestamp_future: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl From<&Config> for IngestOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_batch_size: config.max_batch_size,
+            max_timestamp_age: Duration::from_secs(config.max_timestamp_age_secs),
+            max_timestamp_future: Duration::from_secs(config.max_timestamp_future_secs),
+        }
+    }
+}
+
+/// Page sizes for `GET /metrics`.
+#[derive(Debug, Clone)]
+pub struct PageOptions {
+    /// Used when the request has no `limit`.
+    pub default_limit: usize,
+    /// Upper bound on `limit`, however large the request asks for.
+    pub max_limit: usize,
+}
+
+impl Default for PageOptions {
+    fn default() -> Self {
+        Self {
+            default_limit: 100,
+            max_limit: 1000,
+        }
+    }
+}
+
+impl From<&Config> for PageOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            default_limit: config.default_page_size,
+            max_limit: config.max_page_size,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TelemetryService {
-    mongo: MongoDb,
-    redis: RedisDb,
+    store: Arc<dyn MetricStore>,
+    cache: MetricCache,
+    ingest: IngestOptions,
+    paging: PageOptions,
 }
 
 impl TelemetryService {
-    pub fn new(mongo: MongoDb, redis: RedisDb) -> Self {
-        Self { mongo, redis }
+    /// Creates a service backed by `store`. Query results are cached in `redis`
+    /// when given; the in-process tier is off until `with_cache_options`.
+    pub fn new(store: Arc<dyn MetricStore>, redis: Option<RedisDb>) -> Self {
+        Self {
+            store,
+            cache: MetricCache::new(redis, CacheOptions::disabled()),
+            ingest: IngestOptions::default(),
+            paging: PageOptions::default(),
+        }
     }
 
-    pub async fn create_metric(
-        &self,
-        request: CreateMetricRequest,
-    ) -> Result<Metric, Box<dyn std::error::Error>> {
-        let metric = Metric {
+    pub fn with_cache_options(mut self, options: CacheOptions) -> Self {
+        self.cache = MetricCache::new(self.cache.redis().cloned(), options);
+        self
+    }
+
+    pub fn with_ingest_options(mut self, ingest: IngestOptions) -> Self {
+        self.ingest = ingest;
+        self
+    }
+
+    pub fn with_page_options(mut self, paging: PageOptions) -> Self {
+        self.paging = paging;
+        self
+    }
+
+    pub fn ingest_options(&self) -> &IngestOptions {
+        &self.ingest
+    }
+
+    fn build_metric(&self, request: CreateMetricRequest) -> Result<Metric, InvalidMetric> {
+        let value = request.validated_value()?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        let timestamp = match &request.timestamp {
+            Some(timestamp) => self.check_timestamp(timestamp.to_datetime()?, now)?,
+            None => DateTime::from_millis(now),
+        };
+
+        // Labels derived from `key:value` tags are overridden by explicit labels.
+        let mut labels = labels_from_tags(request.tags.as_deref().unwrap_or_default());
+        labels.extend(request.labels.unwrap_or_default());
+
+        if let Some(name) = labels.keys().find(|name| !is_valid_label_name(name)) {
+            return Err(InvalidMetric::LabelName(name.clone()));
+        }
+
+        Ok(Metric {
             id: None,
             name: request.name,
             tags: request.tags,
-            value: request.value,
-            timestamp: DateTime::from_millis(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as i64,
-            ),
-        };
+            labels,
+            kind: request.kind,
+            value,
+            histogram: request.histogram,
+            summary: request.summary,
+            timestamp,
+        })
+    }
+
+    fn check_timestamp(&self, timestamp: DateTime, now: i64) -> Result<DateTime, InvalidMetric> {
+        let max_age = self.ingest.max_timestamp_age;
+        let max_future = self.ingest.max_timestamp_future;
+
+        // Bounds past the range of i64 milliseconds admit every timestamp.
+        let millis = |offset: std::time::Duration| i64::try_from(offset.as_millis()).ok();
+        let oldest = millis(max_age).and_then(|age| now.checked_sub(age));
+        let latest = millis(max_future).and_then(|future| now.checked_add(future));
+
+        if oldest.is_some_and(|oldest| timestamp.timestamp_millis() < oldest) {
+            return Err(InvalidMetric::TooOld {
+                timestamp: timestamp.to_string(),
+                max_age_secs: max_age.as_secs(),
+            });
+        }
 
-        let collection = self.mongo.metrics_collection();
-        let insert_result = collection.insert_one(&metric, None).await?;
+        if latest.is_some_and(|latest| timestamp.timestamp_millis() > latest) {
+            return Err(InvalidMetric::TooFarInFuture {
+                timestamp: timestamp.to_string(),
+                max_future_secs: max_future.as_secs(),
+            });
+        }
 
-        let mut created_metric = metric.clone();
-        created_metric.id = Some(insert_result.inserted_id.as_object_id().unwrap());
+        Ok(timestamp)
+    }
+
+    /// Stores a single metric. Returns an `InvalidMetric` error if its timestamp is
+    /// malformed or outside the configured bounds.
+    pub async fn create_metric(
+        &self,
+        request: CreateMetricRequest,
+    ) -> Result<Metric, Box<dyn std::error::Error>> {
+        let metric = self.build_metric(request)?;
+
+        let created_metric = self.store.insert(metric).await?;
+        self.invalidate([created_metric.name.as_str()]).await;
 
         Ok(created_metric)
     }
 
+    /// Inserts a batch of metrics in one round trip. Items that failed to parse are
+    /// passed in as `Err` and reported back at their original index.
+    pub async fn create_metrics(
+        &self,
+        requests: Vec<Result<CreateMetricRequest, String>>,
+    ) -> Result<BatchResponse, Box<dyn std::error::Error>> {
+        let mut results = Vec::with_capacity(requests.len());
+        let mut indexes = Vec::new();
+        let mut metrics = Vec::new();
+
+        for (index, request) in requests.into_iter().enumerate() {
+            match request.and_then(|request| self.build_metric(request).map_err(|e| e.to_string()))
+            {
+                Ok(metric) => {
+                    indexes.push(index);
+                    metrics.push(metric);
+                }
+                Err(error) => results.push(BatchItemResult::Failed { index, error }),
+            }
+        }
+
+        let inserted = self.store.insert_many(metrics).await?;
+
+        let names = inserted.iter().flatten().map(|metric| metric.name.as_str());
+        self.invalidate(names).await;
+
+        for (index, result) in indexes.into_iter().zip(inserted) {
+            results.push(match result {
+                Ok(metric) => BatchItemResult::Created { index, metric },
+                Err(e) => BatchItemResult::Failed {
+                    index,
+                    error: e.to_string(),
+                },
+            });
+        }
+
+        Ok(BatchResponse::new(results))
+    }
+
+    pub async fn get_metric(&self, id: &str) -> Result<Option<Metric>, Box<dyn std::error::Error>> {
+        Ok(self.store.get(id).await?)
+    }
+
     pub async fn get_metrics(
         &self,
         filter: MetricFilter,
     ) -> Result<Vec<Metric>, Box<dyn std::error::Error>> {
-        let cache_key = format!("metrics:{filter:?}");
+        self.cached("find", &filter, self.store.find(&filter)).await
+    }
+
+    /// Streams every metric matching `filter` straight from the store, for exports
+    /// too large to buffer. Bypasses the cache and ignores `PageOptions`.
+    pub async fn stream_metrics(
+        &self,
+        filter: MetricFilter,
+    ) -> Result<MetricStream, Box<dyn std::error::Error>> {
+        Ok(self.store.stream(&filter).await?)
+    }
 
-        let mut conn = self.redis.conn.clone();
-        if let Ok(cached) = conn.get::<_, String>(&cache_key).await {
-            if let Ok(metrics) = serde_json::from_str::<Vec<Metric>>(&cached) {
-                log::debug!("Cache hit for key: {cache_key}");
-                return Ok(metrics);
+    /// Returns one page of metrics matching `filter`. The page size is the
+    /// filter's `limit`, defaulted and capped by `PageOptions`; a cursor must
+    /// come from a page with the same sort and order.
+    pub async fn list_metrics(
+        &self,
+        mut filter: MetricFilter,
+    ) -> Result<MetricPage, Box<dyn std::error::Error>> {
+        if let Some(cursor) = &filter.cursor {
+            if cursor.after.field() != filter.sort || cursor.order != filter.order {
+                return Err(CursorError::SortMismatch.into());
             }
         }
 
-        let mut query = doc! {};
+        let limit = filter
+            .limit
+            .unwrap_or(self.paging.default_limit)
+            .min(self.paging.max_limit)
+            .max(1);
+
+        // One extra row tells whether there is a next page
+        filter.limit = Some(limit + 1);
+        let mut metrics = self.get_metrics(filter.clone()).await?;
+
+        let next = if metrics.len() > limit {
+            metrics.truncate(limit);
+            metrics
+                .last()
+                .and_then(|last| PageCursor::at(last, filter.sort, filter.order))
+        } else {
+            None
+        };
 
-        if let Some(name) = &filter.name {
-            query.insert("name", name);
-        }
+        Ok(MetricPage { metrics, next })
+    }
 
-        if let Some(tags) = &filter.tags {
-            query.insert("tags", doc! { "$in": tags });
-        }
+    /// The distinct metric name and label set of every metric matching `filter`.
+    pub async fn list_series(
+        &self,
+        filter: MetricFilter,
+    ) -> Result<Vec<(String, Labels)>, Box<dyn std::error::Error>> {
+        self.cached("series", &filter, self.store.series(&filter))
+            .await
+    }
 
-        if filter.start_date.is_some() || filter.end_date.is_some() {
-            let mut date_filter = doc! {};
+    /// The distinct names of all stored metrics, sorted.
+    pub async fn metric_names(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.cached("names", &MetricFilter::default(), self.store.names())
+            .await
+    }
 
-            if let Some(start) = filter.start_date {
-                if let Ok(start_date) = chrono::DateTime::parse_from_rfc3339(&start) {
-                    date_filter
-                        .insert("$gte", DateTime::from_millis(start_date.timestamp_millis()));
-                }
-            }
+    /// Running totals of matching values per series, split as `grouping` says.
+    pub async fn summarize_metrics(
+        &self,
+        filter: MetricFilter,
+        grouping: &Grouping,
+    ) -> Result<Vec<SeriesSummary>, Box<dyn std::error::Error>> {
+        let namespace = match grouping {
+            Grouping::Name => "summary".to_string(),
+            grouping => format!("summary:{grouping}"),
+        };
 
-            if let Some(end) = filter.end_date {
-                if let Ok(end_date) = chrono::DateTime::parse_from_rfc3339(&end) {
-                    date_filter.insert("$lte", DateTime::from_millis(end_date.timestamp_millis()));
-                }
-            }
+        self.cached(&namespace, &filter, self.store.summarize(&filter, grouping))
+            .await
+    }
 
-            if !date_filter.is_empty() {
-                query.insert("timestamp", date_filter);
-            }
-        }
+    /// Collects matching values per series for quantiles, exactly or in sketches.
+    /// Bypasses the cache.
+    pub async fn metric_distributions(
+        &self,
+        filter: MetricFilter,
+        grouping: &Grouping,
+    ) -> Result<Vec<SeriesDistribution>, Box<dyn std::error::Error>> {
+        Ok(self.store.distributions(&filter, grouping).await?)
+    }
 
-        let find_options = FindOptions::builder()
-            .sort(doc! { "timestamp": -1 })
-            .build();
+    /// Applies a rate function to each series over the whole time range, summed
+    /// per grouping. Bypasses the cache.
+    pub async fn metric_rates(
+        &self,
+        filter: MetricFilter,
+        grouping: &Grouping,
+        function: RateFunction,
+    ) -> Result<Vec<SeriesValue>, Box<dyn std::error::Error>> {
+        Ok(self.store.rates(&filter, grouping, function).await?)
+    }
+
+    /// Reduces the metrics selected by `query` into aligned time buckets.
+    pub async fn query_range(
+        &self,
+        query: RangeQuery,
+    ) -> Result<RangeResult, Box<dyn std::error::Error>> {
+        // The filter fingerprint does not cover the bucketing, so the namespace does
+        let namespace = format!(
+            "range:{}:{}:{}",
+            query.step.secs(),
+            query.function,
+            query.grouping
+        );
+        let buckets = self
+            .cached(
+                &namespace,
+                &query.filter,
+                self.store.aggregate_range(&query),
+            )
+            .await?;
+
+        Ok(RangeResult::from_buckets(&query, buckets))
+    }
+
+    /// Cache hit and miss counts, or `None` when caching is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.is_enabled().then(|| self.cache.stats())
+    }
 
-        let collection = self.mongo.metrics_collection();
-        let cursor = collection.find(query, find_options).await?;
-        let metrics: Vec<Metric> = cursor.try_collect().await?;
+    /// Keeps this instance's in-process cache in step with writes made through
+    /// other instances. Runs until the task is dropped; returns at once when
+    /// there is no Redis or no in-process tier.
+    pub async fn listen_for_cache_invalidations(&self) {
+        self.cache.listen_for_invalidations().await
+    }
+
+    /// Serves `filter` from the cache when possible, otherwise awaits `load` and
+    /// caches its result.
+    async fn cached<T, F>(
+        &self,
+        namespace: &str,
+        filter: &MetricFilter,
+        load: F,
+    ) -> Result<T, Box<dyn std::error::Error>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, StoreError>>,
+    {
+        if !self.cache.is_enabled() {
+            return Ok(load.await?);
+        }
 
-        let serialized = serde_json::to_string(&metrics)?;
-        let _: () = conn.set_ex(&cache_key, serialized, 300).await?;
+        let json = self
+            .cache
+            .get_or_load(namespace, filter, async {
+                Ok(serde_json::to_string(&load.await?)?)
+            })
+            .await?;
 
-        Ok(metrics)
+        Ok(serde_json::from_str(&json)?)
     }
 
     pub async fn update_metric(
         &self,
         id: &str,
-        request: UpdateMetricRequest,
+        mut request: UpdateMetricRequest,
     ) -> Result<Option<Metric>, Box<dyn std::error::Error>> {
-        let object_id = ObjectId::parse_str(id)?;
-
-        let mut update_doc = doc! {};
-
-        if let Some(name) = request.name {
-            update_doc.insert("name", name);
+        if request.is_empty() {
+            return Ok(None);
         }
 
-        if let Some(tags) = request.tags {
-            update_doc.insert("tags", tags);
+        if let Some(labels) = &request.labels {
+            if let Some(name) = labels.keys().find(|name| !is_valid_label_name(name)) {
+                return Err(InvalidMetric::LabelName(name.clone()).into());
+            }
         }
 
-        if let Some(value) = request.value {
-            update_doc.insert("value", value);
-        }
+        // A new value is checked against the stored kind, new tags re-derive the
+        // labels that came from the old ones, and a rename moves the metric out
+        // of its old name's cached queries too.
+        let retag = request.tags.is_some() && request.labels.is_none();
+        let previous = match (&request.value, &request.name) {
+            (Some(_), _) => self.store.get(id).await?,
+            _ if retag => self.store.get(id).await?,
+            (None, Some(_)) if self.cache.is_enabled() => self.store.get(id).await?,
+            _ => None,
+        };
 
-        if update_doc.is_empty() {
-            return Ok(None);
+        if let Some(metric) = &previous {
+            request.validate(metric.kind)?;
         }
 
-        let collection = self.mongo.metrics_collection();
-        let filter = doc! { "_id": object_id };
-        let update = doc! { "$set": update_doc };
-        let options = mongodb::options::FindOneAndUpdateOptions::builder()
-            .return_document(mongodb::options::ReturnDocument::After)
-            .build();
+        if let (Some(metric), Some(tags), None) = (&previous, &request.tags, &request.labels) {
+            request.labels = Some(retag_labels(metric, tags));
+        }
 
-        let result = collection
-            .find_one_and_update(filter, update, options)
-            .await?;
+        let updated = self.store.update(id, request).await?;
+        if let Some(metric) = &updated {
+            let previous_name = previous.as_ref().map(|metric| metric.name.as_str());
+            self.invalidate(previous_name.into_iter().chain([metric.name.as_str()]))
+                .await;
+        }
 
-        Ok(result)
+        Ok(updated)
     }
 
     pub async fn delete_metric(&self, id: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let object_id = ObjectId::parse_str(id)?;
+        let existing = if self.cache.is_enabled() {
+            self.store.get(id).await?
+        } else {
+            None
+        };
 
-        let collection = self.mongo.metrics_collection();
-        let filter = doc! { "_id": object_id };
+        let deleted = self.store.delete(id).await?;
+        if let Some(metric) = existing.filter(|_| deleted) {
+            self.invalidate([metric.name.as_str()]).await;
+        }
 
-        let result = collection.delete_one(filter, None).await?;
+        Ok(deleted)
+    }
 
-        Ok(result.deleted_count > 0)
+    /// Evicts cached queries that may include metrics named `names`.
+    async fn invalidate<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        self.cache.invalidate(names).await;
     }
 }
+
+/// The labels of `metric` once its tags become `tags`. Labels derived from the
+/// old tags are replaced by those of the new ones; explicit labels are kept and
+/// still override derived ones.
+fn retag_labels(metric: &Metric, tags: &[String]) -> Labels {
+    let stale = labels_from_tags(metric.tags.as_deref().unwrap_or_default());
+    let mut labels = metric.labels.clone();
+    labels.retain(|name, value| stale.get(name) != Some(value));
+
+    for (name, value) in labels_from_tags(tags) {
+        labels.entry(name).or_insert(value);
+    }
+
+    labels
+}
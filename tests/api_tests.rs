@@ -1,6 +1,15 @@
-use actix_web::{test, App};
-use serde_json::Value;
-use telemetry_server::{health_check, version};
+mod common;
+
+use actix_web::{test, web, App};
+use common::in_memory_services;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use telemetry_server::{
+    db::InMemoryStore,
+    health_check, routes,
+    services::{IngestOptions, PageOptions, TelemetryService},
+    version,
+};
 
 #[actix_rt::test]
 async fn test_health_check() {
@@ -26,3 +35,547 @@ async fn test_version() {
     assert!(body["version"].is_string());
     assert!(body["build_date"].is_string());
 }
+
+#[actix_rt::test]
+async fn test_metrics_crud() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/metrics")
+        .set_json(json!({ "name": "cpu_usage", "tags": ["production"], "value": 42.5 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+
+    let created: Value = test::read_body_json(resp).await;
+    let id = created["_id"]["$oid"].as_str().unwrap().to_string();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/metrics/{id}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let fetched: Value = test::read_body_json(resp).await;
+    assert_eq!(fetched["value"], 42.5);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/metrics/{id}"))
+        .set_json(json!({ "value": 100.0 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let updated: Value = test::read_body_json(resp).await;
+    assert_eq!(updated["value"], 100.0);
+    assert_eq!(updated["name"], "cpu_usage");
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/metrics/{id}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/metrics/{id}"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_get_metrics_filters_by_name_and_tag() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for (name, tag) in [
+        ("cpu_usage", "production"),
+        ("cpu_usage", "staging"),
+        ("memory_usage", "production"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": name, "tags": [tag], "value": 1.0 }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=cpu_usage")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 2);
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=metrics+named+memory_usage")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["tags"][0], "production");
+}
+
+#[actix_rt::test]
+async fn test_query_aggregations() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for (name, tag, value) in [
+        ("memory_usage", "production", 10.0),
+        ("memory_usage", "production", 30.0),
+        ("memory_usage", "staging", 500.0),
+        ("cpu_usage", "production", 2.0),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": name, "tags": [tag], "value": value }))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=average+memory_usage+metric+tagged+with+production")
+        .to_request();
+    let result: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(result["aggregation"], "average");
+    assert_eq!(result["value"], 20.0);
+    assert_eq!(result["points"], 2);
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=count+of+everything+tagged+with+production")
+        .to_request();
+    let result: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(result["value"], 3.0);
+    assert_eq!(result["series"][0]["name"], "cpu_usage");
+    assert_eq!(result["series"][0]["value"], 1.0);
+    assert_eq!(result["series"][1]["value"], 2.0);
+}
+
+#[actix_rt::test]
+async fn test_metrics_batch() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None)
+        .with_ingest_options(IngestOptions {
+            max_batch_size: 3,
+            ..Default::default()
+        });
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            { "name": "cpu_usage", "value": 1.0 },
+            { "name": "cpu_usage", "value": "not a number" },
+            { "name": "cpu_usage", "value": 3.0 }
+        ]))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 207);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["created"], 2);
+    assert_eq!(body["failed"], 1);
+    assert_eq!(body["results"][1]["status"], "failed");
+    assert_eq!(body["results"][2]["status"], "created");
+    assert_eq!(body["results"][2]["metric"]["value"], 3.0);
+
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .insert_header(("content-type", "application/x-ndjson"))
+        .set_payload("{\"name\":\"disk\",\"value\":1}\n\n{\"name\":\"disk\",\"value\":2}\n")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["created"], 2);
+
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(vec![json!({ "name": "a", "value": 1 }); 4])
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 413);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=cpu_usage")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 2);
+}
+
+#[actix_rt::test]
+async fn test_create_metric_with_timestamp() {
+    let (telemetry_service, _) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let five_minutes_ago = chrono::Utc::now() - chrono::Duration::minutes(5);
+
+    for timestamp in [
+        json!(five_minutes_ago.to_rfc3339()),
+        json!(five_minutes_ago.timestamp_millis()),
+        json!(five_minutes_ago.timestamp_nanos_opt().unwrap()),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": "backfill", "value": 1.0, "timestamp": timestamp }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let created: Value = test::read_body_json(resp).await;
+        let stored = created["timestamp"]["$date"]["$numberLong"]
+            .as_str()
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+        assert_eq!(stored, five_minutes_ago.timestamp_millis());
+    }
+
+    for timestamp in [
+        json!("yesterday"),
+        json!((chrono::Utc::now() - chrono::Duration::days(365)).to_rfc3339()),
+        json!((chrono::Utc::now() + chrono::Duration::hours(1)).timestamp_millis()),
+        json!(i64::MIN),
+        json!(i64::MAX),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": "backfill", "value": 1.0, "timestamp": timestamp }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["error"].as_str().unwrap().contains("timestamp"));
+    }
+}
+
+#[actix_rt::test]
+async fn test_get_metrics_filters_by_labels() {
+    let (telemetry_service, _) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for body in [
+        json!({ "name": "requests", "labels": { "env": "production", "host": "web-1" }, "value": 1.0 }),
+        json!({ "name": "requests", "labels": { "env": "production", "host": "web-2" }, "value": 2.0 }),
+        json!({ "name": "requests", "tags": ["env:staging", "canary"], "value": 3.0 }),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+    }
+
+    let query = |selector: &str| {
+        let url =
+            reqwest::Url::parse_with_params("http://localhost/metrics", [("labels", selector)])
+                .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+
+    let metrics: Vec<Value> =
+        test::call_and_read_body_json(&app, query("env=production,host!=web-2")).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["labels"]["host"], "web-1");
+
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, query("env=staging")).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["tags"][1], "canary");
+
+    // New tags replace the labels the old ones gave, explicit labels stay
+    let id = metrics[0]["_id"]["$oid"].as_str().unwrap();
+    let req = test::TestRequest::put()
+        .uri(&format!("/metrics/{id}"))
+        .set_json(json!({ "tags": ["env:production", "tier:web"] }))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        updated["labels"],
+        json!({ "env": "production", "tier": "web" })
+    );
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, query("env=staging")).await;
+    assert!(metrics.is_empty());
+
+    let id = test::call_and_read_body_json::<_, _, Vec<Value>>(&app, query("host=web-1")).await[0]
+        ["_id"]["$oid"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let req = test::TestRequest::put()
+        .uri(&format!("/metrics/{id}"))
+        .set_json(json!({ "tags": ["env:staging", "host:web-9"] }))
+        .to_request();
+    let updated: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        updated["labels"],
+        json!({ "env": "production", "host": "web-1" })
+    );
+
+    let metrics: Vec<Value> =
+        test::call_and_read_body_json(&app, query("host=~\"web-.*\",env in (production,staging)"))
+            .await;
+    assert_eq!(metrics.len(), 2);
+
+    let resp = test::call_service(&app, query("env=~\"(\"")).await;
+    assert_eq!(resp.status(), 400);
+
+    let req = test::TestRequest::post()
+        .uri("/metrics")
+        .set_json(json!({ "name": "requests", "labels": { "service.name": "api" }, "value": 1.0 }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_create_metric_kinds() {
+    let (telemetry_service, _) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let histogram = json!({
+        "name": "request_latency",
+        "kind": "histogram",
+        "histogram": { "buckets": [{ "le": 0.1, "count": 3 }, { "le": 1.0, "count": 5 }], "count": 6, "sum": 2.5 }
+    });
+    let req = test::TestRequest::post()
+        .uri("/metrics")
+        .set_json(histogram)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+    let created: Value = test::read_body_json(resp).await;
+    assert_eq!(created["kind"], "histogram");
+    assert_eq!(created["value"], 2.5);
+
+    for invalid in [
+        json!({ "name": "requests", "kind": "counter", "value": -1.0 }),
+        json!({ "name": "requests", "kind": "counter" }),
+        json!({ "name": "latency", "kind": "histogram", "value": 1.0 }),
+        json!({ "name": "latency", "kind": "summary", "summary": { "quantiles": [{ "quantile": 2.0, "value": 1.0 }], "count": 1, "sum": 1.0 } }),
+        json!({ "name": "memory", "value": 1.0, "histogram": { "buckets": [], "count": 0, "sum": 0.0 } }),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(invalid)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/metrics")
+        .set_json(json!({ "name": "memory", "value": 512.0 }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(created["kind"], "gauge");
+
+    // Updates are checked against the stored kind
+    let req = test::TestRequest::post()
+        .uri("/metrics")
+        .set_json(json!({ "name": "requests", "kind": "counter", "value": 5.0 }))
+        .to_request();
+    let counter: Value = test::call_and_read_body_json(&app, req).await;
+    let id = counter["_id"]["$oid"].as_str().unwrap();
+    for (value, status) in [(-1.0, 400), (7.0, 200)] {
+        let req = test::TestRequest::put()
+            .uri(&format!("/metrics/{id}"))
+            .set_json(json!({ "value": value }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            status,
+            "{value}"
+        );
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?kind=histogram")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["name"], "request_latency");
+}
+
+#[actix_rt::test]
+async fn test_get_metrics_pages_with_cursor() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None)
+        .with_page_options(PageOptions {
+            default_limit: 2,
+            max_limit: 3,
+        });
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    // Two metrics share a value so the id tie-breaker is exercised
+    for value in [5.0, 1.0, 4.0, 2.0, 4.0] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": "latency", "value": value }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+    }
+
+    let mut uri = "/metrics?name=latency&sort=value&order=asc".to_string();
+    let mut values = Vec::new();
+    let mut pages = 0;
+    loop {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let next = resp
+            .headers()
+            .get("link")
+            .map(|link| link.to_str().unwrap().to_string());
+
+        let page: Vec<Value> = test::read_body_json(resp).await;
+        assert!(page.len() <= 2);
+        values.extend(page.iter().map(|metric| metric["value"].as_f64().unwrap()));
+        pages += 1;
+
+        let Some(link) = next else { break };
+        assert!(link.ends_with("; rel=\"next\""));
+        uri = link[1..link.find('>').unwrap()].to_string();
+        assert!(uri.contains("sort=value") && uri.contains("cursor="));
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(values, [1.0, 2.0, 4.0, 4.0, 5.0]);
+
+    // The limit is capped, and newest-first is the default order
+    let req = test::TestRequest::get()
+        .uri("/metrics?limit=100")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 3);
+    let timestamps: Vec<i64> = metrics
+        .iter()
+        .map(|metric| {
+            metric["timestamp"]["$date"]["$numberLong"]
+                .as_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    assert!(timestamps.windows(2).all(|pair| pair[0] >= pair[1]));
+
+    // A cursor is only valid for the sort it was issued for
+    let cursor = uri.split("cursor=").nth(1).unwrap();
+    let req = test::TestRequest::get()
+        .uri(&format!("/metrics?sort=timestamp&cursor={cursor}"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?cursor=not-a-cursor")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_streaming_responses() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let telemetry_service = telemetry_service.with_page_options(PageOptions {
+        default_limit: 1,
+        max_limit: 1,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for value in [1.0, 3.0, 2.0] {
+        let req = test::TestRequest::post()
+            .uri("/metrics")
+            .set_json(json!({ "name": "cpu_usage", "value": value }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+    }
+
+    // Streams ignore the page size
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=cpu_usage&sort=value&order=asc")
+        .insert_header(("accept", "application/x-ndjson"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/x-ndjson"
+    );
+    let body = test::read_body(resp).await;
+    let values: Vec<f64> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<Value>(line).unwrap()["value"]
+                .as_f64()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(values, [1.0, 2.0, 3.0]);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=cpu_usage&stream=json&limit=2")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 2);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=missing&stream=json")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(metrics.is_empty());
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=top%202%20cpu_usage%20metrics&stream=ndjson")
+        .to_request();
+    let body = test::read_body(test::call_service(&app, req).await).await;
+    let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"value\":3.0"));
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=average%20of%20cpu_usage%20metrics&stream=ndjson")
+        .to_request();
+    let body = test::read_body(test::call_service(&app, req).await).await;
+    let result: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(result["value"], 2.0);
+}
@@ -0,0 +1,490 @@
+use actix_web::{test as actix_test, web, App};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use telemetry_server::{
+    db::{InMemoryStore, MetricStore, RedisDb, StoreError},
+    models::{CreateMetricRequest, Metric, MetricFilter, UpdateMetricRequest},
+    routes,
+    services::{CacheOptions, TelemetryService},
+};
+
+/// Wraps `InMemoryStore`, counting `find` calls and making them slow enough for
+/// concurrent requests to overlap.
+#[derive(Default)]
+struct CountingStore {
+    inner: InMemoryStore,
+    finds: AtomicUsize,
+}
+
+#[async_trait::async_trait]
+impl MetricStore for CountingStore {
+    async fn insert(&self, metric: Metric) -> Result<Metric, StoreError> {
+        self.inner.insert(metric).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Metric>, StoreError> {
+        self.inner.get(id).await
+    }
+
+    async fn find(&self, filter: &MetricFilter) -> Result<Vec<Metric>, StoreError> {
+        self.finds.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        self.inner.find(filter).await
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        update: UpdateMetricRequest,
+    ) -> Result<Option<Metric>, StoreError> {
+        self.inner.update(id, update).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, StoreError> {
+        self.inner.delete(id).await
+    }
+}
+
+fn named(name: &str) -> MetricFilter {
+    MetricFilter {
+        name: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+fn gauge(name: &str, value: f64) -> CreateMetricRequest {
+    serde_json::from_value(serde_json::json!({ "name": name, "value": value })).unwrap()
+}
+
+fn filter(query: &str) -> MetricFilter {
+    web::Query::<MetricFilter>::from_query(query)
+        .unwrap()
+        .into_inner()
+}
+
+#[test]
+fn test_filter_fingerprint_is_canonical() {
+    let fingerprint = |query: &str| filter(query).fingerprint();
+
+    // Order, duplicates and equivalent timestamp spellings do not matter
+    let with_tags = |query: &str, tags: &[&str]| MetricFilter {
+        tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+        ..filter(query)
+    };
+    assert_eq!(
+        with_tags("name=cpu&labels=env%3Dprod%2Ctier+in+(x%2Cy)", &["a", "b"]).fingerprint(),
+        with_tags(
+            "labels=tier+in+(y%2Cx%2Cx)%2Cenv%3Dprod&name=cpu",
+            &["b", "a", "b"]
+        )
+        .fingerprint(),
+    );
+    assert_ne!(with_tags("", &[]).fingerprint(), fingerprint(""));
+    assert_eq!(
+        fingerprint("start_date=2024-05-01T12:00:00Z"),
+        fingerprint("start_date=2024-05-01T14:00:00.000%2B02:00"),
+    );
+    assert_eq!(fingerprint(""), fingerprint("labels="));
+    assert_eq!(fingerprint("").len(), 64);
+
+    // Values that only look alike when concatenated stay distinct
+    assert_ne!(
+        fingerprint("labels=tier+in+(%22a%2Cb%22)"),
+        fingerprint("labels=tier+in+(a%2Cb)"),
+    );
+    assert_ne!(
+        fingerprint("name=cpu"),
+        with_tags("", &["cpu"]).fingerprint()
+    );
+    assert_ne!(fingerprint("kind=counter"), fingerprint(""));
+    assert_ne!(
+        fingerprint("start_date=2024-05-01T12:00:00Z"),
+        fingerprint("end_date=2024-05-01T12:00:00Z"),
+    );
+}
+
+#[test]
+fn test_fingerprint_cache_bucket() {
+    let bucketed = |query: &str| MetricFilter {
+        cache_bucket: Some(chrono::Duration::seconds(60)),
+        ..filter(query)
+    };
+
+    // Bounds within the same minute share a key, but only when bucketed
+    assert_eq!(
+        bucketed("start_date=2024-05-01T11:00:17Z&end_date=2024-05-01T12:00:17Z").fingerprint(),
+        bucketed("start_date=2024-05-01T11:00:42Z&end_date=2024-05-01T12:00:42Z").fingerprint(),
+    );
+    assert_ne!(
+        bucketed("start_date=2024-05-01T11:00:59Z").fingerprint(),
+        bucketed("start_date=2024-05-01T11:01:00Z").fingerprint(),
+    );
+    assert_ne!(
+        filter("start_date=2024-05-01T11:00:17Z").fingerprint(),
+        filter("start_date=2024-05-01T11:00:42Z").fingerprint(),
+    );
+    assert_ne!(
+        bucketed("start_date=2024-05-01T11:00:00Z").fingerprint(),
+        filter("start_date=2024-05-01T11:00:00Z").fingerprint(),
+    );
+}
+
+#[actix_rt::test]
+async fn test_cache_stats_without_redis() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let app = actix_test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let req = actix_test::TestRequest::get()
+        .uri("/cache/stats")
+        .to_request();
+    let resp = actix_test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let body: Value = actix_test::read_body_json(resp).await;
+    assert_eq!(body["enabled"], false);
+}
+
+#[actix_rt::test]
+async fn test_local_cache_coalesces_and_invalidates() {
+    let store = Arc::new(CountingStore::default());
+    let service = TelemetryService::new(store.clone(), None).with_cache_options(CacheOptions {
+        max_entries: 3,
+        ..Default::default()
+    });
+
+    service.create_metric(gauge("cpu", 1.0)).await.unwrap();
+    service.create_metric(gauge("mem", 2.0)).await.unwrap();
+
+    // Concurrent identical queries reach the store once
+    let results =
+        futures::future::join_all((0..8).map(|_| service.get_metrics(named("cpu")))).await;
+    assert!(results.iter().all(|r| r.as_ref().unwrap().len() == 1));
+    assert_eq!(store.finds.load(Ordering::SeqCst), 1);
+
+    service.get_metrics(named("mem")).await.unwrap();
+    service.get_metrics(MetricFilter::default()).await.unwrap();
+    assert_eq!(store.finds.load(Ordering::SeqCst), 3);
+
+    // A write evicts queries over its name and unfiltered queries only
+    service.create_metric(gauge("mem", 3.0)).await.unwrap();
+    service.get_metrics(named("cpu")).await.unwrap();
+    assert_eq!(store.finds.load(Ordering::SeqCst), 3);
+
+    let mem = service.get_metrics(named("mem")).await.unwrap();
+    assert_eq!(mem.len(), 2);
+    assert_eq!(store.finds.load(Ordering::SeqCst), 4);
+
+    // The cache holds three entries, so a fourth evicts the least recently used (cpu)
+    service.get_metrics(MetricFilter::default()).await.unwrap();
+    service.get_metrics(named("disk")).await.unwrap();
+    service.get_metrics(named("mem")).await.unwrap();
+    assert_eq!(store.finds.load(Ordering::SeqCst), 6);
+
+    service.get_metrics(named("cpu")).await.unwrap();
+    assert_eq!(store.finds.load(Ordering::SeqCst), 7);
+
+    let stats = service.cache_stats().unwrap();
+    assert_eq!(stats.misses, 7);
+    assert_eq!(stats.hits, 9);
+    assert_eq!(stats.local_hits, stats.hits);
+}
+
+/// Accepts Redis connections and answers every command with an error.
+async fn failing_redis() -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    // Each command is a RESP array, whose header line starts with '*'
+                    let commands = String::from_utf8_lossy(&buf[..n])
+                        .split("\r\n")
+                        .filter(|line| line.starts_with('*'))
+                        .count();
+                    let reply = b"-ERR unavailable\r\n".repeat(commands);
+                    if n == 0 || socket.write_all(&reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[actix_rt::test]
+async fn test_redis_failures_do_not_fail_reads() {
+    let addr = failing_redis().await;
+    let redis = connect(addr).await;
+
+    let store = Arc::new(CountingStore::default());
+    let service = TelemetryService::new(store.clone(), Some(redis));
+
+    service.create_metric(gauge("cpu", 1.0)).await.unwrap();
+    for _ in 0..8 {
+        let metrics = service.get_metrics(named("cpu")).await.unwrap();
+        assert_eq!(metrics.len(), 1);
+    }
+    assert_eq!(store.finds.load(Ordering::SeqCst), 8);
+
+    // Five consecutive failures open the breaker, after which Redis is skipped
+    let stats = service.cache_stats().unwrap();
+    assert!(stats.circuit_open);
+    assert_eq!(stats.redis_errors, 5);
+    assert_eq!(stats.misses, 8);
+    assert_eq!(stats.hits, 0);
+}
+
+/// Serves the Redis commands the cache uses from an in-process keyspace, with a
+/// single pub/sub channel whose subscribers are counted by the returned sender.
+/// Expiry is not modelled.
+async fn fake_redis() -> (std::net::SocketAddr, tokio::sync::broadcast::Sender<String>) {
+    fake_redis_on("127.0.0.1:0".parse().unwrap()).await
+}
+
+async fn fake_redis_on(
+    addr: std::net::SocketAddr,
+) -> (std::net::SocketAddr, tokio::sync::broadcast::Sender<String>) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let keys: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+    let (published, _) = tokio::sync::broadcast::channel(16);
+    let channel = published.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let keys = keys.clone();
+            let published = published.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = socket.into_split();
+                let mut reader = BufReader::new(reader);
+                let mut line = String::new();
+                loop {
+                    // Each command is an array of bulk strings
+                    line.clear();
+                    if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let len: usize = line.trim_start_matches('*').trim().parse().unwrap();
+                    let mut args = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        line.clear();
+                        reader.read_line(&mut line).await.unwrap();
+                        let size: usize = line.trim_start_matches('$').trim().parse().unwrap();
+                        let mut arg = vec![0; size + 2];
+                        reader.read_exact(&mut arg).await.unwrap();
+                        arg.truncate(size);
+                        args.push(String::from_utf8(arg).unwrap());
+                    }
+
+                    let bulk = |value: Option<&String>| match value {
+                        Some(value) => format!("${}\r\n{value}\r\n", value.len()),
+                        None => "$-1\r\n".to_string(),
+                    };
+
+                    // The connection only receives messages from then on
+                    if args[0].eq_ignore_ascii_case("SUBSCRIBE") {
+                        let mut messages = published.subscribe();
+                        let mut reply =
+                            format!("*3\r\n$9\r\nsubscribe\r\n{}:1\r\n", bulk(Some(&args[1])));
+                        while writer.write_all(reply.as_bytes()).await.is_ok() {
+                            let Ok(message) = messages.recv().await else {
+                                break;
+                            };
+                            reply = format!(
+                                "*3\r\n$7\r\nmessage\r\n{}{}",
+                                bulk(Some(&args[1])),
+                                bulk(Some(&message))
+                            );
+                        }
+                        break;
+                    }
+
+                    let reply = {
+                        let mut keys = keys.lock().unwrap();
+                        match args[0].to_uppercase().as_str() {
+                            "GET" => bulk(keys.get(&args[1])),
+                            "MGET" => args[1..]
+                                .iter()
+                                .fold(format!("*{}\r\n", args.len() - 1), |reply, key| {
+                                    reply + &bulk(keys.get(key))
+                                }),
+                            "SET" => {
+                                keys.insert(args[1].clone(), args[2].clone());
+                                "+OK\r\n".to_string()
+                            }
+                            "SETEX" => {
+                                keys.insert(args[1].clone(), args[3].clone());
+                                "+OK\r\n".to_string()
+                            }
+                            "INCR" | "INCRBY" => {
+                                let by = args.get(2).map_or(1, |by| by.parse::<i64>().unwrap());
+                                let value = keys.entry(args[1].clone()).or_default();
+                                let next = value.parse::<i64>().unwrap_or(0) + by;
+                                *value = next.to_string();
+                                format!(":{next}\r\n")
+                            }
+                            "PUBLISH" => {
+                                format!(":{}\r\n", published.send(args[2].clone()).unwrap_or(0))
+                            }
+                            _ => "+OK\r\n".to_string(),
+                        }
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, channel)
+}
+
+async fn connect(addr: std::net::SocketAddr) -> RedisDb {
+    let redis = RedisDb::new(redis::Client::open(format!("redis://{addr}")).unwrap());
+    redis.connection().await.unwrap();
+    redis
+}
+
+#[actix_rt::test]
+async fn test_writes_bump_redis_generations() {
+    let (addr, _) = fake_redis().await;
+    let store = Arc::new(CountingStore::default());
+
+    // Two instances sharing a store and Redis, with no in-process tier
+    let reader = TelemetryService::new(store.clone(), Some(connect(addr).await))
+        .with_cache_options(CacheOptions::disabled());
+    let writer = TelemetryService::new(store.clone(), Some(connect(addr).await))
+        .with_cache_options(CacheOptions::disabled());
+
+    writer.create_metric(gauge("cpu", 1.0)).await.unwrap();
+    writer.create_metric(gauge("mem", 2.0)).await.unwrap();
+
+    assert_eq!(reader.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    assert_eq!(reader.get_metrics(named("mem")).await.unwrap().len(), 1);
+    assert_eq!(
+        reader
+            .get_metrics(MetricFilter::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(store.finds.load(Ordering::SeqCst), 3);
+
+    // Served from Redis, to either instance
+    assert_eq!(writer.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    assert_eq!(
+        reader
+            .get_metrics(MetricFilter::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    assert_eq!(store.finds.load(Ordering::SeqCst), 3);
+
+    // A write on one instance moves the other to new keys for its name and
+    // unfiltered queries, leaving other names cached
+    writer.create_metric(gauge("cpu", 3.0)).await.unwrap();
+    assert_eq!(reader.get_metrics(named("cpu")).await.unwrap().len(), 2);
+    assert_eq!(
+        reader
+            .get_metrics(MetricFilter::default())
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(store.finds.load(Ordering::SeqCst), 5);
+    assert_eq!(reader.get_metrics(named("mem")).await.unwrap().len(), 1);
+    assert_eq!(store.finds.load(Ordering::SeqCst), 5);
+
+    let stats = reader.cache_stats().unwrap();
+    assert_eq!(
+        (stats.hits, stats.local_hits, stats.redis_errors),
+        (2, 0, 0)
+    );
+}
+
+#[actix_rt::test]
+async fn test_writes_evict_other_instances_local_tier() {
+    let (addr, published) = fake_redis().await;
+    let store = Arc::new(CountingStore::default());
+
+    // The writer has no in-process tier of its own
+    let reader = TelemetryService::new(store.clone(), Some(connect(addr).await))
+        .with_cache_options(CacheOptions::default());
+    let writer = TelemetryService::new(store.clone(), Some(connect(addr).await))
+        .with_cache_options(CacheOptions::disabled());
+
+    let listener = reader.clone();
+    actix_rt::spawn(async move { listener.listen_for_cache_invalidations().await });
+    while published.receiver_count() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Let the listener clear the tier on subscribing before filling it
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    writer.create_metric(gauge("cpu", 1.0)).await.unwrap();
+    assert_eq!(reader.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    assert_eq!(reader.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    assert_eq!(reader.cache_stats().unwrap().local_hits, 1);
+
+    writer.create_metric(gauge("cpu", 2.0)).await.unwrap();
+    let mut evicted = false;
+    for _ in 0..100 {
+        if reader.get_metrics(named("cpu")).await.unwrap().len() == 2 {
+            evicted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(evicted);
+}
+
+#[actix_rt::test]
+async fn test_redis_down_at_startup_is_connected_later() {
+    // Nothing listens on the address until the fake starts
+    let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let redis = RedisDb::new(redis::Client::open(format!("redis://{addr}")).unwrap());
+    assert!(redis.connection().await.is_err());
+
+    let store = Arc::new(CountingStore::default());
+    let service = TelemetryService::new(store.clone(), Some(redis));
+
+    service.create_metric(gauge("cpu", 1.0)).await.unwrap();
+    assert_eq!(service.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    let stats = service.cache_stats().unwrap();
+    assert_eq!((stats.redis_errors, stats.circuit_open), (2, false));
+
+    // Once Redis is up, results are cached in it
+    fake_redis_on(addr).await;
+    for _ in 0..2 {
+        assert_eq!(service.get_metrics(named("cpu")).await.unwrap().len(), 1);
+    }
+    assert_eq!(store.finds.load(Ordering::SeqCst), 2);
+
+    let stats = service.cache_stats().unwrap();
+    assert_eq!(
+        (stats.hits, stats.local_hits, stats.redis_errors),
+        (1, 0, 2)
+    );
+}
@@ -0,0 +1,12 @@
+use std::sync::Arc;
+use telemetry_server::{
+    db::InMemoryStore,
+    services::{QueryService, TelemetryService},
+};
+
+/// Services over an empty in-memory store, without a cache.
+pub fn in_memory_services() -> (TelemetryService, QueryService) {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let query_service = QueryService::new(telemetry_service.clone());
+    (telemetry_service, query_service)
+}
@@ -0,0 +1,50 @@
+use std::env;
+use telemetry_server::config::{Config, ConfigError};
+
+/// Environment variables are process-wide, so every case runs in this one test.
+#[test]
+fn test_config_rejects_unusable_limits() {
+    env::set_var("STORAGE_BACKEND", "memory");
+
+    let invalid_key = |vars: &[(&str, &str)]| {
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        let result = Config::from_env();
+        for (key, _) in vars {
+            env::remove_var(key);
+        }
+        match result {
+            Err(ConfigError::Invalid { key, .. }) => Some(key),
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => None,
+        }
+    };
+
+    assert_eq!(
+        invalid_key(&[("MAX_PAGE_SIZE", "0")]),
+        Some("MAX_PAGE_SIZE")
+    );
+    assert_eq!(
+        invalid_key(&[("DEFAULT_PAGE_SIZE", "0")]),
+        Some("DEFAULT_PAGE_SIZE")
+    );
+    assert_eq!(
+        invalid_key(&[("DEFAULT_PAGE_SIZE", "500"), ("MAX_PAGE_SIZE", "100")]),
+        Some("DEFAULT_PAGE_SIZE")
+    );
+    assert_eq!(
+        invalid_key(&[("STATSD_FLUSH_INTERVAL_SECS", "0")]),
+        Some("STATSD_FLUSH_INTERVAL_SECS")
+    );
+    assert_eq!(
+        invalid_key(&[("DEFAULT_PAGE_SIZE", "1"), ("MAX_PAGE_SIZE", "1")]),
+        None
+    );
+
+    let config = Config::from_env().unwrap();
+    assert_eq!(
+        (config.default_page_size, config.max_page_size),
+        (100, 1000)
+    );
+}
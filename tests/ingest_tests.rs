@@ -0,0 +1,684 @@
+use actix_web::{test, web, App};
+use prost::Message;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use telemetry_server::{
+    db::InMemoryStore,
+    ingest::graphite::{self, GraphiteTemplates},
+    ingest::otlp,
+    ingest::prometheus::{Label, MetricMetadata, MetricType, Sample, TimeSeries, WriteRequest},
+    ingest::statsd::{self, StatsdAggregator, StatsdType, StatsdValue},
+    models::{MetricFilter, MetricKind},
+    routes,
+    services::TelemetryService,
+};
+
+fn label(name: &str, value: &str) -> Label {
+    Label {
+        name: name.to_string(),
+        value: value.to_string(),
+    }
+}
+
+#[actix_rt::test]
+async fn test_prometheus_remote_write() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let write_request = WriteRequest {
+        timeseries: vec![
+            TimeSeries {
+                labels: vec![
+                    label("__name__", "http_requests_total"),
+                    label("job", "api"),
+                    label("instance", "web-1:9090"),
+                ],
+                samples: vec![
+                    Sample {
+                        value: 10.0,
+                        timestamp: now - 15_000,
+                    },
+                    Sample {
+                        value: 12.0,
+                        timestamp: now,
+                    },
+                ],
+            },
+            TimeSeries {
+                labels: vec![
+                    label("__name__", "request_duration_seconds_bucket"),
+                    label("le", "0.5"),
+                ],
+                samples: vec![
+                    Sample {
+                        value: 3.0,
+                        timestamp: now,
+                    },
+                    // Staleness marker
+                    Sample {
+                        value: f64::NAN,
+                        timestamp: now,
+                    },
+                ],
+            },
+            TimeSeries {
+                labels: vec![label("__name__", "process_resident_memory_bytes")],
+                samples: vec![Sample {
+                    value: 1024.0,
+                    timestamp: now,
+                }],
+            },
+        ],
+        metadata: vec![MetricMetadata {
+            r#type: MetricType::Histogram as i32,
+            metric_family_name: "request_duration_seconds".to_string(),
+        }],
+    };
+
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&write_request.encode_to_vec())
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri("/api/v1/write")
+        .insert_header(("content-encoding", "snappy"))
+        .insert_header(("content-type", "application/x-protobuf"))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=http_requests_total")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(metrics[0]["value"], 12.0);
+    assert_eq!(metrics[0]["kind"], "counter");
+    assert_eq!(metrics[0]["labels"]["instance"], "web-1:9090");
+    assert!(metrics[0]["labels"].get("__name__").is_none());
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=request_duration_seconds_bucket")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["kind"], "counter");
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=process_resident_memory_bytes")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics[0]["kind"], "gauge");
+
+    let req = test::TestRequest::post()
+        .uri("/api/v1/write")
+        .set_payload("not snappy")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    // A header claiming 4 GB decompressed is rejected without allocating it
+    let req = test::TestRequest::post()
+        .uri("/api/v1/write")
+        .set_payload(vec![0xff, 0xff, 0xff, 0xff, 0x0f, 0x00])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 413);
+}
+
+fn otlp_string(key: &str, value: &str) -> otlp::KeyValue {
+    otlp::KeyValue {
+        key: key.to_string(),
+        value: Some(otlp::AnyValue {
+            value: Some(otlp::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+#[actix_rt::test]
+async fn test_otlp_protobuf_export() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let now = chrono::Utc::now().timestamp_nanos_opt().unwrap() as u64;
+    let metric = |name: &str, data: otlp::MetricData| otlp::Metric {
+        name: name.to_string(),
+        data: Some(data),
+    };
+
+    let export_request = otlp::ExportMetricsServiceRequest {
+        resource_metrics: vec![otlp::ResourceMetrics {
+            resource: Some(otlp::Resource {
+                attributes: vec![otlp_string("service.name", "checkout")],
+            }),
+            scope_metrics: vec![otlp::ScopeMetrics {
+                scope: Some(otlp::InstrumentationScope {
+                    name: "io.opentelemetry.http".to_string(),
+                    ..Default::default()
+                }),
+                metrics: vec![
+                    metric(
+                        "http.server.requests",
+                        otlp::MetricData::Sum(otlp::Sum {
+                            data_points: vec![otlp::NumberDataPoint {
+                                attributes: vec![otlp_string("http.method", "GET")],
+                                time_unix_nano: now,
+                                value: Some(otlp::NumberValue::AsInt(42)),
+                            }],
+                            aggregation_temporality: otlp::AggregationTemporality::Cumulative
+                                as i32,
+                            is_monotonic: true,
+                        }),
+                    ),
+                    metric(
+                        "http.server.duration",
+                        otlp::MetricData::Histogram(otlp::Histogram {
+                            data_points: vec![otlp::HistogramDataPoint {
+                                time_unix_nano: now,
+                                count: 6,
+                                sum: Some(1.5),
+                                bucket_counts: vec![1, 2, 3],
+                                explicit_bounds: vec![0.1, 0.5],
+                                ..Default::default()
+                            }],
+                            ..Default::default()
+                        }),
+                    ),
+                    metric(
+                        "http.server.size",
+                        otlp::MetricData::ExponentialHistogram(otlp::ExponentialHistogram {
+                            data_points: vec![otlp::ExponentialHistogramDataPoint {
+                                time_unix_nano: now,
+                                count: 4,
+                                sum: Some(10.0),
+                                scale: 0,
+                                zero_count: 1,
+                                positive: Some(otlp::Buckets {
+                                    offset: 1,
+                                    bucket_counts: vec![2, 1],
+                                }),
+                                ..Default::default()
+                            }],
+                            ..Default::default()
+                        }),
+                    ),
+                ],
+            }],
+        }],
+    };
+
+    let req = test::TestRequest::post()
+        .uri("/v1/metrics")
+        .insert_header(("content-type", "application/x-protobuf"))
+        .set_payload(export_request.encode_to_vec())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body = test::read_body(resp).await;
+    let export_response = otlp::ExportMetricsServiceResponse::decode(body).unwrap();
+    assert!(export_response.partial_success.is_none());
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=http.server.requests")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["kind"], "counter");
+    assert_eq!(metrics[0]["value"], 42.0);
+    assert_eq!(metrics[0]["labels"]["service_name"], "checkout");
+    assert_eq!(metrics[0]["labels"]["http_method"], "GET");
+    assert_eq!(
+        metrics[0]["labels"]["otel_scope_name"],
+        "io.opentelemetry.http"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=http.server.duration")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics[0]["kind"], "histogram");
+    assert_eq!(
+        metrics[0]["histogram"]["buckets"],
+        json!([{ "le": 0.1, "count": 1 }, { "le": 0.5, "count": 3 }])
+    );
+    assert_eq!(metrics[0]["histogram"]["count"], 6);
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=http.server.size")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    // Scale 0 means base 2: zero bucket, then (2, 4] and (4, 8]
+    assert_eq!(
+        metrics[0]["histogram"]["buckets"],
+        json!([{ "le": 0.0, "count": 1 }, { "le": 4.0, "count": 3 }, { "le": 8.0, "count": 4 }])
+    );
+}
+
+#[actix_rt::test]
+async fn test_otlp_json_export() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let now = chrono::Utc::now().timestamp_nanos_opt().unwrap();
+    let body = json!({
+        "resourceMetrics": [{
+            "resource": { "attributes": [{ "key": "host.name", "value": { "stringValue": "web-1" } }] },
+            "scopeMetrics": [{
+                "metrics": [
+                    {
+                        "name": "system.memory.usage",
+                        "gauge": { "dataPoints": [
+                            { "timeUnixNano": now.to_string(), "asInt": "2048",
+                              "attributes": [{ "key": "state", "value": { "stringValue": "used" } }] }
+                        ] }
+                    },
+                    {
+                        "name": "rpc.latency",
+                        "summary": { "dataPoints": [
+                            { "timeUnixNano": now.to_string(), "count": "10", "sum": 3.5,
+                              "quantileValues": [{ "quantile": 0.99, "value": 0.9 }, { "quantile": 0.5, "value": 0.3 }] }
+                        ] }
+                    },
+                    {
+                        "name": "queue.length",
+                        "gauge": { "dataPoints": [{ "timeUnixNano": "1", "asDouble": 3.0 }] }
+                    }
+                ]
+            }]
+        }]
+    });
+
+    let req = test::TestRequest::post()
+        .uri("/v1/metrics")
+        .set_json(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let export_response: Value = test::read_body_json(resp).await;
+    // The 1970 data point is outside the accepted timestamp range
+    assert_eq!(export_response["partialSuccess"]["rejectedDataPoints"], "1");
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=system.memory.usage")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics[0]["value"], 2048.0);
+    assert_eq!(metrics[0]["labels"]["host_name"], "web-1");
+    assert_eq!(metrics[0]["labels"]["state"], "used");
+
+    let req = test::TestRequest::get()
+        .uri("/metrics?name=rpc.latency")
+        .to_request();
+    let metrics: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(metrics[0]["kind"], "summary");
+    assert_eq!(metrics[0]["summary"]["quantiles"][0]["quantile"], 0.5);
+
+    let req = test::TestRequest::post()
+        .uri("/v1/metrics")
+        .insert_header(("content-type", "application/json"))
+        .set_payload("{\"resourceMetrics\": 5}")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    // Timestamps past i64 nanoseconds and overflowing buckets are rejected
+    let max = u64::MAX.to_string();
+    for metric in [
+        json!({ "name": "x", "gauge": { "dataPoints": [{ "timeUnixNano": max, "asDouble": 1.0 }] } }),
+        json!({ "name": "x", "histogram": { "dataPoints": [{
+            "explicitBounds": [1.0, 2.0], "bucketCounts": [max, "1", "0"], "count": "1"
+        }] } }),
+        json!({ "name": "x", "exponentialHistogram": { "dataPoints": [{
+            "scale": 0, "zeroCount": max, "positive": { "offset": 0, "bucketCounts": ["1"] }
+        }] } }),
+        json!({ "name": "x", "exponentialHistogram": { "dataPoints": [{
+            "scale": 0, "positive": { "offset": i32::MAX, "bucketCounts": ["1"] }
+        }] } }),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/v1/metrics")
+            .set_json(json!({ "resourceMetrics": [{ "scopeMetrics": [{ "metrics": [metric] }] }] }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid data point"));
+    }
+}
+
+#[actix_rt::test]
+async fn test_statsd_parse_and_aggregate() {
+    let sample = statsd::parse_line("api.requests:2:3|c|@0.5|#env:prod,canary").unwrap();
+    assert_eq!(sample.name, "api.requests");
+    assert_eq!(sample.metric_type, StatsdType::Counter);
+    assert_eq!(
+        sample.values,
+        vec![StatsdValue::Number(2.0), StatsdValue::Number(3.0)]
+    );
+    assert_eq!(sample.sample_rate, 0.5);
+    assert_eq!(sample.tags, vec!["env:prod", "canary"]);
+
+    assert!(statsd::parse_line("no_type:1").is_err());
+    assert!(statsd::parse_line("bad:1|x").is_err());
+    assert!(statsd::parse_line("bad:abc|c").is_err());
+    assert!(statsd::parse_line("_e{5,4}:title|text").is_err());
+
+    let mut aggregator = StatsdAggregator::new();
+    for line in [
+        "api.requests:2:3|c|@0.5|#env:prod",
+        "queue.depth:10|g",
+        "queue.depth:-4|g",
+        "api.latency:10|ms",
+        "api.latency:20|ms",
+        "api.latency:30|ms|@0.5",
+        "api.users:alice|s",
+        "api.users:bob|s",
+        "api.users:alice|s",
+    ] {
+        aggregator.add(statsd::parse_line(line).unwrap());
+    }
+
+    let flushed = aggregator.flush();
+    let find = |name: &str| flushed.iter().find(|r| r.name == name).unwrap();
+
+    let requests = find("api.requests");
+    assert_eq!(requests.kind, MetricKind::Counter);
+    assert_eq!(requests.value, Some(10.0));
+    assert_eq!(requests.tags, Some(vec!["env:prod".to_string()]));
+
+    assert_eq!(find("queue.depth").value, Some(6.0));
+    assert_eq!(find("api.users").value, Some(2.0));
+
+    let latency = find("api.latency");
+    assert_eq!(latency.kind, MetricKind::Summary);
+    let summary = latency.summary.as_ref().unwrap();
+    assert_eq!(summary.count, 4);
+    assert_eq!(summary.sum, 90.0);
+    assert_eq!(summary.quantiles[0].value, 20.0);
+    assert_eq!(summary.quantiles[2].value, 30.0);
+
+    // Counters stay cumulative across flushes; untouched series are not rewritten
+    aggregator.add(statsd::parse_line("api.requests:1|c|#env:prod").unwrap());
+    let flushed = aggregator.flush();
+    assert_eq!(flushed.len(), 1);
+    assert_eq!(flushed[0].value, Some(11.0));
+
+    // A counter past the largest float starts over instead of staying infinite
+    for line in ["huge:1e308|c", "huge:1e308|c"] {
+        aggregator.add(statsd::parse_line(line).unwrap());
+    }
+    assert_eq!(aggregator.flush()[0].value, Some(1e308));
+    aggregator.add(statsd::parse_line("huge:1|c").unwrap());
+    assert!(aggregator.flush()[0].value.unwrap().is_finite());
+
+    // Series idle for long enough are forgotten; a counter then restarts from zero
+    for _ in 0..statsd::IDLE_FLUSHES {
+        aggregator.flush();
+    }
+    aggregator.add(statsd::parse_line("api.requests:1|c|#env:prod").unwrap());
+    assert_eq!(aggregator.flush()[0].value, Some(1.0));
+}
+
+#[actix_rt::test]
+async fn test_statsd_listener_flushes_to_store() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    actix_rt::spawn(statsd::run(
+        socket,
+        telemetry_service.clone(),
+        std::time::Duration::from_millis(50),
+    ));
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(
+            b"jobs.done:5|c|#queue:email\njobs.done:2|c|#queue:email",
+            addr,
+        )
+        .await
+        .unwrap();
+
+    let mut stored = Vec::new();
+    for _ in 0..40 {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        stored = telemetry_service
+            .get_metrics(MetricFilter {
+                name: Some("jobs.done".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        if !stored.is_empty() {
+            break;
+        }
+    }
+
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].kind, MetricKind::Counter);
+    assert_eq!(stored[0].value, 7.0);
+    assert_eq!(
+        stored[0].labels.get("queue").map(String::as_str),
+        Some("email")
+    );
+}
+
+#[actix_rt::test]
+async fn test_influx_line_protocol_write() {
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service.clone()))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let now = chrono::Utc::now().timestamp();
+    let body = format!(
+        "# telegraf output\n\
+         cpu,host=web\\ 1,region=eu usage_user=12.5,usage_idle=80i,online=t,note=\"a, b\" {now}\n\
+         mem used=1024u\n"
+    );
+    let req = test::TestRequest::post()
+        .uri("/write?db=telegraf&precision=s")
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    let stored = telemetry_service
+        .get_metrics(MetricFilter::default())
+        .await
+        .unwrap();
+    let mut names: Vec<&str> = stored.iter().map(|m| m.name.as_str()).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["cpu.online", "cpu.usage_idle", "cpu.usage_user", "mem.used"]
+    );
+
+    let usage = stored.iter().find(|m| m.name == "cpu.usage_user").unwrap();
+    assert_eq!(usage.value, 12.5);
+    assert_eq!(usage.labels.get("host").map(String::as_str), Some("web 1"));
+    assert_eq!(usage.timestamp.timestamp_millis(), now * 1000);
+
+    // Good lines are written; bad ones are reported by line number
+    let req = test::TestRequest::post()
+        .uri("/write")
+        .set_payload("disk free=1\ndisk\ndisk free=abc\ndisk,path free=2\n")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let body: Value = test::read_body_json(resp).await;
+    let lines: Vec<u64> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["line"].as_u64().unwrap())
+        .collect();
+    assert_eq!(lines, vec![2, 3, 4]);
+    assert!(body["error"].as_str().unwrap().starts_with("partial write"));
+
+    let req = test::TestRequest::post()
+        .uri("/write?precision=h")
+        .set_payload("disk free=1")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_graphite_templates_and_pickle() {
+    let sample = graphite::parse_line("servers.web1.cpu.load 1.5 1700000000").unwrap();
+    assert_eq!(sample.path, "servers.web1.cpu.load");
+    assert_eq!(sample.value, 1.5);
+    assert_eq!(sample.timestamp, Some(1700000000000));
+    assert_eq!(graphite::parse_line("a.b 2 -1").unwrap().timestamp, None);
+    assert_eq!(
+        graphite::parse_line("a.b 2 1700000000.25")
+            .unwrap()
+            .timestamp,
+        Some(1700000000250)
+    );
+    assert!(graphite::parse_line("a.b 2 -5").is_err());
+    assert!(graphite::parse_line("a.b").is_err());
+    assert!(graphite::parse_line("a.b nan 1").is_err());
+
+    let templates: GraphiteTemplates =
+        "servers.* .host.measurement* env=prod; stats.* .app..measurement"
+            .parse()
+            .unwrap();
+    assert!("servers.* .host.bad-name"
+        .parse::<GraphiteTemplates>()
+        .is_err());
+
+    let request = templates.to_metric_request(sample);
+    assert_eq!(request.name, "cpu.load");
+    let labels = request.labels.unwrap();
+    assert_eq!(labels["host"], "web1");
+    assert_eq!(labels["env"], "prod");
+
+    let request = templates.to_metric_request(
+        graphite::parse_line("stats.checkout.timers.latency 3 1700000000").unwrap(),
+    );
+    assert_eq!(request.name, "latency");
+    assert_eq!(request.labels.unwrap()["app"], "checkout");
+
+    // Unmatched paths keep their full name; tagged series carry labels
+    let request = templates
+        .to_metric_request(graphite::parse_line("disk.used;host=db1;mount.point=/ 7").unwrap());
+    assert_eq!(request.name, "disk.used");
+    let labels = request.labels.unwrap();
+    assert_eq!(labels["host"], "db1");
+    assert_eq!(labels["mount_point"], "/");
+
+    // pickle.dumps([...], protocol=2) and protocol=0 as sent by carbon-relay
+    let protocol_2 = b"\x80\x02]q\x00(X\x15\x00\x00\x00servers.web1.cpu.loadq\x01J\x00\xf1SeG?\xf8\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x12\x00\x00\x00disk.used;host=db1q\x04J<\xf1SeK*\x86q\x05\x86q\x06e.";
+    let samples = graphite::unpickle(protocol_2).unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[0].path, "servers.web1.cpu.load");
+    assert_eq!(samples[0].value, 1.5);
+    assert_eq!(samples[0].timestamp, Some(1700000000000));
+    assert_eq!(samples[1].path, "disk.used;host=db1");
+    assert_eq!(samples[1].value, 42.0);
+
+    let protocol_0 = b"(lp0\n(Va.b\np1\n(I1700000000\nF2.5\ntp2\ntp3\na.";
+    let samples = graphite::unpickle(protocol_0).unwrap();
+    assert_eq!(samples[0].path, "a.b");
+    assert_eq!(samples[0].value, 2.5);
+
+    // Negative and non-numeric timestamps are rejected, not taken as "now"
+    assert!(graphite::unpickle(b"(lp0\n(Va.b\np1\n(I-5\nF2.5\ntp2\ntp3\na.").is_err());
+    assert!(graphite::unpickle(b"(lp0\n(Va.b\np1\n(Vsoon\nF2.5\ntp2\ntp3\na.").is_err());
+
+    // Object construction opcodes are refused
+    assert!(graphite::unpickle(b"cos\nsystem\n(S'ls'\ntR.").is_err());
+
+    // A pair of the memoized value, memoized again: doubles on every round
+    let mut doubling = b"\x80\x02]q\x00".to_vec();
+    for _ in 0..64 {
+        doubling.extend_from_slice(b"h\x00h\x00\x86q\x00");
+    }
+    doubling.push(b'.');
+    assert!(graphite::unpickle(&doubling).is_err());
+
+    let mut nested = b"]".to_vec();
+    nested.extend(std::iter::repeat_n(0x85, 100_000));
+    nested.push(b'.');
+    assert!(graphite::unpickle(&nested).is_err());
+}
+
+#[actix_rt::test]
+async fn test_graphite_plaintext_listener() {
+    use tokio::io::AsyncWriteExt;
+
+    let telemetry_service = TelemetryService::new(Arc::new(InMemoryStore::new()), None);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let templates: GraphiteTemplates = "servers.* .host.measurement*".parse().unwrap();
+
+    actix_rt::spawn(graphite::run_plaintext(
+        listener,
+        telemetry_service.clone(),
+        templates,
+    ));
+
+    let now = chrono::Utc::now().timestamp();
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("servers.web1.cpu.load 0.75 {now}\nnot a valid line\n").as_bytes())
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+    drop(stream);
+
+    // A line past the length limit closes the connection before the next line
+    let mut long = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut payload = vec![b'x'; 100_000];
+    payload.extend_from_slice(format!("\nservers.web2.cpu.load 1 {now}\n").as_bytes());
+    long.write_all(&payload).await.unwrap();
+    let mut buf = [0u8; 1];
+    let closed = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        tokio::io::AsyncReadExt::read(&mut long, &mut buf),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(closed, Ok(0) | Err(_)));
+
+    let mut stored = Vec::new();
+    for _ in 0..40 {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        stored = telemetry_service
+            .get_metrics(MetricFilter::default())
+            .await
+            .unwrap();
+        if !stored.is_empty() {
+            break;
+        }
+    }
+
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].name, "cpu.load");
+    assert_eq!(stored[0].value, 0.75);
+    assert_eq!(stored[0].labels["host"], "web1");
+    assert_eq!(stored[0].timestamp.timestamp_millis(), now * 1000);
+}
@@ -85,3 +85,67 @@ async fn test_query_endpoint() {
         assert!(!results.is_empty());
     }
 }
+
+#[tokio::test]
+async fn test_writes_invalidate_cached_queries() {
+    let base_url = "http://localhost:8081";
+    let client = reqwest::Client::new();
+    let name = format!("cache_test_{}", chrono::Utc::now().timestamp_millis());
+    let list_url = format!("{base_url}/metrics?name={name}");
+
+    let create = |value: f64| {
+        client
+            .post(format!("{base_url}/metrics"))
+            .json(&json!({ "name": name, "value": value }))
+            .send()
+    };
+
+    // Populate the cache, then write and read again
+    if create(1.0).await.is_err() {
+        return;
+    }
+    let first: Vec<serde_json::Value> = client
+        .get(&list_url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first.len(), 1);
+
+    let created: serde_json::Value = create(2.0).await.unwrap().json().await.unwrap();
+    let second: Vec<serde_json::Value> = client
+        .get(&list_url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(second.len(), 2);
+
+    let Some(metric_id) = created
+        .get("_id")
+        .and_then(|id| id.get("$oid"))
+        .and_then(|oid| oid.as_str())
+    else {
+        return;
+    };
+    let delete_response = client
+        .delete(format!("{base_url}/metrics/{metric_id}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete_response.status(), 204);
+
+    let third: Vec<serde_json::Value> = client
+        .get(&list_url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(third.len(), 1);
+}
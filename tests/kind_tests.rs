@@ -0,0 +1,145 @@
+use telemetry_server::models::{
+    counter_increase, DDSketch, Distribution, Histogram, HistogramBucket, RateBuckets,
+    RateFunction, Step, EXACT_QUANTILE_POINTS,
+};
+
+fn histogram(buckets: &[(f64, u64)], count: u64, sum: f64) -> Histogram {
+    Histogram {
+        buckets: buckets
+            .iter()
+            .map(|&(le, count)| HistogramBucket { le, count })
+            .collect(),
+        count,
+        sum,
+    }
+}
+
+#[test]
+fn test_counter_increase_handles_resets() {
+    assert_eq!(counter_increase([]), 0.0);
+    assert_eq!(counter_increase([5.0]), 0.0);
+    assert_eq!(counter_increase([1.0, 4.0, 10.0]), 9.0);
+    // Reset between 10 and 2: the 2 after the reset counts in full
+    assert_eq!(counter_increase([1.0, 10.0, 2.0, 5.0]), 9.0 + 2.0 + 3.0);
+}
+
+#[test]
+fn test_histogram_validation() {
+    assert!(histogram(&[(0.1, 1), (0.5, 3)], 4, 1.0).validate().is_ok());
+    assert!(histogram(&[(0.5, 1), (0.1, 3)], 4, 1.0).validate().is_err());
+    assert!(histogram(&[(0.1, 3), (0.5, 1)], 4, 1.0).validate().is_err());
+    assert!(histogram(&[(0.1, 1), (0.5, 3)], 2, 1.0).validate().is_err());
+    assert!(histogram(&[(f64::INFINITY, 1)], 1, 1.0).validate().is_err());
+}
+
+#[test]
+fn test_histogram_merge_and_quantile() {
+    let mut merged = histogram(&[(0.1, 2), (0.5, 6), (1.0, 8)], 10, 4.0);
+    merged
+        .merge(&histogram(&[(0.1, 8), (0.5, 12), (1.0, 12)], 10, 2.0))
+        .unwrap();
+
+    assert_eq!(
+        merged,
+        histogram(&[(0.1, 10), (0.5, 18), (1.0, 20)], 20, 6.0)
+    );
+
+    // Median is the 10th of 20 observations: the top of the first bucket
+    assert_eq!(merged.quantile(0.5), Some(0.1));
+    // p70 is the 14th observation, halfway through the (0.1, 0.5] bucket
+    assert!((merged.quantile(0.7).unwrap() - 0.3).abs() < 1e-9);
+    assert_eq!(merged.quantile(1.0), Some(1.0));
+    assert_eq!(merged.quantile(1.5), None);
+
+    let different_bounds = histogram(&[(0.2, 1)], 1, 0.2);
+    assert!(merged.merge(&different_bounds).is_err());
+
+    // Overflowing counts leave the histogram as it was
+    let full = histogram(
+        &[(0.1, u64::MAX), (0.5, u64::MAX), (1.0, u64::MAX)],
+        u64::MAX,
+        0.0,
+    );
+    assert!(merged.merge(&full).is_err());
+    assert_eq!(merged.count, 20);
+
+    // Below zero the first bucket does not start at zero
+    let negative = histogram(&[(-10.0, 5), (-5.0, 10), (0.0, 10)], 10, -75.0);
+    assert_eq!(negative.quantile(0.2), Some(-10.0));
+    assert_eq!(negative.quantile(0.75), Some(-7.5));
+    assert_eq!(histogram(&[], 0, 0.0).quantile(0.5), None);
+}
+
+#[test]
+fn test_sketch_quantiles_within_relative_error() {
+    let mut low = DDSketch::new();
+    let mut high = DDSketch::new();
+    for i in 1..=5000 {
+        low.add(i as f64);
+        high.add((i + 5000) as f64);
+    }
+    low.merge(&high);
+
+    assert_eq!(low.count(), 10_000);
+    for (q, expected) in [(0.5, 5000.0), (0.9, 9000.0), (0.99, 9900.0)] {
+        let estimate = low.quantile(q).unwrap();
+        assert!(
+            (estimate - expected).abs() <= expected * 0.011,
+            "q={q}: {estimate}"
+        );
+    }
+    assert_eq!(low.quantile(0.0), Some(1.0));
+    assert_eq!(low.quantile(1.0), Some(10_000.0));
+    assert_eq!(low.quantile(1.5), None);
+
+    let mut exact = Distribution::new();
+    [4.0, 1.0, 3.0, 2.0].iter().for_each(|&v| exact.add(v));
+    assert_eq!(exact.quantile(0.5), Some(2.5));
+
+    let mut sketch = Distribution::Sketch(DDSketch::new());
+    sketch.add(-10.0);
+    exact.merge(&sketch);
+    assert!(matches!(exact, Distribution::Sketch(_)));
+    assert_eq!(exact.count(), 5);
+    assert_eq!(exact.quantile(0.0), Some(-10.0));
+
+    // Exact until the limit is passed, by adding or by merging
+    let mut values = Distribution::new();
+    (0..EXACT_QUANTILE_POINTS).for_each(|v| values.add(v as f64));
+    assert!(matches!(values, Distribution::Exact(_)));
+    let mut merged = values.clone();
+    values.add(0.0);
+    assert!(matches!(values, Distribution::Sketch(_)));
+    assert_eq!(values.count(), EXACT_QUANTILE_POINTS as u64 + 1);
+    merged.merge(&Distribution::Exact(vec![1.0]));
+    assert!(matches!(merged, Distribution::Sketch(_)));
+}
+
+#[test]
+fn test_rate_functions_handle_resets_and_spacing() {
+    // 10s, then 30s apart, with a reset to 5 before the last sample
+    let samples = [(0, 100.0), (10_000, 110.0), (40_000, 140.0), (50_000, 5.0)];
+
+    assert_eq!(RateFunction::Increase.apply(&samples), Some(45.0));
+    assert_eq!(RateFunction::Rate.apply(&samples), Some(0.9));
+    assert_eq!(RateFunction::Irate.apply(&samples), Some(0.5));
+    assert_eq!(RateFunction::Delta.apply(&samples), Some(-95.0));
+    assert_eq!(RateFunction::Irate.apply(&samples[..3]), Some(1.0));
+
+    let gauge = [(0, 1.0), (1_000, 3.0), (4_000, 9.0)];
+    let slope = RateFunction::Derivative.apply(&gauge).unwrap();
+    assert!((slope - 2.0).abs() < 1e-9, "{slope}");
+
+    assert_eq!(RateFunction::Rate.apply(&samples[..1]), None);
+    assert_eq!(RateFunction::Rate.apply(&[(0, 1.0), (0, 2.0)]), None);
+
+    // Each minute's window starts at the previous sample, so increases add up
+    let mut buckets = RateBuckets::new(Step::from_secs(60).unwrap(), RateFunction::Increase);
+    let samples = [(0, 1.0), (30_000, 4.0), (70_000, 10.0), (200_000, 2.0)];
+    let mut increases: Vec<(i64, f64)> = samples
+        .into_iter()
+        .filter_map(|sample| buckets.add(sample))
+        .collect();
+    increases.extend(buckets.finish());
+    assert_eq!(increases, [(0, 3.0), (60_000, 6.0), (180_000, 2.0)]);
+}
@@ -0,0 +1,73 @@
+use telemetry_server::models::{labels_from_tags, LabelMatcher, LabelSelector, Labels};
+
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_parse_label_selector() {
+    let selector: LabelSelector =
+        r#"env=production, host!="web,1", region=~"eu-.*", tier in (api, "worker"), zone notin (a)"#
+            .parse()
+            .unwrap();
+
+    assert_eq!(selector.0.len(), 5);
+    assert!(matches!(&selector.0[0], LabelMatcher::Equal(n, v) if n == "env" && v == "production"));
+    assert!(matches!(&selector.0[1], LabelMatcher::NotEqual(n, v) if n == "host" && v == "web,1"));
+    assert!(
+        matches!(&selector.0[2], LabelMatcher::Regex(n, p, _) if n == "region" && p == "eu-.*")
+    );
+    assert!(
+        matches!(&selector.0[3], LabelMatcher::In(n, v) if n == "tier" && v == &["api", "worker"])
+    );
+    assert!(matches!(&selector.0[4], LabelMatcher::NotIn(n, _) if n == "zone"));
+
+    let promql_style: LabelSelector = r#"{env="production"}"#.parse().unwrap();
+    assert_eq!(promql_style.0.len(), 1);
+
+    assert!("env".parse::<LabelSelector>().is_err());
+    assert!("1env=a".parse::<LabelSelector>().is_err());
+    assert!(r#"env=~"(""#.parse::<LabelSelector>().is_err());
+    assert!("tier in (a,b".parse::<LabelSelector>().is_err());
+}
+
+#[test]
+fn test_label_selector_matching() {
+    let metric_labels = labels(&[("env", "production"), ("region", "eu-west")]);
+
+    let matches = |selector: &str| {
+        selector
+            .parse::<LabelSelector>()
+            .unwrap()
+            .matches(&metric_labels)
+    };
+
+    assert!(matches("env=production"));
+    assert!(!matches("env!=production"));
+    assert!(matches("region=~eu-.*"));
+    assert!(!matches("region=~eu"), "regexes are anchored");
+    assert!(matches("region!~us-.*"));
+    assert!(matches("env in (staging,production)"));
+    assert!(!matches("env notin (staging,production)"));
+
+    // A missing label fails positive matchers and passes negative ones
+    assert!(!matches("host=web-1"));
+    assert!(!matches("host=~.*"));
+    assert!(matches("host!=web-1"));
+    assert!(matches("host notin (web-1)"));
+}
+
+#[test]
+fn test_labels_from_tags() {
+    let tags = vec![
+        "env:production".to_string(),
+        "critical".to_string(),
+        "bad key:x".to_string(),
+        "empty:".to_string(),
+    ];
+
+    assert_eq!(labels_from_tags(&tags), labels(&[("env", "production")]));
+}
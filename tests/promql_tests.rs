@@ -0,0 +1,375 @@
+mod common;
+
+use actix_web::{test as actix_test, web, App};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use common::in_memory_services;
+use serde_json::{json, Value};
+use telemetry_server::{promql, routes};
+
+/// A recent minute boundary, so test samples fall at known offsets.
+fn origin() -> DateTime<Utc> {
+    (Utc::now() - Duration::hours(1))
+        .duration_trunc(Duration::minutes(1))
+        .unwrap()
+}
+
+fn sample(name: &str, labels: Value, value: f64, at: DateTime<Utc>) -> Value {
+    json!({ "name": name, "labels": labels, "value": value, "timestamp": at.to_rfc3339() })
+}
+
+fn get(path: &str, params: &[(&str, String)]) -> actix_test::TestRequest {
+    let url = reqwest::Url::parse_with_params(&format!("http://localhost{path}"), params).unwrap();
+    actix_test::TestRequest::get().uri(&format!(
+        "{}?{}",
+        url.path(),
+        url.query().unwrap_or_default()
+    ))
+}
+
+fn seconds(at: DateTime<Utc>) -> String {
+    at.timestamp().to_string()
+}
+
+#[actix_rt::test]
+async fn test_parse_promql() {
+    for query in [
+        "up",
+        r#"http_requests_total{job="api", status=~"5..", method!="GET"}[5m]"#,
+        "sum by (job) (rate(http_requests_total[5m]))",
+        "sum(rate(http_requests_total[5m])) without (instance)",
+        "histogram_quantile(0.99, sum by (le) (rate(latency_bucket[1h30m])))",
+        "avg_over_time(cpu[10m]) > bool 0.5",
+        "errors / ignoring (code) requests * 100",
+        "-2 ^ 2 + time()",
+        "a and on (host) b or c unless d",
+        r#"{__name__=~"cpu_.*"}"#,
+    ] {
+        assert!(promql::parse(query).is_ok(), "{query}");
+    }
+
+    for query in [
+        "rate(x[5m]",
+        "x{job=api}",
+        "x[5]",
+        "unknown_fn(x)",
+        "histogram_quantile(x)",
+        r#"{job!="api"}"#,
+        "1 + bool 2",
+        "x )",
+    ] {
+        assert!(promql::parse(query).is_err(), "{query}");
+    }
+
+    // Nesting is bounded rather than overflowing the stack
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(promql::parse(&nested(255)).is_ok());
+    assert!(promql::parse(&nested(256)).is_err());
+    for query in [
+        "(".repeat(100_000),
+        format!("{}1", "-".repeat(100_000)),
+        format!("{}1", "2 ^ ".repeat(100_000)),
+        format!("{}x{}", "abs(".repeat(100_000), ")".repeat(100_000)),
+    ] {
+        assert!(promql::parse(&query).is_err());
+    }
+
+    assert_eq!(promql::parse_duration("1h30m").unwrap(), 5_400_000);
+
+    // Precedence and associativity, checked on the evaluated result
+    let (telemetry_service, _) = in_memory_services();
+    let engine = promql::Engine::new(&telemetry_service);
+    for (query, expected) in [
+        ("-2 ^ 2", -4.0),
+        ("2 ^ 3 ^ 2", 512.0),
+        ("1 + 2 * 3", 7.0),
+        ("10 - 4 - 3", 3.0),
+        ("2 * 3 % 4", 2.0),
+        ("(1 + 2) * 3", 9.0),
+    ] {
+        let expr = promql::parse(query).unwrap();
+        let value = engine.instant(&expr, 0).await.unwrap();
+        assert_eq!(value, promql::Value::Scalar(expected), "{query}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_promql_http_api() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = actix_test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    // Counters for two hosts scraped every 30s, one resetting, plus histogram buckets
+    let origin = origin();
+    let mut samples = Vec::new();
+    for i in 0..=10 {
+        let at = origin + Duration::seconds(30 * i);
+        samples.push(sample(
+            "http_requests_total",
+            json!({ "host": "web-1", "job": "api" }),
+            (30 * i) as f64,
+            at,
+        ));
+        let web_2 = if i < 5 { 60 * i } else { 60 * (i - 5) };
+        samples.push(sample(
+            "http_requests_total",
+            json!({ "host": "web-2", "job": "api" }),
+            web_2 as f64,
+            at,
+        ));
+    }
+    for (le, count) in [("0.1", 50.0), ("0.5", 90.0), ("+Inf", 100.0)] {
+        samples.push(sample(
+            "latency_bucket",
+            json!({ "le": le, "job": "api" }),
+            count,
+            origin + Duration::minutes(5),
+        ));
+    }
+    let req = actix_test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(samples)
+        .to_request();
+    assert_eq!(actix_test::call_service(&app, req).await.status(), 201);
+
+    let at = origin + Duration::minutes(5);
+    let instant = |query: &str| {
+        get(
+            "/api/v1/query",
+            &[("query", query.to_string()), ("time", seconds(at))],
+        )
+        .to_request()
+    };
+
+    // Instant vector, keeping __name__
+    let body: Value =
+        actix_test::call_and_read_body_json(&app, instant(r#"http_requests_total{host="web-1"}"#))
+            .await;
+    assert_eq!(body["status"], "success");
+    assert_eq!(body["data"]["resultType"], "vector");
+    let result = &body["data"]["result"][0];
+    assert_eq!(
+        result["metric"],
+        json!({ "__name__": "http_requests_total", "host": "web-1", "job": "api" })
+    );
+    assert_eq!(result["value"], json!([at.timestamp() as f64, "300"]));
+
+    // rate per host, then summed; web-2's reset is counted as a restart from zero
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        instant("sum by (job) (rate(http_requests_total[5m]))"),
+    )
+    .await;
+    let result = &body["data"]["result"];
+    assert_eq!(result.as_array().unwrap().len(), 1);
+    assert_eq!(result[0]["metric"], json!({ "job": "api" }));
+    // The window excludes its start: web-1 rises 270 in 270s, web-2 rises 180,
+    // resets to 0 and rises another 300
+    let expected = 1.0 + 480.0 / 270.0;
+    let value: f64 = result[0]["value"][1].as_str().unwrap().parse().unwrap();
+    assert!((value - expected).abs() < 1e-9, "{value}");
+
+    // Binary operators with scalars and between vectors
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        instant(
+            r#"http_requests_total{host="web-1"} / on (job) http_requests_total{host="web-2"} * 2"#,
+        ),
+    )
+    .await;
+    assert_eq!(body["data"]["result"][0]["metric"], json!({ "job": "api" }));
+    assert_eq!(body["data"]["result"][0]["value"][1], "2");
+
+    let body: Value =
+        actix_test::call_and_read_body_json(&app, instant("irate(http_requests_total[1m]) > 1.5"))
+            .await;
+    let result = body["data"]["result"].as_array().unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(
+        result[0]["metric"],
+        json!({ "host": "web-2", "job": "api" })
+    );
+    assert_eq!(result[0]["value"][1], "2");
+
+    let body: Value = actix_test::call_and_read_body_json(&app, instant("1 + 2 * 3")).await;
+    assert_eq!(body["data"]["resultType"], "scalar");
+    assert_eq!(body["data"]["result"][1], "7");
+
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        instant("histogram_quantile(0.9, latency_bucket)"),
+    )
+    .await;
+    assert_eq!(body["data"]["result"][0]["metric"], json!({ "job": "api" }));
+    assert_eq!(body["data"]["result"][0]["value"][1], "0.5");
+
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        instant(r#"avg_over_time(http_requests_total{host="web-1"}[1m])"#),
+    )
+    .await;
+    assert_eq!(body["data"]["result"][0]["value"][1], "285");
+
+    // Range query, one point per step
+    let req = get(
+        "/api/v1/query_range",
+        &[
+            ("query", r#"http_requests_total{host="web-1"}"#.to_string()),
+            ("start", seconds(origin)),
+            ("end", seconds(origin + Duration::minutes(2))),
+            ("step", "1m".to_string()),
+        ],
+    )
+    .to_request();
+    let body: Value = actix_test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["resultType"], "matrix");
+    let values = &body["data"]["result"][0]["values"];
+    assert_eq!(values.as_array().unwrap().len(), 3);
+    assert_eq!(
+        values[2],
+        json!([(origin + Duration::minutes(2)).timestamp() as f64, "120"])
+    );
+
+    // Form-encoded POST, as Grafana sends it
+    let req = actix_test::TestRequest::post()
+        .uri("/api/v1/query")
+        .insert_header(("content-type", "application/x-www-form-urlencoded"))
+        .set_payload(format!(
+            "query=count%28http_requests_total%29&time={}",
+            at.timestamp()
+        ))
+        .to_request();
+    let body: Value = actix_test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["result"][0]["value"][1], "2");
+
+    // Metadata endpoints
+    let req = get(
+        "/api/v1/series",
+        &[(
+            "match[]",
+            r#"{__name__=~"http_.*", host="web-2"}"#.to_string(),
+        )],
+    )
+    .to_request();
+    let body: Value = actix_test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["data"],
+        json!([{ "__name__": "http_requests_total", "host": "web-2", "job": "api" }])
+    );
+
+    let body: Value =
+        actix_test::call_and_read_body_json(&app, get("/api/v1/labels", &[]).to_request()).await;
+    assert_eq!(body["data"], json!(["__name__", "host", "job", "le"]));
+
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        get("/api/v1/label/__name__/values", &[]).to_request(),
+    )
+    .await;
+    assert_eq!(
+        body["data"],
+        json!(["http_requests_total", "latency_bucket"])
+    );
+
+    let req = get(
+        "/api/v1/label/host/values",
+        &[("match[]", "http_requests_total".to_string())],
+    )
+    .to_request();
+    let body: Value = actix_test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"], json!(["web-1", "web-2"]));
+
+    // Errors in Prometheus' format
+    for (req, status, error_type) in [
+        (instant("sum(rate(x[5m])"), 400, "bad_data"),
+        (instant("rate(http_requests_total)"), 422, "execution"),
+        (get("/api/v1/query", &[]).to_request(), 400, "bad_data"),
+        (get("/api/v1/series", &[]).to_request(), 400, "bad_data"),
+        (
+            get(
+                "/api/v1/query_range",
+                &[
+                    ("query", "up".to_string()),
+                    ("start", "0".to_string()),
+                    ("end", "100000".to_string()),
+                    ("step", "1".to_string()),
+                ],
+            )
+            .to_request(),
+            400,
+            "bad_data",
+        ),
+    ] {
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status);
+        let body: Value = actix_test::read_body_json(resp).await;
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["errorType"], error_type);
+    }
+
+    // Times and steps that do not fit a timestamp are rejected, not clamped
+    let range = |start: &str, end: &str, step: &str| {
+        get(
+            "/api/v1/query_range",
+            &[
+                ("query", "up".to_string()),
+                ("start", start.to_string()),
+                ("end", end.to_string()),
+                ("step", step.to_string()),
+            ],
+        )
+        .to_request()
+    };
+    for req in [
+        range("-1e300", "1e300", "1e300"),
+        range("0", "inf", "60"),
+        range("nan", "60", "60"),
+        range("0", "60", "inf"),
+        get(
+            "/api/v1/query",
+            &[("query", "up".to_string()), ("time", "-inf".to_string())],
+        )
+        .to_request(),
+    ] {
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    // A window reaching before any representable time selects everything
+    let body: Value = actix_test::call_and_read_body_json(
+        &app,
+        instant(r#"count_over_time(http_requests_total{host="web-1"}[290000000y])"#),
+    )
+    .await;
+    assert_eq!(body["data"]["result"][0]["value"][1], "11");
+
+    // Samples written out of order are read back in time order
+    let samples: Vec<Value> = (0..=4)
+        .rev()
+        .map(|i| {
+            sample(
+                "queue_depth",
+                json!({}),
+                i as f64,
+                origin + Duration::minutes(i),
+            )
+        })
+        .collect();
+    let req = actix_test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(samples)
+        .to_request();
+    assert_eq!(actix_test::call_service(&app, req).await.status(), 201);
+    for (query, expected) in [
+        ("queue_depth", "4"),
+        ("delta(queue_depth[3m])", "1"),
+        ("count_over_time(queue_depth[3m])", "2"),
+    ] {
+        let body: Value = actix_test::call_and_read_body_json(&app, instant(query)).await;
+        assert_eq!(body["data"]["result"][0]["value"][1], expected, "{query}");
+    }
+}
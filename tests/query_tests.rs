@@ -0,0 +1,920 @@
+mod common;
+
+use actix_web::{test, web, App};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use common::in_memory_services;
+use serde_json::{json, Value};
+use telemetry_server::{
+    models::{AggregationType, Grouping, RateFunction},
+    routes,
+    services::prompt::{self, PromptError},
+};
+
+/// A recent five-minute boundary, so test points fall in known buckets.
+fn bucket_origin() -> DateTime<Utc> {
+    (Utc::now() - Duration::hours(2))
+        .duration_trunc(Duration::minutes(5))
+        .unwrap()
+}
+
+fn point(name: &str, value: f64, at: DateTime<Utc>) -> Value {
+    json!({ "name": name, "value": value, "timestamp": at.to_rfc3339() })
+}
+
+#[actix_rt::test]
+async fn test_range_query_buckets_and_fills_gaps() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let origin = bucket_origin();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            point("cpu_usage", 1.0, origin),
+            point("cpu_usage", 3.0, origin + Duration::seconds(60)),
+            point("cpu_usage", 5.0, origin + Duration::minutes(10)),
+            point("mem_usage", 7.0, origin + Duration::minutes(5)),
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let range = |params: &str| {
+        let url = reqwest::Url::parse_with_params(
+            &format!("http://localhost/query/range?{params}"),
+            [
+                ("start", origin.to_rfc3339()),
+                (
+                    "end",
+                    (origin + Duration::minutes(15) - Duration::seconds(1)).to_rfc3339(),
+                ),
+            ],
+        )
+        .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+    let values = |series: &Value| -> Vec<Option<f64>> {
+        series["points"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|point| point["value"].as_f64())
+            .collect()
+    };
+
+    let body: Value = test::call_and_read_body_json(&app, range("name=cpu_usage&step=5m")).await;
+    assert_eq!(body["aggregation"], "avg");
+    assert_eq!(body["step"], 300);
+    let series = body["series"].as_array().unwrap();
+    assert_eq!(series.len(), 1);
+    assert_eq!(values(&series[0]), [Some(2.0), Some(5.0)]);
+    let first: DateTime<Utc> =
+        serde_json::from_value(series[0]["points"][0]["timestamp"].clone()).unwrap();
+    assert_eq!(first, origin);
+
+    for (fill, expected) in [
+        ("null", [Some(2.0), None, Some(5.0)]),
+        ("zero", [Some(2.0), Some(0.0), Some(5.0)]),
+        ("previous", [Some(2.0), Some(2.0), Some(5.0)]),
+        ("linear", [Some(2.0), Some(3.5), Some(5.0)]),
+    ] {
+        let body: Value = test::call_and_read_body_json(
+            &app,
+            range(&format!("name=cpu_usage&step=300&fill={fill}")),
+        )
+        .await;
+        assert_eq!(values(&body["series"][0]), expected, "fill={fill}");
+    }
+
+    // One series per metric name
+    let body: Value =
+        test::call_and_read_body_json(&app, range("step=5m&aggregation=max&fill=null")).await;
+    let series = body["series"].as_array().unwrap();
+    assert_eq!(series.len(), 2);
+    assert_eq!(values(&series[0]), [Some(3.0), None, Some(5.0)]);
+    assert_eq!(series[1]["name"], "mem_usage");
+    assert_eq!(values(&series[1]), [None, Some(7.0), None]);
+
+    let body: Value =
+        test::call_and_read_body_json(&app, range("name=cpu_usage&step=10m&aggregation=count"))
+            .await;
+    assert_eq!(values(&body["series"][0]), [Some(2.0), Some(1.0)]);
+
+    for uri in [
+        "/query/range?name=cpu_usage&step=5m",
+        "/query/range?start=2024-01-01T00:00:00Z&step=5x",
+        "/query/range?start=2024-01-01T00:00:00Z&end=2024-12-31T00:00:00Z&step=1s",
+        "/query/range?start=2024-01-02T00:00:00Z&end=2024-01-01T00:00:00Z&step=1h",
+    ] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(resp.status(), 400, "{uri}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_aggregations_group_by_labels() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service.clone()))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let origin = bucket_origin();
+    let request = |host: &str, service: &str, value: f64| {
+        json!({
+            "name": "requests",
+            "labels": { "host": host, "service": service },
+            "value": value,
+            "timestamp": origin.to_rfc3339(),
+        })
+    };
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            request("web-1", "api", 1.0),
+            request("web-1", "auth", 2.0),
+            request("web-2", "api", 4.0),
+            { "name": "requests", "value": 8.0, "timestamp": origin.to_rfc3339() },
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let series_of = |body: &Value| -> Vec<(Value, f64)> {
+        body["series"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|series| {
+                let value = series["value"]
+                    .as_f64()
+                    .or_else(|| series["points"][0]["value"].as_f64())
+                    .unwrap();
+                (series["labels"].clone(), value)
+            })
+            .collect()
+    };
+
+    // Structured, on range queries
+    let uri = |grouping: &str| {
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "http://localhost/query/range?name=requests&step=1h&aggregation=sum&{grouping}"
+            ),
+            [("start", origin.to_rfc3339())],
+        )
+        .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+
+    let body: Value = test::call_and_read_body_json(&app, uri("by=host")).await;
+    assert_eq!(
+        series_of(&body),
+        [
+            (Value::Null, 8.0),
+            (json!({ "host": "web-1" }), 3.0),
+            (json!({ "host": "web-2" }), 4.0),
+        ]
+    );
+
+    let body: Value = test::call_and_read_body_json(&app, uri("without=host")).await;
+    assert_eq!(
+        series_of(&body),
+        [
+            (Value::Null, 8.0),
+            (json!({ "service": "api" }), 5.0),
+            (json!({ "service": "auth" }), 2.0),
+        ]
+    );
+
+    for grouping in ["by=host&without=service", "by=host-name"] {
+        let resp = test::call_service(&app, uri(grouping)).await;
+        assert_eq!(resp.status(), 400, "{grouping}");
+    }
+
+    // Through the prompt
+    let parsed = query_service.parse_prompt("sum requests metrics by host and service last 1 days");
+    assert_eq!(
+        parsed.grouping,
+        Grouping::By(vec!["host".to_string(), "service".to_string()])
+    );
+    assert!(parsed.time_range.is_some());
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=sum%20of%20requests%20metrics%20by%20service")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["value"], 15.0);
+    assert_eq!(
+        series_of(&body),
+        [
+            (Value::Null, 8.0),
+            (json!({ "service": "api" }), 5.0),
+            (json!({ "service": "auth" }), 2.0),
+        ]
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=count%20requests%20metrics%20without%20service")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        series_of(&body),
+        [
+            (Value::Null, 1.0),
+            (json!({ "host": "web-1" }), 2.0),
+            (json!({ "host": "web-2" }), 1.0),
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn test_percentile_and_spread_aggregations() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service.clone()))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for (prompt, expected) in [
+        ("p99 latency metrics", AggregationType::Percentile(99.0)),
+        (
+            "95th percentile of latency",
+            AggregationType::Percentile(95.0),
+        ),
+        ("p99.9 latency today", AggregationType::Percentile(99.9)),
+        ("median latency metrics", AggregationType::Median),
+        ("standard deviation of latency", AggregationType::StdDev),
+        ("max latency metrics last 1 hours", AggregationType::Max),
+        ("lowest latency metrics", AggregationType::Min),
+    ] {
+        let parsed = query_service.parse_prompt(prompt);
+        assert_eq!(parsed.aggregation, Some(expected), "{prompt}");
+    }
+    assert_eq!(
+        query_service
+            .parse_prompt("p200 latency metrics")
+            .aggregation,
+        None
+    );
+
+    let origin = bucket_origin();
+    let points: Vec<Value> = (1..=100)
+        .map(|i| point("latency", i as f64, origin))
+        .collect();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(points)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let query = |prompt: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/query?prompt={}", prompt.replace(' ', "%20")))
+            .to_request()
+    };
+
+    for (prompt, expected) in [
+        ("median of latency metrics", 50.5),
+        ("p90 latency metrics", 90.1),
+        ("min latency metrics", 1.0),
+        ("max latency metrics", 100.0),
+    ] {
+        let body: Value = test::call_and_read_body_json(&app, query(prompt)).await;
+        let value = body["value"].as_f64().unwrap();
+        assert!((value - expected).abs() < 1e-9, "{prompt}: {value}");
+        assert_eq!(body["points"], 100, "{prompt}");
+    }
+
+    let body: Value = test::call_and_read_body_json(&app, query("stddev latency metrics")).await;
+    let stddev = body["value"].as_f64().unwrap();
+    assert!((stddev - 28.866_070_047_722_12).abs() < 1e-9, "{stddev}");
+    assert_eq!(body["aggregation"], "std_dev");
+
+    // Large values with a small spread, per series and merged across them
+    let points: Vec<Value> = [("a", -1.0), ("a", 1.0), ("b", 1.0), ("b", 3.0)]
+        .into_iter()
+        .map(|(host, offset)| {
+            json!({
+                "name": "balance",
+                "labels": { "host": host },
+                "value": 1e9 + offset,
+                "timestamp": origin.to_rfc3339(),
+            })
+        })
+        .collect();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(points)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let body: Value = test::call_and_read_body_json(&app, query("stddev balance by host")).await;
+    assert_eq!(body["series"][0]["value"], 1.0);
+    assert_eq!(body["series"][1]["value"], 1.0);
+    let stddev = body["value"].as_f64().unwrap();
+    assert!((stddev - 2f64.sqrt()).abs() < 1e-9, "{stddev}");
+
+    let body: Value = test::call_and_read_body_json(&app, query("p99 latency metrics")).await;
+    assert_eq!(body["aggregation"], json!({ "percentile": 99.0 }));
+
+    // Histogram metrics are summarized by their buckets, merged across series
+    let histograms: Vec<Value> = [("a", [2, 6, 8], 10), ("b", [8, 12, 12], 12)]
+        .into_iter()
+        .map(|(host, counts, count)| {
+            json!({
+                "name": "request_seconds",
+                "kind": "histogram",
+                "labels": { "host": host },
+                "histogram": {
+                    "buckets": [
+                        { "le": 0.1, "count": counts[0] },
+                        { "le": 0.5, "count": counts[1] },
+                        { "le": 1.0, "count": counts[2] },
+                    ],
+                    "count": count,
+                    "sum": 3.0,
+                },
+                "timestamp": origin.to_rfc3339(),
+            })
+        })
+        .collect();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(histograms)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let body: Value =
+        test::call_and_read_body_json(&app, query("p70 request_seconds by host")).await;
+    let value = body["value"].as_f64().unwrap();
+    assert!((value - 0.37).abs() < 1e-9, "{value}");
+    assert_eq!(body["points"], 22);
+    let body: Value =
+        test::call_and_read_body_json(&app, query("median request_seconds by host")).await;
+    let series: Vec<f64> = body["series"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|series| series["value"].as_f64().unwrap())
+        .collect();
+    assert!((series[0] - 0.4).abs() < 1e-9, "{series:?}");
+    assert!((series[1] - 0.075).abs() < 1e-9, "{series:?}");
+}
+
+#[actix_rt::test]
+async fn test_counter_rate_functions() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service.clone()))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    for (prompt, function, name) in [
+        ("requests per second", RateFunction::Rate, "requests"),
+        ("rate of errors last 1 hours", RateFunction::Rate, "errors"),
+        ("irate of requests", RateFunction::Irate, "requests"),
+        (
+            "increase in requests metrics",
+            RateFunction::Increase,
+            "requests",
+        ),
+        ("delta of temperature", RateFunction::Delta, "temperature"),
+        (
+            "derivative of temperature",
+            RateFunction::Derivative,
+            "temperature",
+        ),
+    ] {
+        let parsed = query_service.parse_prompt(prompt);
+        assert_eq!(
+            parsed.aggregation,
+            Some(AggregationType::Rate(function)),
+            "{prompt}"
+        );
+        assert_eq!(parsed.metric_name.as_deref(), Some(name), "{prompt}");
+    }
+
+    // Two counters, one resetting in the second five-minute bucket
+    let origin = bucket_origin();
+    let sample = |host: &str, value: f64, seconds: i64| {
+        json!({
+            "name": "requests",
+            "kind": "counter",
+            "labels": { "host": host },
+            "value": value,
+            "timestamp": (origin + Duration::seconds(seconds)).to_rfc3339(),
+        })
+    };
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            sample("web-1", 0.0, 0),
+            sample("web-1", 60.0, 60),
+            sample("web-1", 300.0, 300),
+            sample("web-1", 30.0, 420),
+            sample("web-2", 10.0, 0),
+            sample("web-2", 70.0, 300),
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let range = |aggregation: &str| {
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "http://localhost/query/range?name=requests&step=5m&aggregation={aggregation}"
+            ),
+            [("start", origin.to_rfc3339())],
+        )
+        .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+    let values = |body: &Value| -> Vec<f64> {
+        body["series"][0]["points"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|point| point["value"].as_f64().unwrap())
+            .collect()
+    };
+
+    // The second bucket counts from each series' last sample before it
+    let body: Value = test::call_and_read_body_json(&app, range("increase")).await;
+    assert_eq!(body["aggregation"], "increase");
+    assert_eq!(values(&body), [60.0, 330.0]);
+
+    let body: Value = test::call_and_read_body_json(&app, range("rate")).await;
+    assert_eq!(values(&body), [1.0, 270.0 / 360.0 + 60.0 / 300.0]);
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=increase%20in%20requests%20metrics%20by%20host")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["aggregation"], "increase");
+    assert_eq!(body["value"], 390.0);
+    assert_eq!(body["series"][0]["labels"], json!({ "host": "web-1" }));
+    assert_eq!(body["series"][0]["value"], 330.0);
+    assert_eq!(body["series"][1]["value"], 60.0);
+}
+
+#[actix_rt::test]
+async fn test_structured_query_dsl() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let origin = bucket_origin();
+    let cpu = |host: &str, value: f64, at: DateTime<Utc>| {
+        json!({
+            "name": "cpu",
+            "labels": { "host": host },
+            "value": value,
+            "timestamp": at.to_rfc3339(),
+        })
+    };
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            cpu("web-1", 10.0, origin),
+            cpu("web-1", 30.0, origin + Duration::minutes(1)),
+            cpu("web-1", 90.0, origin + Duration::minutes(10)),
+            cpu("web-2", 50.0, origin),
+            cpu("web-2", 70.0, origin + Duration::minutes(6)),
+            point("mem", 5.0, origin),
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let query = |body: Value| {
+        test::TestRequest::post()
+            .uri("/query")
+            .set_json(body)
+            .to_request()
+    };
+
+    // Raw metrics, filtered by labels and values, sorted ascending
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        query(json!({
+            "metric": "cpu",
+            "labels": "host=~\"web-.*\"",
+            "values": [{ "op": "gt", "value": 20 }, { "op": "<", "value": 90 }],
+            "order": { "by": "value", "direction": "asc" },
+        })),
+    )
+    .await;
+    let values: Vec<f64> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|metric| metric["value"].as_f64().unwrap())
+        .collect();
+    assert_eq!(values, [30.0, 50.0, 70.0]);
+
+    // A limit of 0 selects nothing rather than everything
+    let body: Value =
+        test::call_and_read_body_json(&app, query(json!({ "metric": "cpu", "limit": 0 }))).await;
+    assert_eq!(body, json!([]));
+
+    // Aggregated per host, series ordered by value and limited
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        query(json!({
+            "metric": "cpu",
+            "aggregation": "max",
+            "by": ["host"],
+            "order": { "by": "value", "direction": "asc" },
+            "limit": 1,
+        })),
+    )
+    .await;
+    assert_eq!(body["value"], 90.0);
+    assert_eq!(body["series"].as_array().unwrap().len(), 1);
+    assert_eq!(body["series"][0]["labels"], json!({ "host": "web-2" }));
+    assert_eq!(body["series"][0]["value"], 70.0);
+
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        query(json!({ "metric": "cpu", "aggregation": { "percentile": 50 } })),
+    )
+    .await;
+    assert_eq!(body["aggregation"], json!({ "percentile": 50.0 }));
+    assert_eq!(body["value"], 50.0);
+
+    // The same execution path as prompts
+    let from_dsl: Value = test::call_and_read_body_json(
+        &app,
+        query(json!({ "metric": "cpu", "aggregation": "average" })),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=average%20cpu%20metrics")
+        .to_request();
+    let from_prompt: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(from_dsl, from_prompt);
+    assert_eq!(from_dsl["value"], 50.0);
+
+    // Bucketed with a step
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        query(json!({
+            "metric": "cpu",
+            "start": origin.to_rfc3339(),
+            "end": (origin + Duration::minutes(15) - Duration::seconds(1)).to_rfc3339(),
+            "step": "5m",
+            "aggregation": "sum",
+        })),
+    )
+    .await;
+    assert_eq!(body["step"], 300);
+    let points: Vec<f64> = body["series"][0]["points"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|point| point["value"].as_f64().unwrap())
+        .collect();
+    assert_eq!(points, [90.0, 70.0, 90.0]);
+
+    for invalid in [
+        json!({ "metric": "cpu", "step": "5m", "start": origin.to_rfc3339(), "aggregation": "median" }),
+        json!({ "metric": "cpu", "step": "5m" }),
+        json!({ "by": ["host"], "without": ["env"] }),
+        json!({ "by": ["not-a-label"] }),
+        json!({ "start": origin.to_rfc3339(), "end": (origin - Duration::hours(1)).to_rfc3339() }),
+        json!({ "aggregation": { "percentile": 120 } }),
+        json!({ "values": [{ "op": "like", "value": 1 }] }),
+        json!({ "metric": "cpu", "unknown": true }),
+    ] {
+        let resp = test::call_service(&app, query(invalid.clone())).await;
+        assert_eq!(resp.status(), 400, "{invalid}");
+    }
+}
+
+#[actix_rt::test]
+async fn test_explain_query() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let explain = |prompt: &str| {
+        let url =
+            reqwest::Url::parse_with_params("http://localhost/query/explain", [("prompt", prompt)])
+                .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+
+    let body: Value = test::call_and_read_body_json(
+        &app,
+        explain("Show p99 latency metrics by host last 1 hours frobnicate"),
+    )
+    .await;
+
+    let parsed = &body["parsed"];
+    assert_eq!(parsed["metric_name"], "latency");
+    assert_eq!(parsed["aggregation"], json!({ "percentile": 99.0 }));
+    assert_eq!(parsed["grouping"], json!({ "by": ["host"] }));
+    assert_eq!(parsed["limit"], Value::Null);
+
+    // Resolved to exact absolute bounds
+    let bound = |key: &str| {
+        DateTime::parse_from_rfc3339(parsed["time_range"][key].as_str().unwrap()).unwrap()
+    };
+    assert_eq!(bound("end") - bound("start"), Duration::hours(1));
+
+    let matched: Vec<(&str, &str)> = body["matches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|rule| {
+            (
+                rule["part"].as_str().unwrap(),
+                rule["text"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        matched,
+        [
+            ("aggregation", "p99"),
+            ("metric_name", "latency metrics"),
+            ("grouping", "by host"),
+            ("time_range", "last 1 hours"),
+        ]
+    );
+    assert_eq!(body["matches"][0]["rule"], "pNUMBER");
+    assert_eq!(body["matches"][1]["rule"], "NAME metrics");
+    assert_eq!(body["unrecognized"], json!(["frobnicate"]));
+    assert!(body["error"].as_str().unwrap().contains("frobnicate"));
+    assert!(body["confidence"].as_f64().unwrap() < prompt::MIN_CONFIDENCE);
+
+    // Totals per series, then the values themselves for the percentile
+    let mongo = body["mongo"].as_array().unwrap();
+    assert_eq!(mongo.len(), 2);
+    assert_eq!(mongo[0]["operation"], "aggregate");
+    let stage = &mongo[0]["pipeline"][0]["$match"];
+    assert_eq!(stage["name"], "latency");
+    assert!(stage["timestamp"]["$gte"]["$date"].is_string());
+    assert_eq!(
+        mongo[0]["pipeline"][1]["$group"]["_id"]["labels"],
+        json!({ "host": "$labels.host" })
+    );
+    assert_eq!(mongo[1]["operation"], "find");
+
+    let body: Value = test::call_and_read_body_json(&app, explain("top 5 events today")).await;
+    assert_eq!(body["parsed"]["aggregation"], json!({ "top": 5 }));
+    assert_eq!(body["parsed"]["metric_name"], "events");
+    assert_eq!(body["unrecognized"], json!([]));
+    assert!(body.get("error").is_none());
+    assert_eq!(
+        body["mongo"],
+        json!([{
+            "operation": "find",
+            "filter": body["mongo"][0]["filter"],
+            "sort": { "value": -1, "_id": -1 },
+            "limit": 5,
+        }])
+    );
+    assert!(body["mongo"][0]["filter"]["timestamp"]["$lte"]["$date"].is_string());
+
+    // Nothing runs for a limit of 0
+    let body: Value = test::call_and_read_body_json(&app, explain("top 0 events today")).await;
+    assert_eq!(body["parsed"]["aggregation"], json!({ "top": 0 }));
+    assert_eq!(body["mongo"], json!([]));
+}
+
+#[actix_rt::test]
+async fn test_prompt_time_ranges_are_exact() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    // A second before the hour, inside the minute it starts in
+    let now = Utc::now();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            point(
+                "disk_io",
+                1.0,
+                now - Duration::hours(1) - Duration::seconds(1)
+            ),
+            point("disk_io", 2.0, now - Duration::minutes(59)),
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let req = test::TestRequest::get()
+        .uri("/query?prompt=disk_io+metrics+last+1+hours")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let values: Vec<f64> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|metric| metric["value"].as_f64().unwrap())
+        .collect();
+    assert_eq!(values, [2.0]);
+}
+
+#[actix_rt::test]
+async fn test_prompt_grammar_resolves_metric_names() {
+    let catalog: Vec<String> = [
+        "cpu_usage",
+        "cpu_temp",
+        "memory_usage",
+        "events",
+        "http_requests_total",
+    ]
+    .map(String::from)
+    .to_vec();
+    let parse = |prompt: &str| prompt::parse(prompt, Some(&catalog));
+
+    let query = parse("show me memory").into_query().unwrap();
+    assert_eq!(query.metric_name.as_deref(), Some("memory_usage"));
+    assert_eq!(query.aggregation, None);
+
+    let query = parse("number of http requests last 2 hours")
+        .into_query()
+        .unwrap();
+    assert_eq!(query.metric_name.as_deref(), Some("http_requests_total"));
+    assert_eq!(query.aggregation, Some(AggregationType::Count));
+    assert!(query.time_range.is_some());
+
+    // A typo is still close enough to one name
+    let parsed = parse("memroy_usage by host");
+    assert_eq!(
+        parsed.best.query.metric_name.as_deref(),
+        Some("memory_usage")
+    );
+    assert!(parsed.best.confidence >= prompt::MIN_CONFIDENCE);
+    assert!(parsed.error.is_none());
+
+    let query = parse("top 5 events today").into_query().unwrap();
+    assert_eq!(query.metric_name.as_deref(), Some("events"));
+    assert_eq!(query.aggregation, Some(AggregationType::Top(5)));
+
+    // Several aggregations: the most specific wins, the others are alternatives
+    let parsed = parse("average cpu_usage p95");
+    assert_eq!(
+        parsed.best.query.aggregation,
+        Some(AggregationType::Percentile(95.0))
+    );
+    assert_eq!(
+        parsed.alternatives[0].query.aggregation,
+        Some(AggregationType::Average)
+    );
+
+    let parsed = parse("show me cpu");
+    assert_eq!(
+        parsed.error,
+        Some(PromptError::Ambiguous {
+            phrase: "cpu".to_string(),
+            candidates: vec!["cpu_usage".to_string(), "cpu_temp".to_string()],
+        })
+    );
+    assert_eq!(parsed.alternatives.len(), 1);
+
+    // "number" only counts as "number of", so it is not taken as an aggregation
+    let parsed = parse("cpu_usage number");
+    assert_eq!(parsed.best.query.metric_name.as_deref(), Some("cpu_usage"));
+    assert_eq!(parsed.best.query.aggregation, None);
+    assert_eq!(parsed.unrecognized, ["number"]);
+
+    let parsed = parse("memory_usage summary");
+    assert!(matches!(
+        parsed.error,
+        Some(PromptError::Unrecognized { ref words, .. }) if words == &["summary"]
+    ));
+
+    assert_eq!(parse("").error, Some(PromptError::Empty));
+    assert_eq!(parse("show me").error, Some(PromptError::NoQuery));
+    assert!(parse("show me all metrics").error.is_none());
+
+    // Long prompts are rejected before any name is resolved
+    let words = |count: usize| vec!["memroy"; count].join(" ");
+    assert_eq!(
+        parse(&words(prompt::MAX_PROMPT_WORDS + 1)).error,
+        Some(PromptError::TooLong)
+    );
+    assert_eq!(
+        parse(&"m".repeat(prompt::MAX_PROMPT_CHARS + 1)).error,
+        Some(PromptError::TooLong)
+    );
+
+    // The longest accepted prompt against a large catalog still resolves quickly
+    let large: Vec<String> = (0..1_000).map(|i| format!("metric_{i}_usage")).collect();
+    let started = std::time::Instant::now();
+    let parsed = prompt::parse(&words(prompt::MAX_PROMPT_WORDS), Some(&large));
+    assert!(parsed.error.is_some());
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+    // Without a catalog, words marked as a name are taken as they are
+    let query = prompt::parse("metrics named disk_io", None)
+        .into_query()
+        .unwrap();
+    assert_eq!(query.metric_name.as_deref(), Some("disk_io"));
+}
+
+#[actix_rt::test]
+async fn test_unclear_prompts_are_rejected() {
+    let (telemetry_service, query_service) = in_memory_services();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(telemetry_service))
+            .app_data(web::Data::new(query_service))
+            .configure(routes::configure_routes),
+    )
+    .await;
+
+    let origin = bucket_origin();
+    let req = test::TestRequest::post()
+        .uri("/metrics/batch")
+        .set_json(json!([
+            point("cpu_usage", 10.0, origin),
+            point("cpu_temp", 60.0, origin),
+            point("memory_usage", 30.0, origin),
+        ]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+    let query = |prompt: &str| {
+        let url = reqwest::Url::parse_with_params("http://localhost/query", [("prompt", prompt)])
+            .unwrap();
+        test::TestRequest::get()
+            .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+            .to_request()
+    };
+
+    let body: Value = test::call_and_read_body_json(&app, query("show me memory")).await;
+    assert_eq!(body[0]["name"], "memory_usage");
+
+    let resp = test::call_service(&app, query("show me cpu")).await;
+    assert_eq!(resp.status(), 422);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["reason"], "ambiguous");
+    assert_eq!(body["candidates"], json!(["cpu_temp", "cpu_usage"]));
+    assert!(body["error"].as_str().unwrap().contains("cpu_usage"));
+
+    // Instead of every metric
+    let resp = test::call_service(&app, query("average memroy")).await;
+    assert_eq!(resp.status(), 422);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["reason"], "unrecognized");
+    assert_eq!(body["words"], json!(["memroy"]));
+    assert_eq!(body["suggestions"], json!(["memory_usage"]));
+
+    let resp = test::call_service(&app, query("show me")).await;
+    assert_eq!(resp.status(), 422);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["reason"], "no_query");
+
+    // The explanation reports the alternatives and why the prompt is rejected
+    let url = reqwest::Url::parse_with_params(
+        "http://localhost/query/explain",
+        [("prompt", "show me cpu")],
+    )
+    .unwrap();
+    let req = test::TestRequest::get()
+        .uri(&format!("{}?{}", url.path(), url.query().unwrap()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["parsed"]["metric_name"], "cpu_temp");
+    assert_eq!(body["alternatives"][0]["query"]["metric_name"], "cpu_usage");
+    assert!(body["error"].as_str().unwrap().contains("could mean"));
+}